@@ -0,0 +1,756 @@
+// `archive`: tar (plain or Zstd, Gzip and XZ compressed) and zip archives of whole trees, where
+// the compress commands handle one file at a time. Zip is written and read here directly: stored
+// and deflated entries, Unix modes and the extended timestamp, without Zip64
+use anyhow::{Context, Result, bail, ensure};
+use clap::{Args, Subcommand, ValueEnum};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+use crate::batch::{self, Selector, WalkArgs};
+use crate::compression::{self, Algorithm};
+use crate::{errors, stdio};
+
+#[derive(Args)]
+#[command[name = "archive", about = "Create, list and extract tar and zip archives"]]
+pub struct ArchiveArgs {
+    #[command(subcommand)]
+    command: ArchiveCommand,
+}
+
+#[derive(Subcommand)]
+enum ArchiveCommand {
+    /// Pack files and directory trees into a new archive
+    Create(CreateArgs),
+    /// Print the entries of an archive: mode, size, modification time (UTC) and path
+    List(ListArgs),
+    /// Unpack an archive, refusing entries that would land outside the output directory
+    Extract(ExtractArgs),
+}
+
+#[derive(Args)]
+struct CreateArgs {
+    /// Archive to write, `-` for stdout. Its extension picks the format unless --format
+    output: PathBuf,
+    /// Files and directories to pack. A directory is stored under its own name
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+    /// Archive format. Default: from the output's extension
+    #[arg(short, long, value_enum)]
+    format: Option<Format>,
+    #[arg(short, long, default_value_t = 5)]
+    compression_level: u32,
+    #[command(flatten)]
+    walk: WalkArgs,
+}
+
+#[derive(Args)]
+struct ListArgs {
+    /// Archive to read, `-` for stdin
+    archive: PathBuf,
+    #[command(flatten)]
+    filter: EntryFilter,
+}
+
+#[derive(Args)]
+struct ExtractArgs {
+    /// Archive to read, `-` for stdin
+    archive: PathBuf,
+    /// Directory to unpack into. Default: the archive's directory
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    #[command(flatten)]
+    filter: EntryFilter,
+}
+
+/// Which entries of an archive are listed or extracted
+#[derive(Args)]
+struct EntryFilter {
+    /// Only entries whose path in the archive matches this glob, e.g. "docs/**" (repeatable)
+    #[arg(long)]
+    include: Vec<String>,
+    /// Skip entries whose path in the archive matches this glob (repeatable)
+    #[arg(long)]
+    exclude: Vec<String>,
+}
+
+impl EntryFilter {
+    // Entry paths are relative already, so they are matched against an empty root
+    fn selector(&self) -> Result<Selector> {
+        let walk = WalkArgs { include: self.include.clone(), exclude: self.exclude.clone(), ..Default::default() };
+        Selector::new(Path::new(""), &walk, true)
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum, Debug, PartialEq)]
+enum Format {
+    Tar,
+    TarZst,
+    TarGz,
+    TarXz,
+    Zip,
+}
+
+impl Format {
+    fn from_name(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+        [
+            (".tar", Format::Tar),
+            (".tar.zst", Format::TarZst),
+            (".tzst", Format::TarZst),
+            (".tar.gz", Format::TarGz),
+            (".tgz", Format::TarGz),
+            (".tar.xz", Format::TarXz),
+            (".txz", Format::TarXz),
+            (".zip", Format::Zip),
+        ]
+        .into_iter()
+        .find(|(ext, _)| name.ends_with(ext))
+        .map(|(_, format)| format)
+    }
+
+    const fn algorithm(self) -> Option<Algorithm> {
+        match self {
+            Format::TarZst => Some(Algorithm::Zstd),
+            Format::TarGz => Some(Algorithm::Gzip),
+            Format::TarXz => Some(Algorithm::Xz),
+            Format::Tar | Format::Zip => None,
+        }
+    }
+}
+
+// One archive entry as listed
+struct Entry {
+    path: String,
+    size: u64,
+    mode: Option<u32>,
+    mtime: u64,
+    dir: bool,
+}
+
+pub fn archive(a: ArchiveArgs) -> Result<()> {
+    match a.command {
+        ArchiveCommand::Create(a) => create(a),
+        ArchiveCommand::List(a) => list(a),
+        ArchiveCommand::Extract(a) => extract(a),
+    }
+}
+
+// CREATE
+fn create(a: CreateArgs) -> Result<()> {
+    let format = match a.format.or_else(|| Format::from_name(&a.output)) {
+        Some(format) => format,
+        None if stdio::is_dash(&a.output) => bail!("pass --format to write an archive to stdout"),
+        None => bail!("cannot tell the archive format from {}; pass --format", a.output.display()),
+    };
+    if !stdio::check_output(&a.output)? {
+        return Ok(());
+    }
+
+    // (source, name in the archive), directories under their own name like tar does
+    let mut files = Vec::new();
+    for input in &a.inputs {
+        stdio::ensure_not_dash(input, "archive create inputs")?;
+        let meta = fs::metadata(input).with_context(|| format!("Cannot find: {}", input.display()))?;
+        let base = fs::canonicalize(input)?.file_name().map(PathBuf::from).unwrap_or_default();
+        if meta.is_dir() {
+            for path in batch::collect(input, &a.walk, true, |_| true)? {
+                let name = base.join(path.strip_prefix(input).unwrap_or(&path));
+                files.push((path, entry_name(&name)));
+            }
+        } else {
+            files.push((input.clone(), entry_name(&base)));
+        }
+    }
+
+    let output = stdio::output(&a.output, "", true)?;
+    let bar = crate::progress::files(files.len(), "Archiving");
+    stdio::write_atomic(output.path(), |temp| {
+        if format == Format::Zip {
+            let mut zip = ZipWriter::new(BufWriter::new(File::create(temp)?));
+            for (path, name) in &files {
+                debug!("Adding {}", name);
+                zip.add_file(path, name, a.compression_level)
+                    .with_context(|| format!("add {}", path.display()))?;
+                bar.inc(1);
+            }
+            zip.finish()?.flush()?;
+        } else {
+            let out = BufWriter::new(File::create(temp)?);
+            let mut builder = tar::Builder::new(TarWriter::new(out, format.algorithm(), a.compression_level)?);
+            for (path, name) in &files {
+                debug!("Adding {}", name);
+                builder.append_path_with_name(path, name)
+                    .with_context(|| format!("add {}", path.display()))?;
+                bar.inc(1);
+            }
+            builder.into_inner()?.finish()?.flush()?;
+        }
+        Ok(())
+    })?;
+    bar.finish_and_clear();
+    output.finish()?;
+    info!("Wrote archive: {} ({} files)", stdio::display(&a.output), files.len());
+    Ok(())
+}
+
+// Forward slashes whatever the platform, as both formats expect
+fn entry_name(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+// A tar stream, compressed on the way out
+enum TarWriter<W: Write> {
+    Plain(W),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+    Gzip(flate2::write::GzEncoder<W>),
+    Xz(xz2::write::XzEncoder<W>),
+}
+
+impl<W: Write> TarWriter<W> {
+    fn new(out: W, algorithm: Option<Algorithm>, level: u32) -> Result<Self> {
+        Ok(match algorithm {
+            None => TarWriter::Plain(out),
+            Some(Algorithm::Zstd) => TarWriter::Zstd(zstd::stream::write::Encoder::new(out, level as i32)?),
+            Some(Algorithm::Gzip) => TarWriter::Gzip(flate2::write::GzEncoder::new(out, flate2::Compression::new(level.min(9)))),
+            Some(Algorithm::Xz) => TarWriter::Xz(xz2::write::XzEncoder::new(out, level.min(9))),
+            Some(other) => bail!("tar archives are not written with {:?}", other),
+        })
+    }
+
+    fn finish(self) -> Result<W> {
+        Ok(match self {
+            TarWriter::Plain(w) => w,
+            TarWriter::Zstd(e) => e.finish()?,
+            TarWriter::Gzip(e) => e.finish()?,
+            TarWriter::Xz(e) => e.finish()?,
+        })
+    }
+}
+
+impl<W: Write> Write for TarWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TarWriter::Plain(w) => w.write(buf),
+            TarWriter::Zstd(e) => e.write(buf),
+            TarWriter::Gzip(e) => e.write(buf),
+            TarWriter::Xz(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TarWriter::Plain(w) => w.flush(),
+            TarWriter::Zstd(e) => e.flush(),
+            TarWriter::Gzip(e) => e.flush(),
+            TarWriter::Xz(e) => e.flush(),
+        }
+    }
+}
+
+// LIST / EXTRACT
+// Stdin is spooled, zip needs to seek to its central directory
+enum Opened {
+    Zip(Vec<ZipEntry>, File),
+    Tar(Box<dyn Read>),
+}
+
+fn open(path: &Path) -> Result<Opened> {
+    let mut file = File::open(path).with_context(|| errors::at("open", path))?;
+    let mut magic = [0u8; 6];
+    let n = file.read(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+    if magic[..n].starts_with(b"PK\x03\x04") || magic[..n].starts_with(b"PK\x05\x06") {
+        let entries = read_zip(&mut file).with_context(|| format!("read zip {}", stdio::display(path)))?;
+        return Ok(Opened::Zip(entries, file));
+    }
+    let reader = BufReader::new(file);
+    Ok(Opened::Tar(match compression::magic_algorithm(&magic[..n]) {
+        Some(algorithm) => compression::decompress_reader(reader, algorithm)?,
+        None => Box::new(reader),
+    }))
+}
+
+fn list(a: ListArgs) -> Result<()> {
+    let selector = a.filter.selector()?;
+    let input = stdio::input(&a.archive)?;
+    let mut out = io::stdout().lock();
+    let mut print = |entry: Entry| -> Result<()> {
+        if selector.matches(Path::new(&entry.path)) {
+            writeln!(out, "{} {:>12} {} {}", mode_string(entry.dir, entry.mode), entry.size, format_time(entry.mtime), entry.path)?;
+        }
+        Ok(())
+    };
+    match open(input.path())? {
+        Opened::Zip(entries, _) => {
+            for e in entries {
+                print(Entry { dir: e.name.ends_with('/'), path: e.name, size: e.size, mode: e.mode, mtime: e.mtime })?;
+            }
+        }
+        Opened::Tar(reader) => {
+            let mut archive = tar::Archive::new(reader);
+            for entry in archive.entries().context("read tar entries")? {
+                let entry = entry.context("invalid tar entry")?;
+                let header = entry.header();
+                print(Entry {
+                    path: entry.path()?.to_string_lossy().into_owned(),
+                    size: header.size()?,
+                    mode: header.mode().ok(),
+                    mtime: header.mtime().unwrap_or(0),
+                    dir: header.entry_type().is_dir(),
+                })?;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn extract(a: ExtractArgs) -> Result<()> {
+    let selector = a.filter.selector()?;
+    let dest = match &a.output {
+        Some(out) => out.clone(),
+        None if stdio::is_dash(&a.archive) => PathBuf::from("."),
+        None => a.archive.parent().unwrap_or(Path::new("")).to_path_buf(),
+    };
+    stdio::ensure_not_dash(&dest, "archive extract --output")?;
+    fs::create_dir_all(&dest).with_context(|| errors::at("create", &dest))?;
+    let input = stdio::input(&a.archive)?;
+
+    let mut count = 0usize;
+    match open(input.path())? {
+        Opened::Zip(entries, mut file) => {
+            let root = fs::canonicalize(&dest)?;
+            for entry in &entries {
+                let Some(rel) = safe_path(&entry.name)? else { continue };
+                if !selector.matches(&rel) {
+                    continue;
+                }
+                let target = dest.join(&rel);
+                if entry.name.ends_with('/') {
+                    fs::create_dir_all(&target).with_context(|| errors::at("create", &target))?;
+                    continue;
+                }
+                if entry.mode.is_some_and(|m| m & 0o170000 == 0o120000) {
+                    warn!("Skipping symlink entry: {}", entry.name);
+                    continue;
+                }
+                if !check_target(&target, &root)? {
+                    continue;
+                }
+                extract_zip_entry(&mut file, entry, &target).with_context(|| format!("extract {}", entry.name))?;
+                count += 1;
+            }
+        }
+        Opened::Tar(reader) => {
+            // Permissions are kept without setuid, setgid and sticky bits, which an archive from
+            // elsewhere has no business setting
+            let mut archive = tar::Archive::new(reader);
+            archive.set_preserve_permissions(false);
+            let root = fs::canonicalize(&dest)?;
+            for entry in archive.entries().context("read tar entries")? {
+                let mut entry = entry.context("invalid tar entry")?;
+                let name = entry.path()?.to_string_lossy().into_owned();
+                let Some(rel) = safe_path(&name)? else { continue };
+                if !selector.matches(&rel) {
+                    continue;
+                }
+                let is_file = !entry.header().entry_type().is_dir();
+                if is_file && !check_target(&dest.join(&rel), &root)? {
+                    continue;
+                }
+                // Also refuses links that would lead the entry out of `dest`
+                entry.unpack_in(&dest).with_context(|| format!("extract {}", name))?;
+                count += usize::from(is_file);
+            }
+        }
+    }
+    info!("Extracted {} file(s) to {}", count, dest.display());
+    Ok(())
+}
+
+// The relative path an entry unpacks to, or None for the archive root itself. Absolute paths and
+// `..` are refused rather than skipped, an archive carrying them is not to be trusted
+fn safe_path(name: &str) -> Result<Option<PathBuf>> {
+    let mut rel = PathBuf::new();
+    for component in Path::new(&name.replace('\\', "/")).components() {
+        match component {
+            Component::Normal(part) => rel.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                bail!("refusing entry outside the output directory: {}", name)
+            }
+        }
+    }
+    Ok((!rel.as_os_str().is_empty()).then_some(rel))
+}
+
+// Whether to extract to `target`: an existing file is replaced or skipped as the overwrite flags
+// say, and nothing is written through a symlinked directory that leads out of `root`. A file being
+// replaced is removed first, so a symlink or hard link there is not written through
+fn check_target(target: &Path, root: &Path) -> Result<bool> {
+    if !stdio::check_output_or(target, stdio::Clobber::Refuse)? {
+        return Ok(false);
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).with_context(|| errors::at("create", parent))?;
+        if !fs::canonicalize(parent)?.starts_with(root) {
+            bail!("refusing to write through a link outside the output directory: {}", target.display());
+        }
+    }
+    if fs::symlink_metadata(target).is_ok_and(|m| !m.is_dir()) {
+        fs::remove_file(target).with_context(|| errors::at("replace", target))?;
+    }
+    Ok(true)
+}
+
+pub(crate) fn mode_string(dir: bool, mode: Option<u32>) -> String {
+    let Some(mode) = mode else {
+        return if dir { "d?????????".to_string() } else { "-?????????".to_string() };
+    };
+    let mut s = String::from(if dir { "d" } else { "-" });
+    for shift in [6, 3, 0] {
+        let bits = (mode >> shift) & 0o7;
+        s.push(if bits & 4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 2 != 0 { 'w' } else { '-' });
+        s.push(if bits & 1 != 0 { 'x' } else { '-' });
+    }
+    s
+}
+
+fn format_time(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, rem / 3600, rem % 3600 / 60)
+}
+
+// Days since 1970-01-01 to a proleptic Gregorian date, and back
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// ZIP
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL: u32 = 0x0605_4b50;
+// Extended timestamp extra field: flags, then the modification time in Unix seconds
+const EXTENDED_TIME: u16 = 0x5455;
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+struct ZipEntry {
+    name: String,
+    method: u16,
+    flags: u16,
+    crc: u32,
+    compressed: u64,
+    size: u64,
+    mode: Option<u32>,
+    mtime: u64,
+    offset: u64,
+}
+
+struct ZipWriter<W: Write + Seek> {
+    out: W,
+    entries: Vec<ZipEntry>,
+}
+
+impl<W: Write + Seek> ZipWriter<W> {
+    fn new(out: W) -> Self {
+        ZipWriter { out, entries: Vec::new() }
+    }
+
+    // Deflates the file at `path` as `name`. The local header is written ahead of the data and
+    // patched with the CRC and sizes once they are known
+    fn add_file(&mut self, path: &Path, name: &str, level: u32) -> Result<()> {
+        let meta = fs::metadata(path)?;
+        let mtime = meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs());
+        let mut entry = ZipEntry {
+            name: name.to_string(),
+            method: DEFLATED,
+            flags: 0x0800, // UTF-8 names
+            crc: 0,
+            compressed: 0,
+            size: 0,
+            mode: Some(file_mode(&meta)),
+            mtime,
+            offset: self.out.stream_position()?,
+        };
+        write_local_header(&mut self.out, &entry)?;
+        let data_start = self.out.stream_position()?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut encoder = flate2::write::DeflateEncoder::new(&mut self.out, flate2::Compression::new(level.min(9)));
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut buffer = vec![0u8; 1 << 16];
+        loop {
+            let n = reader.read(&mut buffer)?;
+            if n == 0 { break }
+            hasher.update(&buffer[..n]);
+            encoder.write_all(&buffer[..n])?;
+            entry.size += n as u64;
+        }
+        encoder.finish()?;
+        let end = self.out.stream_position()?;
+        entry.crc = hasher.finalize();
+        entry.compressed = end - data_start;
+        if entry.size > u32::MAX as u64 || entry.compressed > u32::MAX as u64 || entry.offset > u32::MAX as u64 {
+            bail!("zip archives over 4 GiB need Zip64, which is not supported; use a tar format");
+        }
+
+        self.out.seek(SeekFrom::Start(entry.offset + 14))?;
+        self.out.write_all(&entry.crc.to_le_bytes())?;
+        self.out.write_all(&(entry.compressed as u32).to_le_bytes())?;
+        self.out.write_all(&(entry.size as u32).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(end))?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<W> {
+        if self.entries.len() > u16::MAX as usize {
+            bail!("zip archives of more than {} entries need Zip64, which is not supported; use a tar format", u16::MAX);
+        }
+        let start = self.out.stream_position()?;
+        for e in &self.entries {
+            let (time, date) = dos_time(e.mtime);
+            let w = &mut self.out;
+            w.write_all(&CENTRAL_HEADER.to_le_bytes())?;
+            w.write_all(&((3u16 << 8) | 20).to_le_bytes())?; // made by Unix, spec 2.0
+            w.write_all(&20u16.to_le_bytes())?;
+            w.write_all(&e.flags.to_le_bytes())?;
+            w.write_all(&e.method.to_le_bytes())?;
+            w.write_all(&time.to_le_bytes())?;
+            w.write_all(&date.to_le_bytes())?;
+            w.write_all(&e.crc.to_le_bytes())?;
+            w.write_all(&(e.compressed as u32).to_le_bytes())?;
+            w.write_all(&(e.size as u32).to_le_bytes())?;
+            w.write_all(&(e.name.len() as u16).to_le_bytes())?;
+            w.write_all(&9u16.to_le_bytes())?;
+            w.write_all(&[0u8; 6])?; // comment length, disk, internal attributes
+            w.write_all(&(e.mode.unwrap_or(0) << 16).to_le_bytes())?;
+            w.write_all(&(e.offset as u32).to_le_bytes())?;
+            w.write_all(e.name.as_bytes())?;
+            write_extended_time(w, e.mtime)?;
+        }
+        let end = self.out.stream_position()?;
+        if end > u32::MAX as u64 {
+            bail!("zip archives over 4 GiB need Zip64, which is not supported; use a tar format");
+        }
+        let count = (self.entries.len() as u16).to_le_bytes();
+        let w = &mut self.out;
+        w.write_all(&END_OF_CENTRAL.to_le_bytes())?;
+        w.write_all(&[0u8; 4])?; // this disk, disk with the directory
+        w.write_all(&count)?;
+        w.write_all(&count)?;
+        w.write_all(&((end - start) as u32).to_le_bytes())?;
+        w.write_all(&(start as u32).to_le_bytes())?;
+        w.write_all(&0u16.to_le_bytes())?;
+        Ok(self.out)
+    }
+}
+
+fn write_local_header(w: &mut impl Write, e: &ZipEntry) -> Result<()> {
+    let (time, date) = dos_time(e.mtime);
+    w.write_all(&LOCAL_HEADER.to_le_bytes())?;
+    w.write_all(&20u16.to_le_bytes())?;
+    w.write_all(&e.flags.to_le_bytes())?;
+    w.write_all(&e.method.to_le_bytes())?;
+    w.write_all(&time.to_le_bytes())?;
+    w.write_all(&date.to_le_bytes())?;
+    w.write_all(&[0u8; 12])?; // CRC and sizes, patched after the data
+    w.write_all(&(e.name.len() as u16).to_le_bytes())?;
+    w.write_all(&9u16.to_le_bytes())?;
+    w.write_all(e.name.as_bytes())?;
+    write_extended_time(w, e.mtime)?;
+    Ok(())
+}
+
+fn write_extended_time(w: &mut impl Write, mtime: u64) -> Result<()> {
+    w.write_all(&EXTENDED_TIME.to_le_bytes())?;
+    w.write_all(&5u16.to_le_bytes())?;
+    w.write_all(&[1])?;
+    w.write_all(&(mtime.min(u32::MAX as u64) as u32).to_le_bytes())?;
+    Ok(())
+}
+
+// Type and permission bits as Unix stores them, made up from the read-only flag elsewhere
+fn file_mode(meta: &fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        meta.permissions().mode()
+    }
+    #[cfg(not(unix))]
+    {
+        0o100000 | if meta.permissions().readonly() { 0o444 } else { 0o644 }
+    }
+}
+
+// MS-DOS time and date fields, in UTC since zip has no time zone; clamped to 1980, their epoch
+fn dos_time(secs: u64) -> (u16, u16) {
+    let min = days_from_civil(1980, 1, 1) as u64 * 86_400;
+    let secs = secs.max(min);
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    let time = ((rem / 3600) << 11) | ((rem % 3600 / 60) << 5) | (rem % 60 / 2);
+    let date = ((year.min(2107) - 1980) << 9) as u32 | (month << 5) | day;
+    (time as u16, date as u16)
+}
+
+fn from_dos_time(time: u16, date: u16) -> u64 {
+    let days = days_from_civil(1980 + i64::from(date >> 9), u32::from((date >> 5) & 0xF).max(1), u32::from(date & 0x1F).max(1));
+    let secs = u64::from(time >> 11) * 3600 + u64::from((time >> 5) & 0x3F) * 60 + u64::from(time & 0x1F) * 2;
+    days.max(0) as u64 * 86_400 + secs
+}
+
+// Entries from the central directory at the end of the file
+fn read_zip(file: &mut File) -> Result<Vec<ZipEntry>> {
+    // The end record is 22 bytes plus a comment of up to 64 KiB
+    let len = file.seek(SeekFrom::End(0))?;
+    let tail_len = len.min(22 + u16::MAX as u64);
+    file.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = vec![0u8; tail_len as usize];
+    file.read_exact(&mut tail)?;
+    let end = (0..tail.len().saturating_sub(21)).rev()
+        .find(|&i| tail[i..i + 4] == END_OF_CENTRAL.to_le_bytes())
+        .context("no end of central directory record, not a zip archive or truncated")?;
+    let eocd = &tail[end..];
+    let count = u16le(eocd, 10);
+    let dir_size = u32le(eocd, 12);
+    let dir_offset = u32le(eocd, 16);
+    if count == u16::MAX || dir_size == u32::MAX || dir_offset == u32::MAX {
+        bail!("Zip64 archives are not supported");
+    }
+
+    // Checked before the directory is read into memory, so a corrupt size allocates nothing
+    ensure!(u64::from(dir_offset) + u64::from(dir_size) <= len, "truncated central directory");
+    file.seek(SeekFrom::Start(u64::from(dir_offset)))?;
+    let mut dir = vec![0u8; dir_size as usize];
+    file.read_exact(&mut dir).context("truncated central directory")?;
+    let mut entries = Vec::with_capacity(count as usize);
+    let mut pos = 0;
+    for _ in 0..count {
+        if dir.len() < pos + 46 || u32le(&dir, pos) != CENTRAL_HEADER {
+            bail!("corrupt central directory");
+        }
+        let h = &dir[pos..];
+        let made_by = u16le(h, 4) >> 8;
+        let (name_len, extra_len, comment_len) = (u16le(h, 28) as usize, u16le(h, 30) as usize, u16le(h, 32) as usize);
+        if h.len() < 46 + name_len + extra_len {
+            bail!("corrupt central directory");
+        }
+        let name = String::from_utf8_lossy(&h[46..46 + name_len]).into_owned();
+        let extra = &h[46 + name_len..46 + name_len + extra_len];
+        let attributes = u32le(h, 38);
+        let (compressed, size, offset) = (u32le(h, 20), u32le(h, 24), u32le(h, 42));
+        if compressed == u32::MAX || size == u32::MAX || offset == u32::MAX {
+            bail!("Zip64 archives are not supported");
+        }
+        entries.push(ZipEntry {
+            name,
+            method: u16le(h, 10),
+            flags: u16le(h, 8),
+            crc: u32le(h, 16),
+            compressed: u64::from(compressed),
+            size: u64::from(size),
+            // Only archives made on Unix carry a mode in the upper attribute bits
+            mode: (made_by == 3 && attributes >> 16 != 0).then_some(attributes >> 16),
+            mtime: extended_time(extra).unwrap_or_else(|| from_dos_time(u16le(h, 12), u16le(h, 14))),
+            offset: u64::from(offset),
+        });
+        pos += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+fn extended_time(mut extra: &[u8]) -> Option<u64> {
+    while extra.len() >= 4 {
+        let (id, len) = (u16le(extra, 0), u16le(extra, 2) as usize);
+        let data = extra.get(4..4 + len)?;
+        if id == EXTENDED_TIME && len >= 5 && data[0] & 1 != 0 {
+            return Some(u64::from(u32le(data, 1)));
+        }
+        extra = &extra[4 + len..];
+    }
+    None
+}
+
+fn extract_zip_entry(file: &mut File, e: &ZipEntry, target: &Path) -> Result<()> {
+    if e.flags & 1 != 0 {
+        bail!("encrypted entries are not supported");
+    }
+    let mut header = [0u8; 30];
+    file.seek(SeekFrom::Start(e.offset))?;
+    file.read_exact(&mut header)?;
+    if u32le(&header, 0) != LOCAL_HEADER {
+        bail!("corrupt local header");
+    }
+    let skip = u16le(&header, 26) as i64 + u16le(&header, 28) as i64;
+    file.seek(SeekFrom::Current(skip))?;
+
+    let data = BufReader::new(&mut *file).take(e.compressed);
+    let reader: Box<dyn Read> = match e.method {
+        STORED => Box::new(data),
+        DEFLATED => Box::new(flate2::read::DeflateDecoder::new(data)),
+        other => bail!("compression method {} is not supported", other),
+    };
+    // Written aside and moved into place only once its size and CRC check out, so a corrupt
+    // entry leaves nothing behind. One byte past the declared size is enough to tell that an
+    // entry inflates to more, so a zip bomb stops there
+    let mut reader = reader.take(e.size + 1);
+    let mut out = stdio::AtomicFile::create(target)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0u8; 1 << 16];
+    let mut written = 0u64;
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 { break }
+        hasher.update(&buffer[..n]);
+        out.write_all(&buffer[..n]).with_context(|| errors::at("write", target))?;
+        written += n as u64;
+    }
+    if written != e.size {
+        bail!("size mismatch: {} bytes where the directory says {}", written, e.size);
+    }
+    if hasher.finalize() != e.crc {
+        bail!("CRC mismatch");
+    }
+
+    out.flush().with_context(|| errors::at("write", target))?;
+    #[cfg(unix)]
+    if let Some(mode) = e.mode {
+        use std::os::unix::fs::PermissionsExt;
+        out.file().set_permissions(fs::Permissions::from_mode(mode & 0o777))?;
+    }
+    out.file().set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(e.mtime))?;
+    out.commit()?;
+    Ok(())
+}
+
+fn u16le(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([b[at], b[at + 1]])
+}
+
+fn u32le(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
@@ -0,0 +1,246 @@
+// Directory inputs, shared by every command with a batch mode: which files a walk picks up, and
+// running a command over them on a pool of workers behind one progress bar
+use anyhow::{Context, Result};
+use clap::Args;
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{debug, error, warn};
+use walkdir::WalkDir;
+use crate::errors;
+use crate::gitignore::Gitignore;
+
+/// Which files under a directory input are picked up
+#[derive(Args, Default)]
+pub struct WalkArgs {
+    /// Only files whose path relative to the input directory matches this glob, e.g. "icons/*.png" (repeatable)
+    #[arg(long)]
+    pub(crate) include: Vec<String>,
+    /// Skip files whose relative path matches this glob (repeatable)
+    #[arg(long)]
+    pub(crate) exclude: Vec<String>,
+    /// Descend at most this many levels below the input directory, 1 for its own files only
+    #[arg(long)]
+    pub(crate) max_depth: Option<usize>,
+    /// Descend into symlinked directories, which are skipped otherwise. Symlinked files are
+    /// always read
+    #[arg(long)]
+    pub(crate) follow_symlinks: bool,
+    /// Skip what the .gitignore files of the input and of its checkout rule out, and .git itself
+    #[arg(long)]
+    pub(crate) respect_gitignore: bool,
+}
+
+/// Directory input of a command that processes files one by one. --output becomes the root of a
+/// mirrored tree
+#[derive(Args)]
+pub struct BatchArgs {
+    /// Descend into the subdirectories of a directory input
+    #[arg(short = 'r', long)]
+    pub(crate) recursive: bool,
+    #[command(flatten)]
+    pub(crate) walk: WalkArgs,
+    /// Files processed at once (0 or unset = all cores)
+    #[arg(short = 't', long)]
+    pub(crate) threads: Option<u32>,
+}
+
+impl BatchArgs {
+    // Subdirectories only with -r
+    pub(crate) fn collect(&self, root: &Path, keep: impl Fn(&Path) -> bool) -> Result<Vec<PathBuf>> {
+        collect(root, &self.walk, self.recursive, keep)
+    }
+}
+
+// Files under `root` in name order, filtered by the globs and then by `keep`. Without
+// `recursive` only the files directly in `root`. Symlinks to files are included either way, and
+// symlinked directories only descended into when followed. Unreadable entries, dangling links and
+// symlink loops are logged and skipped
+pub(crate) fn collect(root: &Path, walk: &WalkArgs, recursive: bool, keep: impl Fn(&Path) -> bool) -> Result<Vec<PathBuf>> {
+    let selector = Selector::new(root, walk, recursive)?;
+    let mut walker = WalkDir::new(root).follow_links(walk.follow_symlinks).sort_by_file_name();
+    match (recursive, walk.max_depth) {
+        (true, Some(depth)) => walker = walker.max_depth(depth),
+        (true, None) => {}
+        (false, _) => walker = walker.max_depth(1),
+    }
+
+    let mut files = Vec::new();
+    // Ignored directories are not descended into at all
+    for entry in walker.into_iter().filter_entry(|entry| entry.depth() == 0 || !entry.file_type().is_dir() || !selector.prunes(entry.path())) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!("Skipping: {}", err);
+                continue;
+            }
+        };
+        // Followed links report their target's type, unfollowed ones stay symlinks
+        let path = entry.path();
+        let is_file = if entry.file_type().is_symlink() {
+            match std::fs::metadata(path) {
+                Ok(meta) => meta.is_file(),
+                Err(err) => {
+                    warn!("Skipping {}: {}", path.display(), err);
+                    false
+                }
+            }
+        } else {
+            entry.file_type().is_file()
+        };
+        if !is_file { continue }
+        if !selector.matches(path) { continue }
+        if keep(path) {
+            files.push(path.to_path_buf());
+        }
+    }
+    debug!("Batch of {} file(s) in {}", files.len(), root.display());
+    Ok(files)
+}
+
+// The filters of a walk applied to single paths, for files found other than by walking
+pub(crate) struct Selector {
+    root: PathBuf,
+    include: Option<globset::GlobSet>,
+    exclude: globset::GlobSet,
+    max_depth: Option<usize>,
+    gitignore: Option<Gitignore>,
+}
+
+impl Selector {
+    pub(crate) fn new(root: &Path, walk: &WalkArgs, recursive: bool) -> Result<Self> {
+        Ok(Selector {
+            root: root.to_path_buf(),
+            include: if walk.include.is_empty() { None } else { Some(globs(&walk.include)?) },
+            exclude: globs(&walk.exclude)?,
+            max_depth: if recursive { walk.max_depth } else { Some(1) },
+            gitignore: if walk.respect_gitignore { Some(Gitignore::new(root)?) } else { None },
+        })
+    }
+
+    // Depth, globs and ignore rules only, so this also answers for files that are gone
+    pub(crate) fn matches(&self, path: &Path) -> bool {
+        let Ok(rel) = path.strip_prefix(&self.root) else { return false };
+        if self.max_depth.is_some_and(|depth| rel.components().count() > depth) {
+            return false;
+        }
+        if self.gitignore.as_ref().is_some_and(|gitignore| gitignore.ignored(rel, false)) {
+            return false;
+        }
+        let rel = rel.to_string_lossy().replace('\\', "/");
+        self.include.as_ref().is_none_or(|include| include.is_match(&rel)) && !self.exclude.is_match(&rel)
+    }
+
+    // Whether the directory at `path` is ignored, and with it everything below
+    fn prunes(&self, path: &Path) -> bool {
+        let Ok(rel) = path.strip_prefix(&self.root) else { return false };
+        self.gitignore.as_ref().is_some_and(|gitignore| gitignore.ignored(rel, true))
+    }
+
+    // Whether a walk would have picked up the file at `path`, a symlink to a file included
+    pub(crate) fn selects(&self, path: &Path) -> bool {
+        std::fs::metadata(path).is_ok_and(|m| m.is_file()) && self.matches(path)
+    }
+}
+
+fn globs(patterns: &[String]) -> Result<globset::GlobSet> {
+    let mut builder = globset::GlobSetBuilder::new();
+    for p in patterns {
+        builder.add(globset::Glob::new(p).with_context(|| format!("invalid glob '{}'", p))?);
+    }
+    Ok(builder.build()?)
+}
+
+// `f` over every item on a pool of `threads` workers, results in input order whatever order the
+// workers finished in. Without `keep_going`, items not started yet once one fails are None
+pub(crate) fn map<I: Sync, T: Send>(
+    items: &[I],
+    threads: Option<u32>,
+    keep_going: bool,
+    what: &str,
+    f: impl Fn(&I) -> Result<T> + Sync,
+) -> Result<Vec<Option<Result<T>>>> {
+    let stop = AtomicBool::new(false);
+    let bar = crate::progress::files(items.len(), what);
+    let results = pool(threads)?.install(|| {
+        items.par_iter().map(|item| {
+            if stop.load(Ordering::Relaxed) {
+                return None;
+            }
+            let result = f(item);
+            if result.is_err() && !keep_going {
+                stop.store(true, Ordering::Relaxed);
+            }
+            bar.inc(1);
+            Some(result)
+        }).collect()
+    });
+    bar.finish_and_clear();
+    Ok(results)
+}
+
+pub(crate) fn pool(threads: Option<u32>) -> Result<rayon::ThreadPool> {
+    Ok(rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.unwrap_or(0) as usize)
+        .build()?)
+}
+
+// Outcome of a batch: what was written, what failed and why, and how many files were never
+// started because the batch stopped at the first failure
+pub(crate) struct Report {
+    pub(crate) succeeded: Vec<PathBuf>,
+    pub(crate) failed: Vec<(PathBuf, anyhow::Error)>,
+    pub(crate) skipped: usize,
+}
+
+impl Report {
+    pub(crate) fn counts(&self) -> String {
+        let total = self.succeeded.len() + self.failed.len() + self.skipped;
+        let mut line = format!("Processed {} file(s): {} succeeded, {} failed", total, self.succeeded.len(), self.failed.len());
+        if self.skipped > 0 {
+            line.push_str(&format!(", {} skipped", self.skipped));
+        }
+        line
+    }
+
+    pub(crate) fn log_failures(&self) {
+        for (path, e) in &self.failed {
+            error!("Failed: {}: {:#}", path.display(), e);
+        }
+    }
+
+    pub(crate) fn into_result(self) -> Result<()> {
+        if !self.failed.is_empty() {
+            return Err(errors::Batch(self.failed).into());
+        }
+        Ok(())
+    }
+}
+
+// `map` over files, for commands whose work is the files they write
+pub(crate) fn process(
+    files: &[PathBuf],
+    threads: Option<u32>,
+    keep_going: bool,
+    what: &str,
+    f: impl Fn(&Path) -> Result<()> + Sync,
+) -> Result<Report> {
+    let results = map(files, threads, keep_going, what, |path| f(path))?;
+    let mut report = Report { succeeded: Vec::new(), failed: Vec::new(), skipped: 0 };
+    for (path, result) in files.iter().zip(results) {
+        match result {
+            Some(Ok(())) => report.succeeded.push(path.clone()),
+            Some(Err(e)) => report.failed.push((path.clone(), e)),
+            None => report.skipped += 1,
+        }
+    }
+    Ok(report)
+}
+
+// Keeps going past failures, which are logged at the end before the counts line on stdout
+pub(crate) fn run(files: &[PathBuf], threads: Option<u32>, f: impl Fn(&Path) -> Result<()> + Sync) -> Result<()> {
+    let report = process(files, threads, true, "Processing", f)?;
+    report.log_failures();
+    println!("{}", report.counts());
+    report.into_result()
+}
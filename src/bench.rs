@@ -0,0 +1,273 @@
+// `bench`: how fast the toolkit's building blocks run on this machine, to pick settings by: hash
+// throughput per algorithm, compression ratio and speed per algorithm and level, Argon2id time
+// per cost setting, and image resizing per filter. Sample data is generated, or taken from a file
+// so compression is measured on what will actually be compressed
+use anyhow::{Context, Result, bail};
+use clap::{Args, ValueEnum};
+use serde_json::{Value, json};
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::info;
+use crate::{compression, crypt, errors, hash, image, split, stdio};
+
+#[derive(Args)]
+#[command[name = "bench", about = "Measure hashing, compression, Argon2 and image resizing speed on this machine"]]
+pub struct BenchArgs {
+    /// Parts to run. Default: all
+    #[arg(long, value_enum, value_delimiter = ',')]
+    only: Vec<Part>,
+    /// Bytes of sample data to hash and compress, with an optional K, M or G suffix
+    #[arg(short, long, value_parser = split::parse_size, default_value = "16M")]
+    size: u64,
+    /// Hash and compress (up to --size of) this file instead of generated text-like data
+    #[arg(short, long)]
+    input: Option<PathBuf>,
+    /// Compression levels to try for the algorithms that take one
+    #[arg(short = 'c', long, value_delimiter = ',', default_values_t = [5])]
+    levels: Vec<u32>,
+}
+
+#[derive(Clone, Copy, ValueEnum, Debug, PartialEq)]
+pub enum Part {
+    Hash,
+    Compression,
+    Argon2,
+    Image,
+}
+
+// Each measurement repeats until it has run this long, so short runs are not dominated by noise
+const MIN_TIME: Duration = Duration::from_millis(300);
+
+// Argon2id settings timed: encrypt's default, then the OWASP alternatives with more memory
+const KDF_SETTINGS: [crypt::KdfParams; 3] = [
+    crypt::KdfParams { m_cost_kib: 19_456, t_cost: 2, p_cost: 1 },
+    crypt::KdfParams { m_cost_kib: 65_536, t_cost: 3, p_cost: 1 },
+    crypt::KdfParams { m_cost_kib: 262_144, t_cost: 4, p_cost: 1 },
+];
+
+// Image resized: a 24-megapixel photo to a 1920-pixel-wide preview
+const IMAGE_SIZE: (u32, u32) = (6000, 4000);
+const RESIZED: (u32, u32) = (1920, 1280);
+
+pub fn bench(a: BenchArgs) -> Result<()> {
+    if a.size == 0 {
+        bail!("--size must be at least 1 byte");
+    }
+    let run = |part: Part| a.only.is_empty() || a.only.contains(&part);
+    let data = match &a.input {
+        Some(path) => {
+            let mut data = Vec::new();
+            stdio::reader(path)?.take(a.size).read_to_end(&mut data).with_context(|| errors::at("read", path))?;
+            if data.is_empty() {
+                bail!("{} is empty", stdio::display(path));
+            }
+            data
+        }
+        None => sample_text(a.size as usize),
+    };
+    let source = a.input.as_deref().map_or_else(|| "generated text".to_string(), stdio::display);
+
+    let mut report = serde_json::Map::new();
+    report.insert("threads".into(), json!(std::thread::available_parallelism().map_or(1, |n| n.get())));
+    report.insert("data".into(), json!({ "source": source, "bytes": data.len() }));
+    if run(Part::Hash) {
+        report.insert("hash".into(), bench_hash(&data)?);
+    }
+    if run(Part::Compression) {
+        report.insert("compression".into(), bench_compression(&data, &a.levels)?);
+    }
+    if run(Part::Argon2) {
+        report.insert("argon2".into(), bench_argon2()?);
+    }
+    if run(Part::Image) {
+        report.insert("image_resize".into(), bench_image());
+    }
+
+    if stdio::json() {
+        println!("{}", serde_json::to_string_pretty(&Value::Object(report))?);
+        return Ok(());
+    }
+    print_tables(&report, &source, data.len());
+    Ok(())
+}
+
+// Mean time per run of `f`, repeated for at least MIN_TIME
+fn measure(mut f: impl FnMut() -> Result<()>) -> Result<Duration> {
+    let start = Instant::now();
+    let mut runs = 0u32;
+    while runs == 0 || start.elapsed() < MIN_TIME {
+        f()?;
+        runs += 1;
+    }
+    Ok(start.elapsed() / runs)
+}
+
+fn mib_per_s(bytes: usize, time: Duration) -> f64 {
+    bytes as f64 / (1 << 20) as f64 / time.as_secs_f64()
+}
+
+fn round(x: f64, places: i32) -> f64 {
+    let scale = 10f64.powi(places);
+    (x * scale).round() / scale
+}
+
+// Words drawn from a small vocabulary with a fixed seed: text that compresses the way logs and
+// source code do, neither as well as zeros nor as badly as random bytes
+fn sample_text(len: usize) -> Vec<u8> {
+    const WORDS: [&str; 32] = [
+        "the", "of", "and", "error", "value", "return", "config", "request", "id", "user", "time", "file", "data", "state", "true", "false",
+        "null", "index", "path", "hash", "key", "level", "status", "200", "404", "info", "debug", "2024", "main", "self", "none", "build",
+    ];
+    let mut state = 0x9E37_79B9_7F4A_7C15u64;
+    let mut data = Vec::with_capacity(len + 16);
+    while data.len() < len {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        data.extend_from_slice(WORDS[(state % 32) as usize].as_bytes());
+        data.push(match state >> 60 {
+            0 => b'\n',
+            1 => b',',
+            _ => b' ',
+        });
+        if state >> 56 == 0xFF {
+            data.extend_from_slice(format!("{:x}", state).as_bytes());
+        }
+    }
+    data.truncate(len);
+    data
+}
+
+fn bench_hash(data: &[u8]) -> Result<Value> {
+    let mut results = Vec::new();
+    for &algorithm in hash::Algorithm::value_variants() {
+        info!("Hashing with {}", algorithm);
+        let time = measure(|| hash::hash_reader(data, algorithm, false).map(drop))?;
+        results.push(json!({ "algorithm": algorithm.to_string(), "mib_per_s": round(mib_per_s(data.len(), time), 1) }));
+    }
+    Ok(Value::Array(results))
+}
+
+fn bench_compression(data: &[u8], levels: &[u32]) -> Result<Value> {
+    let mut results = Vec::new();
+    for &algorithm in compression::Algorithm::value_variants() {
+        let name = algorithm.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default();
+        // LZ4 and Snappy have no levels
+        let levelled = !matches!(algorithm, compression::Algorithm::Lz4 | compression::Algorithm::Snappy);
+        let levels = if levelled { levels } else { &levels[..1.min(levels.len())] };
+        for &level in levels {
+            info!("Compressing with {}{}", name, if levelled { format!(" at level {}", level) } else { String::new() });
+            let mut packed = Vec::new();
+            let compress = measure(|| {
+                packed.clear();
+                compression::compress_reader(data, &mut packed, algorithm, level)
+            })?;
+            let mut unpacked = Vec::with_capacity(data.len());
+            let decompress = measure(|| {
+                unpacked.clear();
+                compression::decompress_reader(&packed[..], algorithm)?.read_to_end(&mut unpacked)?;
+                Ok(())
+            })?;
+            if unpacked != data {
+                bail!("{} did not round-trip the sample data", name);
+            }
+            results.push(json!({
+                "algorithm": name,
+                "level": if levelled { json!(level) } else { Value::Null },
+                "ratio": round(data.len() as f64 / packed.len().max(1) as f64, 2),
+                "compress_mib_per_s": round(mib_per_s(data.len(), compress), 1),
+                "decompress_mib_per_s": round(mib_per_s(data.len(), decompress), 1),
+            }));
+        }
+    }
+    Ok(Value::Array(results))
+}
+
+fn bench_argon2() -> Result<Value> {
+    let mut results = Vec::new();
+    for params in KDF_SETTINGS {
+        info!("Deriving a key with Argon2id at {} MiB, {} passes", params.m_cost_kib / 1024, params.t_cost);
+        let time = measure(|| crypt::password_key("correct horse battery staple", params, &[7; 16]).map(drop))?;
+        results.push(json!({
+            "m_cost_kib": params.m_cost_kib,
+            "t_cost": params.t_cost,
+            "p_cost": params.p_cost,
+            "ms": round(time.as_secs_f64() * 1000.0, 1),
+        }));
+    }
+    Ok(Value::Array(results))
+}
+
+fn bench_image() -> Value {
+    let (w, h) = IMAGE_SIZE;
+    // Gradients with a fine pattern, so filters have detail to work on
+    let source = ::image::DynamicImage::ImageRgb8(::image::RgbImage::from_fn(w, h, |x, y| {
+        ::image::Rgb([(x * 255 / w) as u8, (y * 255 / h) as u8, ((x ^ y) & 0xFF) as u8])
+    }));
+    let mut results = Vec::new();
+    for &filter in image::Filter::value_variants() {
+        let name = filter.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default();
+        info!("Resizing with {}", name);
+        let time = measure(|| {
+            std::hint::black_box(source.resize_exact(RESIZED.0, RESIZED.1, image::filter_to_type(filter)));
+            Ok(())
+        })
+        .unwrap_or_default();
+        results.push(json!({
+            "filter": name,
+            "from": format!("{}x{}", w, h),
+            "to": format!("{}x{}", RESIZED.0, RESIZED.1),
+            "ms": round(time.as_secs_f64() * 1000.0, 1),
+            "megapixels_per_s": round(f64::from(w * h) / 1e6 / time.as_secs_f64(), 1),
+        }));
+    }
+    Value::Array(results)
+}
+
+// One table per part, columns padded to their widest cell
+fn print_tables(report: &serde_json::Map<String, Value>, source: &str, bytes: usize) {
+    let rows = |key: &str| report.get(key).and_then(Value::as_array).cloned().unwrap_or_default();
+    let cell = |key: &str, v: &Value| match v {
+        Value::String(s) => s.clone(),
+        Value::Null => "-".to_string(),
+        Value::Number(n) if n.is_f64() => format!("{:.*}", if key == "ratio" { 2 } else { 1 }, n.as_f64().unwrap_or_default()),
+        other => other.to_string(),
+    };
+    let mut first = true;
+    let mut table = |title: String, headers: &[&str], keys: &[&str], rows: Vec<Value>| {
+        if rows.is_empty() {
+            return;
+        }
+        if !first {
+            println!();
+        }
+        first = false;
+        println!("{}", title);
+        let cells: Vec<Vec<String>> = rows.iter().map(|row| keys.iter().map(|k| cell(k, &row[*k])).collect()).collect();
+        let widths: Vec<usize> = (0..headers.len()).map(|i| cells.iter().map(|r| r[i].len()).chain([headers[i].len()]).max().unwrap_or(0)).collect();
+        let line = |values: Vec<&str>| {
+            let padded: Vec<String> = values.iter().zip(&widths).enumerate().map(|(i, (v, w))| if i == 0 { format!("{:<w$}", v) } else { format!("{:>w$}", v) }).collect();
+            println!("  {}", padded.join("  ").trim_end());
+        };
+        line(headers.to_vec());
+        for row in &cells {
+            line(row.iter().map(String::as_str).collect());
+        }
+    };
+    let mib = bytes as f64 / (1 << 20) as f64;
+    table(format!("Hashing {:.1} MiB of {}", mib, source), &["Algorithm", "MiB/s"], &["algorithm", "mib_per_s"], rows("hash"));
+    table(
+        format!("Compressing {:.1} MiB of {}", mib, source),
+        &["Algorithm", "Level", "Ratio", "Compress MiB/s", "Decompress MiB/s"],
+        &["algorithm", "level", "ratio", "compress_mib_per_s", "decompress_mib_per_s"],
+        rows("compression"),
+    );
+    table("Argon2id key derivation (encrypt --m-cost-kib, --t-cost, --p-cost)".to_string(), &["Memory KiB", "Passes", "Lanes", "ms"], &["m_cost_kib", "t_cost", "p_cost", "ms"], rows("argon2"));
+    table(
+        format!("Resizing {}x{} to {}x{} (image-scale --filter)", IMAGE_SIZE.0, IMAGE_SIZE.1, RESIZED.0, RESIZED.1),
+        &["Filter", "ms", "Megapixels/s"],
+        &["filter", "ms", "megapixels_per_s"],
+        rows("image_resize"),
+    );
+}
@@ -0,0 +1,220 @@
+// --copy: a command's result put on the system clipboard instead of printed. There is no clipboard
+// in std, so it goes through the platform's own tool: pbcopy, clip.exe, wl-copy, xclip or xsel.
+// With --clear-after, a detached run of this program empties the clipboard later unless something
+// else was copied in the meantime, so a password does not linger for the next paste. That run is
+// handed, on its stdin, a random key and the text's BLAKE3 digest under that key, never the text
+// or anything derived from it on its command line, where other users could read it
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use rand::TryRngCore;
+use rand::rngs::OsRng;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use tracing::{debug, info};
+use zeroize::Zeroizing;
+use crate::stdio;
+
+/// Where a command's result goes, for commands whose result is worth pasting elsewhere
+#[derive(Args, Default)]
+pub struct CopyArgs {
+    /// Put the result on the clipboard instead of printing it
+    #[arg(long)]
+    pub(crate) copy: bool,
+    /// With --copy, clear the clipboard after this many seconds, unless it holds something else
+    /// by then
+    #[arg(long, value_name = "SECONDS", requires = "copy")]
+    pub(crate) clear_after: Option<u64>,
+}
+
+impl CopyArgs {
+    // Stdout, or a buffer that `Output::finish` puts on the clipboard
+    pub(crate) fn output(&self) -> Result<Output> {
+        if !self.copy {
+            return Ok(Output::Stdout(stdio::writer(Path::new("-"), false)?));
+        }
+        Ok(Output::Clipboard { text: Zeroizing::new(Vec::new()), clear_after: self.clear_after })
+    }
+}
+
+pub(crate) enum Output {
+    Stdout(stdio::Writer),
+    Clipboard { text: Zeroizing<Vec<u8>>, clear_after: Option<u64> },
+}
+
+impl Output {
+    // The final newline of what was written stays out of the clipboard, so a paste does not
+    // submit a form or run a command
+    pub(crate) fn finish(self) -> Result<()> {
+        let (text, clear_after) = match self {
+            Output::Stdout(out) => return out.finish(),
+            Output::Clipboard { text, clear_after } => (text, clear_after),
+        };
+        let mut len = text.len();
+        if text.ends_with(b"\n") {
+            len -= if text.ends_with(b"\r\n") { 2 } else { 1 };
+        }
+        copy(&text[..len])?;
+        info!("Copied to the clipboard");
+        if let Some(seconds) = clear_after {
+            let mut check = Zeroizing::new([0u8; 64]);
+            OsRng.try_fill_bytes(&mut check[..32])?;
+            let key: [u8; 32] = check[..32].try_into()?;
+            check[32..].copy_from_slice(blake3::keyed_hash(&key, &text[..len]).as_bytes());
+            let exe = std::env::current_exe().context("find the toolkit executable")?;
+            let mut command = Command::new(exe);
+            command.args(["clipboard-clear", "--after", &seconds.to_string()]);
+            command.stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::null());
+            // Out of the terminal's process group, so Ctrl-C in the shell does not stop it
+            #[cfg(unix)]
+            std::os::unix::process::CommandExt::process_group(&mut command, 0);
+            let mut child = command.spawn().context("start the clipboard timer")?;
+            child.stdin.take().context("clipboard timer stdin")?.write_all(&check[..]).context("start the clipboard timer")?;
+            info!("Clearing it in {}s", seconds);
+        }
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout(out) => out.write(buf),
+            Output::Clipboard { text, .. } => text.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(out) => out.flush(),
+            Output::Clipboard { .. } => Ok(()),
+        }
+    }
+}
+
+// Run by --clear-after in the background, with the key and the keyed digest of the text to clear
+// on stdin
+#[derive(Args)]
+#[command[name = "clipboard-clear", hide = true]]
+pub struct ClearArgs {
+    #[arg(long)]
+    after: u64,
+}
+
+pub fn clear(a: ClearArgs) -> Result<()> {
+    let mut check = Zeroizing::new([0u8; 64]);
+    io::stdin().lock().read_exact(&mut check[..]).context("read the clipboard check from stdin")?;
+    let key: [u8; 32] = check[..32].try_into()?;
+    let digest = blake3::Hash::from_bytes(check[32..].try_into()?);
+    std::thread::sleep(Duration::from_secs(a.after));
+    // A clipboard that cannot be read is cleared anyway: losing a paste beats leaving a secret.
+    // Hash compares in constant time
+    match paste() {
+        Ok(text) if !unchanged(&text, &key, &digest) => debug!("The clipboard changed; leaving it"),
+        _ => copy(b"")?,
+    }
+    Ok(())
+}
+
+// Whether the clipboard still holds the copied text, by its keyed digest. Get-Clipboard -Raw
+// ends what it prints with a CRLF the text did not have, so the text without one also counts
+fn unchanged(pasted: &[u8], key: &[u8; 32], digest: &blake3::Hash) -> bool {
+    [Some(pasted), pasted.strip_suffix(b"\r\n")].into_iter().flatten().any(|text| blake3::keyed_hash(key, text) == *digest)
+}
+
+// A program that sets the clipboard from its stdin, and one that prints it
+type Tool = (&'static [&'static str], &'static [&'static str]);
+
+// The tools of this platform, in order of preference
+#[cfg(target_os = "macos")]
+fn tools() -> Result<Vec<Tool>> {
+    Ok(vec![(&["pbcopy"], &["pbpaste"])])
+}
+
+#[cfg(windows)]
+fn tools() -> Result<Vec<Tool>> {
+    Ok(vec![(&["clip.exe"], &["powershell", "-NoProfile", "-Command", "Get-Clipboard -Raw"])])
+}
+
+// Wayland first, since X11 tools there only reach XWayland programs
+#[cfg(not(any(target_os = "macos", windows)))]
+fn tools() -> Result<Vec<Tool>> {
+    let mut tools: Vec<Tool> = Vec::new();
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        tools.push((&["wl-copy"], &["wl-paste", "--no-newline"]));
+    }
+    if std::env::var_os("DISPLAY").is_some() {
+        tools.push((&["xclip", "-selection", "clipboard"], &["xclip", "-selection", "clipboard", "-o"]));
+        tools.push((&["xsel", "--clipboard", "--input"], &["xsel", "--clipboard", "--output"]));
+    }
+    if tools.is_empty() {
+        bail!("no clipboard: neither WAYLAND_DISPLAY nor DISPLAY is set");
+    }
+    Ok(tools)
+}
+
+fn copy(text: &[u8]) -> Result<()> {
+    for (command, _) in tools()? {
+        // A tool that stays behind to serve the selection, as xclip does, must not hold on to
+        // this program's stdout
+        let child = Command::new(command[0]).args(&command[1..]).stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::null()).spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("run {}", command[0])),
+        };
+        child.stdin.take().context("clipboard tool stdin")?.write_all(text).with_context(|| format!("write to {}", command[0]))?;
+        let status = child.wait().with_context(|| format!("run {}", command[0]))?;
+        if !status.success() {
+            bail!("{} failed: {}", command[0], status);
+        }
+        debug!("Clipboard set with {}", command[0]);
+        return Ok(());
+    }
+    bail!("no clipboard tool found; install {}", names(true)?)
+}
+
+fn paste() -> Result<Zeroizing<Vec<u8>>> {
+    for (_, command) in tools()? {
+        let child = Command::new(command[0]).args(&command[1..]).stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::null()).spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("run {}", command[0])),
+        };
+        let mut text = Zeroizing::new(Vec::new());
+        child.stdout.take().context("clipboard tool stdout")?.read_to_end(&mut text)?;
+        if !child.wait()?.success() {
+            bail!("{} failed", command[0]);
+        }
+        return Ok(text);
+    }
+    bail!("no clipboard tool found; install {}", names(false)?)
+}
+
+fn names(copying: bool) -> Result<String> {
+    let names: Vec<&str> = tools()?.iter().map(|(copy, paste)| if copying { copy[0] } else { paste[0] }).collect();
+    Ok(names.join(" or "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unchanged;
+
+    #[test]
+    fn a_trailing_crlf_from_the_paste_tool_still_matches() {
+        let key = [7u8; 32];
+        let digest = blake3::keyed_hash(&key, b"hunter2");
+        assert!(unchanged(b"hunter2", &key, &digest));
+        assert!(unchanged(b"hunter2\r\n", &key, &digest));
+        assert!(!unchanged(b"hunter2\r\n\r\n", &key, &digest));
+        assert!(!unchanged(b"hunter", &key, &digest));
+        assert!(!unchanged(b"hunter2", &[8u8; 32], &digest));
+
+        // Text that kept a line break of its own matches with or without the tool's
+        let digest = blake3::keyed_hash(&key, b"two\r\n");
+        assert!(unchanged(b"two\r\n", &key, &digest));
+        assert!(unchanged(b"two\r\n\r\n", &key, &digest));
+    }
+}
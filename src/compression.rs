@@ -1,36 +1,79 @@
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail};
+use tracing::{debug, info};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::{fs, fs::File};
-use std::{io, io::{Read, Write}};
+use std::{io, io::{Read, Seek, SeekFrom, Write}};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use clap::{Args, ValueEnum};
-use walkdir::WalkDir;
+use crate::batch::{self, BatchArgs};
+use crate::watch::{self, WatchArgs};
+use crate::{errors, hash, http, progress, stdio};
 
 #[derive(Args)]
-#[command[name = "compression", about = "Simple file compression using Zstd, LZ4, Brotli or Snappy"]]
+#[command[name = "compression", about = "Simple file compression using Zstd, LZ4, Brotli, Snappy, Gzip, XZ or Bzip2"]]
 pub struct CompressionArgs {
     input: PathBuf,
-    #[arg(short = 'r', long)]
-    recursive: bool,
+    #[command(flatten)]
+    batch: BatchArgs,
     #[arg(short, long, value_enum, default_value_t = Algorithm::Zstd)]
     algorithm: Algorithm,
     #[arg(short, long, default_value_t = 5)]
     compression_level: u32,
     #[arg(short, long)]
     output: Option<PathBuf>,
-    #[arg(short = 't', long)]
-    threads: Option<u32>,
+    #[arg(short = 'k', long)]
+    keep_going: bool,
+    #[arg(long)]
+    dry_run: bool,
+    #[arg(long)]
+    verify: bool,
+    #[arg(long)]
+    container: bool,
+    #[command(flatten)]
+    watch: WatchArgs,
 }
 
 #[derive(Args)]
-#[command[name = "decompression", about = "Simple file decompression supporting Zstd, LZ4, Brotli or Snappy"]]
+#[command[name = "decompression", about = "Simple file decompression supporting Zstd, LZ4, Brotli, Snappy, Gzip, XZ, Bzip2 and tarballs"]]
 pub struct DecompressionArgs {
     input: PathBuf,
-    #[arg(short = 'r', long)]
-    recursive: bool,
+    #[command(flatten)]
+    batch: BatchArgs,
     #[arg(short, long)]
     algorithm: Option<Algorithm>,
     #[arg(short, long)]
     output: Option<PathBuf>,
+    #[arg(short = 'k', long)]
+    keep_going: bool,
+    #[arg(long)]
+    dry_run: bool,
+    #[arg(long, conflicts_with_all = ["output", "dry_run"])]
+    test: bool,
+}
+
+// Container layout: magic | version | algorithm | name len (u16) | name | size (u64) | mtime secs (u64) | mtime nanos (u32) | blake3
+const CONTAINER_MAGIC: &[u8; 6] = b"JJPACK";
+const CONTAINER_VERSION: u8 = 1;
+const CONTAINER_EXTENSION: &str = "jjz";
+
+struct Container {
+    algorithm: Algorithm,
+    name: String,
+    size: u64,
+    mtime: SystemTime,
+    digest: [u8; 32],
+    header_len: u64,
+}
+
+struct DecompressJob {
+    input: PathBuf,
+    output: PathBuf,
+    extract_dir: PathBuf,
+    algorithm: Algorithm,
+    container: Option<Container>,
+    // False when writing to stdout, where a tarball stays a tarball
+    unpack_tar: bool,
 }
 
 #[derive(Clone, Copy, ValueEnum, Debug)]
@@ -39,6 +82,9 @@ pub enum Algorithm {
     Lz4,
     Brotli,
     Snappy,
+    Gzip,
+    Xz,
+    Bzip2,
 }
 
 impl Algorithm {
@@ -48,97 +94,192 @@ impl Algorithm {
             Algorithm::Lz4 => "lz4",
             Algorithm::Brotli => "br",
             Algorithm::Snappy => "sz",
+            Algorithm::Gzip => "gz",
+            Algorithm::Xz => "xz",
+            Algorithm::Bzip2 => "bz2",
+        }
+    }
+
+    const fn name(self) -> &'static str {
+        match self {
+            Algorithm::Zstd => "ZSTD",
+            Algorithm::Lz4 => "LZ4",
+            Algorithm::Brotli => "Brotli",
+            Algorithm::Snappy => "Snappy",
+            Algorithm::Gzip => "Gzip",
+            Algorithm::Xz => "XZ",
+            Algorithm::Bzip2 => "Bzip2",
+        }
+    }
+
+    const fn id(self) -> u8 {
+        match self {
+            Algorithm::Zstd => 0,
+            Algorithm::Lz4 => 1,
+            Algorithm::Brotli => 2,
+            Algorithm::Snappy => 3,
+            Algorithm::Gzip => 4,
+            Algorithm::Xz => 5,
+            Algorithm::Bzip2 => 6,
+        }
+    }
+
+    const fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Algorithm::Zstd),
+            1 => Some(Algorithm::Lz4),
+            2 => Some(Algorithm::Brotli),
+            3 => Some(Algorithm::Snappy),
+            4 => Some(Algorithm::Gzip),
+            5 => Some(Algorithm::Xz),
+            6 => Some(Algorithm::Bzip2),
+            _ => None,
+        }
+    }
+
+    // Name plus level for the algorithms that take one, e.g. "ZSTD@5"
+    fn describe(self, level: u32) -> String {
+        match self {
+            Algorithm::Zstd | Algorithm::Brotli | Algorithm::Gzip | Algorithm::Xz | Algorithm::Bzip2 => {
+                format!("{}@{}", self.name(), level)
+            }
+            Algorithm::Lz4 | Algorithm::Snappy => self.name().to_string(),
         }
     }
 }
 
+/// Compresses everything `input` yields into `output` as a bare stream of `algorithm`, the same
+/// bytes the compress command writes without `--container`. `level` is clamped to what the
+/// algorithm accepts, and ignored by LZ4 and Snappy
+pub fn compress_reader(input: impl Read, output: impl Write, algorithm: Algorithm, level: u32) -> Result<()> {
+    encode(input, output, algorithm, level, 1)
+}
+
+/// Reader over the decompressed bytes of a bare `algorithm` stream. Concatenated Gzip members
+/// and XZ streams are read through, as the decompress command does
+pub fn decompress_reader<'a>(input: impl Read + 'a, algorithm: Algorithm) -> Result<Box<dyn Read + 'a>> {
+    let reader = io::BufReader::new(input);
+    Ok(match algorithm {
+        Algorithm::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(reader)?),
+        Algorithm::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(reader)),
+        Algorithm::Brotli => Box::new(brotli2::read::BrotliDecoder::new(reader)),
+        Algorithm::Snappy => Box::new(snap::read::FrameDecoder::new(reader)),
+        Algorithm::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+        Algorithm::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(reader)),
+        Algorithm::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(reader)),
+    })
+}
+
+// Zstd is the only encoder here that takes worker threads
+fn encode(input: impl Read, output: impl Write, algorithm: Algorithm, level: u32, threads: u32) -> Result<()> {
+    match algorithm {
+        Algorithm::Zstd => compress_zstd(input, output, level as i32, threads),
+        Algorithm::Lz4 => compress_lz4(input, output),
+        Algorithm::Brotli => compress_brotli(input, output, level),
+        Algorithm::Snappy => compress_snappy(input, output),
+        Algorithm::Gzip => compress_gzip(input, output, level),
+        Algorithm::Xz => compress_xz(input, output, level),
+        Algorithm::Bzip2 => compress_bzip2(input, output, level),
+    }
+}
+
 pub fn compress(a: CompressionArgs) -> Result<()> {
+    let watching = a.watch.start(&a.input, a.batch.recursive)?;
+    if stdio::is_dash(&a.input) || a.output.as_deref().is_some_and(stdio::is_dash) {
+        if a.container && stdio::is_dash(&a.input) {
+            bail!("--container records the input file's name and times, so it needs a file input");
+        }
+        let input = stdio::input(&a.input)?;
+        let output = stdio::output(a.output.as_deref().unwrap_or(Path::new("-")), output_extension(&a), true)?;
+        compress_file(input.path(), output.path(), &a, encoder_threads(&a))?;
+        return output.finish();
+    }
     if a.input.is_file() {
         let ext = a.input.extension().unwrap().to_str().unwrap();
-        let output_path = a.output.unwrap_or_else(|| {
+        let output_path = a.output.clone().unwrap_or_else(|| {
             let stem = a.input.file_stem()
                 .map(|s| s.to_string_lossy().into_owned())
                 .unwrap_or_else(|| "output".to_string());
-            PathBuf::from(format!("{}.{}.{}", stem, ext, &a.algorithm.extension()))
+            PathBuf::from(format!("{}.{}.{}", stem, ext, output_extension(&a)))
         });
-        let mut input_file = File::open(&a.input)?;
-        let output_file = File::create(&output_path)?;
-
-        match a.algorithm {
-            Algorithm::Zstd => {
-                println!("Compressing: {} -> {} with {}@{}", &a.input.display(), &output_path.display(), "ZSTD", a.compression_level);
-                compress_zstd(&input_file, &output_file, a.compression_level as i32, a.threads.unwrap_or(1))
-            }
-            Algorithm::Lz4 => {
-                println!("Compressing: {} -> {} with {}", &a.input.display(), &output_path.display(), "LZ4");
-                compress_lz4(&mut input_file, &output_file)
-            }
-            Algorithm::Brotli => {
-                println!("Compressing: {} -> {} with {}@{}", &a.input.display(), &output_path.display(), "Brotli", a.compression_level);
-                compress_brotli(&input_file, &output_file, a.compression_level)
-            }
-            Algorithm::Snappy => {
-                println!("Compressing: {} -> {} with {}", &a.input.display(), &output_path.display(), "Snappy");
-                compress_snappy(&mut input_file, &output_file)
-            }
-        }
+        compress_file(&a.input, &output_path, &a, encoder_threads(&a))
     } else if a.input.is_dir() {
-        if !a.recursive { bail!("'{}' is a directory. Use -r/--recursive.", a.input.display()); }
+        if !a.batch.recursive { bail!("'{}' is a directory. Use -r/--recursive.", a.input.display()); }
         let output_root = a.output.clone();
-        if let Some(dir) = &output_root {fs::create_dir_all(dir)?;}
-
-        for entry in WalkDir::new(&a.input).into_iter().filter_map(|e| e.ok()) {
-            if !entry.file_type().is_file() { continue }
-            let input_path = entry.path();
+        if let Some(dir) = &output_root && !a.dry_run { fs::create_dir_all(dir)?; }
 
+        let files = a.batch.collect(&a.input, |_| true)?;
+        let compress_one = |input_path: &Path| -> Result<()> {
             let relative = input_path.strip_prefix(&a.input)?;
             let relative_parent = relative.parent().unwrap_or_else(|| Path::new(""));
 
             let output_dir = if let Some(root) = &output_root {
                 let d = root.join(relative_parent);
-                fs::create_dir_all(&d)?;
+                if !a.dry_run { fs::create_dir_all(&d)?; }
                 d
             } else {
                 input_path.parent().unwrap().to_path_buf()
             };
 
             // Add extension
-            let new_name = format!("{}.{}", input_path.file_name().unwrap().to_string_lossy(), a.algorithm.extension());
-            let output_path = output_dir.join(new_name);
-
-            let mut input_file = File::open(input_path)?;
-            let output_file = File::create(&output_path)?;
-
-            match a.algorithm {
-                Algorithm::Zstd => {
-                    println!("Compressing: {} -> {} with {}@{}", &input_path.display(), &output_path.display(), "ZSTD", a.compression_level);
-                    compress_zstd(&input_file, &output_file, a.compression_level as i32, a.threads.unwrap_or(1))?
-                }
-                Algorithm::Lz4 => {
-                    println!("Compressing: {} -> {} with {}", &input_path.display(), &output_path.display(), "LZ4");
-                    compress_lz4(&mut input_file, &output_file)?
-                }
-                Algorithm::Brotli => {
-                    println!("Compressing: {} -> {} with {}@{}", &input_path.display(), &output_path.display(), "Brotli", a.compression_level);
-                    compress_brotli(&input_file, &output_file, a.compression_level)?
-                }
-                Algorithm::Snappy => {
-                    println!("Compressing: {} -> {} with {}", &input_path.display(), &output_path.display(), "Snappy");
-                    compress_snappy(&mut input_file, &output_file)?
-                }
-            }
-        }
-        Ok(())
+            let new_name = format!("{}.{}", input_path.file_name().unwrap().to_string_lossy(), output_extension(&a));
+            compress_file(input_path, &output_dir.join(new_name), &a, 1)
+        };
+        // Files go in parallel, each with a single-threaded encoder; a dry run lists them in order
+        let threads = if a.dry_run { Some(1) } else { a.batch.threads };
+        let report = batch::process(&files, threads, a.keep_going, "Compressing", compress_one)?;
+        report.log_failures();
+
+        // Outputs written next to their sources are compressed files, which are not picked up again
+        let uncompressed = |path: &Path| {
+            let ext = path.extension().map(|e| e.to_string_lossy()).unwrap_or_default();
+            check_extension(&ext).is_none() && ext != CONTAINER_EXTENSION && output_root.as_deref().is_none_or(|root| !path.starts_with(root))
+        };
+        watch::follow(watching, report.into_result(), &a.input, &a.batch.walk, uncompressed, |changed, _| {
+            let report = batch::process(changed, threads, true, "Compressing", compress_one)?;
+            report.log_failures();
+            report.into_result()
+        })
     } else {
         bail!("Cannot find: {:?}", a.input);
     }
 }
 
 pub fn decompress(a: DecompressionArgs) -> Result<()> {
+    if a.test {
+        return test(&a);
+    }
+    if stdio::is_dash(&a.input) || a.output.as_deref().is_some_and(stdio::is_dash) {
+        // A tarball is passed through as-is rather than unpacked
+        let input = stdio::input(&a.input)?;
+        let output = stdio::output(a.output.as_deref().unwrap_or(Path::new("-")), "", true)?;
+        let container = read_container(input.path())?;
+        let algorithm = match (&container, a.algorithm) {
+            (Some(c), _) => c.algorithm,
+            (None, Some(alg)) => alg,
+            (None, None) => match sniff_magic(input.path())? {
+                Some(alg) => alg,
+                None => bail!("cannot identify compression algorithm; pass --algorithm"),
+            },
+        };
+        let job = DecompressJob {
+            input: input.path().to_path_buf(),
+            output: output.path().to_path_buf(),
+            extract_dir: PathBuf::new(),
+            algorithm,
+            container,
+            unpack_tar: false,
+        };
+        decompress_file(&job, &a)?;
+        return output.finish();
+    }
     if a.input.is_file() {
         let ext = a.input.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let container = read_container(&a.input)?;
 
-        let algorithm = if let Some(alg) = a.algorithm {
+        let algorithm = if let Some(c) = &container {
+            c.algorithm
+        } else if let Some(alg) = a.algorithm {
             alg
         } else if let Some(alg) = sniff_magic(&a.input)? {
             alg
@@ -149,43 +290,30 @@ pub fn decompress(a: DecompressionArgs) -> Result<()> {
         };
 
         let file_name = a.input.file_name().unwrap().to_string_lossy();
-        let stripped = strip_suffix(&file_name, algorithm);
-        let default_name = if stripped == file_name { format!("{}.out", stripped) } else { stripped };
-        let output_path = a.output.unwrap_or_else(|| {
+        let default_name = default_output_name(&file_name, algorithm, container.as_ref());
+        let output_path = a.output.clone().unwrap_or_else(|| {
             a.input.parent().unwrap_or(Path::new("")).join(default_name)
         });
 
-        let input_file = File::open(&a.input)?;
-        let mut output_file = File::create(&output_path)?;
+        let extract_dir = a.output.clone().unwrap_or_else(|| {
+            a.input.parent().unwrap_or(Path::new("")).to_path_buf()
+        });
 
-        match algorithm {
-            Algorithm::Zstd => {
-                println!("Decompressing: {} -> {} with {}", &a.input.display(), &output_path.display(), "ZSTD");
-                decompress_zstd(&input_file, &mut output_file)
-            },
-            Algorithm::Lz4 => {
-                println!("Decompressing: {} -> {} with {}", &a.input.display(), &output_path.display(), "LZ4");
-                decompress_lz4(&input_file, &mut output_file)
-            },
-            Algorithm::Brotli => {
-                println!("Decompressing: {} -> {} with {}", &a.input.display(), &output_path.display(), "Brotli");
-                decompress_brotli(&input_file, &mut output_file)
-            },
-            Algorithm::Snappy => {
-                println!("Decompressing: {} -> {} with {}", &a.input.display(), &output_path.display(), "Snappy");
-                decompress_snappy(&input_file, &mut output_file)
-            },
-        }
+        let job = DecompressJob { input: a.input.clone(), output: output_path, extract_dir, algorithm, container, unpack_tar: true };
+        decompress_file(&job, &a)
     } else if a.input.is_dir() {
-        if !a.recursive { bail!("'{}' is a directory. Use -r/--recursive.", a.input.display()); }
+        if !a.batch.recursive { bail!("'{}' is a directory. Use -r/--recursive.", a.input.display()); }
         let output_root = a.output.clone();
-        if let Some(dir) = &output_root { std::fs::create_dir_all(dir)?; }
+        if let Some(dir) = &output_root && !a.dry_run { std::fs::create_dir_all(dir)?; }
 
-        for entry in walkdir::WalkDir::new(&a.input).into_iter().filter_map(|e| e.ok()) {
-            if !entry.file_type().is_file() { continue; }
-            let input_path = entry.path();
+        let mut jobs: Vec<DecompressJob> = Vec::new();
+        for input_path in &a.batch.collect(&a.input, |_| true)? {
+            let input_path = input_path.as_path();
 
-            let per_file_alg = if let Some(alg) = a.algorithm {
+            let container = read_container(input_path)?;
+            let per_file_alg = if let Some(c) = &container {
+                Some(c.algorithm)
+            } else if let Some(alg) = a.algorithm {
                 Some(alg)
             } else if let Ok(Some(alg)) = sniff_magic(input_path) {
                 Some(alg)
@@ -194,51 +322,372 @@ pub fn decompress(a: DecompressionArgs) -> Result<()> {
                     .and_then(|e| e.to_str())
                     .and_then(check_extension)
             };
-            let Some(alg) = per_file_alg else { continue };
+            let Some(alg) = per_file_alg else {
+                if a.dry_run { println!("Would skip: {} (unknown algorithm)", input_path.display()); }
+                debug!("Skipping {}: no algorithm from its header, magic or extension", input_path.display());
+                continue
+            };
 
             let relative = input_path.strip_prefix(&a.input).unwrap();
             let relative_parent = relative.parent().unwrap_or(Path::new(""));
             let output_dir = if let Some(root) = &output_root {
                 let d = root.join(relative_parent);
-                std::fs::create_dir_all(&d)?; d
+                if !a.dry_run { std::fs::create_dir_all(&d)?; }
+                d
             } else {
                 input_path.parent().unwrap().to_path_buf()
             };
 
             let in_name = input_path.file_name().unwrap().to_string_lossy();
-            let stripped = strip_suffix(&in_name, alg);
-            let out_name = if stripped == in_name { format!("{}.out", stripped) } else { stripped };
+            let out_name = default_output_name(&in_name, alg, container.as_ref());
             let output_path = output_dir.join(out_name);
 
-            let input_file = File::open(input_path)?;
-            let mut output_file = File::create(&output_path)?;
-
-            match alg {
-                Algorithm::Zstd => {
-                    println!("Decompressing: {} -> {} with ZSTD", &input_path.display(), &output_path.display());
-                    decompress_zstd(&input_file, &mut output_file)?
-                }
-                Algorithm::Lz4 => {
-                    println!("Decompressing: {} -> {} with LZ4", &input_path.display(), &output_path.display());
-                    decompress_lz4(&input_file, &mut output_file)?
-                }
-                Algorithm::Brotli => {
-                    println!("Decompressing: {} -> {} with Brotli", &input_path.display(), &output_path.display());
-                    decompress_brotli(&input_file, &mut output_file)?
-                }
-                Algorithm::Snappy => {
-                    println!("Decompressing: {} -> {} with Snappy", &input_path.display(), &output_path.display());
-                    decompress_snappy(&input_file, &mut output_file)?
-                }
-            }
+            jobs.push(DecompressJob {
+                input: input_path.to_path_buf(),
+                output: output_path,
+                extract_dir: output_dir,
+                algorithm: alg,
+                container,
+                unpack_tar: true,
+            });
         }
-        Ok(())
+
+        // Frames are decoded sequentially, so threads parallelize across files; a dry run lists them in order
+        let threads = if a.dry_run { Some(1) } else { a.batch.threads };
+        let inputs: Vec<PathBuf> = jobs.iter().map(|job| job.input.clone()).collect();
+        let by_input: HashMap<&Path, &DecompressJob> = jobs.iter().map(|job| (job.input.as_path(), job)).collect();
+        let report = batch::process(&inputs, threads, a.keep_going, "Decompressing", |input| decompress_file(by_input[input], &a))?;
+        report.log_failures();
+        report.into_result()
     } else {
         bail!("Cannot find: {:?}", a.input);
     }
 }
 
+fn compress_file(input: &Path, output: &Path, a: &CompressionArgs, threads: u32) -> Result<()> {
+    let method = a.algorithm.describe(a.compression_level);
+    if a.dry_run {
+        let verify_note = if a.verify { " and verify" } else { "" };
+        println!("Would compress: {} -> {} with {}{}{}", stdio::display(input), stdio::display(output), method, verify_note, overwrite_note(output));
+        return Ok(());
+    }
+
+    if !stdio::check_output(output)? {
+        return Ok(());
+    }
+    info!("Compressing: {} -> {} with {}", stdio::display(input), stdio::display(output), method);
+    let input_file = File::open(input)?;
+    let bar = crate::progress::bytes(input_file.metadata()?.len(), input);
+    let mut output_file = stdio::AtomicFile::create(output)?;
+    if a.container {
+        write_container_header(&mut output_file, input, a.algorithm)?;
+    }
+
+    encode(bar.wrap_read(input_file), &mut output_file, a.algorithm, a.compression_level, threads)?;
+
+    // Checked before it goes live, a bad output never replaces anything
+    if a.verify {
+        output_file.flush()?;
+        verify_roundtrip(input, output_file.path(), output, a.algorithm, a.container)?;
+    }
+    output_file.commit()?;
+    stdio::finished(input, output)
+}
+
+// Decompresses the written output and compares its Blake3 digest against the original. `written`
+// is where the output is until it is moved to `compressed`
+fn verify_roundtrip(original: &Path, written: &Path, compressed: &Path, alg: Algorithm, container: bool) -> Result<()> {
+    let original_file = File::open(original).with_context(|| errors::at("open", original))?;
+    let expected = hash::hash_reader(io::BufReader::new(original_file), hash::Algorithm::Blake3, false)?;
+
+    let mut compressed_file = File::open(written)?;
+    if container && let Some(c) = read_container(written)? {
+        compressed_file.seek(SeekFrom::Start(c.header_len))?;
+    }
+    let decoder = decompress_reader(compressed_file, alg)?;
+    let got = hash::hash_reader(decoder, hash::Algorithm::Blake3, false)
+        .with_context(|| format!("decode {}", compressed.display()))?;
+
+    if expected != got {
+        bail!("verification failed: {} does not round-trip to {}\nexpected {}\n     got {}",
+            compressed.display(), original.display(), expected, got);
+    }
+    info!("Verified: {} (blake3 {})", compressed.display(), got);
+    Ok(())
+}
+
+// Tarballs are unpacked into `extract_dir`, everything else is written to `output`
+fn decompress_file(job: &DecompressJob, a: &DecompressionArgs) -> Result<()> {
+    let (input, output, extract_dir, alg) = (job.input.as_path(), job.output.as_path(), job.extract_dir.as_path(), job.algorithm);
+
+    let mut input_file = File::open(input)?;
+    let mut remaining = input_file.metadata()?.len();
+    if let Some(c) = &job.container {
+        input_file.seek(SeekFrom::Start(c.header_len))?;
+        remaining = remaining.saturating_sub(c.header_len);
+    }
+    // Compressed bytes consumed, the only size known before decoding
+    let bar = if a.dry_run { indicatif::ProgressBar::hidden() } else { crate::progress::bytes(remaining, input) };
+    let mut decoder = decompress_reader(bar.wrap_read(input_file), alg)?;
+
+    if let Some(c) = &job.container {
+        return restore_container(decoder, input, output, c, a);
+    }
+
+    // Peek at the first tar block to see whether the payload is a tarball
+    let mut head = Vec::with_capacity(512);
+    decoder.by_ref().take(512).read_to_end(&mut head)
+        .with_context(|| format!("decode {}", input.display()))?;
+    let is_tar = job.unpack_tar && is_tar_header(&head);
+    let reader = io::Cursor::new(head).chain(decoder);
+
+    if is_tar {
+        if a.dry_run {
+            println!("Would unpack: {} -> {} with {} (tar)", stdio::display(input), extract_dir.display(), alg.name());
+            return Ok(());
+        }
+        info!("Unpacking: {} -> {} with {} (tar)", input.display(), extract_dir.display(), alg.name());
+        fs::create_dir_all(extract_dir)?;
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries().with_context(|| format!("unpack {}", input.display()))? {
+            let mut entry = entry.with_context(|| format!("unpack {}", input.display()))?;
+            if !entry.header().entry_type().is_dir() && !stdio::check_output(&extract_dir.join(entry.path()?))? {
+                continue;
+            }
+            entry.unpack_in(extract_dir).with_context(|| format!("unpack {}", input.display()))?;
+        }
+        return Ok(());
+    }
+
+    if a.dry_run {
+        println!("Would decompress: {} -> {} with {}{}", stdio::display(input), stdio::display(output), alg.name(), overwrite_note(output));
+        return Ok(());
+    }
+
+    if !stdio::check_output(output)? {
+        return Ok(());
+    }
+    info!("Decompressing: {} -> {} with {}", stdio::display(input), stdio::display(output), alg.name());
+    let mut writer = stdio::AtomicFile::create(output)?;
+    io::copy(&mut io::BufReader::new(reader), &mut writer)
+        .with_context(|| format!("decode {}", input.display()))?;
+    writer.commit()?;
+    stdio::finished(input, output)
+}
+
+// POSIX ustar and GNU tar both carry "ustar" at offset 257 of the first header block
+fn is_tar_header(block: &[u8]) -> bool {
+    block.len() >= 262 && &block[257..262] == b"ustar"
+}
+
+// Writes the original bytes back and checks them against the size and digest recorded at compression time
+fn restore_container(mut decoder: Box<dyn Read>, input: &Path, output: &Path, c: &Container, a: &DecompressionArgs) -> Result<()> {
+    if a.dry_run {
+        println!("Would restore: {} -> {} with {} ({} bytes){}", stdio::display(input), stdio::display(output), c.algorithm.name(), c.size, overwrite_note(output));
+        return Ok(());
+    }
+
+    if !stdio::check_output(output)? {
+        return Ok(());
+    }
+    info!("Restoring: {} -> {} with {}", stdio::display(input), stdio::display(output), c.algorithm.name());
+    let mut writer = stdio::AtomicFile::create(output)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut size = 0u64;
+
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let n = decoder.read(&mut buffer).with_context(|| format!("decode {}", input.display()))?;
+        if n == 0 { break }
+        hasher.update(&buffer[..n]);
+        writer.write_all(&buffer[..n])?;
+        size += n as u64;
+    }
+    writer.flush()?;
+
+    // Dropped uncommitted on a mismatch, so only verified data is restored
+    check_container(c, size, &hasher, input)?;
+    writer.file().set_modified(c.mtime)?;
+    writer.commit()
+}
+
+fn check_container(c: &Container, size: u64, hasher: &blake3::Hasher, input: &Path) -> Result<()> {
+    if size != c.size {
+        bail!("container size mismatch for {}: expected {} bytes, got {}", stdio::display(input), c.size, size);
+    }
+    if hasher.finalize().as_bytes() != &c.digest {
+        bail!("container checksum mismatch for {}", stdio::display(input));
+    }
+    Ok(())
+}
+
+// --test: inputs decoded to the end and thrown away, a container's size and checksum checked too.
+// A URL is streamed as it downloads, never written to disk
+fn test(a: &DecompressionArgs) -> Result<()> {
+    if stdio::is_dash(&a.input) || http::url(&a.input).is_some() || !a.input.is_dir() {
+        return test_input(&a.input, a);
+    }
+    if !a.batch.recursive { bail!("'{}' is a directory. Use -r/--recursive.", a.input.display()); }
+    let inputs = a.batch.collect(&a.input, |_| true)?;
+    let report = batch::process(&inputs, a.batch.threads, a.keep_going, "Testing", |input| test_input(input, a))?;
+    report.log_failures();
+    report.into_result()
+}
+
+fn test_input(input: &Path, a: &DecompressionArgs) -> Result<()> {
+    let (reader, len) = match http::url(input) {
+        Some(url) => {
+            let response = http::get(url, 0, None)?;
+            let len = response.len().unwrap_or(0);
+            (response.body as Box<dyn Read>, len)
+        }
+        None => (stdio::reader(input)?, fs::metadata(input).map_or(0, |m| m.len())),
+    };
+    let bar = progress::bytes(len, input);
+    let mut reader = io::BufReader::new(bar.wrap_read(reader));
+
+    // The first bytes tell a container or the algorithm, and are put back in front otherwise
+    let mut head = Vec::with_capacity(CONTAINER_MAGIC.len());
+    (&mut reader).take(CONTAINER_MAGIC.len() as u64).read_to_end(&mut head).with_context(|| format!("read {}", stdio::display(input)))?;
+    let container = if head == CONTAINER_MAGIC {
+        Some(read_container_fields(&mut reader, input).with_context(|| format!("invalid container header in {}", stdio::display(input)))?)
+    } else {
+        None
+    };
+    let ext = input.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let algorithm = match (&container, a.algorithm) {
+        (Some(c), _) => c.algorithm,
+        (None, Some(alg)) => alg,
+        (None, None) => magic_algorithm(&head).or_else(|| check_extension(ext)).context("cannot identify compression algorithm; pass --algorithm")?,
+    };
+    let rest: Box<dyn Read> = if container.is_some() { Box::new(reader) } else { Box::new(io::Cursor::new(head).chain(reader)) };
+
+    let mut hasher = blake3::Hasher::new();
+    let size = io::copy(&mut decompress_reader(rest, algorithm)?, &mut hasher).with_context(|| format!("decode {}", stdio::display(input)))?;
+    bar.finish_and_clear();
+    if let Some(c) = &container {
+        check_container(c, size, &hasher, input)?;
+    }
+    info!("OK: {} ({} bytes with {})", stdio::display(input), size, algorithm.name());
+    Ok(())
+}
+
+fn write_container_header(output: &mut impl Write, input: &Path, alg: Algorithm) -> Result<()> {
+    let meta = fs::metadata(input)?;
+    let mtime = meta.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut io::BufReader::new(File::open(input)?), &mut hasher)?;
+
+    let name = input.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let name_len = u16::try_from(name.len()).context("file name too long for container")?;
+
+    output.write_all(CONTAINER_MAGIC)?;
+    output.write_all(&[CONTAINER_VERSION, alg.id()])?;
+    output.write_all(&name_len.to_le_bytes())?;
+    output.write_all(name.as_bytes())?;
+    output.write_all(&meta.len().to_le_bytes())?;
+    output.write_all(&mtime.as_secs().to_le_bytes())?;
+    output.write_all(&mtime.subsec_nanos().to_le_bytes())?;
+    output.write_all(hasher.finalize().as_bytes())?;
+    Ok(())
+}
+
+fn read_container(path: &Path) -> Result<Option<Container>> {
+    let mut r = io::BufReader::new(File::open(path).with_context(|| errors::at("open", path))?);
+
+    let mut magic = [0u8; 6];
+    if r.read_exact(&mut magic).is_err() || &magic != CONTAINER_MAGIC {
+        return Ok(None);
+    }
+    read_container_fields(&mut r, path)
+        .with_context(|| format!("invalid container header in {}", path.display()))
+        .map(Some)
+}
+
+fn read_container_fields(r: &mut impl Read, path: &Path) -> Result<Container> {
+    let mut fixed = [0u8; 4];
+    r.read_exact(&mut fixed)?;
+    let [version, alg_id, l0, l1] = fixed;
+    if version != CONTAINER_VERSION {
+        bail!("unsupported container version {} in {}", version, path.display());
+    }
+    let algorithm = Algorithm::from_id(alg_id)
+        .with_context(|| format!("unknown container algorithm {} in {}", alg_id, path.display()))?;
+
+    let name_len = u16::from_le_bytes([l0, l1]) as usize;
+    let mut name = vec![0u8; name_len];
+    r.read_exact(&mut name)?;
+
+    let mut b8 = [0u8; 8];
+    r.read_exact(&mut b8)?;
+    let size = u64::from_le_bytes(b8);
+    r.read_exact(&mut b8)?;
+    let secs = u64::from_le_bytes(b8);
+    let mut b4 = [0u8; 4];
+    r.read_exact(&mut b4)?;
+    let nanos = u32::from_le_bytes(b4);
+    let mut digest = [0u8; 32];
+    r.read_exact(&mut digest)?;
+    // A crafted header must not panic the time arithmetic
+    let mtime = if nanos < 1_000_000_000 { UNIX_EPOCH.checked_add(Duration::new(secs, nanos)) } else { None };
+    let mtime = mtime.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("invalid container modification time in {}", path.display())))?;
+
+    Ok(Container {
+        algorithm,
+        name: String::from_utf8_lossy(&name).into_owned(),
+        size,
+        mtime,
+        digest,
+        header_len: (6 + 4 + name_len + 8 + 8 + 4 + 32) as u64,
+    })
+}
+
+// -t for a single file: Zstd worker threads
+fn encoder_threads(a: &CompressionArgs) -> u32 {
+    a.batch.threads.unwrap_or(1)
+}
+
+fn output_extension(a: &CompressionArgs) -> &'static str {
+    if a.container { CONTAINER_EXTENSION } else { a.algorithm.extension() }
+}
+
+fn default_output_name(file_name: &str, alg: Algorithm, container: Option<&Container>) -> String {
+    // Only the final component of the recorded name is trusted
+    if let Some(name) = container.and_then(|c| Path::new(&c.name).file_name()) {
+        return name.to_string_lossy().into_owned();
+    }
+    let stripped = strip_suffix(file_name, alg);
+    if stripped == file_name { format!("{}.out", stripped) } else { stripped }
+}
+
+// What a real run would do about an existing output
+fn overwrite_note(output: &Path) -> &'static str {
+    if !output.exists() || stdio::is_spooled(output, "stdout") {
+        return "";
+    }
+    match stdio::clobber().unwrap_or(stdio::Clobber::Replace) {
+        stdio::Clobber::Replace => " (overwrites existing file)",
+        stdio::Clobber::Skip => " (skipped, output exists)",
+        stdio::Clobber::Refuse => " (refused, output exists)",
+    }
+}
+
 fn strip_suffix(name: &str, alg: Algorithm) -> String {
+    // Short tarball forms decompress to a plain .tar
+    let short_tar = match alg {
+        Algorithm::Gzip => &["tgz"][..],
+        Algorithm::Xz => &["txz"][..],
+        Algorithm::Bzip2 => &["tbz2", "tbz"][..],
+        Algorithm::Zstd => &["tzst"][..],
+        _ => &[][..],
+    };
+    for ext in short_tar {
+        if let Some(stem) = name.strip_suffix(&format!(".{}", ext)) {
+            return format!("{}.tar", stem);
+        }
+    }
+
     let suffix = format!(".{}", alg.extension());
     if let Some(stripped) = name.strip_suffix(&suffix) {
         stripped.to_string()
@@ -249,39 +698,60 @@ fn strip_suffix(name: &str, alg: Algorithm) -> String {
 
 fn check_extension(ext: &str) -> Option<Algorithm> {
     match ext {
-        "zst" => Some(Algorithm::Zstd),
+        "zst" | "tzst" => Some(Algorithm::Zstd),
         "lz4" => Some(Algorithm::Lz4),
         "br" => Some(Algorithm::Brotli),
         "sz" => Some(Algorithm::Snappy),
+        "gz" | "tgz" => Some(Algorithm::Gzip),
+        "xz" | "txz" => Some(Algorithm::Xz),
+        "bz2" | "tbz2" | "tbz" => Some(Algorithm::Bzip2),
         _ => None,
     }
 }
 
 fn sniff_magic(path: &Path) -> Result<Option<Algorithm>> {
     let mut file = File::open(path)?;
-    let mut buffer = [0u8; 4];
+    let mut buffer = [0u8; 6];
     let n = file.read(&mut buffer)?;
-    if n < 4 { return Ok(None); }
+    Ok(magic_algorithm(&buffer[..n]))
+}
 
+// From the first 6 bytes of a stream
+pub(crate) fn magic_algorithm(buffer: &[u8]) -> Option<Algorithm> {
     // Zstd Magic: 28 B5 2F FD
-    if buffer == [0x28, 0xB5, 0x2F, 0xFD] {
-        return Ok(Some(Algorithm::Zstd));
+    if buffer.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) {
+        return Some(Algorithm::Zstd);
     }
 
     // LZ4 Magic: 04 22 4D 18
-    if buffer == [0x04, 0x22, 0x4D, 0x18] {
-        return Ok(Some(Algorithm::Lz4));
+    if buffer.starts_with(&[0x04, 0x22, 0x4D, 0x18]) {
+        return Some(Algorithm::Lz4);
     }
 
     // Snappy Magic: 73 4E 61 50 70 59 (only first 4 bytes used)
-    if buffer == [0x73, 0x4E, 0x61, 0x50] {
-        return Ok(Some(Algorithm::Snappy));
+    if buffer.starts_with(&[0x73, 0x4E, 0x61, 0x50]) {
+        return Some(Algorithm::Snappy);
+    }
+
+    // Gzip Magic: 1F 8B
+    if buffer.starts_with(&[0x1F, 0x8B]) {
+        return Some(Algorithm::Gzip);
+    }
+
+    // XZ Magic: FD 37 7A 58 5A 00
+    if buffer.starts_with(&[0xFD, 0x37, 0x7A, 0x58, 0x5A, 0x00]) {
+        return Some(Algorithm::Xz);
     }
 
-    Ok(None)
+    // Bzip2 Magic: 42 5A 68 ("BZh")
+    if buffer.starts_with(b"BZh") {
+        return Some(Algorithm::Bzip2);
+    }
+
+    None
 }
 
-fn compress_zstd(input: &File, output: &File, comp_level: i32, threads: u32) -> Result<()> {
+fn compress_zstd(input: impl Read, output: impl Write, comp_level: i32, threads: u32) -> Result<()> {
     let mut reader = io::BufReader::new(input);
     let mut writer = io::BufWriter::new(output);
 
@@ -289,7 +759,7 @@ fn compress_zstd(input: &File, output: &File, comp_level: i32, threads: u32) ->
 
     encoder.multithread(threads)?;
 
-    let mut buffer = vec![0u8; zstd::stream::write::Encoder::<io::BufWriter<File>>::recommended_input_size()];
+    let mut buffer = vec![0u8; zstd::stream::write::Encoder::<io::Sink>::recommended_input_size()];
     loop {
         let n = reader.read(&mut buffer)?;
         if n == 0 { break }
@@ -297,21 +767,11 @@ fn compress_zstd(input: &File, output: &File, comp_level: i32, threads: u32) ->
     }
 
     let _result = encoder.finish()?;
-    Ok(())
-}
-
-fn decompress_zstd(input: &File, output: &File) -> Result<()> {
-    let mut reader = io::BufReader::new(input);
-    let mut writer = io::BufWriter::new(output);
-
-    let mut decoder = zstd::stream::read::Decoder::new(&mut reader)?;
-
-    io::copy(&mut decoder, &mut writer)?;
     writer.flush()?;
     Ok(())
 }
 
-fn compress_lz4(input: &mut File, output: &File) -> Result<()> {
+fn compress_lz4(mut input: impl Read, output: impl Write) -> Result<()> {
     let mut encoder = lz4_flex::frame::FrameEncoder::new(output);
 
     let mut buffer = vec![0u8; 1 << 20];
@@ -324,13 +784,7 @@ fn compress_lz4(input: &mut File, output: &File) -> Result<()> {
     Ok(())
 }
 
-fn decompress_lz4(input: &File, mut output: &mut File) -> Result<()> {
-    let mut decoder = lz4_flex::frame::FrameDecoder::new(input);
-    std::io::copy(&mut decoder, &mut output)?;
-    Ok(())
-}
-
-fn compress_brotli(input: &File, output: &File, comp_level: u32) -> Result<()> {
+fn compress_brotli(input: impl Read, output: impl Write, comp_level: u32) -> Result<()> {
     let mut reader = io::BufReader::new(input);
     let writer = io::BufWriter::new(output);
 
@@ -345,22 +799,11 @@ fn compress_brotli(input: &File, output: &File, comp_level: u32) -> Result<()> {
         if n == 0 { break }
         encoder.write_all(&buffer[..n])?;
     }
-    encoder.finish()?;
-    Ok(())
-}
-
-fn decompress_brotli(input: &File, output: &File) -> Result<()> {
-    let mut reader = io::BufReader::new(input);
-    let mut writer = io::BufWriter::new(output);
-
-    let mut decoder = brotli2::write::BrotliDecoder::new(&mut writer);
-
-    std::io::copy(&mut reader, &mut decoder)?;
-    decoder.flush()?;
+    encoder.finish()?.flush()?;
     Ok(())
 }
 
-fn compress_snappy(input: &mut File, output: &File) -> Result<()> {
+fn compress_snappy(mut input: impl Read, output: impl Write) -> Result<()> {
     let mut encoder = snap::write::FrameEncoder::new(output);
 
     let mut buffer = vec![0u8; 1 << 20];
@@ -373,8 +816,26 @@ fn compress_snappy(input: &mut File, output: &File) -> Result<()> {
     Ok(())
 }
 
-fn decompress_snappy(input: &File, mut output: &mut File) -> Result<()> {
-    let mut decoder = snap::read::FrameDecoder::new(input);
-    std::io::copy(&mut decoder, &mut output)?;
+fn compress_gzip(input: impl Read, output: impl Write, comp_level: u32) -> Result<()> {
+    let writer = io::BufWriter::new(output);
+    let mut encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::new(comp_level.min(9)));
+    io::copy(&mut io::BufReader::new(input), &mut encoder)?;
+    encoder.finish()?.flush()?;
+    Ok(())
+}
+
+fn compress_xz(input: impl Read, output: impl Write, comp_level: u32) -> Result<()> {
+    let writer = io::BufWriter::new(output);
+    let mut encoder = xz2::write::XzEncoder::new(writer, comp_level.min(9));
+    io::copy(&mut io::BufReader::new(input), &mut encoder)?;
+    encoder.finish()?.flush()?;
     Ok(())
-}
\ No newline at end of file
+}
+
+fn compress_bzip2(input: impl Read, output: impl Write, comp_level: u32) -> Result<()> {
+    let writer = io::BufWriter::new(output);
+    let mut encoder = bzip2::write::BzEncoder::new(writer, bzip2::Compression::new(comp_level.clamp(1, 9)));
+    io::copy(&mut io::BufReader::new(input), &mut encoder)?;
+    encoder.finish()?.flush()?;
+    Ok(())
+}
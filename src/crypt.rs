@@ -1,4 +1,4 @@
-use anyhow::{Context, Result, bail, ensure};
+use anyhow::{Context, Result, anyhow, bail, ensure};
 use argon2::{Algorithm, Argon2, Params, Version};
 use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce, aead::Aead};
 use clap::{Args};
@@ -6,13 +6,18 @@ use rand::TryRngCore;
 use rand::rngs::OsRng;
 use std::ffi::OsStr;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+use std::io::{BufReader, Cursor, Read, Write};
 use std::path::{Path, PathBuf};
 use tar::{Archive as TarArchive, Builder as TarBuilder};
-use zeroize::Zeroize;
+use x25519_dalek::{PublicKey, StaticSecret};
+use tracing::info;
+use zeroize::{Zeroize, Zeroizing};
+use crate::{errors, memory, stdio};
 
 const MAGIC: &[u8; 6] = b"JJTOOL";
 const VERSION: u8 = 2;
+// Same payload as VERSION, keyed by X25519 instead of a password
+const RECIPIENT_VERSION: u8 = 3;
 
 #[repr(u8)]
 enum Kind {
@@ -20,6 +25,41 @@ enum Kind {
     Directory = 1,
 }
 
+/// Argon2id cost parameters. They are stored in the header, so decryption only needs the password
+#[derive(Clone, Copy, Debug)]
+pub struct KdfParams {
+    pub m_cost_kib: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams { m_cost_kib: 19_456, t_cost: 2, p_cost: 1 }
+    }
+}
+
+// Header fields and cipher text of an encrypted file, before the key is known
+struct Sealed {
+    version: u8,
+    lock: Lock,
+    nonce: [u8; 24],
+    ciphertext: Vec<u8>,
+}
+
+// How the key of an encrypted file is derived
+enum Lock {
+    // Argon2id over the password
+    Password { params: KdfParams, salt: [u8; 16] },
+    // X25519 between the recipient's key and a one-off key pair, whose public half is stored
+    Recipient { ephemeral: [u8; 32] },
+}
+
+// What opens a `Sealed`
+enum Secret<'a> {
+    Password(&'a str),
+    Identity(&'a StaticSecret),
+}
 
 #[derive(Args)]
 #[command[name = "encrypt", about = "Simple password-based file encryption using Argon2id with ChaCha20Poly1305"]]
@@ -29,12 +69,19 @@ pub struct EncryptArgs {
     output: Option<PathBuf>,
     #[arg(short = 'd', long)]
     directory: bool,
+    /// Encrypt to an X25519 recipient instead of a password: an `age1...` key, or a file holding
+    /// one such as the .pub of `keygen --for-crypt`
+    #[arg(long)]
+    recipient: Option<String>,
     #[arg(long, default_value_t = 19_456)]
     m_cost_kib: u32,
     #[arg(long, default_value_t = 2)]
     t_cost: u32,
     #[arg(long, default_value_t = 1)]
     p_cost: u32,
+    /// Once encrypted, overwrite the input with random data and remove it, as the shred command
+    #[arg(long)]
+    shred: bool,
 }
 
 #[derive(Args)]
@@ -43,48 +90,45 @@ pub struct DecryptArgs {
     input: PathBuf,
     #[arg(short, long)]
     output: Option<PathBuf>,
+    /// Identity file for a file encrypted with --recipient, e.g. the .key of `keygen --for-crypt`
+    #[arg(short, long)]
+    identity: Option<PathBuf>,
 }
 
 pub fn encrypt(a: EncryptArgs) -> Result<()> {
-    let input_path = &a.input;
-    let output_path = a.output.clone().unwrap_or_else(|| {
-        let mut out = input_path.clone();
+    if a.directory {
+        stdio::ensure_not_dash(&a.input, "--directory")?;
+    }
+    if a.shred {
+        stdio::ensure_not_dash(&a.input, "--shred")?;
+    }
+    let input = stdio::input(&a.input)?;
+    let input_path = input.path();
+    let output_path = a.output.clone().or_else(|| stdio::default_output(&a.input)).unwrap_or_else(|| {
+        let mut out = input_path.to_path_buf();
         out.set_extension("jj");
         out
     });
+    if !stdio::check_output(&output_path)? {
+        return Ok(());
+    }
 
-    // Ask for password
-    let mut password = loop {
-        let mut pwd = rpassword::prompt_password("Password: ")?;
-        let mut confirm = rpassword::prompt_password("Repeat password: ")?;
-
-        if pwd == confirm {
-            confirm.zeroize();
-            break pwd;
-        } else {
-            confirm.zeroize();
-            pwd.zeroize();
-            eprintln!("Passwords do not match. Please try again.");
+    if a.shred && !stdio::is_dash(&output_path) {
+        let input = std::fs::canonicalize(input_path).with_context(|| format!("resolve {}", input_path.display()))?;
+        let parent = output_path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let output = std::fs::canonicalize(parent).map(|p| p.join(output_path.file_name().unwrap_or_default()));
+        if output.is_ok_and(|output| output.starts_with(&input)) {
+            bail!("--shred would destroy the output, which is the input or inside it");
         }
-    };
-
-    let kdf_params =
-        Params::new(a.m_cost_kib, a.t_cost, a.p_cost, None).context("invalid Argon2 params")?;
-    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, kdf_params);
+    }
 
-    // Salt + Key
-    let mut salt = [0u8; 16];
-    OsRng.try_fill_bytes(&mut salt)?;
-    let mut key = [0u8; 32];
-    argon2
-        .hash_password_into(password.as_bytes(), &salt, &mut key)
-        .context("argon2 key derivation failed")?;
+    let recipient = a.recipient.as_deref().map(read_recipient).transpose()?;
 
-    // Cipher + Nonce
-    let cipher = XChaCha20Poly1305::new((&key).into());
-    let mut nonce_bytes = [0u8; 24];
-    OsRng.try_fill_bytes(&mut nonce_bytes)?;
-    let nonce = XNonce::from(nonce_bytes);
+    // Ask for password
+    let password = match recipient {
+        Some(_) => Zeroizing::new(String::new()),
+        None => prompt_new_password()?,
+    };
 
     // Build package
     let pkg = if a.directory {
@@ -98,6 +142,10 @@ pub fn encrypt(a: EncryptArgs) -> Result<()> {
         let base_bytes = base_name.as_bytes();
         let base_len = u16::try_from(base_bytes.len()).context("base dir name too long")?;
 
+        // The tar and its compressed copy are both held, at most the size of the files each
+        let total: u64 = walkdir::WalkDir::new(input_path).into_iter().filter_map(|e| e.ok()?.metadata().ok()).filter(|m| m.is_file()).map(|m| m.len()).sum();
+        memory::reserve(total.saturating_mul(2), || format!("encrypting {}", input_path.display()))?;
+
         // TAR
         let mut tar_buf = Vec::new();
         {
@@ -120,13 +168,16 @@ pub fn encrypt(a: EncryptArgs) -> Result<()> {
         ensure!(input_path.is_file(), "input is not a file");
 
         // Read file
-        let mut reader = BufReader::new(
-            File::open(input_path).with_context(|| format!("open {}", input_path.display()))?,
-        );
+        let file = File::open(input_path).with_context(|| errors::at("open", input_path))?;
+        let len = file.metadata()?.len();
+        // The package, then it and its cipher text
+        memory::reserve(len.saturating_mul(2), || format!("encrypting {}", input_path.display()))?;
+        let bar = crate::progress::bytes(len, input_path);
+        let mut reader = BufReader::new(bar.wrap_read(file));
         let mut file_bytes = Vec::new();
         reader
             .read_to_end(&mut file_bytes)
-            .with_context(|| format!("read {}", input_path.display()))?;
+            .with_context(|| errors::at("read", input_path))?;
 
         // Extension
         let ext_str = input_path
@@ -147,23 +198,151 @@ pub fn encrypt(a: EncryptArgs) -> Result<()> {
         pkg
     };
 
-    // Encrypt
-    let ciphertext = cipher.encrypt(&nonce, pkg.as_ref()).unwrap();
+    let params = KdfParams { m_cost_kib: a.m_cost_kib, t_cost: a.t_cost, p_cost: a.p_cost };
+    let mut w = stdio::writer(&output_path, true)?;
+    match &recipient {
+        Some(recipient) => seal_to(&pkg, recipient, &mut w)?,
+        None => seal(&pkg, &password, params, &mut w)?,
+    }
+    w.finish()?;
+    stdio::finished(input_path, &output_path)?;
+    if a.shred {
+        let options = crate::shred::ShredOptions::default();
+        if a.directory {
+            crate::shred::shred_tree(input_path, options)?;
+        } else {
+            crate::shred::shred_file(input_path, options)?;
+        }
+        info!("Shredded {}", input_path.display());
+    }
+    Ok(())
+}
+
+// Asked twice until both entries match
+pub(crate) fn prompt_new_password() -> Result<Zeroizing<String>> {
+    loop {
+        let pwd = Zeroizing::new(rpassword::prompt_password("Password: ")?);
+        let confirm = Zeroizing::new(rpassword::prompt_password("Repeat password: ")?);
+        if pwd == confirm {
+            return Ok(pwd);
+        }
+        eprintln!("Passwords do not match. Please try again.");
+    }
+}
+
+/// Encrypts everything `reader` yields into `writer`, in the format the encrypt command writes
+/// for a file without an extension. The input is sealed as one message, so it is read into
+/// memory whole
+pub fn encrypt_stream(reader: impl Read, writer: impl Write, password: &str, params: KdfParams) -> Result<()> {
+    let pkg = stream_package(reader)?;
+    seal(&pkg, password, params, writer)
+}
+
+/// Like [`encrypt_stream`], but for the holder of an X25519 identity instead of a password, as
+/// `encrypt --recipient` does. `recipient` is an `age1...` key
+pub fn encrypt_stream_to(reader: impl Read, writer: impl Write, recipient: &str) -> Result<()> {
+    encrypt_stream_for(reader, writer, &parse_recipient(recipient)?)
+}
+
+pub(crate) fn encrypt_stream_for(reader: impl Read, writer: impl Write, recipient: &PublicKey) -> Result<()> {
+    let pkg = stream_package(reader)?;
+    seal_to(&pkg, recipient, writer)
+}
+
+/// Decrypts a file written by the encrypt command or [`encrypt_stream`] into `writer`. A wrong
+/// password and a tampered file both fail authentication. Encrypted directories are refused;
+/// they unpack to a tree, which only the decrypt command writes
+pub fn decrypt_stream(reader: impl Read, writer: impl Write, password: &str) -> Result<()> {
+    open_stream(reader, writer, Secret::Password(password))
+}
+
+/// [`decrypt_stream`] for files encrypted to a recipient, with the contents of its identity
+/// file (the `AGE-SECRET-KEY-1...` line, comments allowed)
+pub fn decrypt_stream_with(reader: impl Read, writer: impl Write, identity: &str) -> Result<()> {
+    decrypt_stream_for(reader, writer, &parse_identity(identity)?)
+}
+
+pub(crate) fn decrypt_stream_for(reader: impl Read, writer: impl Write, identity: &StaticSecret) -> Result<()> {
+    open_stream(reader, writer, Secret::Identity(identity))
+}
+
+// The Argon2id parameters in an encrypted file's header, none when it opens with an identity
+// rather than a password
+pub(crate) fn kdf_params(reader: impl Read) -> Result<Option<KdfParams>> {
+    Ok(match read_sealed(reader)?.lock {
+        Lock::Password { params, .. } => Some(params),
+        Lock::Recipient { .. } => None,
+    })
+}
+
+// Package of a file without an extension
+fn stream_package(reader: impl Read) -> Result<Zeroizing<Vec<u8>>> {
+    let data = Zeroizing::new(memory::read_to_end(reader, 2, || "encrypting the input".to_string())?);
+    let mut pkg = Zeroizing::new(Vec::with_capacity(3 + data.len()));
+    pkg.push(Kind::File as u8);
+    pkg.extend_from_slice(&0u16.to_le_bytes());
+    pkg.extend_from_slice(&data);
+    Ok(pkg)
+}
+
+fn open_stream(reader: impl Read, mut writer: impl Write, secret: Secret) -> Result<()> {
+    let sealed = read_sealed(reader)?;
+    let version = sealed.version;
+    let mut pkg = sealed.open(secret)?;
+    let data = match version {
+        1 => legacy_file(&pkg)?.1,
+        _ => match file_package(&pkg)? {
+            Some((_, data)) => data,
+            None => bail!("this file holds an encrypted directory; use the decrypt command"),
+        },
+    };
+    writer.write_all(data)?;
+    writer.flush()?;
+    pkg.zeroize();
+    Ok(())
+}
+
+// Derives the key from the password, encrypts `pkg` and writes header + cipher text
+fn seal(pkg: &[u8], password: &str, params: KdfParams, w: impl Write) -> Result<()> {
+    // Salt + Key
+    let mut salt = [0u8; 16];
+    OsRng.try_fill_bytes(&mut salt)?;
+    let key = password_key(password, params, &salt)?;
+
+    let mut header = vec![VERSION];
+    header.extend_from_slice(&params.m_cost_kib.to_le_bytes());
+    header.extend_from_slice(&params.t_cost.to_le_bytes());
+    header.extend_from_slice(&params.p_cost.to_le_bytes());
+    header.extend_from_slice(&salt);
+    seal_with(pkg, &key, &header, w)
+}
+
+// Same for a recipient: only the one-off public key goes in the header
+fn seal_to(pkg: &[u8], recipient: &PublicKey, w: impl Write) -> Result<()> {
+    let mut secret = Zeroizing::new([0u8; 32]);
+    OsRng.try_fill_bytes(secret.as_mut())?;
+    let ephemeral = StaticSecret::from(*secret);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let key = recipient_key(ephemeral.diffie_hellman(recipient), &ephemeral_public, recipient)?;
+
+    let mut header = vec![RECIPIENT_VERSION];
+    header.extend_from_slice(ephemeral_public.as_bytes());
+    seal_with(pkg, &key, &header, w)
+}
+
+fn seal_with(pkg: &[u8], key: &[u8; 32], header: &[u8], mut w: impl Write) -> Result<()> {
+    // Cipher + Nonce
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let mut nonce_bytes = [0u8; 24];
+    OsRng.try_fill_bytes(&mut nonce_bytes)?;
+    let nonce = XNonce::from(nonce_bytes);
 
-    // Zeroize secrets
-    password.zeroize();
-    key.zeroize();
+    // Encrypt
+    let ciphertext = cipher.encrypt(&nonce, pkg).map_err(|_| anyhow!("encryption failed"))?;
 
     // Write header + cipher text
-    let mut w = BufWriter::new(
-        File::create(&output_path).with_context(|| format!("create {}", output_path.display()))?,
-    );
     w.write_all(MAGIC)?;
-    w.write_all(&[VERSION])?;
-    w.write_all(&a.m_cost_kib.to_le_bytes())?;
-    w.write_all(&a.t_cost.to_le_bytes())?;
-    w.write_all(&a.p_cost.to_le_bytes())?;
-    w.write_all(&salt)?;
+    w.write_all(header)?;
     w.write_all(&nonce_bytes)?;
     let ct_len = ciphertext.len() as u64;
     w.write_all(&ct_len.to_le_bytes())?;
@@ -172,14 +351,7 @@ pub fn encrypt(a: EncryptArgs) -> Result<()> {
     Ok(())
 }
 
-pub fn decrypt(a: DecryptArgs) -> Result<()> {
-    let input_path = &a.input;
-
-    // Parse header
-    let mut r = BufReader::new(
-        File::open(input_path).with_context(|| format!("open {}", input_path.display()))?,
-    );
-
+fn read_sealed(mut r: impl Read) -> Result<Sealed> {
     let mut magic = [0u8; 6];
     r.read_exact(&mut magic)?;
     if &magic != MAGIC {
@@ -188,88 +360,140 @@ pub fn decrypt(a: DecryptArgs) -> Result<()> {
 
     let mut ver = [0u8; 1];
     r.read_exact(&mut ver)?;
-    if ver[0] != 1 && ver[0] != VERSION {
-        bail!("unsupported version {}", ver[0]);
-    }
-    let payload_version = ver[0];
-
-    let m_cost_kib = read_u32(&mut r)?;
-    let t_cost = read_u32(&mut r)?;
-    let p_cost = read_u32(&mut r)?;
+    let lock = match ver[0] {
+        1 | VERSION => {
+            let m_cost_kib = read_u32(&mut r)?;
+            let t_cost = read_u32(&mut r)?;
+            let p_cost = read_u32(&mut r)?;
+            let mut salt = [0u8; 16];
+            r.read_exact(&mut salt)?;
+            Lock::Password { params: KdfParams { m_cost_kib, t_cost, p_cost }, salt }
+        }
+        RECIPIENT_VERSION => {
+            let mut ephemeral = [0u8; 32];
+            r.read_exact(&mut ephemeral)?;
+            Lock::Recipient { ephemeral }
+        }
+        v => bail!("unsupported version {}", v),
+    };
 
-    let mut salt = [0u8; 16];
-    r.read_exact(&mut salt)?;
-    let mut nonce_bytes = [0u8; 24];
-    r.read_exact(&mut nonce_bytes)?;
+    let mut nonce = [0u8; 24];
+    r.read_exact(&mut nonce)?;
 
     // Read cipher text
     let ct_len = read_u64(&mut r)?;
+    // The cipher text, then the plain text beside it
+    memory::reserve(ct_len.saturating_mul(2), || format!("decrypting {} of cipher text", memory::size(ct_len)))?;
     let mut ciphertext = vec![0u8; ct_len as usize];
     r.read_exact(&mut ciphertext)?;
 
-    // Password + Key
-    let mut password = rpassword::prompt_password("Password: ")?;
-    let kdf_params = Params::new(m_cost_kib, t_cost, p_cost, None)?;
-    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, kdf_params);
+    Ok(Sealed { version: ver[0], lock, nonce, ciphertext })
+}
+
+impl Sealed {
+    fn open(self, secret: Secret) -> Result<Vec<u8>> {
+        let key = match (&self.lock, secret) {
+            (Lock::Password { params, salt }, Secret::Password(password)) => password_key(password, *params, salt)?,
+            (Lock::Recipient { ephemeral }, Secret::Identity(identity)) => {
+                let ephemeral = PublicKey::from(*ephemeral);
+                recipient_key(identity.diffie_hellman(&ephemeral), &ephemeral, &PublicKey::from(identity))?
+            }
+            (Lock::Password { .. }, Secret::Identity(_)) => bail!("this file is encrypted with a password, not to a recipient"),
+            (Lock::Recipient { .. }, Secret::Password(_)) => bail!("this file is encrypted to a recipient; decrypt it with its identity"),
+        };
 
-    let mut key = [0u8; 32];
+        let cipher = XChaCha20Poly1305::new(key.as_ref().into());
+        let wrong = match self.lock {
+            Lock::Password { .. } => "wrong password",
+            Lock::Recipient { .. } => "wrong identity",
+        };
+        cipher
+            .decrypt(&XNonce::from(self.nonce), self.ciphertext.as_ref())
+            .map_err(|_| anyhow!("decryption failed: {} or corrupted file", wrong))
+    }
+}
+
+pub(crate) fn password_key(password: &str, params: KdfParams, salt: &[u8; 16]) -> Result<Zeroizing<[u8; 32]>> {
+    memory::reserve(u64::from(params.m_cost_kib) * 1024, || format!("Argon2id with {} KiB", params.m_cost_kib))?;
+    let kdf_params = Params::new(params.m_cost_kib, params.t_cost, params.p_cost, None).context("invalid Argon2 params")?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, kdf_params);
+    let mut key = Zeroizing::new([0u8; 32]);
     argon2
-        .hash_password_into(password.as_bytes(), &salt, &mut key)
+        .hash_password_into(password.as_bytes(), salt, key.as_mut())
         .context("argon2 key derivation failed")?;
+    Ok(key)
+}
 
-    // Decrypt
-    let cipher = XChaCha20Poly1305::new((&key).into());
-    let nonce = XNonce::from(nonce_bytes);
-    let pkg = cipher.decrypt(&nonce, ciphertext.as_ref()).unwrap();
-
-    // Zeroize secrets
-    password.zeroize();
-    key.zeroize();
-
-    // Legacy V1
-    if payload_version == 1 {
-        ensure!(pkg.len() >= 2, "truncated payload");
-        let ext_len = u16::from_le_bytes([pkg[0], pkg[1]]) as usize;
-        ensure!(pkg.len() >= 2 + ext_len, "truncated payload");
-        let ext_bytes = &pkg[2..2 + ext_len];
-        let file_bytes = &pkg[2 + ext_len..];
-        let org_ext = String::from_utf8_lossy(ext_bytes).to_string();
+// HKDF-SHA256 over the X25519 shared secret, salted with both public keys so the key is bound
+// to this recipient. Sealing computes `shared` with the one-off secret, opening with the identity
+fn recipient_key(shared: x25519_dalek::SharedSecret, ephemeral: &PublicKey, recipient: &PublicKey) -> Result<Zeroizing<[u8; 32]>> {
+    ensure!(shared.was_contributory(), "invalid X25519 public key");
+    let mut key = Zeroizing::new([0u8; 32]);
+    let salt = [&ephemeral.as_bytes()[..], recipient.as_bytes()].concat();
+    hkdf::Hkdf::<sha2::Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(b"jj_toolkit recipient", key.as_mut())
+        .map_err(|_| anyhow!("HKDF output length is invalid"))?;
+    Ok(key)
+}
 
-        let output_path = a.output.clone().unwrap_or_else(|| {
-            let stem = input_path
-                .file_stem()
-                .unwrap_or_else(|| OsStr::new("output"));
-            let parent = input_path.parent().unwrap_or(Path::new("."));
-            let mut out = parent.join(stem);
-            let ext_for_out = if org_ext.is_empty() { "out" } else { &org_ext };
-            out.set_extension(ext_for_out);
-            out
-        });
+// V1 payload: ext len (u16) | ext | data
+fn legacy_file(pkg: &[u8]) -> Result<(&[u8], &[u8])> {
+    ensure!(pkg.len() >= 2, "truncated payload");
+    let ext_len = u16::from_le_bytes([pkg[0], pkg[1]]) as usize;
+    ensure!(pkg.len() >= 2 + ext_len, "truncated payload");
+    Ok((&pkg[2..2 + ext_len], &pkg[2 + ext_len..]))
+}
 
-        let mut w = BufWriter::new(
-            File::create(&output_path)
-                .with_context(|| format!("create {}", output_path.display()))?,
-        );
-        w.write_all(file_bytes)?;
-        w.flush()?;
-        return Ok(());
+// V2 file payload: kind | ext len (u16) | ext | data. None for a directory
+fn file_package(pkg: &[u8]) -> Result<Option<(&[u8], &[u8])>> {
+    ensure!(!pkg.is_empty(), "truncated payload");
+    if pkg[0] != Kind::File as u8 {
+        return Ok(None);
     }
+    ensure!(pkg.len() > 2, "truncated payload");
+    let ext_len = u16::from_le_bytes([pkg[1], pkg[2]]) as usize;
+    ensure!(pkg.len() >= 3 + ext_len, "truncated payload");
+    Ok(Some((&pkg[3..3 + ext_len], &pkg[3 + ext_len..])))
+}
 
-    // Active V2
-    ensure!(pkg.len() >= 1, "truncated payload");
-    let kind = pkg[0];
+pub fn decrypt(a: DecryptArgs) -> Result<()> {
+    let input_path = &a.input;
 
-    if kind == Kind::File as u8 {
-        ensure!(pkg.len() >= 1 + 2, "truncated payload");
-        let ext_len = u16::from_le_bytes([pkg[1], pkg[2]]) as usize;
-        ensure!(pkg.len() >= 3 + ext_len + 1, "truncated payload");
-        let ext_bytes = &pkg[3..3 + ext_len];
-        let data = &pkg[3 + ext_len..];
+    // Parse header
+    let sealed = if stdio::is_dash(input_path) {
+        read_sealed(stdio::reader(input_path)?)?
+    } else {
+        let file = File::open(input_path).with_context(|| errors::at("open", input_path))?;
+        let bar = crate::progress::bytes(file.metadata()?.len(), input_path);
+        let sealed = read_sealed(BufReader::new(bar.wrap_read(file)))?;
+        bar.finish_and_clear();
+        sealed
+    };
+    let payload_version = sealed.version;
+
+    // Identity or Password + Decrypt
+    let pkg = match &a.identity {
+        Some(path) => sealed.open(Secret::Identity(&read_identity(path)?))?,
+        None => {
+            if matches!(sealed.lock, Lock::Recipient { .. }) {
+                bail!("this file is encrypted to a recipient; pass its identity with --identity");
+            }
+            let mut password = rpassword::prompt_password("Password: ")?;
+            let pkg = sealed.open(Secret::Password(&password));
+            password.zeroize();
+            pkg?
+        }
+    };
 
-        let file_bytes = data.to_vec();
+    // Legacy V1 carries only files, V2 files or directories
+    let file = match payload_version {
+        1 => Some(legacy_file(&pkg)?),
+        _ => file_package(&pkg)?,
+    };
 
+    if let Some((ext_bytes, file_bytes)) = file {
         let org_ext = String::from_utf8_lossy(ext_bytes).to_string();
-        let output_path = a.output.clone().unwrap_or_else(|| {
+        let output_path = a.output.clone().or_else(|| stdio::default_output(input_path)).unwrap_or_else(|| {
             let stem = input_path
                 .file_stem()
                 .unwrap_or_else(|| OsStr::new("output"));
@@ -280,16 +504,20 @@ pub fn decrypt(a: DecryptArgs) -> Result<()> {
             out
         });
 
-        let mut w = BufWriter::new(
-            File::create(&output_path)
-                .with_context(|| format!("create {}", output_path.display()))?,
-        );
-        w.write_all(&file_bytes)?;
-        w.flush()?;
+        if !stdio::check_output(&output_path)? {
+            return Ok(());
+        }
+        let mut w = stdio::writer(&output_path, true)?;
+        w.write_all(file_bytes)?;
+        w.finish()?;
+        stdio::finished(input_path, &output_path)?;
     } else {
-        ensure!(pkg.len() >= 1 + 2, "truncated payload");
+        if a.output.as_deref().map_or(stdio::is_dash(input_path), stdio::is_dash) {
+            bail!("this file holds an encrypted directory, which cannot be written to stdout; pass --output <DIR>");
+        }
+        ensure!(pkg.len() > 2, "truncated payload");
         let name_len = u16::from_le_bytes([pkg[1], pkg[2]]) as usize;
-        ensure!(pkg.len() >= 3 + name_len + 1, "truncated payload");
+        ensure!(pkg.len() > 3 + name_len, "truncated payload");
         let _base_name = &pkg[3..3 + name_len]; // informational
         let data = &pkg[3 + name_len..];
 
@@ -300,7 +528,7 @@ pub fn decrypt(a: DecryptArgs) -> Result<()> {
         let extract_parent = if let Some(out) = a.output.clone() {
             if !out.exists() {
                 std::fs::create_dir_all(&out)
-                    .with_context(|| format!("create {}", out.display()))?;
+                    .with_context(|| errors::at("create", &out))?;
             }
             out
         } else {
@@ -311,12 +539,47 @@ pub fn decrypt(a: DecryptArgs) -> Result<()> {
         let mut ar = TarArchive::new(decoded);
         for entry in ar.entries().context("reading tar entries failed")? {
             let mut e = entry.context("invalid tar entry")?;
+            if !e.header().entry_type().is_dir() && !stdio::check_output(&extract_parent.join(e.path()?))? {
+                continue;
+            }
             e.unpack_in(&extract_parent).context("tar unpack failed")?;
         }
     }
     Ok(())
 }
 
+// An `age1...` key, or the first such line of a file
+pub(crate) fn read_recipient(arg: &str) -> Result<PublicKey> {
+    if arg.starts_with("age1") {
+        return parse_recipient(arg);
+    }
+    let text = std::fs::read_to_string(arg).with_context(|| format!("read recipient {}", arg))?;
+    let line = text.lines().map(str::trim).find(|l| l.starts_with("age1")).with_context(|| format!("no age1... recipient in {}", arg))?;
+    parse_recipient(line)
+}
+
+fn parse_recipient(text: &str) -> Result<PublicKey> {
+    let (hrp, data) = bech32::decode(text.trim()).context("invalid recipient")?;
+    ensure!(hrp.as_str() == "age", "invalid recipient: expected an age1... key");
+    let bytes: [u8; 32] = data.try_into().map_err(|_| anyhow!("invalid recipient: expected a 32-byte X25519 key"))?;
+    Ok(PublicKey::from(bytes))
+}
+
+// The AGE-SECRET-KEY-1... line of an identity file written by `keygen --for-crypt`
+fn parse_identity(text: &str) -> Result<StaticSecret> {
+    let line = text.lines().map(str::trim).find(|l| l.starts_with("AGE-SECRET-KEY-1")).context("no AGE-SECRET-KEY-1... line in the identity")?;
+    let (hrp, data) = bech32::decode(line).context("invalid identity")?;
+    let data = Zeroizing::new(data);
+    ensure!(hrp.as_str().eq_ignore_ascii_case("age-secret-key-"), "invalid identity");
+    let bytes: Zeroizing<[u8; 32]> = Zeroizing::new(data.as_slice().try_into().map_err(|_| anyhow!("invalid identity: expected a 32-byte X25519 key"))?);
+    Ok(StaticSecret::from(*bytes))
+}
+
+pub(crate) fn read_identity(path: &Path) -> Result<StaticSecret> {
+    let text = Zeroizing::new(std::fs::read_to_string(path).with_context(|| format!("read identity {}", path.display()))?);
+    parse_identity(&text)
+}
+
 fn read_u32(r: &mut dyn Read) -> Result<u32> {
     let mut b = [0u8; 4];
     r.read_exact(&mut b)?;
@@ -0,0 +1,236 @@
+// How a failed run ends. The exit code tells scripts what kind of failure it was, so a missing
+// input and a refused output can be told apart without reading the message, and with --json the
+// error goes to stderr as one JSON object instead of anyhow's text. Errors a caller may act on
+// are typed here and raised where they happen; everything else is classified by the I/O error
+// at the bottom of its chain, if any
+use serde_json::{Map, Value, json};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use crate::stdio;
+
+/// What went wrong, as far as a caller can act on it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// The command line was not understood
+    Usage,
+    /// An input, or a directory on the way to an output, does not exist
+    NotFound,
+    /// Reading or writing was not allowed
+    PermissionDenied,
+    /// An output exists and replacing it was refused
+    OutputExists,
+    /// An input is not what it claims to be, or ends early
+    InvalidData,
+    /// --max-memory stopped the command
+    MemoryLimit,
+    /// Worth retrying: interrupted, timed out, a dropped connection or a server error
+    Temporary,
+    /// Any other I/O error, e.g. a full disk
+    Io,
+    /// Files of a batch failed, each for its own reason
+    Batch,
+    /// Anything else
+    Failed,
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Usage => "usage",
+            Kind::NotFound => "not_found",
+            Kind::PermissionDenied => "permission_denied",
+            Kind::OutputExists => "output_exists",
+            Kind::InvalidData => "invalid_data",
+            Kind::MemoryLimit => "memory_limit",
+            Kind::Temporary => "temporary",
+            Kind::Io => "io",
+            Kind::Batch => "batch",
+            Kind::Failed => "failed",
+        }
+    }
+
+    /// The process exit code, from sysexits.h where one fits. Usage errors keep clap's 2
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Kind::Usage => 2,
+            Kind::InvalidData => 65,
+            Kind::NotFound => 66,
+            Kind::MemoryLimit => 69,
+            Kind::OutputExists => 73,
+            Kind::Io => 74,
+            Kind::Temporary => 75,
+            Kind::PermissionDenied => 77,
+            Kind::Batch | Kind::Failed => 1,
+        }
+    }
+}
+
+// An output that exists when replacing it is refused
+#[derive(Debug)]
+pub(crate) struct OutputExists(pub(crate) PathBuf);
+
+impl fmt::Display for OutputExists {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Output exists (use --force or --skip-existing): {}", self.0.display())
+    }
+}
+
+impl std::error::Error for OutputExists {}
+
+// Work refused by --max-memory, with the message saying what needed how much
+#[derive(Debug)]
+pub(crate) struct MemoryLimit(pub(crate) String);
+
+impl fmt::Display for MemoryLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for MemoryLimit {}
+
+// A server answering a request with a status other than success
+#[derive(Debug)]
+pub(crate) struct HttpStatus {
+    pub(crate) url: String,
+    pub(crate) status: u16,
+}
+
+impl fmt::Display for HttpStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "GET {}: HTTP status {}", self.url, self.status)
+    }
+}
+
+impl std::error::Error for HttpStatus {}
+
+// Files of a batch that failed, already logged one by one as they did
+#[derive(Debug)]
+pub(crate) struct Batch(pub(crate) Vec<(PathBuf, anyhow::Error)>);
+
+impl fmt::Display for Batch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} file(s) failed", self.0.len())
+    }
+}
+
+impl std::error::Error for Batch {}
+
+// Context naming the file an operation failed on, e.g. "open photos/a.png", which the JSON form
+// reports as the error's path
+#[derive(Debug)]
+pub(crate) struct At {
+    action: &'static str,
+    path: PathBuf,
+}
+
+pub(crate) fn at(action: &'static str, path: &Path) -> At {
+    At { action, path: path.to_path_buf() }
+}
+
+impl fmt::Display for At {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.action, stdio::display(&self.path))
+    }
+}
+
+/// The kind of a failure
+pub fn kind(e: &anyhow::Error) -> Kind {
+    if e.downcast_ref::<Batch>().is_some() {
+        return Kind::Batch;
+    }
+    if e.downcast_ref::<OutputExists>().is_some() {
+        return Kind::OutputExists;
+    }
+    if e.downcast_ref::<MemoryLimit>().is_some() {
+        return Kind::MemoryLimit;
+    }
+    if let Some(HttpStatus { status, .. }) = e.downcast_ref::<HttpStatus>() {
+        return match status {
+            404 | 410 => Kind::NotFound,
+            401 | 403 => Kind::PermissionDenied,
+            408 | 429 | 500..=599 => Kind::Temporary,
+            _ => Kind::Failed,
+        };
+    }
+    match e.chain().find_map(|cause| cause.downcast_ref::<io::Error>()).map(io::Error::kind) {
+        Some(io::ErrorKind::NotFound) => Kind::NotFound,
+        Some(io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem) => Kind::PermissionDenied,
+        Some(io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof) => Kind::InvalidData,
+        Some(
+            io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted,
+        ) => Kind::Temporary,
+        Some(_) => Kind::Io,
+        None => Kind::Failed,
+    }
+}
+
+// The file a failure is about, when it names one
+fn path(e: &anyhow::Error) -> Option<String> {
+    if let Some(OutputExists(path)) = e.downcast_ref::<OutputExists>() {
+        return Some(stdio::display(path));
+    }
+    e.downcast_ref::<At>().map(|at| stdio::display(&at.path))
+}
+
+// kind, message, causes below it, and path
+fn describe(e: &anyhow::Error) -> Map<String, Value> {
+    let mut object = Map::new();
+    object.insert("kind".into(), json!(kind(e).name()));
+    object.insert("message".into(), json!(e.to_string()));
+    object.insert("causes".into(), json!(e.chain().skip(1).map(|c| c.to_string()).collect::<Vec<_>>()));
+    object.insert("path".into(), json!(path(e)));
+    object
+}
+
+/// Prints `e` on stderr, as JSON with --json, and returns the exit code for its kind
+pub fn report(e: &anyhow::Error) -> ExitCode {
+    let kind = kind(e);
+    if !stdio::json() {
+        eprintln!("Error: {:?}", e);
+        return ExitCode::from(kind.exit_code());
+    }
+    let mut object = describe(e);
+    object.insert("exit_code".into(), json!(kind.exit_code()));
+    if let Some(Batch(failed)) = e.downcast_ref::<Batch>() {
+        let failures = failed
+            .iter()
+            .map(|(file, e)| {
+                let mut failure = describe(e);
+                failure.insert("path".into(), json!(stdio::display(file)));
+                Value::Object(failure)
+            })
+            .collect();
+        object.insert("failures".into(), Value::Array(failures));
+    }
+    eprintln!("{}", Value::Object(object));
+    ExitCode::from(kind.exit_code())
+}
+
+/// Ends a run whose command line clap rejected. Help and version requests print as usual, and
+/// so do errors unless `--json` is among the arguments, since the flags were never parsed
+pub fn usage(e: clap::Error) -> ExitCode {
+    use clap::error::ErrorKind;
+    let json = std::env::args_os().skip(1).take_while(|a| a != "--").any(|a| a == "--json");
+    if !json || matches!(e.kind(), ErrorKind::DisplayHelp | ErrorKind::DisplayVersion | ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand) {
+        e.exit();
+    }
+    let text = e.to_string();
+    let message = text.lines().next().unwrap_or_default().trim_start_matches("error: ");
+    let object = json!({
+        "kind": Kind::Usage.name(),
+        "message": message,
+        "causes": [],
+        "path": null,
+        "exit_code": Kind::Usage.exit_code(),
+    });
+    eprintln!("{}", object);
+    ExitCode::from(Kind::Usage.exit_code())
+}
@@ -0,0 +1,218 @@
+// `fetch`: a download checked against a known digest before it goes into place, in place of the
+// curl-then-sha256sum pairs of provisioning scripts. The body lands in `<output>.part`, which a
+// later run picks up from with a Range request when a connection drops, and is only renamed to
+// the output once its digest, computed by the hash module, matches. The URL and the server's
+// validator for the file are kept in `<output>.part.validator`, so a resumed download is known
+// to continue the same file
+use anyhow::{Context, Result, anyhow, bail};
+use clap::Args;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use crate::hash::{self, Algorithm};
+use crate::{errors, http, progress, stdio};
+
+#[derive(Args)]
+#[command[name = "fetch", about = "Download a file over HTTP, resuming where it stopped and checking its digest before it goes into place"]]
+pub struct FetchArgs {
+    /// http:// or https:// URL to download
+    url: String,
+    /// File to write, `-` for stdout. Default: the last segment of the URL, in the current directory
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Expected SHA-256 digest, in hex
+    #[arg(long, conflicts_with = "manifest", value_parser = parse_sha256)]
+    sha256: Option<String>,
+    /// Manifest written by `hash` that lists the expected digest under the output's file name
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+    /// Further attempts after a dropped connection or a server error, each resuming the last
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+}
+
+pub fn fetch(a: FetchArgs) -> Result<()> {
+    let named = http::Url::parse(&a.url)?.file_name().map(PathBuf::from);
+    let output = match (&a.output, &named) {
+        (Some(output), _) => output.clone(),
+        (None, Some(named)) => named.clone(),
+        (None, None) => bail!("{} names no file; pass -o", a.url),
+    };
+    let expected = match (&a.sha256, &a.manifest) {
+        (Some(hex), _) => Some((Algorithm::Sha256, hex.clone())),
+        // Stdout has no name to look up, the URL's is used instead
+        (None, Some(manifest)) => {
+            let listed = if stdio::is_dash(&output) { named.as_deref().with_context(|| format!("{} names no file to find in the manifest", a.url))? } else { &output };
+            Some(from_manifest(manifest, listed)?)
+        }
+        (None, None) => None,
+    };
+    if !stdio::check_output_or(&output, stdio::Clobber::Refuse)? {
+        return Ok(());
+    }
+    if stdio::is_dash(&output) {
+        return fetch_to_stdout(&a.url, expected);
+    }
+
+    let name = output.file_name().context("output has no file name")?.to_string_lossy().into_owned();
+    let part = output.with_file_name(format!("{}.part", name));
+    let validator = output.with_file_name(format!("{}.part.validator", name));
+    for attempt in 0..=a.retries {
+        match download(&a.url, &part, &validator, &output, expected.is_some()) {
+            Ok(()) => break,
+            Err(e) if attempt < a.retries && errors::kind(&e) == errors::Kind::Temporary => {
+                let wait = Duration::from_secs(1 << attempt.min(5));
+                warn!("{:#}; retrying in {}s", e, wait.as_secs());
+                std::thread::sleep(wait);
+            }
+            Err(e) if fs::metadata(&part).is_ok_and(|m| m.len() > 0) => {
+                return Err(e).with_context(|| format!("{} kept; run again to resume", part.display()));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let algorithm = expected.as_ref().map_or(Algorithm::Sha256, |(algorithm, _)| *algorithm);
+    let got = hash::hash_file(&part, algorithm, false)?;
+    if let Some((_, hex)) = &expected
+        && !hash::eq_hex(&got, hex)
+    {
+        // A resumed download may have joined two versions of the file; the next run starts over
+        let _ = fs::remove_file(&part);
+        let _ = fs::remove_file(&validator);
+        return Err(mismatch(&a.url, algorithm, hex, &got));
+    }
+    File::open(&part).and_then(|f| f.sync_all()).with_context(|| errors::at("sync", &part))?;
+    stdio::replace(&part, &output)?;
+    let _ = fs::remove_file(&validator);
+    let len = fs::metadata(&output).map_or(0, |m| m.len());
+    match expected {
+        Some(_) => info!("Fetched {} ({} bytes, {} verified)", output.display(), len, algorithm),
+        None => info!("Fetched {} ({} bytes, {} {})", output.display(), len, algorithm, got),
+    }
+    Ok(())
+}
+
+// One attempt, appending to what earlier ones left in `part` when the server allows it and it is
+// still the same file: the validator saved with `part` goes out as If-Range, so a file that
+// changed comes back whole. Without a validator only the expected digest, when `checked`, can
+// catch a joined file, and without either the download starts over
+fn download(url: &str, part: &Path, validator: &Path, output: &Path, checked: bool) -> Result<()> {
+    let saved = saved_validator(validator, url);
+    let mut from = fs::metadata(part).map_or(0, |m| m.len());
+    if from > 0 && saved.is_none() && !checked {
+        info!("{} cannot be told to be the same file; starting over", part.display());
+        from = 0;
+    }
+    let response = match http::get(url, from, saved.as_deref()) {
+        Ok(response) => response,
+        // Everything is there already: a changed file would have come back whole, and otherwise
+        // the digest tells whether it is right
+        Err(e) if from > 0 && e.downcast_ref::<errors::HttpStatus>().is_some_and(|s| s.status == 416) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let resumed = from > 0 && response.status == 206 && response.header("Content-Range").is_some_and(|r| r.trim().starts_with(&format!("bytes {}-", from)));
+    if from > 0 && !resumed {
+        debug!("{} does not resume; starting over", url);
+    }
+    let start = if resumed { from } else { 0 };
+    if resumed {
+        info!("Resuming {} at {} bytes", output.display(), from);
+    }
+    let file = if resumed {
+        OpenOptions::new().append(true).open(part)
+    } else {
+        // Saved before the body, so an attempt that drops part way can be resumed
+        save_validator(validator, url, &response)?;
+        OpenOptions::new().write(true).create(true).truncate(true).open(part)
+    };
+    let mut out = io::BufWriter::new(file.with_context(|| errors::at("create", part))?);
+    let bar = progress::bytes(response.len().map_or(0, |len| start + len), output);
+    bar.set_position(start);
+    io::copy(&mut bar.wrap_read(response.body), &mut out).with_context(|| format!("download {}", url))?;
+    out.flush().with_context(|| errors::at("write", part))?;
+    bar.finish_and_clear();
+    Ok(())
+}
+
+// The validator saved for `url`, none for a file left by another URL
+fn saved_validator(path: &Path, url: &str) -> Option<String> {
+    let saved = fs::read_to_string(path).ok()?;
+    let mut lines = saved.lines();
+    (lines.next() == Some(url)).then(|| lines.next().map(str::to_string)).flatten().filter(|v| !v.is_empty())
+}
+
+// The response's strong ETag, or else its Last-Modified date, saved for resuming. Weak ETags do
+// not qualify for If-Range
+fn save_validator(path: &Path, url: &str, response: &http::Response) -> Result<()> {
+    let etag = response.header("ETag").filter(|tag| !tag.starts_with("W/"));
+    match etag.or_else(|| response.header("Last-Modified")) {
+        Some(value) => stdio::write(path, format!("{}\n{}\n", url, value).as_bytes(), false),
+        None => {
+            let _ = fs::remove_file(path);
+            Ok(())
+        }
+    }
+}
+
+// --sha256 is checked before anything is downloaded
+fn parse_sha256(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if s.len() != 64 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("expected 64 hex digits, got '{}'", s));
+    }
+    Ok(s.to_string())
+}
+
+// Streams to stdout and checks the digest at the end, when it can only fail the run
+fn fetch_to_stdout(url: &str, expected: Option<(Algorithm, String)>) -> Result<()> {
+    let response = http::get(url, 0, None)?;
+    let mut out = stdio::writer(Path::new("-"), true)?;
+    let algorithm = expected.as_ref().map_or(Algorithm::Sha256, |(algorithm, _)| *algorithm);
+    let got = hash::hash_reader(Tee { inner: response.body, out: &mut out }, algorithm, false).with_context(|| format!("download {}", url))?;
+    out.finish()?;
+    match expected {
+        Some((_, hex)) if !hash::eq_hex(&got, &hex) => Err(mismatch(url, algorithm, &hex, &got)),
+        Some(_) => Ok(()),
+        None => {
+            info!("Fetched {} ({} {})", url, algorithm, got);
+            Ok(())
+        }
+    }
+}
+
+// Data read from `inner` is also written to `out`
+struct Tee<R, W> {
+    inner: R,
+    out: W,
+}
+
+impl<R: Read, W: Write> Read for Tee<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.out.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+fn mismatch(url: &str, algorithm: Algorithm, expected: &str, got: &str) -> anyhow::Error {
+    anyhow!(io::Error::new(io::ErrorKind::InvalidData, format!("{} digest mismatch for {}: expected {}, got {}", algorithm, url, expected, got)))
+}
+
+// The digest a `hash` manifest lists for `output`, by its path or its file name
+fn from_manifest(manifest: &Path, output: &Path) -> Result<(Algorithm, String)> {
+    let (algorithm, entries) = hash::read_manifest(manifest)?;
+    let name = output.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let full = output.to_string_lossy().replace('\\', "/");
+    if let Some(hex) = entries.get(&full) {
+        return Ok((algorithm, hex.clone()));
+    }
+    let matches: Vec<&String> = entries.iter().filter(|(key, _)| key.rsplit('/').next() == Some(name.as_str())).map(|(_, hex)| hex).collect();
+    match matches.as_slice() {
+        [hex] => Ok((algorithm, hex.to_string())),
+        [] => bail!("{} lists no digest for {}", manifest.display(), name),
+        _ => bail!("{} lists several files named {}", manifest.display(), name),
+    }
+}
@@ -1,92 +1,272 @@
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, anyhow, bail};
 use clap::{Args, ValueEnum};
 use serde_json::Value;
-use std::{fs, path::PathBuf};
+use std::{fs, path::{Path, PathBuf}};
 use std::fmt::Debug;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor};
+use tracing::info;
+use crate::{memory, stdio};
+
+// Protobuf is not self-describing, and only the format command takes a descriptor
+const NO_DESCRIPTOR: &str = "Protobuf needs --proto-descriptor and --proto-message, which only the format command takes";
+
+// Memory per input byte while converting, for --max-memory: the input, its parsed tree at about
+// three times its size, and the serialized output
+const PARSED: u64 = 5;
+
+// Raw bincode is not self-describing, so BINCODE output starts with this header: the magic, the
+// container version, the schema (1 = the BincodeValue tree below), the bincode config
+// (0 = standard) and the bincode major version. Any change to how the value is encoded bumps one
+// of them
+const BINCODE_HEADER: [u8; 8] = [b'J', b'J', b'B', b'C', 1, 1, 0, 2];
 
 #[derive(Args)]
-#[command[name = "format", about = "Simple format converter for JSON, BSON and BINCODE"]]
+#[command[name = "format", about = "Simple format converter for JSON, JSON5, NDJSON, BSON, BINCODE, MessagePack and Protobuf, reading Parquet and Arrow"]]
 pub struct FormatArgs {
     input: PathBuf,
-    /// Target format: JSON, BSON or BINCODE
+    /// Target format: JSON, JSON5, NDJSON, BSON, BINCODE, MessagePack or Protobuf
     #[arg(short = 'f', long, value_enum, default_value_t = Format::Bson)]
     format: Format,
+    /// Input format, instead of detecting it from the file extension or content
+    #[arg(long, value_enum)]
+    from: Option<Format>,
+    /// Output file, or the root of the mirrored tree for a directory input
     #[arg(short = 'o', long)]
     output: Option<PathBuf>,
+    /// Indented JSON output, the default
+    #[arg(long, conflicts_with = "compact")]
+    pretty: bool,
+    /// JSON output on a single line, without whitespace
+    #[arg(long, conflicts_with = "indent")]
+    compact: bool,
+    /// Spaces per indentation level of JSON output [default: 2]
+    #[arg(long, value_name = "N")]
+    indent: Option<usize>,
+    /// JSON Schema (draft 2020-12) the input must satisfy; nothing is written when it does not
+    #[arg(long, value_name = "SCHEMA")]
+    schema: Option<PathBuf>,
+    /// FileDescriptorSet describing Protobuf input or output, as written by
+    /// `protoc --include_imports --descriptor_set_out`
+    #[arg(long, value_name = "SET", requires = "proto_message")]
+    proto_descriptor: Option<PathBuf>,
+    /// Fully qualified message type in the descriptor set, e.g. `shop.Order`
+    #[arg(long, value_name = "MESSAGE", requires = "proto_descriptor")]
+    proto_message: Option<String>,
+    #[command(flatten)]
+    batch: crate::batch::BatchArgs,
+}
+
+#[derive(Args)]
+#[command[name = "format-validate", about = "Check that files parse in their format, without converting them"]]
+pub struct ValidateArgs {
+    /// Files to check
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+    /// Input format, instead of detecting it from each file extension or content
+    #[arg(long, value_enum)]
+    from: Option<Format>,
+}
+
+#[derive(Args)]
+#[command[name = "format-diff", about = "Structural diff of two files in any supported format"]]
+pub struct DiffArgs {
+    old: PathBuf,
+    new: PathBuf,
+    /// Input format of both files, instead of detecting it from each file extension or content
+    #[arg(long, value_enum)]
+    from: Option<Format>,
+}
+
+#[derive(Args)]
+#[command[name = "format-merge", about = "Deep-merge structured files, later files overriding earlier ones"]]
+pub struct MergeArgs {
+    /// Base file first, then the overrides in order
+    #[arg(required = true, num_args = 2..)]
+    inputs: Vec<PathBuf>,
+    /// Output format
+    #[arg(short = 'f', long, value_enum, default_value_t = Format::Json)]
+    format: Format,
+    /// Output file. Text formats default to stdout
+    #[arg(short = 'o', long)]
+    output: Option<PathBuf>,
+    /// Input format of every file, instead of detecting it from each file extension or content
+    #[arg(long, value_enum)]
+    from: Option<Format>,
+    /// How an array in an override combines with the array it overrides
+    #[arg(long, value_enum, default_value_t = ArrayStrategy::Replace)]
+    arrays: ArrayStrategy,
+    /// Object member that identifies array items for --arrays merge-by-key
+    #[arg(long, default_value = "name")]
+    key: String,
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum ArrayStrategy {
+    /// The override's array wins
+    Replace,
+    /// Override items follow the base items
+    Append,
+    /// Objects with the same --key member are merged, the others appended
+    MergeByKey,
+}
+
+/// How JSON output is laid out
+#[derive(Copy, Clone)]
+enum Layout {
+    Pretty { indent: usize },
+    Compact,
 }
 
 #[derive(Copy, Clone, Debug, ValueEnum)]
 pub enum Format {
     Json,
+    /// JSON with comments, trailing commas and the rest of JSON5; also reads JSONC
+    Json5,
+    /// One JSON value per line; an array writes one line per item
+    Ndjson,
     Bson,
     Bincode,
+    Msgpack,
+    /// Binary Protobuf message; needs --proto-descriptor and --proto-message
+    Protobuf,
+    /// Parquet file, read as an array of row objects; input only
+    Parquet,
+    /// Arrow IPC file or stream (Feather v2), read as an array of row objects; input only
+    Arrow,
 }
 
 impl Format {
     fn name(self) -> &'static str {
         match self {
             Format::Json => "JSON",
+            Format::Json5 => "JSON5",
+            Format::Ndjson => "NDJSON",
             Format::Bson => "BSON",
             Format::Bincode => "BINCODE",
+            Format::Msgpack => "MessagePack",
+            Format::Protobuf => "Protobuf",
+            Format::Parquet => "Parquet",
+            Format::Arrow => "Arrow IPC",
         }
     }
 
     fn from_extension(ext: &str) -> Option<Self> {
         match ext.to_ascii_lowercase().as_str() {
             "json" => Some(Format::Json),
+            "json5" | "jsonc" => Some(Format::Json5),
+            "ndjson" | "jsonl" => Some(Format::Ndjson),
             "bson" => Some(Format::Bson),
             "bin" | "bincode" => Some(Format::Bincode),
+            "msgpack" | "mpk" => Some(Format::Msgpack),
+            "parquet" => Some(Format::Parquet),
+            "arrow" | "feather" | "ipc" => Some(Format::Arrow),
             _ => None,
         }
     }
 
+    fn is_text(self) -> bool {
+        matches!(self, Format::Json | Format::Json5 | Format::Ndjson)
+    }
+
     fn default_extension(self) -> &'static str {
         match self {
             Format::Json => "json",
+            Format::Json5 => "json5",
+            Format::Ndjson => "ndjson",
             Format::Bson => "bson",
             Format::Bincode => "bin",
+            Format::Msgpack => "msgpack",
+            Format::Protobuf => "pb",
+            Format::Parquet => "parquet",
+            Format::Arrow => "arrow",
         }
     }
 }
 
 pub fn format_convert(a: FormatArgs) -> Result<()> {
-    let input_path = a.input;
+    let target_format = a.format;
+    if (a.pretty || a.compact || a.indent.is_some()) && !matches!(target_format, Format::Json) {
+        bail!("--pretty, --compact and --indent apply to JSON output only");
+    }
+    if matches!(target_format, Format::Parquet | Format::Arrow) {
+        bail!("{} is read-only; convert to JSON or NDJSON instead", target_format.name());
+    }
+    let layout = if a.compact { Layout::Compact } else { Layout::Pretty { indent: a.indent.unwrap_or(2) } };
+    let schema = a.schema.as_deref().map(load_schema).transpose()?;
+    let proto = match (&a.proto_descriptor, &a.proto_message) {
+        (Some(set), Some(name)) => Some(load_message(set, name)?),
+        _ => None,
+    };
 
-    if !input_path.is_file() {
-        bail!("Input path {:?} is not a file", input_path);
+    if a.input.is_dir() {
+        let files = collect_files(&a.input, &a)?;
+        return crate::batch::run(&files, a.batch.threads, |input| {
+            let output = match &a.output {
+                Some(out) => out.join(input.strip_prefix(&a.input).unwrap_or(input)),
+                None => input.to_path_buf(),
+            };
+            convert_file(input, &output.with_extension(target_format.default_extension()), &a, layout, schema.as_ref(), proto.as_ref())
+        });
     }
 
-    let input_format = input_path
-        .extension()
-        .and_then(|e| e.to_str())
-        .and_then(Format::from_extension)
-        .context("Could not detect input format from file extension. Use .json, .bson or .bin")?;
+    if !stdio::is_dash(&a.input) && !a.input.is_file() {
+        bail!("Input path {:?} is not a file", a.input);
+    }
+    let output_path = a.output.clone().or_else(|| stdio::default_output(&a.input)).unwrap_or_else(|| {
+        let mut p = a.input.clone();
+        p.set_extension(target_format.default_extension());
+        p
+    });
+    convert_file(&a.input, &output_path, &a, layout, schema.as_ref(), proto.as_ref())
+}
 
+fn convert_file(
+    input_path: &Path,
+    output_path: &Path,
+    a: &FormatArgs,
+    layout: Layout,
+    schema: Option<&Schema>,
+    proto: Option<&MessageDescriptor>,
+) -> Result<()> {
     let target_format = a.format;
+    if output_path == input_path && !stdio::is_dash(input_path) {
+        bail!("Output would overwrite the input {:?}, use --output", input_path);
+    }
+    if !stdio::check_output(output_path)? {
+        return Ok(());
+    }
 
     // Read file as bytes
-    let data = fs::read(&input_path)
+    let data = memory::read(input_path, PARSED)
         .with_context(|| format!("Failed to read input file {:?}", input_path))?;
+    let input_format = input_format(input_path, &data, a.from)?;
 
     // Parse input
-    let value = read_as_value(&data, input_format)
+    let value = read_as_value(&data, input_format, proto)
         .with_context(|| format!("Failed to deserialize input as {:?}", input_format.name()))?;
+    if let Some(schema) = schema {
+        check_schema(&value, schema)?;
+    }
 
     // Serialize to target format
-    let out_bytes = write_from_value(&value, target_format)
+    let out_bytes = write_from_value(&value, target_format, layout, proto)
         .with_context(|| format!("Failed to serialize to {:?}", target_format.name()))?;
 
     // Output
-    let output_path = a.output.unwrap_or_else(|| {
-        let mut p = input_path.clone();
-        p.set_extension(target_format.default_extension());
-        p
-    });
-
-    fs::write(&output_path, &out_bytes)
-        .with_context(|| format!("Failed to write output file {:?}", output_path))?;
+    if stdio::is_dash(output_path) {
+        stdio::write(output_path, &out_bytes, !target_format.is_text())?;
+    } else {
+        if let Some(parent) = output_path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create directory {:?}", parent))?;
+        }
+        stdio::write(output_path, &out_bytes, !target_format.is_text())
+            .with_context(|| format!("Failed to write output file {:?}", output_path))?;
+        stdio::finished(input_path, output_path)?;
+    }
 
-    println!(
+    info!(
         "Converted {:?} ({:?}) -> {:?} ({:?})",
         input_path, input_format.name(), output_path, target_format.name()
     );
@@ -94,27 +274,540 @@ pub fn format_convert(a: FormatArgs) -> Result<()> {
     Ok(())
 }
 
-fn read_as_value(bytes: &[u8], format: Format) -> Result<Value> {
+// Files with a known extension, or every file with --from, filtered by --include/--exclude.
+// An output directory inside the input is skipped so reruns do not convert their own output
+fn collect_files(root: &Path, a: &FormatArgs) -> Result<Vec<PathBuf>> {
+    if !a.batch.recursive { bail!("'{}' is a directory. Use -r/--recursive.", root.display()); }
+    a.batch.collect(root, |path| {
+        let inside_output = a.output.as_deref().is_some_and(|out| out != root && path.starts_with(out));
+        let known = path.extension().and_then(|e| e.to_str()).and_then(Format::from_extension).is_some();
+        !inside_output && (known || a.from.is_some())
+    })
+}
+
+pub fn diff(a: DiffArgs) -> Result<()> {
+    let old = read_file(&a.old, a.from)?;
+    let new = read_file(&a.new, a.from)?;
+    let mut changes = Vec::new();
+    diff_values("", &old, &new, &mut changes);
+
+    if stdio::json() {
+        let report: Vec<Value> = changes
+            .iter()
+            .map(|c| {
+                let mut entry = serde_json::json!({ "op": c.op(), "path": c.path });
+                if let Some(old) = c.old { entry["old"] = old.clone(); }
+                if let Some(new) = c.new { entry["new"] = new.clone(); }
+                entry
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    for c in &changes {
+        let path = if c.path.is_empty() { "/" } else { &c.path };
+        match (c.old, c.new) {
+            (Some(old), Some(new)) => println!("~ {}: {} -> {}", path, old, new),
+            (None, Some(new)) => println!("+ {}: {}", path, new),
+            (Some(old), None) => println!("- {}: {}", path, old),
+            (None, None) => {}
+        }
+    }
+    match changes.len() {
+        0 => println!("No differences"),
+        n => println!("{} change(s)", n),
+    }
+    Ok(())
+}
+
+/// One difference, at a JSON pointer into both documents
+struct Change<'v> {
+    path: String,
+    old: Option<&'v Value>,
+    new: Option<&'v Value>,
+}
+
+impl Change<'_> {
+    fn op(&self) -> &'static str {
+        match (self.old, self.new) {
+            (Some(_), Some(_)) => "changed",
+            (None, _) => "added",
+            (_, None) => "removed",
+        }
+    }
+}
+
+// Objects compare by key, so key order never shows up; arrays compare by index
+fn diff_values<'v>(path: &str, old: &'v Value, new: &'v Value, changes: &mut Vec<Change<'v>>) {
+    match (old, new) {
+        (Value::Object(old_map), Value::Object(new_map)) => {
+            let keys: std::collections::BTreeSet<&String> = old_map.keys().chain(new_map.keys()).collect();
+            for key in keys {
+                let path = format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"));
+                match (old_map.get(key), new_map.get(key)) {
+                    (Some(o), Some(n)) => diff_values(&path, o, n, changes),
+                    (o, n) => changes.push(Change { path, old: o, new: n }),
+                }
+            }
+        }
+        (Value::Array(old_items), Value::Array(new_items)) => {
+            for i in 0..old_items.len().max(new_items.len()) {
+                let path = format!("{}/{}", path, i);
+                match (old_items.get(i), new_items.get(i)) {
+                    (Some(o), Some(n)) => diff_values(&path, o, n, changes),
+                    (o, n) => changes.push(Change { path, old: o, new: n }),
+                }
+            }
+        }
+        _ if old != new => changes.push(Change { path: path.to_string(), old: Some(old), new: Some(new) }),
+        _ => {}
+    }
+}
+
+pub fn merge(a: MergeArgs) -> Result<()> {
+    if let Some(path) = &a.output
+        && !stdio::check_output(path)?
+    {
+        return Ok(());
+    }
+    let mut merged = read_file(&a.inputs[0], a.from)?;
+    for path in &a.inputs[1..] {
+        merge_values(&mut merged, read_file(path, a.from)?, a.arrays, &a.key);
+    }
+    let out = write_from_value(&merged, a.format, Layout::Pretty { indent: 2 }, None)
+        .with_context(|| format!("Failed to serialize to {:?}", a.format.name()))?;
+    match &a.output {
+        Some(path) => stdio::write(path, &out, !a.format.is_text()).with_context(|| format!("Failed to write output file {:?}", path))?,
+        None if a.format.is_text() => println!("{}", String::from_utf8_lossy(&out)),
+        None => bail!("{} is binary, pass --output (`-o -` for stdout)", a.format.name()),
+    }
+    Ok(())
+}
+
+// Objects merge member by member; any other value in `over` replaces the base, null included
+fn merge_values(base: &mut Value, over: Value, arrays: ArrayStrategy, key: &str) {
+    match (base, over) {
+        (Value::Object(base_map), Value::Object(over_map)) => {
+            for (k, v) in over_map {
+                match base_map.get_mut(&k) {
+                    Some(existing) => merge_values(existing, v, arrays, key),
+                    None => { base_map.insert(k, v); }
+                }
+            }
+        }
+        (Value::Array(base_items), Value::Array(over_items)) if !matches!(arrays, ArrayStrategy::Replace) => {
+            for item in over_items {
+                let id = item.get(key).filter(|_| matches!(arrays, ArrayStrategy::MergeByKey)).cloned();
+                match id.and_then(|id| base_items.iter_mut().find(|b| b.get(key) == Some(&id))) {
+                    Some(existing) => merge_values(existing, item, arrays, key),
+                    None => base_items.push(item),
+                }
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+// Whole-file parse with format detection, for the commands that read several inputs
+fn read_file(path: &Path, from: Option<Format>) -> Result<Value> {
+    let data = memory::read(path, PARSED).with_context(|| format!("Failed to read input file {:?}", path))?;
+    let format = input_format(path, &data, from)?;
+    read_as_value(&data, format, None).with_context(|| format!("Failed to deserialize {:?} as {:?}", path, format.name()))
+}
+
+pub fn validate(a: ValidateArgs) -> Result<()> {
+    let mut failed = 0;
+    for path in &a.inputs {
+        let data = memory::read(path, PARSED).with_context(|| format!("Failed to read input file {:?}", path))?;
+        let format = input_format(path, &data, a.from)?;
+        match parse_located(&data, format) {
+            Ok(()) => println!("{}: OK ({})", stdio::display(path), format.name()),
+            Err(e) => {
+                failed += 1;
+                let at = match (e.line, e.offset) {
+                    (Some((line, column)), Some(offset)) => format!("{}:{}: byte {}", line, column, offset),
+                    (None, Some(offset)) => format!("byte {}", offset),
+                    _ => String::new(),
+                };
+                println!("{}:{}: invalid {}: {}", stdio::display(path), at, format.name(), e.message);
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} files are invalid", failed, a.inputs.len());
+    }
+    Ok(())
+}
+
+/// A compiled --schema, built once for a whole batch
+struct Schema {
+    path: PathBuf,
+    validator: jsonschema::Validator,
+}
+
+fn load_schema(schema_path: &Path) -> Result<Schema> {
+    let schema = fs::read(schema_path).with_context(|| format!("Failed to read schema {:?}", schema_path))?;
+    let schema: Value = serde_json::from_slice(&schema).with_context(|| format!("Schema {:?} is not valid JSON", schema_path))?;
+    let validator = jsonschema::draft202012::new(&schema).map_err(|e| anyhow!("Invalid schema {:?}: {}", schema_path, e))?;
+    Ok(Schema { path: schema_path.to_path_buf(), validator })
+}
+
+// Every violation, one per line with the JSON pointer of the offending value
+fn check_schema(value: &Value, schema: &Schema) -> Result<()> {
+    let schema_path = &schema.path;
+    let violations: Vec<String> = schema
+        .validator
+        .iter_errors(value)
+        .map(|e| {
+            let at = e.instance_path().to_string();
+            format!("  {}: {}", if at.is_empty() { "/" } else { &at }, e)
+        })
+        .collect();
+    if !violations.is_empty() {
+        bail!("Input does not match schema {:?}, {} violations:\n{}", schema_path, violations.len(), violations.join("\n"));
+    }
+    Ok(())
+}
+
+fn load_message(set_path: &Path, name: &str) -> Result<MessageDescriptor> {
+    let set = fs::read(set_path).with_context(|| format!("Failed to read descriptor set {:?}", set_path))?;
+    let pool = DescriptorPool::decode(set.as_slice())
+        .with_context(|| format!("{:?} is not a FileDescriptorSet", set_path))?;
+    pool.get_message_by_name(name)
+        .ok_or_else(|| anyhow!("No message {:?} in descriptor set {:?}", name, set_path))
+}
+
+// --from, else the extension, else the content
+fn input_format(path: &Path, data: &[u8], from: Option<Format>) -> Result<Format> {
+    if let Some(format) = from {
+        return Ok(format);
+    }
+    if let Some(format) = path.extension().and_then(|e| e.to_str()).and_then(Format::from_extension) {
+        return Ok(format);
+    }
+    sniff(data).with_context(|| format!("Could not detect the format of {:?} from its extension or content", path))
+}
+
+// Content heuristics for files without a known extension
+fn sniff(data: &[u8]) -> Result<Format> {
+    if data.starts_with(&BINCODE_HEADER[..4]) {
+        return Ok(Format::Bincode);
+    }
+    // BSON: a little-endian length equal to the file size, and a closing NUL
+    if data.len() >= 5 && u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize == data.len() && data[data.len() - 1] == 0 {
+        return Ok(Format::Bson);
+    }
+    if data.starts_with(b"PAR1") {
+        return Ok(Format::Parquet);
+    }
+    if data.starts_with(b"ARROW1") {
+        return Ok(Format::Arrow);
+    }
+    // CBOR's self-describe tag
+    if data.starts_with(&[0xD9, 0xD9, 0xF7]) {
+        bail!("content looks like CBOR, which format does not support");
+    }
+
+    let text = std::str::from_utf8(data).ok().map(str::trim_start);
+    if let Some(text) = text {
+        if text.starts_with('{') || text.starts_with('[') {
+            // Strict JSON when it parses, JSON5 when only the relaxed grammar accepts it, and
+            // JSON again when neither does, so validation reports the strict error
+            if serde_json::from_str::<Value>(text).is_ok() {
+                return Ok(Format::Json);
+            }
+            if json5::from_str::<Value>(text).is_ok() {
+                return Ok(Format::Json5);
+            }
+            // Several lines that each hold a whole value
+            let mut lines = text.lines().filter(|l| !l.trim().is_empty());
+            let ndjson = lines.clone().nth(1).is_some() && lines.all(|l| serde_json::from_str::<Value>(l).is_ok());
+            return Ok(if ndjson { Format::Ndjson } else { Format::Json });
+        }
+        if text.starts_with("//") || text.starts_with("/*") {
+            return Ok(Format::Json5);
+        }
+        if looks_like_yaml(text) {
+            bail!("content looks like YAML, which format does not support");
+        }
+    }
+    match data.first() {
+        // MessagePack maps and arrays, the usual top level of a payload
+        Some(0x80..=0x9F | 0xDC..=0xDF) => Ok(Format::Msgpack),
+        // A CBOR map; as MessagePack this would be a bare string
+        Some(0xA0..=0xBF) if text.is_none() => bail!("content looks like CBOR, which format does not support"),
+        _ => bail!("no known format matches the content; use a .json, .json5, .jsonc, .ndjson, .bson, .bin, .msgpack, .parquet or .arrow extension, or pass --from"),
+    }
+}
+
+// A document marker or a `key: value` first line, skipping comments
+fn looks_like_yaml(text: &str) -> bool {
+    let Some(line) = text.lines().map(str::trim).find(|l| !l.is_empty() && !l.starts_with('#')) else { return false };
+    if line.starts_with("---") {
+        return true;
+    }
+    match line.split_once(':') {
+        Some((key, rest)) => {
+            !key.is_empty()
+                && key.chars().all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | ' ' | '.'))
+                && (rest.is_empty() || rest.starts_with(' '))
+        }
+        None => line.starts_with("- "),
+    }
+}
+
+/// A parse failure with as much of its position as the format gives: line and column (1-based)
+/// for text formats, and the byte offset
+struct ParseError {
+    line: Option<(usize, usize)>,
+    offset: Option<usize>,
+    message: String,
+}
+
+// Same parsers as read_as_value, but keeping the position of the first error. Binary formats
+// report how far the reader got, and trailing bytes after the value are an error too
+fn parse_located(bytes: &[u8], format: Format) -> std::result::Result<(), ParseError> {
+    use std::io::Cursor;
+
+    let binary = |offset: u64, message: String| ParseError { line: None, offset: Some(offset as usize), message };
+    let trailing = |end: u64| {
+        if (end as usize) < bytes.len() {
+            Err(binary(end, format!("{} trailing bytes after the value", bytes.len() - end as usize)))
+        } else {
+            Ok(())
+        }
+    };
+    match format {
+        Format::Json => serde_json::from_slice::<Value>(bytes).map(drop).map_err(|e| {
+            let suffix = format!(" at line {} column {}", e.line(), e.column());
+            let message = e.to_string();
+            let message = message.strip_suffix(&suffix).unwrap_or(&message).to_string();
+            // serde_json counts columns in bytes
+            let offset = line_start(bytes, e.line()) + e.column().saturating_sub(1);
+            ParseError { line: Some((e.line(), e.column())), offset: Some(offset.min(bytes.len())), message }
+        }),
+        Format::Json5 => {
+            let text = std::str::from_utf8(bytes).map_err(|e| binary(e.valid_up_to() as u64, "invalid UTF-8".into()))?;
+            json5::from_str::<Value>(text).map(drop).map_err(|e| {
+                let message = e.to_string();
+                match e.position() {
+                    Some(position) => {
+                        let message = message.strip_suffix(&format!(" at {}", position)).unwrap_or(&message).to_string();
+                        // json5 counts lines and columns from 0, and columns in characters
+                        let start = line_start(bytes, position.line + 1);
+                        let column_bytes: usize = text[start..].chars().take(position.column).map(char::len_utf8).sum();
+                        ParseError { line: Some((position.line + 1, position.column + 1)), offset: Some(start + column_bytes), message }
+                    }
+                    None => ParseError { line: None, offset: None, message },
+                }
+            })
+        }
+        Format::Ndjson => {
+            // Each line on its own as JSON, its error moved to where the line starts
+            let mut start = 0;
+            for (i, line) in bytes.split(|b| *b == b'\n').enumerate() {
+                if !line.trim_ascii().is_empty() {
+                    parse_located(line, Format::Json).map_err(|e| ParseError {
+                        line: e.line.map(|(_, column)| (i + 1, column)),
+                        offset: e.offset.map(|offset| start + offset),
+                        message: e.message,
+                    })?;
+                }
+                start += line.len() + 1;
+            }
+            Ok(())
+        }
+        Format::Parquet | Format::Arrow => read_as_value(bytes, format, None)
+            .map(drop)
+            .map_err(|e| ParseError { line: None, offset: None, message: format!("{:#}", e) }),
+        Format::Bson => bson::de::deserialize_from_slice::<Value>(bytes)
+            .map(drop)
+            .map_err(|e| ParseError { line: None, offset: None, message: e.to_string() }),
+        Format::Bincode => {
+            bincode_payload(bytes).map_err(|(offset, message)| binary(offset as u64, message))?;
+            let mut cursor = Cursor::new(bytes);
+            cursor.set_position(BINCODE_HEADER.len() as u64);
+            let decoded = decode_bincode(&mut cursor, bytes.len());
+            decoded.map_err(|e| binary(cursor.position(), e.to_string()))?;
+            trailing(cursor.position())
+        }
+        Format::Msgpack => {
+            let mut cursor = Cursor::new(bytes);
+            let decoded: std::result::Result<Value, _> = serde::Deserialize::deserialize(&mut rmp_serde::Deserializer::new(&mut cursor));
+            decoded.map_err(|e| binary(cursor.position(), e.to_string()))?;
+            trailing(cursor.position())
+        }
+        Format::Protobuf => Err(ParseError { line: None, offset: None, message: NO_DESCRIPTOR.to_string() }),
+    }
+}
+
+/// serde_json::Value with its variants spelled out. Value only deserializes from self-describing
+/// formats, which bincode is not, so BINCODE encodes this tree instead
+#[derive(serde::Serialize, serde::Deserialize)]
+enum BincodeValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Uint(u64),
+    Float(f64),
+    String(String),
+    Array(Vec<BincodeValue>),
+    Object(Vec<(String, BincodeValue)>),
+}
+
+impl From<&Value> for BincodeValue {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Null => BincodeValue::Null,
+            Value::Bool(b) => BincodeValue::Bool(*b),
+            Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+                (Some(i), _) => BincodeValue::Int(i),
+                (None, Some(u)) => BincodeValue::Uint(u),
+                _ => BincodeValue::Float(n.as_f64().unwrap_or(f64::NAN)),
+            },
+            Value::String(s) => BincodeValue::String(s.clone()),
+            Value::Array(items) => BincodeValue::Array(items.iter().map(Into::into).collect()),
+            Value::Object(map) => BincodeValue::Object(map.iter().map(|(k, v)| (k.clone(), v.into())).collect()),
+        }
+    }
+}
+
+impl From<BincodeValue> for Value {
+    fn from(value: BincodeValue) -> Self {
+        match value {
+            BincodeValue::Null => Value::Null,
+            BincodeValue::Bool(b) => Value::Bool(b),
+            BincodeValue::Int(i) => i.into(),
+            BincodeValue::Uint(u) => u.into(),
+            BincodeValue::Float(f) => serde_json::Number::from_f64(f).map_or(Value::Null, Value::Number),
+            BincodeValue::String(s) => Value::String(s),
+            BincodeValue::Array(items) => Value::Array(items.into_iter().map(Into::into).collect()),
+            BincodeValue::Object(members) => Value::Object(members.into_iter().map(|(k, v)| (k, v.into())).collect()),
+        }
+    }
+}
+
+// The encoded value after a header this build can read, or the offset of the first header byte
+// that does not match, so a stale or foreign file fails instead of decoding to garbage
+fn bincode_payload(bytes: &[u8]) -> std::result::Result<&[u8], (usize, String)> {
+    if !bytes.starts_with(&BINCODE_HEADER[..4]) {
+        return Err((0, "no jj_toolkit BINCODE header; files written before the header was added must be re-exported".into()));
+    }
+    let field = |i: usize| bytes.get(i).copied();
+    let checks = [(4, "container version"), (5, "schema"), (6, "bincode config"), (7, "bincode version")];
+    for (i, what) in checks {
+        match field(i) {
+            None => return Err((i, "truncated BINCODE header".into())),
+            Some(b) if b != BINCODE_HEADER[i] => {
+                return Err((i, format!("unsupported {} {} (expected {})", what, b, BINCODE_HEADER[i])));
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(&bytes[BINCODE_HEADER.len()..])
+}
+
+// The BINCODE tree, with a limit on the bytes its lengths may claim tied to the input's size:
+// bincode allocates a string's declared length before reading it, so a forged length would abort
+// the process instead of failing. The limit is a const generic, hence the steps
+fn decode_bincode(reader: &mut impl std::io::Read, len: usize) -> std::result::Result<BincodeValue, bincode::error::DecodeError> {
+    fn decode<const N: usize>(reader: &mut impl std::io::Read) -> std::result::Result<BincodeValue, bincode::error::DecodeError> {
+        bincode::serde::decode_from_std_read(reader, bincode::config::standard().with_limit::<N>())
+    }
+    match len {
+        0..0x10_0000 => decode::<0x10_0000>(reader),
+        0x10_0000..0x100_0000 => decode::<0x100_0000>(reader),
+        0x100_0000..0x1000_0000 => decode::<0x1000_0000>(reader),
+        _ => decode::<{ u32::MAX as usize }>(reader),
+    }
+}
+
+// Byte offset where 1-based `line` starts
+fn line_start(bytes: &[u8], line: usize) -> usize {
+    if line <= 1 {
+        return 0;
+    }
+    bytes.iter().enumerate().filter(|(_, b)| **b == b'\n').nth(line - 2).map_or(bytes.len(), |(i, _)| i + 1)
+}
+
+fn read_as_value(bytes: &[u8], format: Format, proto: Option<&MessageDescriptor>) -> Result<Value> {
     match format {
         Format::Json => {
             let v: Value = serde_json::from_slice(bytes)?;
             Ok(v)
         }
+        Format::Json5 => {
+            let v: Value = json5::from_str(std::str::from_utf8(bytes)?)?;
+            Ok(v)
+        }
+        Format::Ndjson => {
+            let mut items = Vec::new();
+            for (i, line) in std::str::from_utf8(bytes)?.lines().enumerate() {
+                if line.trim().is_empty() { continue }
+                items.push(serde_json::from_str(line).with_context(|| format!("line {}", i + 1))?);
+            }
+            Ok(Value::Array(items))
+        }
         Format::Bson => {
             let v: Value = bson::de::deserialize_from_slice(bytes)?;
             Ok(v)
         }
         Format::Bincode => {
-            let v: Value = bincode::serde::decode_from_slice(bytes, bincode::config::standard())?.0;
+            let payload = bincode_payload(bytes).map_err(|(offset, message)| anyhow!("byte {}: {}", offset, message))?;
+            let v = decode_bincode(&mut std::io::Cursor::new(payload), payload.len())?;
+            Ok(v.into())
+        }
+        Format::Msgpack => {
+            let v: Value = rmp_serde::from_slice(bytes)?;
             Ok(v)
         }
+        Format::Protobuf => {
+            let desc = proto.ok_or_else(|| anyhow!(NO_DESCRIPTOR))?;
+            let message = DynamicMessage::decode(desc.clone(), bytes)?;
+            Ok(serde_json::to_value(&message)?)
+        }
+        Format::Parquet => {
+            let reader = parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(bytes::Bytes::copy_from_slice(bytes))?.build()?;
+            rows_to_value(reader)
+        }
+        Format::Arrow => {
+            // The file format starts with its magic; anything else is tried as a stream
+            if bytes.starts_with(b"ARROW1") {
+                rows_to_value(arrow_ipc::reader::FileReader::try_new(std::io::Cursor::new(bytes), None)?)
+            } else {
+                rows_to_value(arrow_ipc::reader::StreamReader::try_new(bytes, None)?)
+            }
+        }
     }
 }
 
-fn write_from_value(value: &Value, format: Format) -> Result<Vec<u8>> {
+fn write_from_value(value: &Value, format: Format, layout: Layout, proto: Option<&MessageDescriptor>) -> Result<Vec<u8>> {
     match format {
-        Format::Json => {
-            let bytes = serde_json::to_vec_pretty(value)?;
+        Format::Json => match layout {
+            Layout::Compact => Ok(serde_json::to_vec(value)?),
+            Layout::Pretty { indent } => {
+                use serde::Serialize;
+                let indent = " ".repeat(indent);
+                let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+                let mut bytes = Vec::new();
+                value.serialize(&mut serde_json::Serializer::with_formatter(&mut bytes, formatter))?;
+                Ok(bytes)
+            }
+        },
+        Format::Json5 => {
+            let bytes = json5::to_string(value)?.into_bytes();
+            Ok(bytes)
+        }
+        Format::Ndjson => {
+            let items = match value {
+                Value::Array(items) => items.as_slice(),
+                other => std::slice::from_ref(other),
+            };
+            let mut bytes = Vec::new();
+            for item in items {
+                serde_json::to_writer(&mut bytes, item)?;
+                bytes.push(b'\n');
+            }
             Ok(bytes)
         }
         Format::Bson => {
@@ -122,8 +815,37 @@ fn write_from_value(value: &Value, format: Format) -> Result<Vec<u8>> {
             Ok(bytes)
         }
         Format::Bincode => {
-            let bytes = bincode::serde::encode_to_vec(value, bincode::config::standard())?;
+            let mut bytes = BINCODE_HEADER.to_vec();
+            bytes.extend(bincode::serde::encode_to_vec(BincodeValue::from(value), bincode::config::standard())?);
+            Ok(bytes)
+        }
+        Format::Msgpack => {
+            let bytes = rmp_serde::to_vec(value)?;
             Ok(bytes)
         }
+        Format::Protobuf => {
+            let desc = proto.ok_or_else(|| anyhow!(NO_DESCRIPTOR))?;
+            let message = DynamicMessage::deserialize(desc.clone(), value)?;
+            Ok(prost::Message::encode_to_vec(&message))
+        }
+        Format::Parquet | Format::Arrow => bail!("{} is read-only", format.name()),
+    }
+}
+
+// Record batches as one object per row. Nulls stay as explicit members so every row has every
+// column; the JSON encoder already renders dates, decimals and nested types
+fn rows_to_value<E>(batches: impl Iterator<Item = std::result::Result<arrow_array::RecordBatch, E>>) -> Result<Value>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let mut writer = arrow_json::WriterBuilder::new()
+        .with_explicit_nulls(true)
+        .build::<_, arrow_json::writer::LineDelimited>(Vec::new());
+    for batch in batches {
+        writer.write(&batch?)?;
     }
+    writer.finish()?;
+    let lines = writer.into_inner();
+    let rows = serde_json::Deserializer::from_slice(&lines).into_iter::<Value>().collect::<std::result::Result<_, _>>()?;
+    Ok(Value::Array(rows))
 }
@@ -0,0 +1,120 @@
+// --respect-gitignore: the ignore rules git applies to a directory input, from the .gitignore
+// files of the input, its subdirectories and its parents up to the top of the checkout, and from
+// .git/info/exclude. Patterns follow gitignore(5): one with a slash before its end is anchored to
+// the directory of its file and others match at any depth, a trailing slash matches directories
+// only, `!` takes a path back out, the last matching line wins with deeper files over shallower
+// ones, and nothing under an ignored directory comes back. Each file is read once, the first time
+// a path below its directory is asked about
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, warn};
+use crate::errors;
+
+struct Rule {
+    glob: GlobMatcher,
+    negated: bool,
+    dir_only: bool,
+}
+
+pub(crate) struct Gitignore {
+    // The input directory, canonical
+    root: PathBuf,
+    // The top of the checkout around `root`, or `root` outside one
+    top: PathBuf,
+    exclude: Vec<Rule>,
+    // .gitignore rules by the directory holding them
+    rules: Mutex<HashMap<PathBuf, Arc<Vec<Rule>>>>,
+}
+
+impl Gitignore {
+    pub(crate) fn new(root: &Path) -> Result<Self> {
+        let root = fs::canonicalize(root).with_context(|| errors::at("open", root))?;
+        let top = root.ancestors().find(|dir| dir.join(".git").exists()).unwrap_or(&root).to_path_buf();
+        debug!("Ignore rules of {} from {} down", root.display(), top.display());
+        let exclude = read(&top.join(".git").join("info").join("exclude"));
+        Ok(Gitignore { root, top, exclude, rules: Mutex::default() })
+    }
+
+    // Whether the path `rel` below the input, or a directory on the way to it, is ignored. Only
+    // the path is looked at, so this also answers for files that are gone
+    pub(crate) fn ignored(&self, rel: &Path, is_dir: bool) -> bool {
+        let names: Vec<_> = rel.components().filter_map(|c| match c {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        }).collect();
+        let mut path = self.root.clone();
+        for (i, name) in names.iter().enumerate() {
+            path.push(name);
+            // The repository itself
+            if *name == ".git" || self.decide(&path, is_dir || i + 1 < names.len()) {
+                return true;
+            }
+        }
+        false
+    }
+
+    // The last rule that matches `path` among those of the directories above it
+    fn decide(&self, path: &Path, is_dir: bool) -> bool {
+        let mut dirs: Vec<&Path> = path.ancestors().skip(1).take_while(|dir| dir.starts_with(&self.top)).collect();
+        dirs.reverse();
+        let mut ignored = last_match(&self.exclude, &self.top, path, is_dir).unwrap_or(false);
+        for dir in dirs {
+            if let Some(decision) = last_match(&self.rules_of(dir), dir, path, is_dir) {
+                ignored = decision;
+            }
+        }
+        ignored
+    }
+
+    fn rules_of(&self, dir: &Path) -> Arc<Vec<Rule>> {
+        let mut rules = self.rules.lock().unwrap();
+        rules.entry(dir.to_path_buf()).or_insert_with(|| Arc::new(read(&dir.join(".gitignore")))).clone()
+    }
+}
+
+// Some(true) when the last rule matching `path` ignores it, Some(false) when it takes it back
+fn last_match(rules: &[Rule], dir: &Path, path: &Path, is_dir: bool) -> Option<bool> {
+    let rel = path.strip_prefix(dir).ok()?.to_string_lossy().replace('\\', "/");
+    rules.iter().rev().find(|rule| (is_dir || !rule.dir_only) && rule.glob.is_match(&rel)).map(|rule| !rule.negated)
+}
+
+// The rules of an ignore file, none if it is missing. Patterns globset cannot take are warned
+// about and left out
+fn read(file: &Path) -> Vec<Rule> {
+    let Ok(text) = fs::read_to_string(file) else { return Vec::new() };
+    text.lines()
+        .filter_map(|line| {
+            let rule = parse(line)?;
+            rule.map_err(|e| warn!("Skipping pattern {:?} of {}: {}", line, file.display(), e)).ok()
+        })
+        .collect()
+}
+
+fn parse(line: &str) -> Option<Result<Rule, globset::Error>> {
+    // Trailing spaces go unless escaped
+    let trimmed = line.trim_end_matches(['\r', ' ']);
+    let mut pattern = if trimmed.ends_with('\\') && trimmed.len() < line.trim_end_matches('\r').len() { &line[..=trimmed.len()] } else { trimmed };
+    if pattern.is_empty() || pattern.starts_with('#') {
+        return None;
+    }
+    let negated = pattern.starts_with('!');
+    // `!` ends up literal either way: globset only reads it inside brackets
+    if negated || pattern.starts_with("\\!") || pattern.starts_with("\\#") {
+        pattern = &pattern[1..];
+    }
+    let dir_only = pattern.ends_with('/');
+    let pattern = pattern.trim_end_matches('/');
+    if pattern.is_empty() {
+        return None;
+    }
+    let pattern = match pattern.strip_prefix('/') {
+        Some(anchored) => anchored.to_string(),
+        None if pattern.contains('/') => pattern.to_string(),
+        None => format!("**/{}", pattern),
+    };
+    Some(GlobBuilder::new(&pattern).literal_separator(true).build().map(|glob| Rule { glob: glob.compile_matcher(), negated, dir_only }))
+}
@@ -1,4 +1,5 @@
 use anyhow::{Context, Result, bail};
+use tracing::{debug, info, warn};
 use clap::{Args, ValueEnum};
 use hex::encode_upper;
 use std::{
@@ -7,7 +8,10 @@ use std::{
     io::{BufRead, BufReader, Read, Write},
     path::{Path, PathBuf},
 };
-use walkdir::WalkDir;
+use crate::batch::{self, WalkArgs};
+use crate::{errors, http, stdio};
+use crate::clipboard::CopyArgs;
+use crate::watch::{self, WatchArgs};
 
 #[derive(Clone, Copy, ValueEnum, Debug)]
 pub enum Algorithm {
@@ -31,6 +35,15 @@ pub struct HashArgs {
     decimal: bool,
     #[arg(short, long)]
     output: Option<PathBuf>,
+    #[command(flatten)]
+    walk: WalkArgs,
+    /// Files hashed at once with -d. Default: all cores
+    #[arg(short = 't', long)]
+    threads: Option<u32>,
+    #[command(flatten)]
+    watch: WatchArgs,
+    #[command(flatten)]
+    copy: CopyArgs,
 }
 
 #[derive(Args)]
@@ -60,7 +73,7 @@ impl std::fmt::Display for Algorithm {
 
 // CORE
 
-fn ensure_decimal_supported(algorithm: Algorithm, decimal: bool) -> Result<()> {
+pub(crate) fn ensure_decimal_supported(algorithm: Algorithm, decimal: bool) -> Result<()> {
     if decimal {
         match algorithm {
             Algorithm::Crc32 | Algorithm::Crc32c => Ok(()),
@@ -70,7 +83,10 @@ fn ensure_decimal_supported(algorithm: Algorithm, decimal: bool) -> Result<()> {
         Ok(())
     }
 }
-fn hash_reader(mut r: impl Read, algorithm: Algorithm, decimal: bool) -> Result<String> {
+
+/// Digest of everything `r` yields, as uppercase hex. With `decimal`, CRC32 and CRC32C are
+/// rendered as decimal numbers instead; other algorithms ignore it
+pub fn hash_reader(mut r: impl Read, algorithm: Algorithm, decimal: bool) -> Result<String> {
     const BUFFER: usize = 1024 * 1024;
     match algorithm {
         Algorithm::Blake3 => {
@@ -164,16 +180,45 @@ fn hash_reader(mut r: impl Read, algorithm: Algorithm, decimal: bool) -> Result<
     }
 }
 
-fn hash_file(path: &Path, algorithm: Algorithm, decimal: bool) -> Result<String> {
-    let f = File::open(path).with_context(|| format!("open {}", path.display()))?;
+/// [`hash_reader`] over the contents of the file at `path`
+pub fn hash_file(path: &Path, algorithm: Algorithm, decimal: bool) -> Result<String> {
+    let f = File::open(path).with_context(|| errors::at("open", path))?;
     hash_reader(f, algorithm, decimal)
 }
 
+// hash_file with a byte progress bar, for single-file commands
+fn hash_file_progress(path: &Path, algorithm: Algorithm, decimal: bool) -> Result<String> {
+    if stdio::is_dash(path) {
+        return hash_reader(stdio::reader(path)?, algorithm, decimal);
+    }
+    // Streamed as it arrives, nothing is written to disk
+    if let Some(url) = http::url(path) {
+        let response = http::get(url, 0, None)?;
+        let bar = crate::progress::bytes(response.len().unwrap_or(0), path);
+        let hex = hash_reader(bar.wrap_read(response.body), algorithm, decimal).with_context(|| format!("download {}", url))?;
+        bar.finish_and_clear();
+        return Ok(hex);
+    }
+    let f = File::open(path).with_context(|| errors::at("open", path))?;
+    let bar = crate::progress::bytes(f.metadata()?.len(), path);
+    let hex = hash_reader(bar.wrap_read(f), algorithm, decimal)?;
+    bar.finish_and_clear();
+    Ok(hex)
+}
+
 // COMMANDS
 pub fn hash(a: HashArgs) -> Result<()> {
     ensure_decimal_supported(a.algorithm, a.decimal)?;
+    if a.watch.watch && !a.directory {
+        bail!("--watch needs --directory");
+    }
+    if a.copy.copy && (a.directory || a.output.is_some()) {
+        bail!("--copy takes the digest of a single file, which -d and -o write to a file instead");
+    }
 
     if a.directory {
+        let watching = a.watch.start(&a.path, true)?;
+        stdio::ensure_not_dash(&a.path, "--directory")?;
         let root = fs::canonicalize(&a.path).unwrap_or(a.path.clone());
         let top = root
             .file_name()
@@ -184,42 +229,62 @@ pub fn hash(a: HashArgs) -> Result<()> {
             Some(p) => p.clone(),
             None => std::env::current_dir()?.join(format!("{top}.{}", a.algorithm)),
         };
+        if !stdio::check_output(&out_path)? {
+            return Ok(());
+        }
 
-        let mut out = File::create(&out_path)?;
-
-        for entry in WalkDir::new(&root) {
-            let entry = entry?;
-            if entry.file_type().is_dir() {
-                continue;
+        let files = batch::collect(&root, &a.walk, true, |_| true)?;
+        let digests = batch::map(&files, a.threads, false, "Hashing", |abs| hash_file(abs, a.algorithm, a.decimal))?;
+        // Relative paths in name order, the manifest is rewritten from these as files change
+        let mut entries = BTreeMap::new();
+        let mut first = Ok(());
+        for (abs, hex) in files.iter().zip(digests) {
+            // Files never started after a failure are passed over until that failure is reached
+            let Some(hex) = hex else { continue };
+            match hex.with_context(|| format!("hash {}", abs.display())) {
+                Ok(hex) => { entries.insert(abs.strip_prefix(&root).unwrap_or(abs).to_path_buf(), hex); }
+                Err(e) => { first = Err(e); break; }
             }
-
-            let abs = entry.path();
-            let rel = abs.strip_prefix(&root).unwrap_or(abs);
-            let rel_with_top = Path::new(&top).join(rel);
-
-            let line_path_unix = rel_with_top.to_string_lossy().replace('\\', "/");
-            let line_path_win = rel_with_top.to_string_lossy().replace('/', "\\");
-
-            let hex = hash_file(abs, a.algorithm, a.decimal)?;
-            writeln!(out, "#{}#{}", a.algorithm, line_path_win)?;
-            writeln!(out, "{} *{}", hex, line_path_unix)?;
+        }
+        if first.is_ok() {
+            first = write_manifest(&out_path, &top, a.algorithm, &entries);
         }
 
-        println!("Wrote manifest: {}", out_path.display());
+        // The manifest is not an entry of itself when written inside the directory
+        let manifest = fs::canonicalize(&out_path).unwrap_or(out_path.clone());
+        watch::follow(watching, first, &root, &a.walk, |path| path != manifest, |changed, removed| {
+            let digests = batch::map(changed, a.threads, true, "Hashing", |abs| hash_file(abs, a.algorithm, a.decimal))?;
+            for (abs, hex) in changed.iter().zip(digests) {
+                let rel = abs.strip_prefix(&root).unwrap_or(abs).to_path_buf();
+                match hex {
+                    Some(Ok(hex)) => { entries.insert(rel, hex); }
+                    // Gone again before it was read, or unreadable: not listed until it changes
+                    Some(Err(e)) => {
+                        warn!("Skipped: {}: {:#}", abs.display(), e);
+                        entries.remove(&rel);
+                    }
+                    None => {}
+                }
+            }
+            for abs in removed {
+                entries.remove(abs.strip_prefix(&root).unwrap_or(abs));
+            }
+            write_manifest(&out_path, &top, a.algorithm, &entries)
+        })?;
     } else {
-        let hex = hash_file(&a.path, a.algorithm, a.decimal)?;
+        if let Some(out) = &a.output
+            && !stdio::check_output(out)?
+        {
+            return Ok(());
+        }
+        let hex = hash_file_progress(&a.path, a.algorithm, a.decimal)?;
         if let Some(out) = a.output {
-            let name = a
-                .path
-                .file_name()
-                .map(|s| s.to_string_lossy().into_owned())
-                .unwrap_or_else(|| a.path.to_string_lossy().into_owned());
-            let unix = name.replace('\\', "/");
-            let win = name.replace('/', "\\");
-
-            let mut w = File::create(&out)?;
-            writeln!(w, "#{}#{}", a.algorithm, win)?;
-            writeln!(w, "{} *{}", hex, unix)?;
+            write_digest_file(&out, &a.path, a.algorithm, &hex)?;
+        } else if a.copy.copy {
+            // The digest alone, to paste where it is checked
+            let mut out = a.copy.output()?;
+            write!(out, "{hex}")?;
+            out.finish()?;
         } else {
             println!("{hex}  {}", a.path.display());
         }
@@ -227,6 +292,40 @@ pub fn hash(a: HashArgs) -> Result<()> {
     Ok(())
 }
 
+// Manifest of `entries`, relative paths and their digests, listed under the directory name `top`
+pub(crate) fn write_manifest(out_path: &Path, top: &str, algorithm: Algorithm, entries: &BTreeMap<PathBuf, String>) -> Result<()> {
+    let mut out = stdio::writer(out_path, false)?;
+    for (rel, hex) in entries {
+        let rel_with_top = Path::new(top).join(rel);
+
+        let line_path_unix = rel_with_top.to_string_lossy().replace('\\', "/");
+        let line_path_win = rel_with_top.to_string_lossy().replace('/', "\\");
+
+        debug!("{} {}", hex, line_path_unix);
+        writeln!(out, "#{}#{}", algorithm, line_path_win)?;
+        writeln!(out, "{} *{}", hex, line_path_unix)?;
+    }
+    out.finish()?;
+
+    info!("Wrote manifest: {}", out_path.display());
+    Ok(())
+}
+
+// One-entry manifest for the file at `path`, named without its directories
+pub(crate) fn write_digest_file(out: &Path, path: &Path, algorithm: Algorithm, hex: &str) -> Result<()> {
+    let name = path
+        .file_name()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string_lossy().into_owned());
+    let unix = name.replace('\\', "/");
+    let win = name.replace('/', "\\");
+
+    let mut w = stdio::writer(out, false)?;
+    writeln!(w, "#{}#{}", algorithm, win)?;
+    writeln!(w, "{} *{}", hex, unix)?;
+    w.finish()
+}
+
 pub fn hash_verify(a: HashVerifyArgs) -> Result<()> {
     if a.expected.is_some() {
         let expected = a.expected.context("error with provided --expected")?;
@@ -234,7 +333,7 @@ pub fn hash_verify(a: HashVerifyArgs) -> Result<()> {
 
         ensure_decimal_supported(algorithm, a.decimal)?;
 
-        let got = hash_file(&a.path, algorithm, a.decimal)?;
+        let got = hash_file_progress(&a.path, algorithm, a.decimal)?;
         if eq_hex(&got, &expected) {
             println!("OK  {}", a.path.display());
             Ok(())
@@ -283,12 +382,11 @@ pub fn hash_verify(a: HashVerifyArgs) -> Result<()> {
         let mut seen: BTreeSet<String> = BTreeSet::new();
         let mut mismatches: Vec<(String, String, String)> = vec![];
 
-        for entry in WalkDir::new(&root) {
-            let entry = entry?;
-            if entry.file_type().is_dir() {
-                continue;
-            }
-            let p = entry.path();
+        let files = batch::collect(&root, &WalkArgs::default(), true, |_| true)?;
+        let digests = batch::map(&files, None, false, "Verifying", |p| hash_file(p, algo, a.decimal))?;
+        for (p, got) in files.iter().zip(digests) {
+            let Some(got) = got else { continue };
+            let got = got.with_context(|| format!("hash {}", p.display()))?;
             let rel = p.strip_prefix(&root).unwrap_or(p);
             let rel_unix = rel.to_string_lossy().replace('\\', "/");
 
@@ -299,12 +397,11 @@ pub fn hash_verify(a: HashVerifyArgs) -> Result<()> {
                 rel_unix
             };
 
-            let got = hash_file(p, algo, a.decimal)?;
             seen.insert(key.clone());
-            if let Some(exp) = map_expected.get(&key) {
-                if !eq_hex(&got, exp) {
-                    mismatches.push((key, exp.clone(), got));
-                }
+            if let Some(exp) = map_expected.get(&key)
+                && !eq_hex(&got, exp)
+            {
+                mismatches.push((key, exp.clone(), got));
             }
         }
 
@@ -341,7 +438,7 @@ pub fn hash_verify(a: HashVerifyArgs) -> Result<()> {
 }
 
 // HELPERS
-fn eq_hex(a: &str, b: &str) -> bool {
+pub(crate) fn eq_hex(a: &str, b: &str) -> bool {
     a.trim().eq_ignore_ascii_case(b.trim())
 }
 
@@ -359,9 +456,8 @@ fn parse_algorithm(s: &str) -> Result<Algorithm> {
     Ok(a)
 }
 
-fn read_manifest(path: &Path) -> Result<(Algorithm, BTreeMap<String, String>)> {
-    let f = File::open(path)?;
-    let r = BufReader::new(f);
+pub(crate) fn read_manifest(path: &Path) -> Result<(Algorithm, BTreeMap<String, String>)> {
+    let r = BufReader::new(stdio::reader(path)?);
 
     let mut algorithm: Option<Algorithm> = None;
     let mut map = BTreeMap::new();
@@ -0,0 +1,210 @@
+// HTTP and HTTPS downloads for `fetch` and URL inputs, through the system's curl: GET with a
+// Range, redirects followed, TLS checked against the system's certificate store. curl writes the
+// headers of every response it reads before the body, so the final status and headers are parsed
+// from its stdout and the rest is the body, which it has already decoded from chunks and checked
+// against Content-Length. Only http:// and https:// are allowed, for redirects too
+use anyhow::{Context, Result, bail};
+use percent_encoding::percent_decode_str;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::time::Duration;
+use tracing::debug;
+use crate::errors;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const REDIRECTS: usize = 5;
+
+// Whether an input names a URL rather than a path
+pub(crate) fn is_url(input: &str) -> bool {
+    let lower = input.get(..8).unwrap_or(input).to_ascii_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+// A command-line input that is a URL, for the read-only commands that accept them
+pub(crate) fn url(input: &Path) -> Option<&str> {
+    input.to_str().filter(|s| is_url(s))
+}
+
+// An http:// or https:// URL, checked before curl sees it
+pub(crate) struct Url {
+    // Path and query, at least `/`
+    target: String,
+}
+
+impl Url {
+    pub(crate) fn parse(url: &str) -> Result<Url> {
+        if !is_url(url) {
+            bail!("{} is not an http:// or https:// URL", url);
+        }
+        let rest = &url[url.find("://").unwrap_or_default() + 3..];
+        let rest = rest.split('#').next().unwrap_or_default();
+        let (authority, target) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_string()),
+            None => (rest, "/".to_string()),
+        };
+        // They would end up on curl's command line, where other users can read them
+        if authority.contains('@') {
+            bail!("{}: credentials in URLs are not supported", url);
+        }
+        if authority.is_empty() || authority.starts_with(':') {
+            bail!("{} has no host", url);
+        }
+        Ok(Url { target })
+    }
+
+    // The last path segment, decoded, for naming a download
+    pub(crate) fn file_name(&self) -> Option<String> {
+        let path = self.target.split('?').next().unwrap_or_default();
+        let name = percent_decode_str(path.rsplit('/').next()?).decode_utf8_lossy().into_owned();
+        (!name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])).then_some(name)
+    }
+}
+
+// A response whose status is 2xx, with its body still to read
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    headers: Vec<(String, String)>,
+    pub(crate) body: Box<dyn Read + Send>,
+}
+
+impl Response {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    // Bytes the body will have, when the server said
+    pub(crate) fn len(&self) -> Option<u64> {
+        self.header("Content-Length").and_then(|n| n.trim().parse().ok())
+    }
+}
+
+// GETs `url` from byte `from` on, following redirects. With `if_range`, an ETag or Last-Modified
+// value, the server sends the whole file instead when it no longer matches. Any status but 2xx
+// is an error, typed so that a 404 counts as not found and a 503 as worth retrying
+pub(crate) fn get(url: &str, from: u64, if_range: Option<&str>) -> Result<Response> {
+    Url::parse(url)?;
+    let mut command = Command::new("curl");
+    // -q first, so a ~/.curlrc cannot change what comes back
+    command.args(["-q", "--silent", "--show-error", "--location", "--dump-header", "-", "--suppress-connect-headers"]);
+    command.args(["--proto", "=http,https", "--proto-redir", "=http,https", "--max-redirs", &REDIRECTS.to_string()]);
+    command.args(["--connect-timeout", &TIMEOUT.as_secs().to_string(), "--speed-limit", "1", "--speed-time", &TIMEOUT.as_secs().to_string()]);
+    command.args(["--header", "Accept-Encoding: identity", "--user-agent", concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"))]);
+    if from > 0 {
+        command.args(["--range", &format!("{}-", from)]);
+        if let Some(validator) = if_range {
+            command.arg("--header").arg(format!("If-Range: {}", validator));
+        }
+    }
+    command.arg("--url").arg(url);
+    command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => bail!("GET {}: downloads go through curl, which is not installed", url),
+        Err(e) => return Err(e).context("run curl"),
+    };
+    let stdout = child.stdout.take().context("curl stdout")?;
+    let mut body = Body { reader: BufReader::new(stdout), child, done: false };
+    let (status, headers) = body.head().with_context(|| format!("GET {}", url))?;
+    if !(200..=299).contains(&status) {
+        return Err(errors::HttpStatus { url: url.to_string(), status }.into());
+    }
+    Ok(Response { status, headers, body: Box::new(body) })
+}
+
+// curl's stdout past the headers, an error at the end when curl says the transfer failed
+struct Body {
+    reader: BufReader<ChildStdout>,
+    child: Child,
+    done: bool,
+}
+
+impl Body {
+    // The status and headers of the last response: informational ones (1xx) and the redirects
+    // curl followed come first, each ending with a blank line
+    fn head(&mut self) -> io::Result<(u16, Vec<(String, String)>)> {
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                self.finish()?;
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "no response"));
+            }
+            let mut parts = line.split_whitespace();
+            let status = match (parts.next(), parts.next()) {
+                (Some(version), Some(status)) if version.starts_with("HTTP/") => status.parse::<u16>().ok(),
+                _ => None,
+            };
+            let status = status.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("malformed status line {:?}", line.trim_end())))?;
+            let mut headers = Vec::new();
+            loop {
+                line.clear();
+                if self.reader.read_line(&mut line)? == 0 {
+                    self.finish()?;
+                    return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "headers cut short"));
+                }
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                let (name, value) = line.split_once(':').ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed header"))?;
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+            let followed = (300..=399).contains(&status) && headers.iter().any(|(k, _)| k.eq_ignore_ascii_case("Location"));
+            if (100..=199).contains(&status) || followed {
+                if followed {
+                    debug!("Redirected with status {}", status);
+                }
+                continue;
+            }
+            return Ok((status, headers));
+        }
+    }
+
+    // Waits for curl, turning its failure into an error of the kind the exit code stands for
+    fn finish(&mut self) -> io::Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        self.done = true;
+        let status = self.child.wait()?;
+        if status.success() {
+            return Ok(());
+        }
+        let mut message = String::new();
+        if let Some(mut stderr) = self.child.stderr.take() {
+            let _ = stderr.read_to_string(&mut message);
+        }
+        let message = message.trim().trim_start_matches("curl: ").to_string();
+        let message = if message.is_empty() { format!("curl failed: {}", status) } else { message };
+        // Failed to connect, timed out, partial transfer, empty reply, send and receive errors,
+        // HTTP/2 stream errors: all worth another attempt
+        let kind = match status.code() {
+            Some(7) => io::ErrorKind::ConnectionRefused,
+            Some(28) => io::ErrorKind::TimedOut,
+            Some(18 | 52 | 55 | 56 | 92) => io::ErrorKind::ConnectionAborted,
+            _ => io::ErrorKind::Other,
+        };
+        Err(io::Error::new(kind, message))
+    }
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.finish()?;
+        }
+        Ok(n)
+    }
+}
+
+// A body dropped before its end stops the download
+impl Drop for Body {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
@@ -1,13 +1,17 @@
 use anyhow::{bail, Context, Result};
+use tracing::{error, info, warn};
 use clap::{Args, ValueEnum};
-use std::fs::File;
+use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::io::{BufWriter, Write};
 use std::collections::BTreeSet;
 use image::{GenericImageView, ImageEncoder};
+use crate::batch::{self, BatchArgs};
+use crate::watch::{self, WatchArgs};
+use crate::{errors, jpeg, memory, stdio};
 
 #[derive(Clone, Copy, ValueEnum, Debug)]
-pub enum ImageFormat { Png, Jpeg, Webp, Bmp, Ico, Tiff, Tga, Dds, Pnm }
+pub enum ImageFormat { Png, Jpeg, Webp, Avif, Gif, Bmp, Ico, Tiff, Tga, Dds, Pnm }
 
 #[derive(Clone, Copy, ValueEnum, Debug)]
 pub enum ResizeMode { Fit, Fill, Exact }
@@ -16,19 +20,82 @@ pub enum ResizeMode { Fit, Fill, Exact }
 pub enum Filter { Nearest, Triangle, CatmullRom, Gaussian, Lanczos3 }
 
 #[derive(Args)]
-#[command[name = "image-convert", about = "Simple image format conversion supporting PNG, JPEG, WEBP, BMP, ICO, TIFF, TGA, DDS and PNM"]]
+#[command[name = "image-convert", about = "Simple image format conversion supporting PNG, JPEG, WEBP, AVIF, GIF, BMP, ICO, TIFF, TGA, DDS and PNM"]]
 pub struct ConvertArgs {
     input: PathBuf,
     #[arg(short, long, value_enum)]
     format: ImageFormat,
     #[arg(short, long)]
     output: Option<PathBuf>,
-    // Quality for JPEG. 1-100. Default: 90
+    // Quality for JPEG and AVIF. 1-100. Default: 90
     #[arg(long, default_value_t = 90)]
     quality: u8,
+    // Encoder speed for AVIF. 1 (slowest, smallest) - 10 (fastest). Default: 4
+    #[arg(long, default_value_t = 4)]
+    speed: u8,
     // Background color for formats without Alpha. Default: FFFFFF
     #[arg(long, default_value = "FFFFFF")]
     background: String,
+    // Frame of an animated GIF/WEBP to convert. Animations are kept when converting to GIF unless set
+    #[arg(long)]
+    frame: Option<usize>,
+    // Reduce to at most this many colors (2-256). PNG output becomes indexed
+    #[arg(long)]
+    colors: Option<u16>,
+    // Error diffusion used with --colors
+    #[arg(long, value_enum, default_value_t = Dither::None, requires = "colors")]
+    dither: Dither,
+    // Physical resolution stored in PNG (pHYs) and JPEG (JFIF) output
+    #[arg(long)]
+    dpi: Option<u16>,
+    // Bits per channel. Default: the source depth when the target format supports it
+    #[arg(long, value_enum)]
+    depth: Option<Depth>,
+    // Convert from the embedded ICC profile (AdobeRGB, Display P3, ...) to sRGB before encoding
+    #[arg(long)]
+    to_srgb: bool,
+    // Embed an sRGB ICC profile in PNG, JPEG and WEBP output
+    #[arg(long, requires = "to_srgb")]
+    embed_srgb: bool,
+    // Keep stored pixel order instead of applying the EXIF orientation tag
+    #[arg(long)]
+    no_auto_orient: bool,
+    #[command(flatten)]
+    png: PngArgs,
+    #[command(flatten)]
+    batch: BatchArgs,
+    #[command(flatten)]
+    watch: WatchArgs,
+}
+
+#[derive(Clone, Copy, ValueEnum, Debug)]
+pub enum Dither { None, FloydSteinberg }
+
+#[derive(Clone, Copy, ValueEnum, Debug, PartialEq)]
+pub enum Depth {
+    #[value(name = "8")]
+    Eight,
+    #[value(name = "16")]
+    Sixteen,
+}
+
+#[derive(Clone, Copy, ValueEnum, Debug)]
+pub enum PngCompression { Fast, Default, Best }
+
+#[derive(Clone, Copy, ValueEnum, Debug)]
+pub enum PngFilter { None, Sub, Up, Avg, Paeth, Adaptive, MinEntropy }
+
+// PNG encoder settings. Channels the image does not use (alpha, color) are always dropped
+#[derive(Args)]
+pub struct PngArgs {
+    #[arg(long, value_enum, default_value_t = PngCompression::Default)]
+    png_compression: PngCompression,
+    // Row filter strategy. Default: adaptive
+    #[arg(long, value_enum, default_value_t = PngFilter::Adaptive)]
+    png_filter: PngFilter,
+    // Write an indexed PNG when the image has at most 256 colors
+    #[arg(long)]
+    png_palette: bool,
 }
 
 #[derive(Args)]
@@ -47,8 +114,258 @@ pub struct ScaleArgs {
     // Resampling filter
     #[arg(long, value_enum, default_value_t = Filter::Lanczos3)]
     filter: Filter,
+    // Side kept by --mode fill when cropping
+    #[arg(long, value_enum, default_value_t = Gravity::Center)]
+    gravity: Gravity,
+    // With --mode fill, keep the most detailed region instead of using --gravity
+    #[arg(long, conflicts_with = "gravity")]
+    smart: bool,
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    // Unsharp mask amount applied after resampling, e.g. 0.5
+    #[arg(long)]
+    sharpen: Option<f32>,
+    // Bits per channel. Default: the source depth when the output format supports it
+    #[arg(long, value_enum)]
+    depth: Option<Depth>,
+    // Keep stored pixel order instead of applying the EXIF orientation tag
+    #[arg(long)]
+    no_auto_orient: bool,
+    #[command(flatten)]
+    batch: BatchArgs,
+}
+
+#[derive(Args)]
+#[command[name = "image-thumb", about = "Generate thumbnails at several sizes per image, in parallel for folders"]]
+pub struct ThumbArgs {
+    input: PathBuf,
+    // Comma separated box sizes, e.g. 128,256,512
+    #[arg(long, value_delimiter = ',', required = true)]
+    sizes: Vec<u32>,
+    // fit | fill | exact
+    #[arg(long, value_enum, default_value_t = ResizeMode::Fit)]
+    mode: ResizeMode,
+    // Resampling filter
+    #[arg(long, value_enum, default_value_t = Filter::Lanczos3)]
+    filter: Filter,
+    // Side kept by --mode fill when cropping
+    #[arg(long, value_enum, default_value_t = Gravity::Center)]
+    gravity: Gravity,
+    // With --mode fill, keep the most detailed region instead of using --gravity
+    #[arg(long, conflicts_with = "gravity")]
+    smart: bool,
+    // Placeholders: {stem}, {size}, {width}, {height}, {ext}
+    #[arg(long, default_value = "{stem}_{size}.{ext}")]
+    name: String,
+    // Output folder. Default: next to each input
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    // Unsharp mask amount applied after resampling, e.g. 0.5
+    #[arg(long)]
+    sharpen: Option<f32>,
+    // Keep stored pixel order instead of applying the EXIF orientation tag
+    #[arg(long)]
+    no_auto_orient: bool,
+    #[command(flatten)]
+    batch: BatchArgs,
+}
+
+#[derive(Clone, Copy, ValueEnum, Debug)]
+pub enum Gravity { Center, N, Ne, E, Se, S, Sw, W, Nw }
+
+#[derive(Args)]
+#[command[name = "image-crop", about = "Crop images to a rectangle, or to a size or aspect ratio anchored by gravity"]]
+pub struct CropArgs {
+    input: PathBuf,
+    // x,y,w,h in pixels or percent of the image, e.g. 10,10,640,480 or 25%,25%,50%,50%
+    #[arg(long, conflicts_with_all = ["width", "height", "aspect", "gravity"])]
+    rect: Option<String>,
+    // Pixels or percent
+    #[arg(long)]
+    width: Option<String>,
+    #[arg(long)]
+    height: Option<String>,
+    // e.g. 16:9. Largest crop with that ratio, or derives the missing side of --width/--height
+    #[arg(long)]
+    aspect: Option<String>,
+    // Where the crop is anchored when it is smaller than the image
+    #[arg(long, value_enum, default_value_t = Gravity::Center)]
+    gravity: Gravity,
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    // Keep stored pixel order instead of applying the EXIF orientation tag
+    #[arg(long)]
+    no_auto_orient: bool,
+    #[command(flatten)]
+    batch: BatchArgs,
+}
+
+#[derive(Args)]
+#[command[name = "image-adjust", about = "Grayscale, brightness, contrast, gamma and invert in a single pass"]]
+pub struct AdjustArgs {
+    input: PathBuf,
+    #[arg(long)]
+    grayscale: bool,
+    // Added to every channel. -255 to 255
+    #[arg(long, allow_hyphen_values = true, default_value_t = 0)]
+    brightness: i32,
+    // Percent, -100 (flat gray) to 100 and beyond
+    #[arg(long, allow_hyphen_values = true, default_value_t = 0.0)]
+    contrast: f32,
+    // Above 1 brightens midtones, below 1 darkens them
+    #[arg(long, default_value_t = 1.0)]
+    gamma: f32,
+    #[arg(long)]
+    invert: bool,
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    // Keep stored pixel order instead of applying the EXIF orientation tag
+    #[arg(long)]
+    no_auto_orient: bool,
+    #[command(flatten)]
+    batch: BatchArgs,
+}
+
+#[derive(Args)]
+#[command[name = "image-border", about = "Add a border, or extend the canvas to a target size"]]
+pub struct BorderArgs {
+    input: PathBuf,
+    // Border width in pixels on every side
+    #[arg(long, required_unless_present = "canvas", conflicts_with = "canvas")]
+    size: Option<u32>,
+    // Target canvas WxH, e.g. 1080x1080. The image is placed by --gravity
+    #[arg(long)]
+    canvas: Option<String>,
+    #[arg(long, value_enum, default_value_t = Gravity::Center, requires = "canvas")]
+    gravity: Gravity,
+    // RRGGBB, RRGGBBAA or "transparent". Default: FFFFFF
+    #[arg(long, default_value = "FFFFFF")]
+    color: String,
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    // Keep stored pixel order instead of applying the EXIF orientation tag
+    #[arg(long)]
+    no_auto_orient: bool,
+    #[command(flatten)]
+    batch: BatchArgs,
+}
+
+#[derive(Args)]
+#[command[name = "image-filter", about = "Gaussian blur, sharpen and unsharp mask, applied in that order"]]
+pub struct FilterArgs {
+    input: PathBuf,
+    // Gaussian blur sigma in pixels
+    #[arg(long)]
+    blur: Option<f32>,
+    // Fixed 3x3 sharpening kernel
+    #[arg(long)]
+    sharpen: bool,
+    // Unsharp mask amount, 1.0 adds the full difference to the blurred copy
+    #[arg(long)]
+    unsharp: Option<f32>,
+    // Unsharp mask blur sigma. Default: 1.0
+    #[arg(long, default_value_t = 1.0, requires = "unsharp")]
+    radius: f32,
+    // Skip differences up to this value (0-255) so flat areas keep their noise level. Default: 0
+    #[arg(long, default_value_t = 0, requires = "unsharp")]
+    threshold: u8,
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    // Keep stored pixel order instead of applying the EXIF orientation tag
+    #[arg(long)]
+    no_auto_orient: bool,
+    #[command(flatten)]
+    batch: BatchArgs,
+}
+
+#[derive(Args)]
+#[command[name = "image-keyout", about = "Chroma key: turn a background color into transparency"]]
+pub struct KeyoutArgs {
+    input: PathBuf,
+    // Key color RRGGBB. Default: 00FF00
+    #[arg(long, default_value = "00FF00")]
+    color: String,
+    // Color distance (0-255) that becomes fully transparent. Default: 12
+    #[arg(long, default_value_t = 12.0)]
+    tolerance: f32,
+    // Width of the distance band above --tolerance that fades from transparent to opaque. Default: 16
+    #[arg(long, default_value_t = 16.0)]
+    feather: f32,
+    // Output path, must support alpha. Default: <INPUT>_keyed.png
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    // Keep stored pixel order instead of applying the EXIF orientation tag
+    #[arg(long)]
+    no_auto_orient: bool,
+    #[command(flatten)]
+    batch: BatchArgs,
+}
+
+#[derive(Clone, Copy, ValueEnum, Debug)]
+pub enum Rotation {
+    #[value(name = "90")]
+    R90,
+    #[value(name = "180")]
+    R180,
+    #[value(name = "270")]
+    R270,
+}
+
+#[derive(Clone, Copy, ValueEnum, Debug)]
+pub enum Flip { Horizontal, Vertical }
+
+#[derive(Args)]
+#[command[name = "image-transform", about = "Rotate by 90, 180 or 270 degrees and flip images, losslessly for baseline JPEG where possible"]]
+pub struct TransformArgs {
+    input: PathBuf,
+    // Clockwise rotation in degrees
+    #[arg(long, value_enum)]
+    rotate: Option<Rotation>,
+    // Applied after the rotation
+    #[arg(long, value_enum)]
+    flip: Option<Flip>,
     #[arg(short, long)]
     output: Option<PathBuf>,
+    // Quality when a JPEG has to be re-encoded. 1-100. Default: 90
+    #[arg(long, default_value_t = 90)]
+    quality: u8,
+}
+
+#[derive(Clone, Copy, ValueEnum, Debug)]
+pub enum PerceptualHash { Phash, Dhash }
+
+#[derive(Args)]
+#[command[name = "image-compare", about = "Compare two images by perceptual hash distance, RMSE and SSIM"]]
+pub struct CompareArgs {
+    a: PathBuf,
+    b: PathBuf,
+    // Write a heatmap of the changed pixels over a faded copy of A. Needs equal sizes
+    #[arg(long)]
+    diff_output: Option<PathBuf>,
+    // Channel differences up to this value (0-255) count as unchanged, e.g. to ignore JPEG noise
+    #[arg(long, default_value_t = 0, requires = "diff_output")]
+    diff_threshold: u8,
+}
+
+#[derive(Args)]
+#[command[name = "image-hash", about = "Print perceptual hashes of images and find near-duplicates"]]
+pub struct ImageHashArgs {
+    input: PathBuf,
+    #[arg(short, long, value_enum, default_value_t = PerceptualHash::Phash)]
+    algorithm: PerceptualHash,
+    // Group images whose hashes differ in at most this many of 64 bits
+    #[arg(long)]
+    duplicates: Option<u32>,
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    #[command(flatten)]
+    batch: BatchArgs,
+}
+
+#[derive(Args)]
+#[command[name = "image-info", about = "Show the format detected from the file contents, dimensions and color type"]]
+pub struct InfoArgs {
+    input: PathBuf,
 }
 
 #[derive(Args)]
@@ -60,71 +377,625 @@ pub struct GetColorArgs {
 }
 
 pub fn convert(a: ConvertArgs) -> Result<()> {
-    let image = image::open(&a.input)
-        .with_context(|| format!("open {}", a.input.display()))?;
-    let output = a.output.unwrap_or_else(|| {
-        let stem = a.input.file_stem()
-            .map(|s| s.to_string_lossy().into_owned())
-            .unwrap_or_else(|| "output".to_string());
-        PathBuf::from(format!("{}.{}", stem, ext_for(a.format)))
-    });
+    let watching = a.watch.start(&a.input, a.batch.recursive)?;
+    if a.input.is_dir() {
+        let images = collect_images(&a.input, &a.batch)?;
+        let convert_one = |input: &Path| {
+            let output = batch_output(&a.input, input, a.output.as_deref()).with_extension(ext_for(a.format));
+            convert_file(input, &output, &a)
+        };
+        let first = batch::run(&images, a.batch.threads, convert_one);
+
+        // Conversions land in the target format, which is not converted again
+        let source = |path: &Path| {
+            let converted = path.extension().is_some_and(|e| e.eq_ignore_ascii_case(ext_for(a.format)));
+            !converted && a.output.as_deref().is_none_or(|out| !path.starts_with(out)) && is_image(path)
+        };
+        return watch::follow(watching, first, &a.input, &a.batch.walk, source, |changed, _| batch::run(changed, a.batch.threads, convert_one));
+    }
+
+    with_stdio(&a.input, a.output.as_deref(), Some(ext_for(a.format)), |input, output| {
+        let output = output.unwrap_or_else(|| {
+            let stem = input.file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "output".to_string());
+            PathBuf::from(format!("{}.{}", stem, ext_for(a.format)))
+        });
+        convert_file(input, &output, &a)
+    })
+}
+
+fn convert_file(input: &Path, output: &Path, a: &ConvertArgs) -> Result<()> {
+    if !writable(input, output)? {
+        return Ok(());
+    }
+    let frames = load_frames(input)?;
+    if let (ImageFormat::Gif, None, Some(frames)) = (a.format, a.frame, &frames)
+        && frames.len() > 1
+    {
+        stdio::write_atomic(output, |output| save_gif_animation(frames.clone(), output))?;
+        stdio::finished(input, output)?;
+        info!("Wrote {} ({} frames)", stdio::display(output), frames.len());
+        return Ok(());
+    }
+
+    let image = match (a.frame, frames) {
+        (Some(n), Some(frames)) => {
+            let count = frames.len();
+            let frame = frames.into_iter().nth(n)
+                .with_context(|| format!("frame {} out of range, {} has {} frames", n, input.display(), count))?;
+            image::DynamicImage::ImageRgba8(frame.into_buffer())
+        }
+        (Some(_), None) => bail!("--frame requires an animated GIF or WEBP input"),
+        (None, _) => {
+            let (image, icc) = open_image_with_icc(input, !a.no_auto_orient)?;
+            match icc {
+                Some(icc) if a.to_srgb => icc_to_srgb(image, &icc).with_context(|| format!("convert {} to sRGB", input.display()))?,
+                _ => image,
+            }
+        }
+    };
+
+    let image = match a.colors {
+        Some(n) if !(2..=256).contains(&n) => bail!("--colors must be between 2 and 256"),
+        Some(n) => image::DynamicImage::ImageRgba8(quantize(&image, n as usize, a.dither)),
+        None => image,
+    };
 
-    match a.format {
-        ImageFormat::Png => save_png(&image, &output)?,
-        ImageFormat::Bmp => save_common(&image, &output, ImageFormat::Bmp)?,
-        ImageFormat::Ico => save_common(&image, &output, ImageFormat::Ico)?,
-        ImageFormat::Tiff => save_common(&image, &output, ImageFormat::Tiff)?,
-        ImageFormat::Tga => save_common(&image, &output, ImageFormat::Tga)?,
-        ImageFormat::Dds => save_common(&image, &output, ImageFormat::Dds)?,
-        ImageFormat::Pnm => save_common(&image, &output, ImageFormat::Pnm)?,
-        ImageFormat::Jpeg => {
-            let bg = parse_hex_rgb(&a.background)?;
-            save_jpeg(&image, &output, a.quality, bg)?
+    // Only PNG and TIFF keep 16 bits per channel, everything else is written at 8
+    let deep = matches!(a.format, ImageFormat::Png | ImageFormat::Tiff);
+    let sixteen = match a.depth {
+        Some(Depth::Sixteen) if !deep => bail!("{:?} output is limited to 8 bits per channel", a.format),
+        Some(Depth::Sixteen) if a.colors.is_some() || a.png.png_palette => {
+            bail!("--depth 16 cannot be combined with --colors or --png-palette")
         }
-        ImageFormat::Webp => save_webp(&image, &output)?,
+        Some(depth) => depth == Depth::Sixteen,
+        None => deep && is_high_depth(&image) && a.colors.is_none(),
+    };
+    let image = with_depth(image, sixteen);
+
+    if a.dpi == Some(0) { bail!("--dpi must be greater than 0"); }
+    if a.dpi.is_some() && !matches!(a.format, ImageFormat::Png | ImageFormat::Jpeg) {
+        warn!("--dpi is only stored for PNG and JPEG, ignoring it for {}", output.display());
+    }
+
+    let icc = if a.embed_srgb { Some(moxcms::ColorProfile::new_srgb().encode()?) } else { None };
+    if a.embed_srgb && !matches!(a.format, ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Webp) {
+        warn!("--embed-srgb is only supported for PNG, JPEG and WEBP, ignoring it for {}", output.display());
     }
 
-    println!("Wrote {}", output.display());
+    stdio::write_atomic(output, |output| {
+        match a.format {
+            ImageFormat::Png => save_png(&image, output, &a.png, a.colors.is_some(), a.dpi, icc.as_deref())?,
+            ImageFormat::Bmp => save_common(&image, output, ImageFormat::Bmp)?,
+            ImageFormat::Ico => save_common(&image, output, ImageFormat::Ico)?,
+            ImageFormat::Tiff => save_common(&image, output, ImageFormat::Tiff)?,
+            ImageFormat::Tga => save_common(&image, output, ImageFormat::Tga)?,
+            ImageFormat::Dds => save_common(&image, output, ImageFormat::Dds)?,
+            ImageFormat::Pnm => save_common(&image, output, ImageFormat::Pnm)?,
+            ImageFormat::Jpeg => {
+                let bg = parse_hex_rgb(&a.background)?;
+                save_jpeg(&image, output, a.quality, bg, a.dpi, icc.as_deref())?
+            }
+            ImageFormat::Webp => save_webp(&image, output, icc.as_deref())?,
+            ImageFormat::Avif => save_avif(&image, output, a.quality, a.speed)?,
+            ImageFormat::Gif => save_common(&image, output, ImageFormat::Gif)?,
+        }
+        Ok(())
+    })?;
+
+    stdio::finished(input, output)?;
+    info!("Wrote {}", stdio::display(output));
     Ok(())
 }
 
 pub fn scale(a: ScaleArgs) -> Result<()> {
-    use image::imageops::resize;
-    let image = image::open(&a.input).with_context(|| format!("open {}", a.input.display()))?;
+    if a.input.is_dir() {
+        let images = collect_images(&a.input, &a.batch)?;
+        return batch::run(&images, a.batch.threads, |input| {
+            scale_file(input, &a, |tw, th| match &a.output {
+                Some(_) => batch_output(&a.input, input, a.output.as_deref()),
+                None => input.with_file_name(scaled_name(input, tw, th)),
+            })
+        });
+    }
+
+    with_stdio(&a.input, a.output.as_deref(), None, |input, output| {
+        scale_file(input, &a, |tw, th| output.unwrap_or_else(|| PathBuf::from(scaled_name(input, tw, th))))
+    })
+}
+
+// `output` receives the target size so default names can include it
+fn scale_file(input: &Path, a: &ScaleArgs, output: impl FnOnce(u32, u32) -> PathBuf) -> Result<()> {
+    let image = open_image(input, !a.no_auto_orient)?;
     let (w, h) = image.dimensions();
 
     // Determine target size
     let (tw, th) = compute_target_size(w, h, a.percent, a.width, a.height)?;
-    let f = filter_to_type(a.filter);
+    let anchor = fill_anchor(a.mode, a.gravity, a.smart)?;
+    let mut output_image = resize_to(&image, tw, th, a.mode, a.filter, anchor);
+    if let Some(amount) = a.sharpen {
+        output_image = unsharp_mask(&output_image, amount, 1.0, 0);
+    }
 
-    let output_image = match a.mode {
-        ResizeMode::Exact => resize(&image, tw, th, f),
-        ResizeMode::Fit => {
-            resize(&image, tw, th, f)
+    let output = output(output_image.width(), output_image.height());
+    if !writable(input, &output)? {
+        return Ok(());
+    }
+    let output_image = match a.depth {
+        Some(Depth::Sixteen) if !keeps_high_depth(&output) => {
+            bail!("{} cannot store 16 bits per channel, use a .png or .tiff output", output.display())
         }
-        ResizeMode::Fill => {
-            // scale to cover and then center-crop
-            let (cw, ch) = cover_size(w, h, tw, th);
-            let tmp = resize(&image, cw, ch, f);
-            let x = (cw.saturating_sub(tw)) / 2;
-            let y = (ch.saturating_sub(th)) / 2;
-            image::imageops::crop_imm(&tmp, x, y, tw, th).to_image()
+        Some(depth) => with_depth(output_image, depth == Depth::Sixteen),
+        None => output_image,
+    };
+    save_inferred(&output_image, &output)?;
+    stdio::finished(input, &output)?;
+    info!("Wrote {}", stdio::display(&output));
+    Ok(())
+}
+
+pub fn thumb(a: ThumbArgs) -> Result<()> {
+    if a.sizes.contains(&0) { bail!("--sizes must be greater than 0"); }
+    if a.sizes.len() > 1 && !["{size}", "{width}", "{height}"].iter().any(|p| a.name.contains(p)) {
+        bail!("--name needs {{size}}, {{width}} or {{height}} when generating several sizes");
+    }
+
+    if a.input.is_dir() {
+        let images = collect_images(&a.input, &a.batch)?;
+        return batch::run(&images, a.batch.threads, |input| {
+            let dir = match &a.output {
+                Some(_) => batch_output(&a.input, input, a.output.as_deref()),
+                None => input.to_path_buf(),
+            };
+            thumb_file(input, dir.parent().unwrap_or(Path::new("")), &a)
+        });
+    }
+
+    // Thumbnails are named after the input, which stdin does not have
+    stdio::ensure_not_dash(&a.input, "image-thumb")?;
+    let dir = a.output.clone().unwrap_or_else(|| a.input.parent().map(Path::to_path_buf).unwrap_or_default());
+    thumb_file(&a.input, &dir, &a)
+}
+
+// Decodes once and writes every size into `dir`
+fn thumb_file(input: &Path, dir: &Path, a: &ThumbArgs) -> Result<()> {
+    let image = open_image(input, !a.no_auto_orient)?;
+    let stem = input.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_else(|| "output".into());
+    let ext = input.extension().map(|s| s.to_string_lossy().into_owned()).unwrap_or_else(|| "png".into());
+
+    let anchor = fill_anchor(a.mode, a.gravity, a.smart)?;
+    for &size in &a.sizes {
+        let mut thumb = resize_to(&image, size, size, a.mode, a.filter, anchor);
+        if let Some(amount) = a.sharpen {
+            thumb = unsharp_mask(&thumb, amount, 1.0, 0);
+        }
+        let name = a.name
+            .replace("{stem}", &stem)
+            .replace("{size}", &size.to_string())
+            .replace("{width}", &thumb.width().to_string())
+            .replace("{height}", &thumb.height().to_string())
+            .replace("{ext}", &ext);
+        let output = dir.join(name);
+        if !writable(input, &output)? {
+            continue;
+        }
+        save_inferred(&thumb, &output)?;
+        stdio::finished(input, &output)?;
+        info!("Wrote {}", stdio::display(&output));
+    }
+    Ok(())
+}
+
+pub fn crop(a: CropArgs) -> Result<()> {
+    if a.input.is_dir() {
+        let images = collect_images(&a.input, &a.batch)?;
+        return batch::run(&images, a.batch.threads, |input| {
+            crop_file(input, &a, |cw, ch| match &a.output {
+                Some(_) => batch_output(&a.input, input, a.output.as_deref()),
+                None => input.with_file_name(scaled_name(input, cw, ch)),
+            })
+        });
+    }
+
+    with_stdio(&a.input, a.output.as_deref(), None, |input, output| {
+        crop_file(input, &a, |cw, ch| output.unwrap_or_else(|| PathBuf::from(scaled_name(input, cw, ch))))
+    })
+}
+
+fn crop_file(input: &Path, a: &CropArgs, output: impl FnOnce(u32, u32) -> PathBuf) -> Result<()> {
+    let image = open_image(input, !a.no_auto_orient)?;
+    let (w, h) = image.dimensions();
+    let (x, y, cw, ch) = crop_rect(w, h, a)?;
+
+    let output = output(cw, ch);
+    if !writable(input, &output)? {
+        return Ok(());
+    }
+    save_inferred(&image.crop_imm(x, y, cw, ch), &output)?;
+    stdio::finished(input, &output)?;
+    info!("Wrote {} ({}x{} at {},{})", stdio::display(&output), cw, ch, x, y);
+    Ok(())
+}
+
+pub fn adjust(a: AdjustArgs) -> Result<()> {
+    if !a.grayscale && !a.invert && a.brightness == 0 && a.contrast == 0.0 && a.gamma == 1.0 {
+        bail!("Nothing to do. Pass --grayscale, --brightness, --contrast, --gamma or --invert.");
+    }
+    if !(-255..=255).contains(&a.brightness) { bail!("--brightness must be between -255 and 255"); }
+    if a.contrast < -100.0 { bail!("--contrast cannot be below -100"); }
+    if a.gamma <= 0.0 { bail!("--gamma must be greater than 0"); }
+    let lut = adjust_lut(&a);
+
+    if a.input.is_dir() {
+        let images = collect_images(&a.input, &a.batch)?;
+        return batch::run(&images, a.batch.threads, |input| {
+            let output = match &a.output {
+                Some(_) => batch_output(&a.input, input, a.output.as_deref()),
+                None => input.with_file_name(suffixed_name(input, "adjusted")),
+            };
+            adjust_file(input, &output, a.grayscale, &lut, !a.no_auto_orient)
+        });
+    }
+
+    with_stdio(&a.input, a.output.as_deref(), None, |input, output| {
+        let output = output.unwrap_or_else(|| PathBuf::from(suffixed_name(input, "adjusted")));
+        adjust_file(input, &output, a.grayscale, &lut, !a.no_auto_orient)
+    })
+}
+
+fn adjust_file(input: &Path, output: &Path, grayscale: bool, lut: &[u8; 256], auto_orient: bool) -> Result<()> {
+    if !writable(input, output)? {
+        return Ok(());
+    }
+    let mut rgba = open_image(input, auto_orient)?.to_rgba8();
+    for px in rgba.pixels_mut() {
+        if grayscale {
+            // Rec. 709 luma, same weights as DynamicImage::grayscale
+            let l = (0.2126 * px[0] as f32 + 0.7152 * px[1] as f32 + 0.0722 * px[2] as f32).round() as u8;
+            px[0] = l; px[1] = l; px[2] = l;
+        }
+        for c in 0..3 { px[c] = lut[px[c] as usize] }
+    }
+    save_inferred(&image::DynamicImage::ImageRgba8(rgba), output)?;
+    stdio::finished(input, output)?;
+    info!("Wrote {}", stdio::display(output));
+    Ok(())
+}
+
+pub fn border(a: BorderArgs) -> Result<()> {
+    let color = parse_hex_rgba(&a.color)?;
+    let canvas = a.canvas.as_deref().map(parse_dimensions).transpose()?;
+
+    if a.input.is_dir() {
+        let images = collect_images(&a.input, &a.batch)?;
+        return batch::run(&images, a.batch.threads, |input| {
+            let output = match &a.output {
+                Some(_) => batch_output(&a.input, input, a.output.as_deref()),
+                None => input.with_file_name(suffixed_name(input, "border")),
+            };
+            border_file(input, &output, &a, canvas, color)
+        });
+    }
+
+    with_stdio(&a.input, a.output.as_deref(), None, |input, output| {
+        let output = output.unwrap_or_else(|| PathBuf::from(suffixed_name(input, "border")));
+        border_file(input, &output, &a, canvas, color)
+    })
+}
+
+fn border_file(input: &Path, output: &Path, a: &BorderArgs, canvas: Option<(u32, u32)>, color: [u8; 4]) -> Result<()> {
+    if !writable(input, output)? {
+        return Ok(());
+    }
+    let image = open_image(input, !a.no_auto_orient)?;
+    let (w, h) = image.dimensions();
+
+    let (cw, ch, x, y) = match (canvas, a.size) {
+        (Some((cw, ch)), _) => {
+            if cw < w || ch < h { bail!("canvas {}x{} is smaller than the {}x{} image", cw, ch, w, h); }
+            let (gx, gy) = gravity_factors(a.gravity);
+            (cw, ch, (cw - w) * gx / 2, (ch - h) * gy / 2)
         }
+        (None, Some(n)) => {
+            let (cw, ch) = (w.checked_add(2 * n), h.checked_add(2 * n));
+            let (Some(cw), Some(ch)) = (cw, ch) else { bail!("border of {} px is too large", n) };
+            (cw, ch, n, n)
+        }
+        (None, None) => unreachable!(),
     };
 
-    let output = a.output.unwrap_or_else(|| {
-        let stem = a.input.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_else(|| "output".into());
-        let ext = a.input.extension().map(|s| s.to_string_lossy().into_owned()).unwrap_or_else(|| "png".into());
-        PathBuf::from(format!("{}_{}x{}.{}", stem, tw, th, ext))
-    });
+    let mut out = image::RgbaImage::from_pixel(cw, ch, image::Rgba(color));
+    image::imageops::overlay(&mut out, &image.to_rgba8(), x as i64, y as i64);
+    save_inferred(&image::DynamicImage::ImageRgba8(out), output)?;
+    stdio::finished(input, output)?;
+    info!("Wrote {} ({}x{})", stdio::display(output), cw, ch);
+    Ok(())
+}
+
+pub fn filter(a: FilterArgs) -> Result<()> {
+    if a.blur.is_none() && !a.sharpen && a.unsharp.is_none() {
+        bail!("Nothing to do. Pass --blur, --sharpen or --unsharp.");
+    }
+    if a.blur.is_some_and(|s| s <= 0.0) || a.radius <= 0.0 { bail!("--blur and --radius must be greater than 0"); }
+
+    if a.input.is_dir() {
+        let images = collect_images(&a.input, &a.batch)?;
+        return batch::run(&images, a.batch.threads, |input| {
+            let output = match &a.output {
+                Some(_) => batch_output(&a.input, input, a.output.as_deref()),
+                None => input.with_file_name(suffixed_name(input, "filtered")),
+            };
+            filter_file(input, &output, &a)
+        });
+    }
+
+    with_stdio(&a.input, a.output.as_deref(), None, |input, output| {
+        let output = output.unwrap_or_else(|| PathBuf::from(suffixed_name(input, "filtered")));
+        filter_file(input, &output, &a)
+    })
+}
+
+fn filter_file(input: &Path, output: &Path, a: &FilterArgs) -> Result<()> {
+    if !writable(input, output)? {
+        return Ok(());
+    }
+    let mut image = open_image(input, !a.no_auto_orient)?;
+    if let Some(sigma) = a.blur {
+        image = image.blur(sigma);
+    }
+    if a.sharpen {
+        let kernel = [0.0, -1.0, 0.0, -1.0, 5.0, -1.0, 0.0, -1.0, 0.0];
+        image = image.filter3x3(&kernel);
+    }
+    if let Some(amount) = a.unsharp {
+        image = unsharp_mask(&image, amount, a.radius, a.threshold);
+    }
+    save_inferred(&image, output)?;
+    stdio::finished(input, output)?;
+    info!("Wrote {}", stdio::display(output));
+    Ok(())
+}
+
+pub fn keyout(a: KeyoutArgs) -> Result<()> {
+    let key = parse_hex_rgb(&a.color)?;
+    if a.tolerance < 0.0 || a.feather < 0.0 { bail!("--tolerance and --feather cannot be negative"); }
+    let keyed_name = |input: &Path| PathBuf::from(suffixed_name(input, "keyed")).with_extension("png");
+
+    if a.input.is_dir() {
+        let images = collect_images(&a.input, &a.batch)?;
+        return batch::run(&images, a.batch.threads, |input| {
+            let output = match &a.output {
+                Some(_) => batch_output(&a.input, input, a.output.as_deref()).with_extension("png"),
+                None => input.with_file_name(keyed_name(input)),
+            };
+            keyout_file(input, &output, &a, key)
+        });
+    }
 
-    output_image.save(&output)?;
-    println!("Wrote {}", output.display());
+    with_stdio(&a.input, a.output.as_deref(), Some("png"), |input, output| {
+        keyout_file(input, &output.unwrap_or_else(|| keyed_name(input)), &a, key)
+    })
+}
+
+fn keyout_file(input: &Path, output: &Path, a: &KeyoutArgs, key: (u8, u8, u8)) -> Result<()> {
+    if matches!(image::ImageFormat::from_path(output), Ok(image::ImageFormat::Jpeg)) {
+        bail!("JPEG has no alpha channel, choose another output format for {}", output.display());
+    }
+    if !writable(input, output)? {
+        return Ok(());
+    }
+    let mut rgba = open_image(input, !a.no_auto_orient)?.to_rgba8();
+    let key = [key.0 as f32, key.1 as f32, key.2 as f32];
+
+    for px in rgba.pixels_mut() {
+        let rgb = [px[0] as f32, px[1] as f32, px[2] as f32];
+        let dist = (rgb.iter().zip(&key).map(|(c, k)| (c - k).powi(2)).sum::<f32>() / 3.0).sqrt();
+        let alpha = if dist <= a.tolerance {
+            0.0
+        } else if a.feather > 0.0 && dist < a.tolerance + a.feather {
+            (dist - a.tolerance) / a.feather
+        } else {
+            continue;
+        };
+        // Undo the blend with the key color that flatten_to_rgb8 would have produced
+        for c in 0..3 {
+            px[c] = if alpha > 0.0 { ((rgb[c] - (1.0 - alpha) * key[c]) / alpha).round().clamp(0.0, 255.0) as u8 } else { 0 };
+        }
+        px[3] = (px[3] as f32 * alpha).round() as u8;
+    }
+
+    save_inferred(&image::DynamicImage::ImageRgba8(rgba), output)?;
+    stdio::finished(input, output)?;
+    info!("Wrote {}", stdio::display(output));
+    Ok(())
+}
+
+pub fn transform(a: TransformArgs) -> Result<()> {
+    if a.rotate.is_none() && a.flip.is_none() { bail!("Nothing to do. Pass --rotate and/or --flip."); }
+    with_stdio(&a.input, a.output.as_deref(), None, |input, output| {
+        let output = output.unwrap_or_else(|| PathBuf::from(suffixed_name(input, "transformed")));
+        transform_file(input, &output, &a)
+    })
+}
+
+fn transform_file(input: &Path, output: &Path, a: &TransformArgs) -> Result<()> {
+    if !writable(input, output)? {
+        return Ok(());
+    }
+
+    let is_jpeg = |p: &Path| matches!(image::ImageFormat::from_path(p), Ok(image::ImageFormat::Jpeg));
+    if matches!(detect_format(input)?, image::ImageFormat::Jpeg) && is_jpeg(output) {
+        let data = fs::read(input).with_context(|| errors::at("open", input))?;
+        match jpeg::read(&data) {
+            Ok(mut coefficients) if coefficients.is_mcu_aligned() => {
+                for t in jpeg_transforms(a.rotate, a.flip) {
+                    coefficients.apply(t);
+                }
+                match jpeg::write(&coefficients) {
+                    Ok(data) => {
+                        stdio::write(output, &data, true)?;
+                        stdio::finished(input, output)?;
+                        info!("Wrote {} (lossless)", stdio::display(output));
+                        return Ok(());
+                    }
+                    Err(e) => warn!("Cannot transform {} losslessly ({}), re-encoding", input.display(), e),
+                }
+            }
+            Ok(_) => warn!("Size of {} is not a multiple of the JPEG block size, re-encoding", input.display()),
+            Err(e) => warn!("Cannot transform {} losslessly ({}), re-encoding", input.display(), e),
+        }
+    }
+
+    let mut image = open_image(input, false)?;
+    image = match a.rotate {
+        Some(Rotation::R90) => image.rotate90(),
+        Some(Rotation::R180) => image.rotate180(),
+        Some(Rotation::R270) => image.rotate270(),
+        None => image,
+    };
+    image = match a.flip {
+        Some(Flip::Horizontal) => image.fliph(),
+        Some(Flip::Vertical) => image.flipv(),
+        None => image,
+    };
+
+    stdio::write_atomic(output, |output| {
+        if is_jpeg(output) {
+            save_jpeg(&image, output, a.quality, (255, 255, 255), None, None)
+        } else {
+            Ok(image.save(output)?)
+        }
+    })?;
+    stdio::finished(input, output)?;
+    info!("Wrote {}", stdio::display(output));
+    Ok(())
+}
+
+pub fn compare(a: CompareArgs) -> Result<()> {
+    let (first, second) = (stdio::input(&a.a)?, stdio::input(&a.b)?);
+    let first = open_image(first.path(), false)?;
+    let second = open_image(second.path(), false)?;
+
+    let phash_distance = (phash(&first) ^ phash(&second)).count_ones();
+    let dhash_distance = (dhash(&first) ^ dhash(&second)).count_ones();
+    println!("pHash distance: {}/64", phash_distance);
+    println!("dHash distance: {}/64", dhash_distance);
+
+    if first.dimensions() != second.dimensions() {
+        let (w1, h1) = first.dimensions();
+        let (w2, h2) = second.dimensions();
+        if a.diff_output.is_some() {
+            bail!("--diff-output needs images of the same size ({}x{} vs {}x{})", w1, h1, w2, h2);
+        }
+        println!("Pixel metrics skipped: sizes differ ({}x{} vs {}x{})", w1, h1, w2, h2);
+        return Ok(());
+    }
+    let (rgba1, rgba2) = (first.to_rgba8(), second.to_rgba8());
+    println!("RMSE: {:.4}", rmse(&rgba1, &rgba2));
+    println!("SSIM: {:.4}", ssim(&first.to_luma8(), &second.to_luma8()));
+
+    if let Some(output) = &a.diff_output
+        && stdio::check_output(output)?
+    {
+        let (heatmap, changed) = diff_heatmap(&rgba1, &rgba2, a.diff_threshold);
+        let total = rgba1.width() as u64 * rgba1.height() as u64;
+        println!("Changed pixels: {} ({:.2}%)", changed, changed as f64 * 100.0 / total.max(1) as f64);
+        save_inferred(&image::DynamicImage::ImageRgb8(heatmap), output)?;
+        info!("Wrote {}", stdio::display(output));
+    }
+    Ok(())
+}
+
+pub fn image_hash(a: ImageHashArgs) -> Result<()> {
+    if let Some(output) = &a.output
+        && !stdio::check_output(output)?
+    {
+        return Ok(());
+    }
+    let hasher = match a.algorithm {
+        PerceptualHash::Phash => phash,
+        PerceptualHash::Dhash => dhash,
+    };
+    let stdin = stdio::input(&a.input)?;
+    let images = if a.input.is_dir() { collect_images(&a.input, &a.batch)? } else { vec![stdin.path().to_path_buf()] };
+
+    let results = batch::map(&images, a.batch.threads, true, "Hashing", |path| Ok(hasher(&open_image(path, false)?)))?;
+
+    let mut hashes = Vec::with_capacity(images.len());
+    let mut failed = Vec::new();
+    for (path, result) in images.iter().zip(results.into_iter().flatten()) {
+        match result {
+            Ok(h) => hashes.push((path, h)),
+            Err(e) => {
+                error!("Failed: {}: {:#}", stdio::display(path), e);
+                failed.push((path.clone(), e));
+            }
+        }
+    }
+
+    let mut out = stdio::writer(a.output.as_deref().unwrap_or(Path::new("-")), false)?;
+    match a.duplicates {
+        None => {
+            for (path, h) in &hashes {
+                writeln!(out, "{:016x}  {}", h, stdio::display(path))?;
+            }
+        }
+        Some(max) => {
+            for (i, group) in duplicate_groups(&hashes, max).iter().enumerate() {
+                writeln!(out, "Group {} ({} images):", i + 1, group.len())?;
+                for &j in group {
+                    writeln!(out, "  {:016x}  {}", hashes[j].1, stdio::display(hashes[j].0))?;
+                }
+            }
+        }
+    }
+    out.finish()?;
+
+    if !failed.is_empty() {
+        return Err(errors::Batch(failed).into());
+    }
+    Ok(())
+}
+
+pub fn info(a: InfoArgs) -> Result<()> {
+    use image::ImageDecoder;
+    let input = stdio::remote_input(&a.input)?;
+    let format = detect_format(input.path())?;
+    let mut decoder = open_reader(input.path(), format)?
+        .into_decoder()
+        .with_context(|| errors::at("open", &a.input))?;
+    let (w, h) = decoder.dimensions();
+    let color = decoder.original_color_type();
+    let orientation = decoder.orientation()?;
+    drop(decoder);
+
+    println!("File:        {}", stdio::display(&a.input));
+    match image::ImageFormat::from_path(&a.input) {
+        Ok(by_ext) if by_ext != format => {
+            println!("Format:      {} (extension says {})", format_name(format), format_name(by_ext))
+        }
+        _ => println!("Format:      {}", format_name(format)),
+    }
+    println!("Dimensions:  {}x{}", w, h);
+    println!("Color:       {:?}", color);
+    if orientation != image::metadata::Orientation::NoTransforms {
+        println!("Orientation: {:?}", orientation);
+    }
+    if let Some(frames) = load_frames(input.path())? {
+        println!("Frames:      {}", frames.len());
+    }
+    println!("Size:        {} bytes", fs::metadata(input.path())?.len());
     Ok(())
 }
 
 pub fn get_color(a: GetColorArgs) -> Result<()> {
-    let image = image::open(&a.input).with_context(|| format!("failed to open image: {}", a.input.display()))?;
+    if let Some(output) = &a.output
+        && !stdio::check_output(output)?
+    {
+        return Ok(());
+    }
+    let image = open_image(stdio::input(&a.input)?.path(), false)?;
 
     let rgba = image.to_rgba8();
     let mut unique_colors = BTreeSet::<u32>::new();
@@ -135,51 +1006,143 @@ pub fn get_color(a: GetColorArgs) -> Result<()> {
         unique_colors.insert(packed);
     }
 
-    match a.output {
-        Some(path) => {
-            let file = File::create(&path).with_context(|| {
-                format!("failed to create output file: {}", path.to_string_lossy())
-            })?;
-            let mut writer = BufWriter::new(file);
-            write_colors(&mut writer, &unique_colors)?;
+    let mut writer = stdio::writer(a.output.as_deref().unwrap_or(Path::new("-")), false)?;
+    write_colors(&mut writer, &unique_colors)?;
+    writer.finish()
+}
+
+// ENCODERS
+fn save_png(image: &image::DynamicImage, output: &Path, p: &PngArgs, indexed: bool, dpi: Option<u16>, icc: Option<&[u8]>) -> Result<()> {
+    let (w, h) = image.dimensions();
+    let palette = if p.png_palette || indexed { build_palette(&image.to_rgba8()) } else { None };
+    if p.png_palette && palette.is_none() {
+        warn!("More than 256 colors, writing {} as truecolor", output.display());
+    }
+
+    let mut f = BufWriter::new(File::create(output)?);
+    let mut info = png::Info::with_size(w, h);
+    info.icc_profile = icc.map(|icc| icc.to_vec().into());
+    let mut enc = png::Encoder::with_info(&mut f, info)?;
+    if let Some(dpi) = dpi {
+        // pHYs only knows pixels per meter
+        let ppm = (dpi as f64 / 0.0254).round() as u32;
+        enc.set_pixel_dims(Some(png::PixelDimensions { xppu: ppm, yppu: ppm, unit: png::Unit::Meter }));
+    }
+    enc.set_compression(match p.png_compression {
+        PngCompression::Fast => png::Compression::Fast,
+        PngCompression::Default => png::Compression::Balanced,
+        PngCompression::Best => png::Compression::High,
+    });
+    enc.set_filter(match p.png_filter {
+        PngFilter::None => png::Filter::NoFilter,
+        PngFilter::Sub => png::Filter::Sub,
+        PngFilter::Up => png::Filter::Up,
+        PngFilter::Avg => png::Filter::Avg,
+        PngFilter::Paeth => png::Filter::Paeth,
+        PngFilter::Adaptive => png::Filter::Adaptive,
+        PngFilter::MinEntropy => png::Filter::MinEntropy,
+    });
+
+    let data = match palette {
+        Some((colors, indices)) => {
+            let depth = match colors.len() {
+                0..=2 => png::BitDepth::One,
+                3..=4 => png::BitDepth::Two,
+                5..=16 => png::BitDepth::Four,
+                _ => png::BitDepth::Eight,
+            };
+            enc.set_color(png::ColorType::Indexed);
+            enc.set_depth(depth);
+            enc.set_palette(colors.iter().flat_map(|c| [c[0], c[1], c[2]]).collect::<Vec<u8>>());
+            // Translucent entries are sorted first, so tRNS can stop at the last of them
+            let translucent = colors.iter().take_while(|c| c[3] != 255).count();
+            if translucent > 0 {
+                enc.set_trns(colors[..translucent].iter().map(|c| c[3]).collect::<Vec<u8>>());
+            }
+            pack_indices(&indices, w as usize, depth as u8)
+        }
+        // 16-bit samples are stored big-endian
+        None if is_high_depth(image) => {
+            let rgba = image.to_rgba16();
+            let opaque = rgba.pixels().all(|px| px[3] == u16::MAX);
+            let gray = rgba.pixels().all(|px| px[0] == px[1] && px[1] == px[2]);
+            let (color, channels) = png_layout(gray, opaque);
+            enc.set_color(color);
+            enc.set_depth(png::BitDepth::Sixteen);
+            rgba.pixels().flat_map(|px| channels.iter().flat_map(|&c| px[c].to_be_bytes())).collect()
         }
         None => {
-            let mut stdout = BufWriter::new(std::io::stdout());
-            write_colors(&mut stdout, &unique_colors)?;
+            let rgba = image.to_rgba8();
+            let opaque = rgba.pixels().all(|px| px[3] == 255);
+            let gray = rgba.pixels().all(|px| px[0] == px[1] && px[1] == px[2]);
+            let (color, channels) = png_layout(gray, opaque);
+            enc.set_color(color);
+            enc.set_depth(png::BitDepth::Eight);
+            rgba.pixels().flat_map(|px| channels.iter().map(|&c| px[c])).collect()
         }
-    }
+    };
 
+    let mut writer = enc.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
     Ok(())
 }
 
-// ENCODERS
-fn save_png(image: &image::DynamicImage, output: &Path) -> Result<()> {
-    use image::codecs::png::{CompressionType, FilterType, PngEncoder};
-    let f = File::create(output)?;
-    let enc = PngEncoder::new_with_quality(f, CompressionType::Default, FilterType::Adaptive);
-    let rgba = image.to_rgba8();
-    enc.write_image(&rgba, rgba.width(), rgba.height(), image::ExtendedColorType::Rgba8)?;
-    Ok(())
+// Color type and the RGBA channels it keeps
+fn png_layout(gray: bool, opaque: bool) -> (png::ColorType, &'static [usize]) {
+    match (gray, opaque) {
+        (true, true) => (png::ColorType::Grayscale, &[0]),
+        (true, false) => (png::ColorType::GrayscaleAlpha, &[0, 3]),
+        (false, true) => (png::ColorType::Rgb, &[0, 1, 2]),
+        (false, false) => (png::ColorType::Rgba, &[0, 1, 2, 3]),
+    }
 }
 
-fn save_jpeg(image: &image::DynamicImage, output: &Path, quality: u8, bg: (u8,u8,u8)) -> Result<()> {
-    use image::codecs::jpeg::JpegEncoder;
+fn save_jpeg(image: &image::DynamicImage, output: &Path, quality: u8, bg: (u8,u8,u8), dpi: Option<u16>, icc: Option<&[u8]>) -> Result<()> {
+    use image::codecs::jpeg::{JpegEncoder, PixelDensity, PixelDensityUnit};
     let f = File::create(output)?;
     let mut enc = JpegEncoder::new_with_quality(f, quality.clamp(1, 100));
+    if let Some(dpi) = dpi {
+        enc.set_pixel_density(PixelDensity { density: (dpi, dpi), unit: PixelDensityUnit::Inches });
+    }
+    if let Some(icc) = icc {
+        enc.set_icc_profile(icc.to_vec())?;
+    }
     let rgb = flatten_to_rgb8(image, bg);
     enc.encode(&rgb, rgb.width(), rgb.height(), image::ExtendedColorType::Rgb8)?;
     Ok(())
 }
 
-fn save_webp(image: &image::DynamicImage, output: &Path) -> Result<()> {
+fn save_webp(image: &image::DynamicImage, output: &Path, icc: Option<&[u8]>) -> Result<()> {
     use image::codecs::webp::WebPEncoder;
     let f = File::create(output)?;
     let rgba = image.to_rgba8();
-    let enc = WebPEncoder::new_lossless(f);
+    let mut enc = WebPEncoder::new_lossless(f);
+    if let Some(icc) = icc {
+        enc.set_icc_profile(icc.to_vec())?;
+    }
     enc.encode(&rgba, rgba.width(), rgba.height(), image::ExtendedColorType::Rgba8)?;
     Ok(())
 }
 
+fn save_avif(image: &image::DynamicImage, output: &Path, quality: u8, speed: u8) -> Result<()> {
+    use image::codecs::avif::AvifEncoder;
+    let f = BufWriter::new(File::create(output)?);
+    let rgba = image.to_rgba8();
+    let enc = AvifEncoder::new_with_speed_quality(f, speed.clamp(1, 10), quality.clamp(1, 100));
+    enc.write_image(&rgba, rgba.width(), rgba.height(), image::ExtendedColorType::Rgba8)?;
+    Ok(())
+}
+
+fn save_gif_animation(frames: Vec<image::Frame>, output: &Path) -> Result<()> {
+    use image::codecs::gif::{GifEncoder, Repeat};
+    let f = BufWriter::new(File::create(output)?);
+    let mut enc = GifEncoder::new(f);
+    enc.set_repeat(Repeat::Infinite)?;
+    enc.encode_frames(frames)?;
+    Ok(())
+}
+
 fn save_common(image: &image::DynamicImage, output: &Path, format: ImageFormat) -> Result<()> {
     match format {
         ImageFormat::Bmp => image.save_with_format(output, image::ImageFormat::Bmp)?,
@@ -188,17 +1151,616 @@ fn save_common(image: &image::DynamicImage, output: &Path, format: ImageFormat)
         ImageFormat::Tga => image.save_with_format(output, image::ImageFormat::Tga)?,
         ImageFormat::Dds => image.save_with_format(output, image::ImageFormat::Dds)?,
         ImageFormat::Pnm => image.save_with_format(output, image::ImageFormat::Pnm)?,
+        ImageFormat::Gif => image.save_with_format(output, image::ImageFormat::Gif)?,
         _ => panic!("unsupported image format"),
     }
     Ok(())
 }
 
+// Picks the encoder from the extension. JPEG has no alpha channel
+fn save_inferred(image: &image::DynamicImage, output: &Path) -> Result<()> {
+    stdio::write_atomic(output, |output| {
+        if matches!(image::ImageFormat::from_path(output), Ok(image::ImageFormat::Jpeg)) {
+            image.to_rgb8().save(output)?;
+        } else if is_high_depth(image) && !keeps_high_depth(output) {
+            with_depth(image.clone(), false).save(output)?;
+        } else {
+            image.save(output)?;
+        }
+        Ok(())
+    })
+}
+
+fn keeps_high_depth(output: &Path) -> bool {
+    matches!(image::ImageFormat::from_path(output), Ok(image::ImageFormat::Png | image::ImageFormat::Tiff))
+}
+
+// BATCH
+fn collect_images(root: &Path, b: &BatchArgs) -> Result<Vec<PathBuf>> {
+    if !b.recursive { bail!("'{}' is a directory. Use -r/--recursive.", root.display()); }
+    b.collect(root, is_image)
+}
+
+// Extensionless downloads are picked up by their content
+fn is_image(path: &Path) -> bool {
+    image::ImageFormat::from_path(path).is_ok() || detect_format(path).is_ok()
+}
+
+// Mirrors `input` under `out_root`, or returns it unchanged when writing next to the sources
+fn batch_output(root: &Path, input: &Path, out_root: Option<&Path>) -> PathBuf {
+    match out_root {
+        Some(out) => out.join(input.strip_prefix(root).unwrap_or(input)),
+        None => input.to_path_buf(),
+    }
+}
+
+// Resolves `-` for a single-image command. Stdin is spooled to a file first; a `-` output, also
+// the default for stdin, is written to a file named with `extension`, else with the input's own
+// format, and copied to stdout once `run` is done with it
+fn with_stdio(
+    input: &Path,
+    output: Option<&Path>,
+    extension: Option<&str>,
+    run: impl FnOnce(&Path, Option<PathBuf>) -> Result<()>,
+) -> Result<()> {
+    let input = stdio::input(input)?;
+    let output = output.map(Path::to_path_buf).or_else(|| input.is_stdin().then(|| PathBuf::from("-")));
+    match output {
+        Some(out) if stdio::is_dash(&out) => {
+            let extension = match extension {
+                Some(ext) => ext.to_string(),
+                None => detect_format(input.path())?.extensions_str()[0].to_string(),
+            };
+            let out = stdio::output(&out, &extension, true)?;
+            run(input.path(), Some(out.path().to_path_buf()))?;
+            out.finish()
+        }
+        output => run(input.path(), output),
+    }
+}
+
+// Whether to go on and write `output`, as the overwrite flags say. The input itself is never
+// replaced, it is still being read
+fn writable(input: &Path, output: &Path) -> Result<bool> {
+    if output == input {
+        bail!("output would overwrite the input, use --output");
+    }
+    if !stdio::check_output(output)? {
+        return Ok(false);
+    }
+    if let Some(parent) = output.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(true)
+}
+
 // HELPERS
+fn scaled_name(input: &Path, tw: u32, th: u32) -> String {
+    let stem = input.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_else(|| "output".into());
+    let ext = input.extension().map(|s| s.to_string_lossy().into_owned()).unwrap_or_else(|| "png".into());
+    format!("{}_{}x{}.{}", stem, tw, th, ext)
+}
+
+// PERCEPTUAL HASHING
+// 64-bit difference hash: brightness gradient between neighbours of a 9x8 thumbnail
+fn dhash(image: &image::DynamicImage) -> u64 {
+    let g = image.thumbnail_exact(9, 8).to_luma8();
+    let mut h = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            h = (h << 1) | (g.get_pixel(x, y)[0] < g.get_pixel(x + 1, y)[0]) as u64;
+        }
+    }
+    h
+}
+
+// 64-bit DCT hash: lowest 8x8 frequencies of a 32x32 thumbnail compared to their median
+fn phash(image: &image::DynamicImage) -> u64 {
+    use std::f64::consts::PI;
+    let g = image.thumbnail_exact(32, 32).to_luma8();
+    let cos: Vec<[f64; 32]> = (0..8)
+        .map(|u| std::array::from_fn(|x| ((2 * x + 1) as f64 * u as f64 * PI / 64.0).cos()))
+        .collect();
+
+    let mut rows = [[0f64; 8]; 32];
+    for (y, row) in rows.iter_mut().enumerate() {
+        for (u, r) in row.iter_mut().enumerate() {
+            *r = (0..32).map(|x| g.get_pixel(x as u32, y as u32)[0] as f64 * cos[u][x]).sum();
+        }
+    }
+    let mut coeffs = [0f64; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            coeffs[v * 8 + u] = (0..32).map(|y| rows[y][u] * cos[v][y]).sum();
+        }
+    }
+
+    // DC only carries average brightness and would skew the median
+    let mut ac = coeffs[1..].to_vec();
+    ac.sort_by(f64::total_cmp);
+    let median = ac[ac.len() / 2];
+    coeffs.iter().fold(0u64, |h, &c| (h << 1) | (c > median) as u64)
+}
+
+// Indices into `hashes`, one group per cluster within `max` bits of each other (transitively)
+fn duplicate_groups(hashes: &[(&PathBuf, u64)], max: u32) -> Vec<Vec<usize>> {
+    let mut parent: Vec<usize> = (0..hashes.len()).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    for i in 0..hashes.len() {
+        for j in i + 1..hashes.len() {
+            if (hashes[i].1 ^ hashes[j].1).count_ones() <= max {
+                let (ri, rj) = (root(&mut parent, i), root(&mut parent, j));
+                parent[rj] = ri;
+            }
+        }
+    }
+
+    let mut groups: std::collections::BTreeMap<usize, Vec<usize>> = Default::default();
+    for i in 0..hashes.len() {
+        let r = root(&mut parent, i);
+        groups.entry(r).or_default().push(i);
+    }
+    groups.into_values().filter(|g| g.len() > 1).collect()
+}
+
+fn rmse(a: &image::RgbaImage, b: &image::RgbaImage) -> f64 {
+    let sum: f64 = a.as_raw().iter().zip(b.as_raw())
+        .map(|(&x, &y)| (x as f64 - y as f64).powi(2))
+        .sum();
+    (sum / a.as_raw().len().max(1) as f64).sqrt()
+}
+
+// Mean SSIM over 8x8 luma windows with a stride of 4
+// Unchanged pixels are a faded gray copy of `a`, changed ones run from yellow (barely) to red
+// (fully) by their largest channel difference. Returns the image and the changed pixel count
+fn diff_heatmap(a: &image::RgbaImage, b: &image::RgbaImage, threshold: u8) -> (image::RgbImage, u64) {
+    let mut changed = 0;
+    let heatmap = image::RgbImage::from_fn(a.width(), a.height(), |x, y| {
+        let (pa, pb) = (a.get_pixel(x, y), b.get_pixel(x, y));
+        let diff = (0..4).map(|c| pa[c].abs_diff(pb[c])).max().unwrap_or(0);
+        if diff <= threshold {
+            let luma = (pa[0] as u32 * 299 + pa[1] as u32 * 587 + pa[2] as u32 * 114) / 1000;
+            let faded = (192 + luma / 4) as u8;
+            return image::Rgb([faded, faded, faded]);
+        }
+        changed += 1;
+        image::Rgb([255, 255 - diff, 0])
+    });
+    (heatmap, changed)
+}
+
+fn ssim(a: &image::GrayImage, b: &image::GrayImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let (w, h) = a.dimensions();
+    let win = 8.min(w).min(h);
+    let (mut total, mut count) = (0.0, 0u32);
+    for y in (0..=h - win).step_by(4) {
+        for x in (0..=w - win).step_by(4) {
+            let (mut sa, mut sb, mut saa, mut sbb, mut sab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for dy in 0..win {
+                for dx in 0..win {
+                    let pa = a.get_pixel(x + dx, y + dy)[0] as f64;
+                    let pb = b.get_pixel(x + dx, y + dy)[0] as f64;
+                    sa += pa; sb += pb;
+                    saa += pa * pa; sbb += pb * pb; sab += pa * pb;
+                }
+            }
+            let n = (win * win) as f64;
+            let (ma, mb) = (sa / n, sb / n);
+            let (va, vb, cov) = (saa / n - ma * ma, sbb / n - mb * mb, sab / n - ma * mb);
+            total += ((2.0 * ma * mb + C1) * (2.0 * cov + C2)) / ((ma * ma + mb * mb + C1) * (va + vb + C2));
+            count += 1;
+        }
+    }
+    total / count.max(1) as f64
+}
+
+// original + amount * (original - blurred) on the color channels, alpha is kept
+// Works in float so 16-bit sources keep their precision. `threshold` is on the 8-bit scale
+fn unsharp_mask(image: &image::DynamicImage, amount: f32, sigma: f32, threshold: u8) -> image::DynamicImage {
+    let mut out = image.to_rgba32f();
+    let blurred = image::imageops::blur(&out, sigma);
+    let threshold = threshold as f32 / 255.0;
+    for (px, b) in out.pixels_mut().zip(blurred.pixels()) {
+        for c in 0..3 {
+            let diff = px[c] - b[c];
+            if diff.abs() > threshold {
+                px[c] = (px[c] + amount * diff).clamp(0.0, 1.0);
+            }
+        }
+    }
+    let sharpened = image::DynamicImage::ImageRgba32F(out);
+    if is_high_depth(image) {
+        image::DynamicImage::ImageRgba16(sharpened.to_rgba16())
+    } else {
+        image::DynamicImage::ImageRgba8(sharpened.to_rgba8())
+    }
+}
+
+// Output is RGBA at the depth of the input
+fn icc_to_srgb(image: image::DynamicImage, icc: &[u8]) -> Result<image::DynamicImage> {
+    use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+    let source = ColorProfile::new_from_slice(icc).context("invalid ICC profile")?;
+    let srgb = ColorProfile::new_srgb();
+    let gray = match source.color_space {
+        DataColorSpace::Rgb => false,
+        DataColorSpace::Gray => true,
+        other => bail!("{:?} ICC profiles are not supported", other),
+    };
+    let layout = if gray { Layout::GrayAlpha } else { Layout::Rgba };
+    let (w, h) = image.dimensions();
+    let options = TransformOptions::default();
+
+    if is_high_depth(&image) {
+        let src = if gray { image.to_luma_alpha16().into_raw() } else { image.to_rgba16().into_raw() };
+        let mut dst = vec![0u16; w as usize * h as usize * 4];
+        source.create_transform_16bit(layout, &srgb, Layout::Rgba, options)?.transform(&src, &mut dst)?;
+        Ok(image::DynamicImage::ImageRgba16(image::ImageBuffer::from_raw(w, h, dst).context("ICC transform size")?))
+    } else {
+        let src = if gray { image.to_luma_alpha8().into_raw() } else { image.to_rgba8().into_raw() };
+        let mut dst = vec![0u8; w as usize * h as usize * 4];
+        source.create_transform_8bit(layout, &srgb, Layout::Rgba, options)?.transform(&src, &mut dst)?;
+        Ok(image::DynamicImage::ImageRgba8(image::ImageBuffer::from_raw(w, h, dst).context("ICC transform size")?))
+    }
+}
+
+fn is_high_depth(image: &image::DynamicImage) -> bool {
+    let color = image.color();
+    color.bytes_per_pixel() > color.channel_count()
+}
+
+// Converts to 8 or 16 bits per channel, keeping gray and alpha layouts
+fn with_depth(image: image::DynamicImage, sixteen: bool) -> image::DynamicImage {
+    use image::DynamicImage as D;
+    let color = image.color();
+    if color.bytes_per_pixel() == color.channel_count() * if sixteen { 2 } else { 1 } {
+        return image;
+    }
+    match (sixteen, color.has_color(), color.has_alpha()) {
+        (false, false, false) => D::ImageLuma8(image.to_luma8()),
+        (false, false, true) => D::ImageLumaA8(image.to_luma_alpha8()),
+        (false, true, false) => D::ImageRgb8(image.to_rgb8()),
+        (false, true, true) => D::ImageRgba8(image.to_rgba8()),
+        (true, false, false) => D::ImageLuma16(image.to_luma16()),
+        (true, false, true) => D::ImageLumaA16(image.to_luma_alpha16()),
+        (true, true, false) => D::ImageRgb16(image.to_rgb16()),
+        (true, true, true) => D::ImageRgba16(image.to_rgba16()),
+    }
+}
+
+// Brightness, contrast, gamma and invert folded into one table, applied in that order
+fn adjust_lut(a: &AdjustArgs) -> [u8; 256] {
+    let contrast = ((100.0 + a.contrast) / 100.0).powi(2);
+    let mut lut = [0u8; 256];
+    for (i, out) in lut.iter_mut().enumerate() {
+        let mut v = (i as f32 + a.brightness as f32).clamp(0.0, 255.0) / 255.0;
+        v = ((v - 0.5) * contrast + 0.5).clamp(0.0, 1.0);
+        v = v.powf(1.0 / a.gamma);
+        if a.invert { v = 1.0 - v }
+        *out = (v * 255.0).round() as u8;
+    }
+    lut
+}
+
+// NeuQuant palette of `colors` entries. Images that already fit are left exact
+fn quantize(image: &image::DynamicImage, colors: usize, dither: Dither) -> image::RgbaImage {
+    let mut rgba = image.to_rgba8();
+    let mut unique = std::collections::HashSet::new();
+    if rgba.pixels().all(|px| { unique.insert(px.0); unique.len() <= colors }) {
+        return rgba;
+    }
+
+    let nq = color_quant::NeuQuant::new(10, colors, rgba.as_raw());
+    let palette: Vec<[u8; 4]> = nq.color_map_rgba().chunks(4).map(|c| [c[0], c[1], c[2], c[3]]).collect();
+    match dither {
+        Dither::None => {
+            for px in rgba.pixels_mut() {
+                px.0 = palette[nq.index_of(&px.0)];
+            }
+        }
+        // Only color error is diffused, alpha noise would fray transparent edges
+        Dither::FloydSteinberg => {
+            let (w, h) = (rgba.width() as usize, rgba.height() as usize);
+            let mut work: Vec<[f32; 3]> = rgba.pixels().map(|px| [px[0] as f32, px[1] as f32, px[2] as f32]).collect();
+            for y in 0..h {
+                for x in 0..w {
+                    let i = y * w + x;
+                    let px = rgba.get_pixel_mut(x as u32, y as u32);
+                    let old = work[i].map(|v| v.round().clamp(0.0, 255.0) as u8);
+                    let new = palette[nq.index_of(&[old[0], old[1], old[2], px[3]])];
+                    let err: [f32; 3] = std::array::from_fn(|c| work[i][c] - new[c] as f32);
+                    px.0 = new;
+
+                    let mut spread = |nx: usize, ny: usize, weight: f32| {
+                        if nx < w && ny < h {
+                            for c in 0..3 { work[ny * w + nx][c] += err[c] * weight }
+                        }
+                    };
+                    spread(x + 1, y, 7.0 / 16.0);
+                    if x > 0 { spread(x - 1, y + 1, 3.0 / 16.0) }
+                    spread(x, y + 1, 5.0 / 16.0);
+                    spread(x + 1, y + 1, 1.0 / 16.0);
+                }
+            }
+        }
+    }
+    rgba
+}
+
+// Palette sorted with translucent colors first, plus one index per pixel. None past 256 colors
+fn build_palette(rgba: &image::RgbaImage) -> Option<(Vec<[u8; 4]>, Vec<u8>)> {
+    let mut colors: Vec<[u8; 4]> = Vec::new();
+    let mut seen = std::collections::HashSet::new();
+    for px in rgba.pixels() {
+        if seen.insert(px.0) {
+            if seen.len() > 256 { return None; }
+            colors.push(px.0);
+        }
+    }
+    colors.sort_by_key(|c| (c[3] == 255, *c));
+    let lookup: std::collections::HashMap<[u8; 4], u8> = colors.iter().enumerate().map(|(i, c)| (*c, i as u8)).collect();
+    let indices = rgba.pixels().map(|px| lookup[&px.0]).collect();
+    Some((colors, indices))
+}
+
+// Packs indices into rows of `depth` bits per pixel, each row padded to a whole byte
+fn pack_indices(indices: &[u8], width: usize, depth: u8) -> Vec<u8> {
+    if depth == 8 { return indices.to_vec(); }
+    let per_byte = 8 / depth as usize;
+    let mut out = Vec::with_capacity(indices.len() / per_byte + indices.len() / width.max(1) + 1);
+    for row in indices.chunks(width) {
+        for chunk in row.chunks(per_byte) {
+            let mut byte = 0u8;
+            for (i, &idx) in chunk.iter().enumerate() {
+                byte |= idx << (8 - depth as usize * (i + 1));
+            }
+            out.push(byte);
+        }
+    }
+    out
+}
+
+// Returns x, y, width, height
+fn crop_rect(w: u32, h: u32, a: &CropArgs) -> Result<(u32, u32, u32, u32)> {
+    if let Some(rect) = &a.rect {
+        let parts: Vec<&str> = rect.split(',').map(str::trim).collect();
+        let [x, y, cw, ch] = parts.as_slice() else { bail!("--rect expects x,y,w,h, got '{}'", rect) };
+        let (x, y) = (parse_length(x, w)?, parse_length(y, h)?);
+        let (cw, ch) = (parse_length(cw, w)?, parse_length(ch, h)?);
+        if cw == 0 || ch == 0 || x as u64 + cw as u64 > w as u64 || y as u64 + ch as u64 > h as u64 {
+            bail!("crop {}x{} at {},{} does not fit the {}x{} image", cw, ch, x, y, w, h);
+        }
+        return Ok((x, y, cw, ch));
+    }
+
+    let width = a.width.as_deref().map(|s| parse_length(s, w)).transpose()?;
+    let height = a.height.as_deref().map(|s| parse_length(s, h)).transpose()?;
+    let aspect = a.aspect.as_deref().map(parse_aspect).transpose()?;
+    let (cw, ch) = match (width, height, aspect) {
+        (Some(_), Some(_), Some(_)) => bail!("--aspect cannot be combined with both --width and --height"),
+        (Some(cw), Some(ch), None) => (cw, ch),
+        (Some(cw), None, None) => (cw, h),
+        (None, Some(ch), None) => (w, ch),
+        (Some(cw), None, Some(r)) => (cw, (cw as f64 / r).round() as u32),
+        (None, Some(ch), Some(r)) => ((ch as f64 * r).round() as u32, ch),
+        (None, None, Some(r)) if w as f64 / h as f64 > r => (((h as f64 * r).round() as u32).min(w), h),
+        (None, None, Some(r)) => (w, ((w as f64 / r).round() as u32).min(h)),
+        (None, None, None) => bail!("Specify --rect, --width/--height or --aspect"),
+    };
+    if cw == 0 || ch == 0 || cw > w || ch > h {
+        bail!("crop {}x{} does not fit the {}x{} image", cw, ch, w, h);
+    }
+
+    let (gx, gy) = gravity_factors(a.gravity);
+    Ok(((w - cw) * gx / 2, (h - ch) * gy / 2, cw, ch))
+}
+
+// Halves of the free space before the content on each axis: 0 = start, 1 = center, 2 = end
+fn gravity_factors(g: Gravity) -> (u32, u32) {
+    match g {
+        Gravity::Nw => (0, 0), Gravity::N => (1, 0), Gravity::Ne => (2, 0),
+        Gravity::W => (0, 1), Gravity::Center => (1, 1), Gravity::E => (2, 1),
+        Gravity::Sw => (0, 2), Gravity::S => (1, 2), Gravity::Se => (2, 2),
+    }
+}
+
+// "1080x720"
+fn parse_dimensions(s: &str) -> Result<(u32, u32)> {
+    let err = || anyhow::anyhow!("invalid size '{}', expected WxH", s);
+    let (w, h) = s.trim().split_once(['x', 'X']).ok_or_else(err)?;
+    let (w, h): (u32, u32) = (w.trim().parse().map_err(|_| err())?, h.trim().parse().map_err(|_| err())?);
+    if w == 0 || h == 0 { return Err(err()); }
+    Ok((w, h))
+}
+
+// Pixels, or a percentage of `total`
+fn parse_length(s: &str, total: u32) -> Result<u32> {
+    let t = s.trim();
+    if let Some(p) = t.strip_suffix('%') {
+        let p: f64 = p.trim().parse().map_err(|_| anyhow::anyhow!("invalid percentage '{}'", s))?;
+        if !(0.0..=100.0).contains(&p) { bail!("percentage '{}' must be between 0 and 100", s); }
+        return Ok((total as f64 * p / 100.0).round() as u32);
+    }
+    t.trim_end_matches("px").parse().map_err(|_| anyhow::anyhow!("invalid length '{}', expected pixels or a percentage", s))
+}
+
+// "16:9" as width / height
+fn parse_aspect(s: &str) -> Result<f64> {
+    let err = || anyhow::anyhow!("invalid aspect ratio '{}', expected W:H", s);
+    let (w, h) = s.split_once(':').ok_or_else(err)?;
+    let (w, h): (f64, f64) = (w.trim().parse().map_err(|_| err())?, h.trim().parse().map_err(|_| err())?);
+    if w <= 0.0 || h <= 0.0 { return Err(err()); }
+    Ok(w / h)
+}
+
+fn suffixed_name(input: &Path, suffix: &str) -> String {
+    let stem = input.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_else(|| "output".into());
+    let ext = input.extension().map(|s| s.to_string_lossy().into_owned()).unwrap_or_else(|| "png".into());
+    format!("{}_{}.{}", stem, suffix, ext)
+}
+
+// Rotations expressed as transposes and flips of the DCT block grid
+fn jpeg_transforms(rotate: Option<Rotation>, flip: Option<Flip>) -> Vec<jpeg::Transform> {
+    use jpeg::Transform::*;
+    let mut ops = match rotate {
+        Some(Rotation::R90) => vec![Transpose, FlipH],
+        Some(Rotation::R180) => vec![FlipH, FlipV],
+        Some(Rotation::R270) => vec![Transpose, FlipV],
+        None => vec![],
+    };
+    match flip {
+        Some(Flip::Horizontal) => ops.push(FlipH),
+        Some(Flip::Vertical) => ops.push(FlipV),
+        None => {}
+    }
+    ops
+}
+
+// Decodes and, unless disabled, rotates/flips to the EXIF orientation. Outputs carry no EXIF, so
+// skipping this leaves phone photos sideways
+pub(crate) fn open_image(path: &Path, auto_orient: bool) -> Result<image::DynamicImage> {
+    open_image_with_icc(path, auto_orient).map(|(image, _)| image)
+}
+
+// Also returns the embedded ICC profile, pixels are left in that color space
+fn open_image_with_icc(path: &Path, auto_orient: bool) -> Result<(image::DynamicImage, Option<Vec<u8>>)> {
+    use image::ImageDecoder;
+    let format = detect_format(path)?;
+    if let Ok(by_ext) = image::ImageFormat::from_path(path)
+        && by_ext != format
+    {
+        warn!("{} is {}, not {}", path.display(), format_name(format), format_name(by_ext));
+    }
+    let mut decoder = open_reader(path, format)?
+        .into_decoder()
+        .with_context(|| errors::at("open", path))?;
+    within_memory(&mut decoder, path)?;
+    let orientation = if auto_orient { decoder.orientation()? } else { image::metadata::Orientation::NoTransforms };
+    let icc = decoder.icc_profile()?;
+    let mut image = image::DynamicImage::from_decoder(decoder).with_context(|| errors::at("open", path))?;
+    image.apply_orientation(orientation);
+    Ok((image, icc))
+}
+
+/// The header of an image and the metadata blocks it carries, as stored: EXIF as a TIFF
+/// structure, XMP as XML, IPTC as Photoshop resources
+pub(crate) struct Embedded {
+    pub format: String,
+    pub dimensions: (u32, u32),
+    pub color: image::ExtendedColorType,
+    pub exif: Option<Vec<u8>>,
+    pub xmp: Option<Vec<u8>>,
+    pub icc: Option<Vec<u8>>,
+    pub iptc: Option<Vec<u8>>,
+}
+
+// Reads the header only, no pixels are decoded
+pub(crate) fn embedded_metadata(path: &Path) -> Result<Embedded> {
+    use image::ImageDecoder;
+    let format = detect_format(path)?;
+    let mut decoder = open_reader(path, format)?
+        .into_decoder()
+        .with_context(|| errors::at("open", path))?;
+    let mut exif = decoder.exif_metadata()?;
+    // TIFF files are themselves the structure EXIF borrows, with the tags in their first IFD
+    if exif.is_none() && format == image::ImageFormat::Tiff {
+        exif = Some(fs::read(path).with_context(|| errors::at("read", path))?);
+    }
+    Ok(Embedded {
+        format: format_name(format),
+        dimensions: decoder.dimensions(),
+        color: decoder.original_color_type(),
+        exif,
+        xmp: decoder.xmp_metadata()?,
+        icc: decoder.icc_profile()?,
+        iptc: decoder.iptc_metadata()?,
+    })
+}
+
+// Decodes every frame of GIF and animated WEBP inputs, other formats yield None
+fn load_frames(path: &Path) -> Result<Option<Vec<image::Frame>>> {
+    use image::AnimationDecoder;
+    use image::codecs::{gif::GifDecoder, webp::WebPDecoder};
+    use std::io::BufReader;
+
+    let frames = match detect_format(path)? {
+        image::ImageFormat::Gif => {
+            let decoder = GifDecoder::new(BufReader::new(File::open(path)?))?;
+            collect_frames(decoder.into_frames(), path)?
+        }
+        image::ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(BufReader::new(File::open(path)?))?;
+            if !decoder.has_animation() { return Ok(None); }
+            collect_frames(decoder.into_frames(), path)?
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(frames))
+}
+
+// Frames are only counted as they are decoded, so --max-memory is checked frame by frame
+fn collect_frames(frames: image::Frames, path: &Path) -> Result<Vec<image::Frame>> {
+    let mut collected = Vec::new();
+    let mut bytes = 0u64;
+    for frame in frames {
+        let frame = frame?;
+        bytes += frame.buffer().as_raw().len() as u64;
+        memory::reserve(bytes, || format!("decoding the frames of {}", path.display()))?;
+        collected.push(frame);
+    }
+    Ok(collected)
+}
+
+// Under --max-memory: refuses an image whose pixels, decoded and then processed into a second
+// buffer, are above it, and holds the decoder's own allocations to it
+fn within_memory(decoder: &mut impl image::ImageDecoder, path: &Path) -> Result<()> {
+    let Some(limit) = memory::limit() else { return Ok(()) };
+    memory::reserve(decoder.total_bytes().saturating_mul(2), || format!("decoding {}", path.display()))?;
+    let mut limits = image::Limits::default();
+    limits.max_alloc = Some(limit);
+    decoder.set_limits(limits)?;
+    Ok(())
+}
+
+// Format from the magic bytes. TGA has no signature, so the extension is the fallback
+fn detect_format(path: &Path) -> Result<image::ImageFormat> {
+    use std::io::Read;
+    let mut head = Vec::with_capacity(64);
+    File::open(path)
+        .with_context(|| errors::at("open", path))?
+        .take(64)
+        .read_to_end(&mut head)
+        .with_context(|| errors::at("read", path))?;
+    image::guess_format(&head)
+        .or_else(|_| image::ImageFormat::from_path(path))
+        .with_context(|| format!("{} is not a supported image", path.display()))
+}
+
+fn open_reader(path: &Path, format: image::ImageFormat) -> Result<image::ImageReader<std::io::BufReader<File>>> {
+    let file = File::open(path).with_context(|| errors::at("open", path))?;
+    Ok(image::ImageReader::with_format(std::io::BufReader::new(file), format))
+}
+
+fn format_name(format: image::ImageFormat) -> String {
+    format!("{:?}", format).to_uppercase()
+}
+
 fn ext_for(format: ImageFormat) -> &'static str {
     match format {
         ImageFormat::Png => "png",
         ImageFormat::Jpeg => "jpg",
         ImageFormat::Webp => "webp",
+        ImageFormat::Avif => "avif",
+        ImageFormat::Gif => "gif",
         ImageFormat::Bmp => "bmp",
         ImageFormat::Ico => "ico",
         ImageFormat::Tiff => "tiff",
@@ -211,7 +1773,8 @@ fn ext_for(format: ImageFormat) -> &'static str {
 fn parse_hex_rgb(s: &str) -> Result<(u8,u8,u8)> {
     let t = s.trim().trim_start_matches('#');
     let err = || anyhow::anyhow!("invalid hex color '{}'", s);
-    if t.len() == 6 {
+    // Byte offsets below, which only fall on character boundaries in ASCII
+    if t.len() == 6 && t.is_ascii() {
         let r = u8::from_str_radix(&t[0..2], 16).map_err(|_| err())?;
         let g = u8::from_str_radix(&t[2..4], 16).map_err(|_| err())?;
         let b = u8::from_str_radix(&t[4..6], 16).map_err(|_| err())?;
@@ -221,6 +1784,22 @@ fn parse_hex_rgb(s: &str) -> Result<(u8,u8,u8)> {
     }
 }
 
+// RRGGBB, RRGGBBAA or "transparent"
+pub(crate) fn parse_hex_rgba(s: &str) -> Result<[u8; 4]> {
+    if s.eq_ignore_ascii_case("transparent") { return Ok([0, 0, 0, 0]); }
+    let t = s.trim().trim_start_matches('#');
+    if !t.is_ascii() {
+        bail!("invalid hex color '{}'", s);
+    }
+    if t.len() == 8 {
+        let (r, g, b) = parse_hex_rgb(&t[..6])?;
+        let a = u8::from_str_radix(&t[6..8], 16).map_err(|_| anyhow::anyhow!("invalid hex color '{}'", s))?;
+        return Ok([r, g, b, a]);
+    }
+    let (r, g, b) = parse_hex_rgb(t)?;
+    Ok([r, g, b, 255])
+}
+
 fn flatten_to_rgb8(image: &image::DynamicImage, bg: (u8,u8,u8)) -> image::ImageBuffer<image::Rgb<u8>, Vec<u8>> {
     use image::{GenericImageView, Rgba};
     let (w, h) = image.dimensions();
@@ -239,7 +1818,7 @@ fn flatten_to_rgb8(image: &image::DynamicImage, bg: (u8,u8,u8)) -> image::ImageB
     output
 }
 
-fn filter_to_type(f: Filter) -> image::imageops::FilterType {
+pub(crate) fn filter_to_type(f: Filter) -> image::imageops::FilterType {
     use image::imageops::FilterType;
     match f {
         Filter::Nearest => FilterType::Nearest,
@@ -257,12 +1836,11 @@ fn compute_target_size(
     if percent.is_none() && width.is_none() && height.is_none() {
         bail!("provide --percent or --width/--height");
     }
-    if let Some(p) = percent {
-        if width.is_none() && height.is_none() {
-            let s = (p as f32) / 100.0;
-            return Ok(((w as f32 * s).round().max(1.0) as u32,
-                       (h as f32 * s).round().max(1.0) as u32));
-        }
+    if let Some(p) = percent
+        && width.is_none() && height.is_none() {
+        let s = (p as f32) / 100.0;
+        return Ok(((w as f32 * s).round().max(1.0) as u32,
+                   (h as f32 * s).round().max(1.0) as u32));
     }
     match (width, height) {
         (Some(tw), Some(th)) => Ok((tw, th)),
@@ -278,6 +1856,76 @@ fn compute_target_size(
     }
 }
 
+// Largest size with the source aspect ratio inside tw x th
+fn fit_size(w: u32, h: u32, tw: u32, th: u32) -> (u32, u32) {
+    let scale = (tw as f32 / w as f32).min(th as f32 / h as f32);
+    ((w as f32 * scale).round().max(1.0) as u32, (h as f32 * scale).round().max(1.0) as u32)
+}
+
+// Where --mode fill crops. `None` picks the region with the most detail
+fn fill_anchor(mode: ResizeMode, gravity: Gravity, smart: bool) -> Result<Option<Gravity>> {
+    if !matches!(mode, ResizeMode::Fill) && (smart || !matches!(gravity, Gravity::Center)) {
+        bail!("--gravity and --smart only apply to --mode fill");
+    }
+    Ok(if smart { None } else { Some(gravity) })
+}
+
+// Keeps the pixel type of `image`, so 16-bit sources stay 16-bit
+fn resize_to(image: &image::DynamicImage, tw: u32, th: u32, mode: ResizeMode, filter: Filter, anchor: Option<Gravity>) -> image::DynamicImage {
+    let (w, h) = image.dimensions();
+    let f = filter_to_type(filter);
+    match mode {
+        ResizeMode::Exact => image.resize_exact(tw, th, f),
+        ResizeMode::Fit => {
+            let (fw, fh) = fit_size(w, h, tw, th);
+            image.resize_exact(fw, fh, f)
+        }
+        ResizeMode::Fill => {
+            // scale to cover and then crop the overflow
+            let (cw, ch) = cover_size(w, h, tw, th);
+            let tmp = image.resize_exact(cw, ch, f);
+            let (x, y) = match anchor {
+                Some(g) => {
+                    let (gx, gy) = gravity_factors(g);
+                    (cw.saturating_sub(tw) * gx / 2, ch.saturating_sub(th) * gy / 2)
+                }
+                None => detail_offset(&tmp, tw, th),
+            };
+            tmp.crop_imm(x, y, tw, th)
+        }
+    }
+}
+
+// Slides a tw x th window along the overflowing axis and returns the offset holding the most
+// edge energy (sum of luma gradients). Ties go to the most central window
+fn detail_offset(image: &image::DynamicImage, tw: u32, th: u32) -> (u32, u32) {
+    let luma = image.to_luma8();
+    let (w, h) = luma.dimensions();
+    let along_x = w > tw;
+    let (len, window) = if along_x { (w, tw) } else { (h, th) };
+    if len <= window { return (0, 0); }
+
+    let mut energy = vec![0u64; len as usize];
+    for (x, y, px) in luma.enumerate_pixels() {
+        let v = px[0] as i32;
+        let dx = if x + 1 < w { (luma.get_pixel(x + 1, y)[0] as i32 - v).unsigned_abs() } else { 0 };
+        let dy = if y + 1 < h { (luma.get_pixel(x, y + 1)[0] as i32 - v).unsigned_abs() } else { 0 };
+        energy[if along_x { x } else { y } as usize] += (dx + dy) as u64;
+    }
+
+    let window = window as usize;
+    let center = (len as usize - window) / 2;
+    let mut sum: u64 = energy[..window].iter().sum();
+    let (mut best, mut best_sum) = (0usize, sum);
+    for start in 1..=energy.len() - window {
+        sum = sum + energy[start + window - 1] - energy[start - 1];
+        if sum > best_sum || (sum == best_sum && start.abs_diff(center) < best.abs_diff(center)) {
+            (best, best_sum) = (start, sum);
+        }
+    }
+    if along_x { (best as u32, 0) } else { (0, best as u32) }
+}
+
 fn cover_size(w: u32, h: u32, tw: u32, th: u32) -> (u32, u32) {
     let sr = w as f32 / h as f32;
     let tr = tw as f32 / th as f32;
@@ -0,0 +1,358 @@
+// `run`: a YAML job file of toolkit commands, each with its inputs, options and the jobs it
+// needs, run in dependency order with independent jobs side by side, then one report of how each
+// went. Every job is a child process of this executable, so its flags and global state are its
+// own, and its output is held back until it ends so parallel jobs do not interleave
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use tracing::info;
+use tracing::level_filters::LevelFilter;
+use crate::{errors, memory, preserve, progress, sidecar, stdio};
+
+#[derive(Args)]
+#[command[name = "run", about = "Run the toolkit commands of a job file in dependency order, independent ones in parallel"]]
+pub struct RunArgs {
+    /// YAML file with `jobs`, each a `name`, `command`, `inputs`, `options` and `needs`
+    file: PathBuf,
+    /// Run only these jobs and the jobs they need (repeatable)
+    #[arg(long)]
+    only: Vec<String>,
+    /// Jobs run at once (0 or unset = the file's `threads`, else all cores)
+    #[arg(short = 't', long)]
+    threads: Option<u32>,
+    /// Start no further jobs once one has failed
+    #[arg(long)]
+    fail_fast: bool,
+    /// Print the command line of every job in the order they would start, without running them
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    /// Also write the report as JSON to this file (`-` for stdout)
+    #[arg(long)]
+    report: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobFile {
+    threads: Option<u32>,
+    jobs: Vec<Job>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Job {
+    name: String,
+    /// A subcommand, e.g. `compress` or `image-convert`
+    command: String,
+    #[serde(default)]
+    inputs: OneOrMany,
+    /// Flags by long name, or short name for one letter: `true` for a switch, a list to repeat it
+    #[serde(default)]
+    options: BTreeMap<String, Value>,
+    #[serde(default)]
+    needs: OneOrMany,
+}
+
+// `inputs: photos`, or a list
+#[derive(Deserialize, Default)]
+#[serde(untagged)]
+enum OneOrMany {
+    #[default]
+    None,
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn to_vec(&self) -> Vec<String> {
+        match self {
+            OneOrMany::None => Vec::new(),
+            OneOrMany::One(one) => vec![one.clone()],
+            OneOrMany::Many(many) => many.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Ok,
+    Failed,
+    Skipped,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Failed => "failed",
+            Status::Skipped => "skipped",
+        }
+    }
+}
+
+// How a job ended: the error is the last line it logged, or why it never started
+struct Outcome {
+    status: Status,
+    time: Duration,
+    error: Option<String>,
+}
+
+pub fn run(a: RunArgs) -> Result<()> {
+    let text = std::fs::read_to_string(&a.file).with_context(|| errors::at("read", &a.file))?;
+    let file: JobFile = serde_norway::from_str(&text).with_context(|| format!("parse {}", a.file.display()))?;
+    let jobs = file.jobs;
+    let order = plan(&jobs, &a.only)?;
+    let lines = jobs.iter().map(command_line).collect::<Result<Vec<_>>>()?;
+
+    if a.dry_run {
+        for &i in &order {
+            let words: Vec<String> = lines[i].iter().map(|w| if w.is_empty() || w.contains(char::is_whitespace) { format!("{:?}", w) } else { w.clone() }).collect();
+            println!("{}: {}", jobs[i].name, words.join(" "));
+        }
+        return Ok(());
+    }
+    if let Some(report) = &a.report
+        && !stdio::check_output_or(report, stdio::Clobber::Refuse)?
+    {
+        return Ok(());
+    }
+
+    // Paths in the file are relative to it, wherever `run` is started from
+    let dir = a.file.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new(".")).to_path_buf();
+    let threads = match a.threads.filter(|&n| n > 0).or(file.threads.filter(|&n| n > 0)) {
+        Some(n) => n as usize,
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let exe = std::env::current_exe().context("find the toolkit executable")?;
+    let globals = global_flags();
+
+    let mut outcomes: Vec<Option<Outcome>> = jobs.iter().map(|_| None).collect();
+    let mut started = vec![false; jobs.len()];
+    let index: HashMap<&str, usize> = jobs.iter().enumerate().map(|(i, j)| (j.name.as_str(), i)).collect();
+    let (tx, rx) = mpsc::channel::<(usize, std::io::Result<Output>, Duration)>();
+    let mut running = 0;
+    let mut failed = false;
+    loop {
+        // In dependency order, so a skip reaches the jobs after it in the same pass
+        for &i in &order {
+            if started[i] {
+                continue;
+            }
+            let needs: Vec<usize> = jobs[i].needs.to_vec().iter().map(|n| index[n.as_str()]).collect();
+            if let Some(&dep) = needs.iter().find(|&&d| outcomes[d].as_ref().is_some_and(|o| o.status != Status::Ok)) {
+                started[i] = true;
+                let why = if outcomes[dep].as_ref().is_some_and(|o| o.status == Status::Failed) { "failed" } else { "was skipped" };
+                outcomes[i] = Some(Outcome { status: Status::Skipped, time: Duration::ZERO, error: Some(format!("needs {}, which {}", jobs[dep].name, why)) });
+                continue;
+            }
+            if running == threads || (failed && a.fail_fast) || !needs.iter().all(|&d| outcomes[d].is_some()) {
+                continue;
+            }
+            started[i] = true;
+            running += 1;
+            info!("Starting {}", jobs[i].name);
+            let mut command = Command::new(&exe);
+            command.args(&globals).args(&lines[i]).current_dir(&dir).stdin(Stdio::null());
+            let tx = tx.clone();
+            std::thread::spawn(move || {
+                let start = Instant::now();
+                let output = command.output();
+                let _ = tx.send((i, output, start.elapsed()));
+            });
+        }
+        if running == 0 {
+            break;
+        }
+        let (i, output, time) = rx.recv().context("job runner stopped")?;
+        running -= 1;
+        let outcome = match output {
+            Ok(output) => {
+                std::io::stdout().lock().write_all(&output.stdout).context("write stdout")?;
+                let log = String::from_utf8_lossy(&output.stderr);
+                let mut writer = progress::log_writer();
+                for line in log.lines() {
+                    let _ = writeln!(writer, "{}: {}", jobs[i].name, line);
+                }
+                let error = (!output.status.success()).then(|| failure(&log, output.status));
+                Outcome { status: if error.is_some() { Status::Failed } else { Status::Ok }, time, error }
+            }
+            Err(e) => Outcome { status: Status::Failed, time, error: Some(format!("start {}: {}", exe.display(), e)) },
+        };
+        failed |= outcome.status == Status::Failed;
+        outcomes[i] = Some(outcome);
+    }
+
+    // Left over only when --fail-fast stopped the run
+    let outcomes: Vec<Outcome> = outcomes
+        .into_iter()
+        .map(|o| o.unwrap_or(Outcome { status: Status::Skipped, time: Duration::ZERO, error: Some("not started after a failure".to_string()) }))
+        .collect();
+    print_report(&jobs, &order, &outcomes);
+    if let Some(path) = &a.report {
+        let report: Vec<Value> = order
+            .iter()
+            .map(|&i| {
+                json!({
+                    "name": jobs[i].name,
+                    "command": lines[i],
+                    "status": outcomes[i].status.name(),
+                    "seconds": (outcomes[i].time.as_secs_f64() * 1000.0).round() / 1000.0,
+                    "error": outcomes[i].error,
+                })
+            })
+            .collect();
+        let text = serde_json::to_string_pretty(&json!({ "file": a.file.display().to_string(), "jobs": report }))? + "\n";
+        stdio::write(path, text.as_bytes(), false)?;
+    }
+
+    let failed = order.iter().filter(|&&i| outcomes[i].status == Status::Failed).count();
+    if failed > 0 {
+        bail!("{} of {} jobs failed", failed, order.len());
+    }
+    Ok(())
+}
+
+// The jobs to run, dependencies before the jobs that need them and otherwise in file order. Names
+// must be unique and every `needs` must name a job, without cycles
+fn plan(jobs: &[Job], only: &[String]) -> Result<Vec<usize>> {
+    let mut index = HashMap::new();
+    for (i, job) in jobs.iter().enumerate() {
+        if job.name.is_empty() {
+            bail!("job {} has an empty name", i + 1);
+        }
+        if index.insert(job.name.as_str(), i).is_some() {
+            bail!("two jobs are named {}", job.name);
+        }
+    }
+    for job in jobs {
+        for need in job.needs.to_vec() {
+            if !index.contains_key(need.as_str()) {
+                bail!("job {} needs {}, which is not in the file", job.name, need);
+            }
+        }
+    }
+
+    // Depth-first from each job in turn; a job met again while still on the path closes a cycle
+    fn visit(i: usize, jobs: &[Job], index: &HashMap<&str, usize>, path: &mut Vec<usize>, done: &mut HashSet<usize>, order: &mut Vec<usize>) -> Result<()> {
+        if done.contains(&i) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|&p| p == i) {
+            let names: Vec<&str> = path[start..].iter().chain([&i]).map(|&p| jobs[p].name.as_str()).collect();
+            bail!("jobs need each other in a cycle: {}", names.join(" -> "));
+        }
+        path.push(i);
+        for need in jobs[i].needs.to_vec() {
+            visit(index[need.as_str()], jobs, index, path, done, order)?;
+        }
+        path.pop();
+        done.insert(i);
+        order.push(i);
+        Ok(())
+    }
+
+    let roots: Vec<usize> = if only.is_empty() {
+        (0..jobs.len()).collect()
+    } else {
+        only.iter().map(|name| index.get(name.as_str()).copied().with_context(|| format!("no job named {}", name))).collect::<Result<_>>()?
+    };
+    let mut order = Vec::new();
+    let mut done = HashSet::new();
+    for i in roots {
+        visit(i, jobs, &index, &mut Vec::new(), &mut done, &mut order)?;
+    }
+    Ok(order)
+}
+
+// The subcommand, its inputs, then its options as flags. Values go after `=` so ones starting
+// with `-` are not taken for flags
+fn command_line(job: &Job) -> Result<Vec<String>> {
+    if job.command.is_empty() || job.command.starts_with('-') {
+        bail!("job {} needs a command, e.g. `command: compress`", job.name);
+    }
+    let mut words = vec![job.command.clone()];
+    words.extend(job.inputs.to_vec());
+    for (key, value) in &job.options {
+        let flag = if key.chars().count() == 1 { format!("-{}", key) } else { format!("--{}", key.replace('_', "-")) };
+        let values = match value {
+            Value::Array(values) => values.clone(),
+            value => vec![value.clone()],
+        };
+        for value in values {
+            match value {
+                Value::Bool(true) => words.push(flag.clone()),
+                Value::Bool(false) | Value::Null => {}
+                Value::String(s) => words.push(format!("{}={}", flag, s)),
+                Value::Number(n) => words.push(format!("{}={}", flag, n)),
+                _ => bail!("option {} of job {} must be a string, number, boolean or a list of them", key, job.name),
+            }
+        }
+    }
+    Ok(words)
+}
+
+// The error a failed job ended with, causes included, from the `Error:` block that closes its log
+fn failure(log: &str, status: std::process::ExitStatus) -> String {
+    let lines: Vec<&str> = log.lines().collect();
+    let Some(at) = lines.iter().rposition(|l| l.starts_with("Error: ")) else {
+        // Arguments clap rejected, followed by usage
+        if let Some(line) = lines.iter().find_map(|l| l.strip_prefix("error: ")) {
+            return line.to_string();
+        }
+        return lines.iter().rev().find(|l| !l.trim().is_empty()).map_or_else(|| status.to_string(), |l| l.to_string());
+    };
+    let causes = lines[at + 1..].iter().map(|l| l.trim()).filter(|l| !l.is_empty() && *l != "Caused by:");
+    // Several causes are numbered `0: `, `1: `...
+    let causes = causes.map(|l| l.split_once(": ").filter(|(n, _)| n.parse::<u32>().is_ok()).map_or(l, |(_, cause)| cause));
+    std::iter::once(&lines[at]["Error: ".len()..]).chain(causes).collect::<Vec<_>>().join(": ")
+}
+
+// What this run was given that every job should share: how existing outputs, memory, preserved
+// attributes and logging are treated. Progress bars would draw over each other, so jobs have none
+fn global_flags() -> Vec<String> {
+    let mut flags = vec!["--no-progress".to_string()];
+    match stdio::clobber() {
+        Some(stdio::Clobber::Replace) => flags.push("--force".into()),
+        Some(stdio::Clobber::Refuse) => flags.push("--no-clobber".into()),
+        Some(stdio::Clobber::Skip) => flags.push("--skip-existing".into()),
+        None => {}
+    }
+    match LevelFilter::current() {
+        level if level < LevelFilter::INFO => flags.push("--quiet".into()),
+        LevelFilter::DEBUG => flags.push("-v".into()),
+        LevelFilter::TRACE => flags.push("-vv".into()),
+        _ => {}
+    }
+    if let Some(limit) = memory::limit() {
+        flags.push(format!("--max-memory={}", limit));
+    }
+    if let Some(attributes) = preserve::flag_value() {
+        flags.push(format!("--preserve={}", attributes));
+    }
+    if sidecar::enabled() {
+        flags.push("--sidecar".into());
+    }
+    flags
+}
+
+// One line per job in the order they were planned, on stderr with the other status lines
+fn print_report(jobs: &[Job], order: &[usize], outcomes: &[Outcome]) {
+    let width = order.iter().map(|&i| jobs[i].name.len()).max().unwrap_or(0).max(3);
+    info!("{:<width$}  {:<7}  {:>8}", "Job", "Status", "Time");
+    for &i in order {
+        let o = &outcomes[i];
+        let time = if o.status == Status::Skipped { "-".to_string() } else { format!("{:.1}s", o.time.as_secs_f64()) };
+        let line = format!("{:<width$}  {:<7}  {:>8}", jobs[i].name, o.status.name(), time);
+        match &o.error {
+            Some(error) => info!("{}  {}", line, error),
+            None => info!("{}", line),
+        }
+    }
+}
@@ -0,0 +1,636 @@
+// Baseline JPEG at the coefficient level. Quantized DCT blocks are read and written without
+// going through pixels, so block-aligned edits (rotation, flips) lose nothing
+use anyhow::{bail, ensure, Context, Result};
+
+#[rustfmt::skip]
+const ZIGZAG: [usize; 64] = [
+     0,  1,  8, 16,  9,  2,  3, 10,
+    17, 24, 32, 25, 18, 11,  4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13,  6,  7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+// Annex K.3 tables, complete for every baseline symbol
+const LUMA_DC_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const CHROMA_DC_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+const LUMA_AC_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7D];
+const CHROMA_AC_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+
+const LUMA_AC_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52, 0xD1, 0xF0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7,
+    0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5,
+    0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2,
+    0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+
+const CHROMA_AC_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33, 0x52, 0xF0,
+    0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18, 0x19, 0x1A, 0x26,
+    0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5,
+    0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3,
+    0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA,
+    0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+
+pub(crate) struct Component {
+    pub id: u8,
+    pub h: u8,
+    pub v: u8,
+    pub tq: u8,
+    pub blocks_w: usize,
+    pub blocks_h: usize,
+    // Quantized coefficients in natural (row-major) order, MCU padding included
+    pub blocks: Vec<[i16; 64]>,
+}
+
+pub(crate) struct Jpeg {
+    pub width: u16,
+    pub height: u16,
+    // Natural order
+    pub qtables: [Option<[u16; 64]>; 4],
+    pub components: Vec<Component>,
+    // APPn and COM segments, copied through unchanged
+    pub segments: Vec<(u8, Vec<u8>)>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Transform { FlipH, FlipV, Transpose }
+
+pub(crate) fn read(data: &[u8]) -> Result<Jpeg> {
+    ensure!(data.starts_with(&[0xFF, 0xD8]), "not a JPEG file");
+    let mut jpeg: Option<Jpeg> = None;
+    let mut qtables = [None; 4];
+    let mut segments = Vec::new();
+    let mut dc_tables: [Option<Huffman>; 4] = Default::default();
+    let mut ac_tables: [Option<Huffman>; 4] = Default::default();
+    let mut restart_interval = 0;
+    let mut pos = 2;
+
+    loop {
+        ensure!(pos + 2 <= data.len(), "truncated JPEG");
+        ensure!(data[pos] == 0xFF, "corrupt JPEG: expected a marker at byte {}", pos);
+        let marker = data[pos + 1];
+        pos += 2;
+        match marker {
+            0xFF => { pos -= 1; continue }
+            0xD9 => break,
+            0x01 | 0xD0..=0xD7 => continue,
+            _ => {}
+        }
+        ensure!(pos + 2 <= data.len(), "truncated JPEG");
+        let len = u16::from_be_bytes([data[pos], data[pos + 1]]) as usize;
+        ensure!(len >= 2 && pos + len <= data.len(), "truncated JPEG segment");
+        let seg = &data[pos + 2..pos + len];
+        pos += len;
+
+        match marker {
+            0xC0 | 0xC1 => jpeg = Some(parse_frame(seg)?),
+            0xC2 | 0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF =>
+                bail!("only baseline JPEGs are supported, file is SOF{}", marker - 0xC0),
+            0xC4 => parse_huffman(seg, &mut dc_tables, &mut ac_tables)?,
+            0xDB => parse_quant(seg, &mut qtables)?,
+            0xDD => {
+                ensure!(seg.len() >= 2, "corrupt DRI segment");
+                restart_interval = u16::from_be_bytes([seg[0], seg[1]]) as usize;
+            }
+            0xDA => {
+                let frame = jpeg.as_mut().context("scan before frame header")?;
+                pos = decode_scan(data, pos, seg, frame, &dc_tables, &ac_tables, restart_interval)?;
+            }
+            0xE0..=0xEF | 0xFE => segments.push((marker, seg.to_vec())),
+            _ => {}
+        }
+    }
+
+    let mut jpeg = jpeg.context("JPEG has no frame header")?;
+    for c in &jpeg.components {
+        ensure!(qtables[c.tq as usize].is_some(), "missing quantization table {}", c.tq);
+    }
+    jpeg.qtables = qtables;
+    jpeg.segments = segments;
+    Ok(jpeg)
+}
+
+pub(crate) fn write(jpeg: &Jpeg) -> Result<Vec<u8>> {
+    let mut out = vec![0xFF, 0xD8];
+    for (marker, seg) in &jpeg.segments {
+        put_segment(&mut out, *marker, seg);
+    }
+
+    let mut extended = false;
+    for (i, table) in jpeg.qtables.iter().enumerate() {
+        let Some(table) = table else { continue };
+        let wide = table.iter().any(|&q| q > 255);
+        extended |= wide;
+        let mut seg = vec![((wide as u8) << 4) | i as u8];
+        for &z in &ZIGZAG {
+            if wide { seg.extend_from_slice(&table[z].to_be_bytes()) } else { seg.push(table[z] as u8) }
+        }
+        put_segment(&mut out, 0xDB, &seg);
+    }
+
+    let mut sof = vec![8];
+    sof.extend_from_slice(&jpeg.height.to_be_bytes());
+    sof.extend_from_slice(&jpeg.width.to_be_bytes());
+    sof.push(jpeg.components.len() as u8);
+    for c in &jpeg.components {
+        sof.extend_from_slice(&[c.id, (c.h << 4) | c.v, c.tq]);
+    }
+    // 16-bit quantization tables need the extended sequential frame type
+    put_segment(&mut out, if extended { 0xC1 } else { 0xC0 }, &sof);
+
+    let mut dht = Vec::new();
+    for (class, id, bits, values) in [
+        (0u8, 0u8, &LUMA_DC_BITS, &DC_VALUES[..]),
+        (1, 0, &LUMA_AC_BITS, &LUMA_AC_VALUES[..]),
+        (0, 1, &CHROMA_DC_BITS, &DC_VALUES[..]),
+        (1, 1, &CHROMA_AC_BITS, &CHROMA_AC_VALUES[..]),
+    ] {
+        dht.push((class << 4) | id);
+        dht.extend_from_slice(bits);
+        dht.extend_from_slice(values);
+    }
+    put_segment(&mut out, 0xC4, &dht);
+
+    let luma = (Encoder::new(&LUMA_DC_BITS, &DC_VALUES), Encoder::new(&LUMA_AC_BITS, &LUMA_AC_VALUES));
+    let chroma = (Encoder::new(&CHROMA_DC_BITS, &DC_VALUES), Encoder::new(&CHROMA_AC_BITS, &CHROMA_AC_VALUES));
+    let tables = |i: usize| if i == 0 { &luma } else { &chroma };
+
+    let mut sos = vec![jpeg.components.len() as u8];
+    for (i, c) in jpeg.components.iter().enumerate() {
+        let t = if i == 0 { 0x00 } else { 0x11 };
+        sos.extend_from_slice(&[c.id, t]);
+    }
+    sos.extend_from_slice(&[0, 63, 0]);
+    put_segment(&mut out, 0xDA, &sos);
+
+    let mut w = BitWriter { out, acc: 0, bits: 0 };
+    let mut preds = vec![0i32; jpeg.components.len()];
+    if let [c] = jpeg.components.as_slice() {
+        let (bw, bh) = scan_size(jpeg, c);
+        for by in 0..bh {
+            for bx in 0..bw {
+                let (dc, ac) = tables(0);
+                encode_block(&mut w, &c.blocks[by * c.blocks_w + bx], &mut preds[0], dc, ac)?;
+            }
+        }
+    } else {
+        let units: usize = jpeg.components.iter().map(|c| c.h as usize * c.v as usize).sum();
+        ensure!(units <= 10, "sampling factors need {} blocks per MCU, baseline allows 10", units);
+        let (mcux, mcuy) = mcu_count(jpeg);
+        for my in 0..mcuy {
+            for mx in 0..mcux {
+                for (i, c) in jpeg.components.iter().enumerate() {
+                    let (dc, ac) = tables(i);
+                    for v in 0..c.v as usize {
+                        for h in 0..c.h as usize {
+                            let (bx, by) = (mx * c.h as usize + h, my * c.v as usize + v);
+                            encode_block(&mut w, &c.blocks[by * c.blocks_w + bx], &mut preds[i], dc, ac)?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    let mut out = w.finish();
+    out.extend_from_slice(&[0xFF, 0xD9]);
+    Ok(out)
+}
+
+impl Jpeg {
+    // Transforms only stay lossless when no partial MCU would end up on the wrong edge
+    pub(crate) fn is_mcu_aligned(&self) -> bool {
+        let (hmax, vmax) = max_sampling(self);
+        (self.width as usize).is_multiple_of(8 * hmax) && (self.height as usize).is_multiple_of(8 * vmax)
+    }
+
+    pub(crate) fn apply(&mut self, t: Transform) {
+        if let Transform::Transpose = t {
+            std::mem::swap(&mut self.width, &mut self.height);
+            for table in self.qtables.iter_mut().flatten() {
+                *table = transpose(table);
+            }
+        }
+        for c in &mut self.components {
+            let (w, h) = (c.blocks_w, c.blocks_h);
+            let mut blocks = Vec::with_capacity(c.blocks.len());
+            match t {
+                Transform::FlipH => for y in 0..h {
+                    for x in (0..w).rev() { blocks.push(negate(&c.blocks[y * w + x], |_, u| u % 2 == 1)) }
+                },
+                Transform::FlipV => for y in (0..h).rev() {
+                    for x in 0..w { blocks.push(negate(&c.blocks[y * w + x], |v, _| v % 2 == 1)) }
+                },
+                Transform::Transpose => {
+                    for x in 0..w {
+                        for y in 0..h { blocks.push(transpose(&c.blocks[y * w + x])) }
+                    }
+                    std::mem::swap(&mut c.blocks_w, &mut c.blocks_h);
+                    std::mem::swap(&mut c.h, &mut c.v);
+                }
+            }
+            c.blocks = blocks;
+        }
+    }
+}
+
+// PARSING
+fn parse_frame(seg: &[u8]) -> Result<Jpeg> {
+    ensure!(seg.len() >= 6, "corrupt frame header");
+    ensure!(seg[0] == 8, "{}-bit JPEGs are not supported", seg[0]);
+    let height = u16::from_be_bytes([seg[1], seg[2]]);
+    let width = u16::from_be_bytes([seg[3], seg[4]]);
+    ensure!(width > 0 && height > 0, "JPEGs with a DNL height are not supported");
+    let n = seg[5] as usize;
+    ensure!((1..=4).contains(&n) && seg.len() >= 6 + 3 * n, "corrupt frame header");
+
+    let mut components = Vec::with_capacity(n);
+    for c in seg[6..6 + 3 * n].chunks(3) {
+        let (h, v) = (c[1] >> 4, c[1] & 15);
+        ensure!((1..=4).contains(&h) && (1..=4).contains(&v) && c[2] < 4, "corrupt frame header");
+        components.push(Component { id: c[0], h, v, tq: c[2], blocks_w: 0, blocks_h: 0, blocks: Vec::new() });
+    }
+    let mut jpeg = Jpeg { width, height, qtables: [None; 4], components, segments: Vec::new() };
+    let (mcux, mcuy) = mcu_count(&jpeg);
+    for c in &mut jpeg.components {
+        c.blocks_w = mcux * c.h as usize;
+        c.blocks_h = mcuy * c.v as usize;
+        c.blocks = vec![[0; 64]; c.blocks_w * c.blocks_h];
+    }
+    Ok(jpeg)
+}
+
+fn parse_quant(mut seg: &[u8], tables: &mut [Option<[u16; 64]>; 4]) -> Result<()> {
+    while !seg.is_empty() {
+        let (wide, id) = (seg[0] >> 4 != 0, (seg[0] & 15) as usize);
+        let size = if wide { 128 } else { 64 };
+        ensure!(id < 4 && seg.len() > size, "corrupt quantization table");
+        let mut table = [0u16; 64];
+        for (i, &z) in ZIGZAG.iter().enumerate() {
+            table[z] = if wide { u16::from_be_bytes([seg[1 + 2 * i], seg[2 + 2 * i]]) } else { seg[1 + i] as u16 };
+        }
+        tables[id] = Some(table);
+        seg = &seg[1 + size..];
+    }
+    Ok(())
+}
+
+fn parse_huffman(mut seg: &[u8], dc: &mut [Option<Huffman>; 4], ac: &mut [Option<Huffman>; 4]) -> Result<()> {
+    while !seg.is_empty() {
+        ensure!(seg.len() >= 17, "corrupt Huffman table");
+        let (class, id) = (seg[0] >> 4, (seg[0] & 15) as usize);
+        ensure!(class < 2 && id < 4, "corrupt Huffman table");
+        let bits: [u8; 16] = seg[1..17].try_into()?;
+        let count: usize = bits.iter().map(|&b| b as usize).sum();
+        ensure!(seg.len() >= 17 + count, "corrupt Huffman table");
+        let table = Huffman::new(&bits, &seg[17..17 + count]);
+        if class == 0 { dc[id] = Some(table) } else { ac[id] = Some(table) }
+        seg = &seg[17 + count..];
+    }
+    Ok(())
+}
+
+// Returns the position of the marker that ends the entropy-coded data
+fn decode_scan(
+    data: &[u8],
+    start: usize,
+    header: &[u8],
+    jpeg: &mut Jpeg,
+    dc_tables: &[Option<Huffman>; 4],
+    ac_tables: &[Option<Huffman>; 4],
+    restart_interval: usize,
+) -> Result<usize> {
+    let n = *header.first().context("corrupt scan header")? as usize;
+    ensure!((1..=4).contains(&n) && header.len() >= 1 + 2 * n + 3, "corrupt scan header");
+    let tail = &header[1 + 2 * n..];
+    ensure!(tail[0] == 0 && tail[1] == 63 && tail[2] == 0, "only baseline JPEGs are supported");
+
+    let mut scan = Vec::with_capacity(n);
+    for s in header[1..1 + 2 * n].chunks(2) {
+        let index = jpeg.components.iter().position(|c| c.id == s[0])
+            .with_context(|| format!("scan references unknown component {}", s[0]))?;
+        let dc = dc_tables[(s[1] >> 4) as usize & 3].as_ref().context("scan uses a missing DC table")?;
+        let ac = ac_tables[(s[1] & 15) as usize & 3].as_ref().context("scan uses a missing AC table")?;
+        scan.push((index, dc, ac));
+    }
+
+    let mut r = BitReader { data, pos: start, acc: 0, bits: 0, marker: false };
+    let mut preds = vec![0i32; jpeg.components.len()];
+    let restart = |r: &mut BitReader, preds: &mut Vec<i32>, unit: usize| -> Result<()> {
+        if restart_interval > 0 && unit > 0 && unit.is_multiple_of(restart_interval) {
+            r.restart()?;
+            preds.iter_mut().for_each(|p| *p = 0);
+        }
+        Ok(())
+    };
+
+    if let [(i, dc, ac)] = scan.as_slice() {
+        let (bw, bh) = scan_size(jpeg, &jpeg.components[*i]);
+        let c = &mut jpeg.components[*i];
+        for by in 0..bh {
+            for bx in 0..bw {
+                restart(&mut r, &mut preds, by * bw + bx)?;
+                decode_block(&mut r, &mut c.blocks[by * c.blocks_w + bx], &mut preds[*i], dc, ac)?;
+            }
+        }
+    } else {
+        let (mcux, mcuy) = mcu_count(jpeg);
+        for my in 0..mcuy {
+            for mx in 0..mcux {
+                restart(&mut r, &mut preds, my * mcux + mx)?;
+                for (i, dc, ac) in &scan {
+                    let c = &mut jpeg.components[*i];
+                    for v in 0..c.v as usize {
+                        for h in 0..c.h as usize {
+                            let (bx, by) = (mx * c.h as usize + h, my * c.v as usize + v);
+                            decode_block(&mut r, &mut c.blocks[by * c.blocks_w + bx], &mut preds[*i], dc, ac)?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let mut pos = r.pos;
+    while pos + 1 < data.len() && !(data[pos] == 0xFF && data[pos + 1] != 0 && !(0xD0..=0xD7).contains(&data[pos + 1])) {
+        pos += 1;
+    }
+    Ok(pos)
+}
+
+fn decode_block(r: &mut BitReader, block: &mut [i16; 64], pred: &mut i32, dc: &Huffman, ac: &Huffman) -> Result<()> {
+    let t = dc.decode(r)?;
+    ensure!(t <= 11, "corrupt JPEG: DC difference of {} bits", t);
+    *pred += extend(r.receive(t), t);
+    block[0] = *pred as i16;
+
+    let mut k = 1;
+    while k < 64 {
+        let rs = ac.decode(r)?;
+        let (run, size) = ((rs >> 4) as usize, rs & 15);
+        if size == 0 {
+            if run != 15 { break }
+            k += 16;
+            continue;
+        }
+        ensure!(size <= 10, "corrupt JPEG: AC coefficient of {} bits", size);
+        k += run;
+        ensure!(k < 64, "corrupt JPEG: coefficient index out of range");
+        block[ZIGZAG[k]] = extend(r.receive(size), size) as i16;
+        k += 1;
+    }
+    Ok(())
+}
+
+// Fails on a coefficient the baseline tables have no code for: a DC difference of more than 11
+// bits or an AC value of more than 10
+fn encode_block(w: &mut BitWriter, block: &[i16; 64], pred: &mut i32, dc: &Encoder, ac: &Encoder) -> Result<()> {
+    let (size, bits) = magnitude(block[0] as i32 - *pred);
+    *pred = block[0] as i32;
+    dc.put(w, size)?;
+    w.put(bits, size);
+
+    let mut run = 0;
+    for &z in &ZIGZAG[1..] {
+        let value = block[z] as i32;
+        if value == 0 { run += 1; continue }
+        while run > 15 {
+            ac.put(w, 0xF0)?;
+            run -= 16;
+        }
+        let (size, bits) = magnitude(value);
+        ac.put(w, (run << 4) | size)?;
+        w.put(bits, size);
+        run = 0;
+    }
+    if run > 0 { ac.put(w, 0x00)? }
+    Ok(())
+}
+
+// HUFFMAN
+#[derive(Default)]
+struct Huffman {
+    maxcode: [i32; 18],
+    valptr: [i32; 17],
+    mincode: [i32; 17],
+    values: Vec<u8>,
+}
+
+impl Huffman {
+    fn new(bits: &[u8; 16], values: &[u8]) -> Self {
+        let mut t = Huffman { values: values.to_vec(), ..Default::default() };
+        let (mut code, mut k) = (0i32, 0i32);
+        for l in 1..=16 {
+            let n = bits[l - 1] as i32;
+            t.valptr[l] = k;
+            t.mincode[l] = code;
+            code += n;
+            k += n;
+            t.maxcode[l] = if n > 0 { code - 1 } else { -1 };
+            code <<= 1;
+        }
+        t.maxcode[17] = i32::MAX;
+        t
+    }
+
+    fn decode(&self, r: &mut BitReader) -> Result<u8> {
+        let mut code = r.bit() as i32;
+        let mut l = 1;
+        while code > self.maxcode[l] {
+            code = (code << 1) | r.bit() as i32;
+            l += 1;
+            ensure!(l <= 16, "corrupt JPEG: invalid Huffman code");
+        }
+        let index = (self.valptr[l] + code - self.mincode[l]) as usize;
+        self.values.get(index).copied().context("corrupt JPEG: invalid Huffman code")
+    }
+}
+
+struct Encoder {
+    codes: [(u16, u8); 256],
+}
+
+impl Encoder {
+    fn new(bits: &[u8; 16], values: &[u8]) -> Self {
+        let mut codes = [(0, 0); 256];
+        let (mut code, mut k) = (0u16, 0);
+        for l in 1..=16u8 {
+            for _ in 0..bits[l as usize - 1] {
+                codes[values[k] as usize] = (code, l);
+                code += 1;
+                k += 1;
+            }
+            code <<= 1;
+        }
+        Encoder { codes }
+    }
+
+    fn put(&self, w: &mut BitWriter, symbol: u8) -> Result<()> {
+        let (code, len) = self.codes[symbol as usize];
+        ensure!(len > 0, "coefficient out of range for a baseline JPEG (no Huffman code for symbol {:#04x})", symbol);
+        w.put(code as u32, len);
+        Ok(())
+    }
+}
+
+// BIT IO
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    acc: u32,
+    bits: u32,
+    marker: bool,
+}
+
+impl BitReader<'_> {
+    // Past a marker the stream reads as zeros, like libjpeg does for truncated files
+    fn fill(&mut self) {
+        while self.bits <= 24 {
+            let mut byte = 0;
+            if !self.marker && self.pos < self.data.len() {
+                byte = self.data[self.pos];
+                if byte != 0xFF {
+                    self.pos += 1;
+                } else if self.data.get(self.pos + 1) == Some(&0) {
+                    self.pos += 2;
+                } else {
+                    self.marker = true;
+                    byte = 0;
+                }
+            }
+            self.acc |= (byte as u32) << (24 - self.bits);
+            self.bits += 8;
+        }
+    }
+
+    fn bit(&mut self) -> u32 {
+        self.receive(1)
+    }
+
+    fn receive(&mut self, n: u8) -> u32 {
+        if n == 0 { return 0 }
+        self.fill();
+        let v = self.acc >> (32 - n as u32);
+        self.acc <<= n;
+        self.bits -= n as u32;
+        v
+    }
+
+    fn restart(&mut self) -> Result<()> {
+        while self.pos + 1 < self.data.len()
+            && !(self.data[self.pos] == 0xFF && (0xD0..=0xD7).contains(&self.data[self.pos + 1]))
+        {
+            self.pos += 1;
+        }
+        ensure!(self.pos + 1 < self.data.len(), "corrupt JPEG: missing restart marker");
+        self.pos += 2;
+        self.acc = 0;
+        self.bits = 0;
+        self.marker = false;
+        Ok(())
+    }
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u32, len: u8) {
+        for i in (0..len).rev() {
+            self.acc = (self.acc << 1) | ((value >> i) & 1);
+            self.bits += 1;
+            if self.bits == 8 {
+                let byte = self.acc as u8;
+                self.out.push(byte);
+                if byte == 0xFF { self.out.push(0) }
+                self.acc = 0;
+                self.bits = 0;
+            }
+        }
+    }
+
+    // Pads the last byte with ones
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            let pad = 8 - self.bits as u8;
+            self.put((1 << pad) - 1, pad);
+        }
+        self.out
+    }
+}
+
+// HELPERS
+fn put_segment(out: &mut Vec<u8>, marker: u8, seg: &[u8]) {
+    out.extend_from_slice(&[0xFF, marker]);
+    out.extend_from_slice(&(seg.len() as u16 + 2).to_be_bytes());
+    out.extend_from_slice(seg);
+}
+
+fn max_sampling(jpeg: &Jpeg) -> (usize, usize) {
+    let h = jpeg.components.iter().map(|c| c.h as usize).max().unwrap_or(1);
+    let v = jpeg.components.iter().map(|c| c.v as usize).max().unwrap_or(1);
+    (h, v)
+}
+
+fn mcu_count(jpeg: &Jpeg) -> (usize, usize) {
+    let (hmax, vmax) = max_sampling(jpeg);
+    ((jpeg.width as usize).div_ceil(8 * hmax), (jpeg.height as usize).div_ceil(8 * vmax))
+}
+
+// Non-interleaved scans only cover the blocks that hold image data, without MCU padding
+fn scan_size(jpeg: &Jpeg, c: &Component) -> (usize, usize) {
+    let (hmax, vmax) = max_sampling(jpeg);
+    let w = (jpeg.width as usize * c.h as usize).div_ceil(hmax);
+    let h = (jpeg.height as usize * c.v as usize).div_ceil(vmax);
+    (w.div_ceil(8), h.div_ceil(8))
+}
+
+fn extend(v: u32, size: u8) -> i32 {
+    if size == 0 { return 0 }
+    let v = v as i32;
+    if v < 1 << (size - 1) { v - (1 << size) + 1 } else { v }
+}
+
+fn magnitude(v: i32) -> (u8, u32) {
+    let size = (32 - v.unsigned_abs().leading_zeros()) as u8;
+    let bits = if v < 0 { (v - 1) as u32 } else { v as u32 };
+    (size, bits & ((1u32 << size) - 1))
+}
+
+fn transpose<T: Copy + Default>(block: &[T; 64]) -> [T; 64] {
+    let mut out = [T::default(); 64];
+    for v in 0..8 {
+        for u in 0..8 { out[u * 8 + v] = block[v * 8 + u] }
+    }
+    out
+}
+
+fn negate(block: &[i16; 64], odd: impl Fn(usize, usize) -> bool) -> [i16; 64] {
+    let mut out = *block;
+    for v in 0..8 {
+        for u in 0..8 {
+            if odd(v, u) { out[v * 8 + u] = -out[v * 8 + u] }
+        }
+    }
+    out
+}
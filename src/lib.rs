@@ -0,0 +1,82 @@
+//! The jj_toolkit commands as a library, for programs that want hashing, compression,
+//! encryption and the rest without shelling out to the CLI.
+//!
+//! Each module holds one command family. Besides the command entry points, which take the
+//! parsed CLI arguments, the reusable pieces are plain functions over readers and writers:
+//!
+//! ```no_run
+//! use jj_toolkit::{compression, crypt, hash};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let digest = hash::hash_file("report.pdf".as_ref(), hash::Algorithm::Blake3, false)?;
+//!
+//! let mut packed = Vec::new();
+//! compression::compress_reader(&b"rows of text"[..], &mut packed, compression::Algorithm::Zstd, 5)?;
+//!
+//! let mut sealed = Vec::new();
+//! crypt::encrypt_stream(&packed[..], &mut sealed, "correct horse", crypt::KdfParams::default())?;
+//! # Ok(())
+//! # }
+//! ```
+
+/// File and manifest hashing
+pub mod hash;
+/// Pixel and JPEG coefficient operations behind the `image-*` commands
+pub mod image;
+/// Password-based encryption of files and directories
+pub mod crypt;
+/// Overwriting files before deleting them
+pub mod shred;
+/// Stream compression and the JJPACK container
+pub mod compression;
+/// Tar and zip archives of files and directory trees
+pub mod archive;
+/// Splitting large files into verified parts and joining them back
+pub mod split;
+/// Key generation, fingerprints and key format conversion
+pub mod keygen;
+/// Random passwords and passphrases
+pub mod passgen;
+/// Time-based one-time passwords
+pub mod totp;
+/// Small named secrets in one encrypted file
+pub mod vault;
+/// QR code encoding and decoding
+pub mod qr;
+/// File attributes and embedded image and audio metadata
+pub mod meta;
+/// Conversion, validation, diff and merge of structured data formats
+pub mod format;
+/// Hiding data in images and detecting it
+pub mod steganography;
+/// SVG rasterization and optimization
+pub mod raster;
+/// Read-only HTTP file serving with integrity headers
+pub mod serve;
+/// Downloads checked against a known digest
+pub mod fetch;
+/// Stream commands chained in one pass
+pub mod pipeline;
+/// Job files of toolkit commands run in dependency order
+pub mod jobs;
+/// Throughput of hashing, compression, key derivation and resizing on this machine
+pub mod bench;
+/// Progress bars shared by the commands
+pub mod progress;
+/// `-` as stdin or stdout for command paths
+pub mod stdio;
+/// The system clipboard, for --copy
+pub mod clipboard;
+/// The --max-memory budget of commands that hold whole inputs
+pub mod memory;
+/// Input attributes carried over to outputs with --preserve
+pub mod preserve;
+/// Exit codes and the JSON form of errors
+pub mod errors;
+/// Provenance sidecars of outputs and their verification
+pub mod sidecar;
+mod batch;
+mod gitignore;
+mod http;
+mod jpeg;
+mod watch;
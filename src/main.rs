@@ -1,20 +1,50 @@
-mod hash;
-mod image;
-mod crypt;
-mod compression;
-mod keygen;
-mod format;
-mod steganography;
-mod raster;
-
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use serde_json::{Map, Value, json};
+use std::process::ExitCode;
+use jj_toolkit::{archive, bench, clipboard, compression, crypt, errors, fetch, format, hash, image, jobs, keygen, memory, meta, passgen, pipeline, preserve, progress, qr, raster, serve, shred, sidecar, split, stdio, steganography, totp, vault};
 
 #[derive(Parser)]
 #[command(author, version, about)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// More log output on stderr: -v for debug, -vv for trace
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Only errors on stderr; results on stdout are unaffected
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    /// No progress bars, which are otherwise drawn on stderr when it is a terminal
+    #[arg(long, global = true)]
+    no_progress: bool,
+    /// Results as JSON where a command has a JSON form (meta, format-diff, bench, verify-sidecar),
+    /// and failures as one JSON object on stderr
+    #[arg(long, global = true)]
+    json: bool,
+    /// Write binary output to stdout even when it is a terminal
+    #[arg(long, global = true)]
+    force_tty: bool,
+    /// Replace existing output files, including those a command refuses to replace by default
+    #[arg(long, global = true, visible_alias = "overwrite", overrides_with_all = ["no_clobber", "skip_existing"])]
+    force: bool,
+    /// Refuse to replace existing output files; overrides an earlier --force
+    #[arg(long, global = true, overrides_with_all = ["force", "skip_existing"])]
+    no_clobber: bool,
+    /// Leave existing output files alone and skip their inputs, without an error
+    #[arg(long, global = true, overrides_with_all = ["force", "no_clobber"])]
+    skip_existing: bool,
+    /// Refuse work that would hold more than this in memory, e.g. 512M or 2G, where the command
+    /// cannot stream instead
+    #[arg(long, global = true, value_name = "SIZE", value_parser = split::parse_size)]
+    max_memory: Option<u64>,
+    /// Give outputs these attributes of their inputs: mtime, perms, xattrs. Alone: mtime,perms
+    #[arg(long, global = true, value_enum, value_delimiter = ',', num_args = 0..=1, require_equals = true, default_missing_values = ["mtime", "perms"], value_name = "ATTRS")]
+    preserve: Vec<preserve::Attribute>,
+    /// Write a <output>.jj.meta JSON file next to every output, with the command, its parameters
+    /// and the digests of source and output
+    #[arg(long, global = true)]
+    sidecar: bool,
 }
 
 #[derive(Subcommand)]
@@ -23,34 +53,177 @@ enum Commands {
     HashVerify(hash::HashVerifyArgs),
     Encrypt(crypt::EncryptArgs),
     Decrypt(crypt::DecryptArgs),
+    Shred(shred::ShredArgs),
     Compress(compression::CompressionArgs),
     Decompress(compression::DecompressionArgs),
+    Archive(archive::ArchiveArgs),
+    Split(split::SplitArgs),
+    Join(split::JoinArgs),
     Keygen(keygen::KeygenArgs),
+    KeyFingerprint(keygen::FingerprintArgs),
+    KeyConvert(keygen::KeyConvertArgs),
+    KeySign(keygen::KeySignArgs),
+    KeyVerify(keygen::KeyVerifyArgs),
+    Passgen(passgen::PassgenArgs),
+    Totp(totp::TotpArgs),
+    Vault(vault::VaultArgs),
+    Qr(qr::QrArgs),
+    Meta(meta::MetaArgs),
     Format(format::FormatArgs),
+    FormatValidate(format::ValidateArgs),
+    FormatDiff(format::DiffArgs),
+    FormatMerge(format::MergeArgs),
     ImageConvert(image::ConvertArgs),
     ImageScale(image::ScaleArgs),
+    ImageThumb(image::ThumbArgs),
+    ImageCrop(image::CropArgs),
+    ImageBorder(image::BorderArgs),
+    ImageFilter(image::FilterArgs),
+    ImageKeyout(image::KeyoutArgs),
+    ImageTransform(image::TransformArgs),
+    ImageAdjust(image::AdjustArgs),
+    ImageCompare(image::CompareArgs),
+    ImageHash(image::ImageHashArgs),
+    ImageInfo(image::InfoArgs),
     ImageGetcolor(image::GetColorArgs),
     SteganoEmbed(steganography::EmbedArgs),
     SteganoExtract(steganography::ExtractArgs),
-    Rasterize(raster::RasterizeArgs)
+    SteganoDetect(steganography::DetectArgs),
+    Rasterize(raster::RasterizeArgs),
+    SvgOptimize(raster::OptimizeArgs),
+    Pipeline(pipeline::PipelineArgs),
+    Run(jobs::RunArgs),
+    VerifySidecar(sidecar::VerifyArgs),
+    Serve(serve::ServeArgs),
+    Fetch(fetch::FetchArgs),
+    Bench(bench::BenchArgs),
+    ClipboardClear(clipboard::ClearArgs),
+}
+
+fn main() -> ExitCode {
+    let matches = match Cli::command().try_get_matches() {
+        Ok(matches) => matches,
+        Err(e) => return errors::usage(e),
+    };
+    let cli = match Cli::from_arg_matches(&matches) {
+        Ok(cli) => cli,
+        Err(e) => return errors::usage(e),
+    };
+    init_logging(cli.verbose, cli.quiet);
+    progress::set_enabled(!cli.quiet && !cli.no_progress);
+    stdio::set_force_tty(cli.force_tty);
+    stdio::set_json(cli.json);
+    memory::set_limit(cli.max_memory);
+    preserve::set(&cli.preserve);
+    if cli.sidecar
+        && let Some((name, args)) = matches.subcommand()
+    {
+        sidecar::enable(sidecar::Invocation { command: name.to_string(), parameters: parameters(name, args) });
+    }
+    // Without any of these, each command keeps its own default
+    match (cli.force, cli.no_clobber, cli.skip_existing) {
+        (true, _, _) => stdio::set_clobber(stdio::Clobber::Replace),
+        (_, true, _) => stdio::set_clobber(stdio::Clobber::Refuse),
+        (_, _, true) => stdio::set_clobber(stdio::Clobber::Skip),
+        _ => {}
+    }
+    match run(cli.command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => errors::report(&e),
+    }
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-    match cli.command {
+fn run(command: Commands) -> Result<()> {
+    match command {
         Commands::Hash(a) => hash::hash(a),
         Commands::HashVerify(a) => hash::hash_verify(a),
         Commands::Encrypt(a) => crypt::encrypt(a),
         Commands::Decrypt(a) => crypt::decrypt(a),
+        Commands::Shred(a) => shred::shred(a),
         Commands::Compress(a) => compression::compress(a),
         Commands::Decompress(a) => compression::decompress(a),
+        Commands::Archive(a) => archive::archive(a),
+        Commands::Split(a) => split::split(a),
+        Commands::Join(a) => split::join(a),
         Commands::Keygen(a) => keygen::generate_key(a),
+        Commands::KeyFingerprint(a) => keygen::fingerprint(a),
+        Commands::KeyConvert(a) => keygen::convert(a),
+        Commands::KeySign(a) => keygen::sign(a),
+        Commands::KeyVerify(a) => keygen::verify(a),
+        Commands::Passgen(a) => passgen::passgen(a),
+        Commands::Totp(a) => totp::totp(a),
+        Commands::Vault(a) => vault::vault(a),
+        Commands::Qr(a) => qr::qr(a),
+        Commands::Meta(a) => meta::meta(a),
         Commands::Format(a) => format::format_convert(a),
+        Commands::FormatValidate(a) => format::validate(a),
+        Commands::FormatDiff(a) => format::diff(a),
+        Commands::FormatMerge(a) => format::merge(a),
         Commands::ImageConvert(a) => image::convert(a),
         Commands::ImageScale(a) => image::scale(a),
+        Commands::ImageThumb(a) => image::thumb(a),
+        Commands::ImageCrop(a) => image::crop(a),
+        Commands::ImageBorder(a) => image::border(a),
+        Commands::ImageFilter(a) => image::filter(a),
+        Commands::ImageKeyout(a) => image::keyout(a),
+        Commands::ImageTransform(a) => image::transform(a),
+        Commands::ImageAdjust(a) => image::adjust(a),
+        Commands::ImageCompare(a) => image::compare(a),
+        Commands::ImageHash(a) => image::image_hash(a),
+        Commands::ImageInfo(a) => image::info(a),
         Commands::ImageGetcolor(a) => image::get_color(a),
         Commands::SteganoEmbed(a) => steganography::embed(a),
         Commands::SteganoExtract(a) => steganography::extract(a),
+        Commands::SteganoDetect(a) => steganography::detect(a),
         Commands::Rasterize(a) => raster::rasterize(a),
+        Commands::SvgOptimize(a) => raster::optimize(a),
+        Commands::Pipeline(a) => pipeline::pipeline(a),
+        Commands::Run(a) => jobs::run(a),
+        Commands::VerifySidecar(a) => sidecar::verify(a),
+        Commands::Serve(a) => serve::serve(a),
+        Commands::Fetch(a) => fetch::fetch(a),
+        Commands::Bench(a) => bench::bench(a),
+        Commands::ClipboardClear(a) => clipboard::clear(a),
     }
 }
+
+// The arguments of subcommand `name` as given or defaulted, for --sidecar: switches that are on,
+// single values as strings and repeated ones as lists. Global flags are left out
+fn parameters(name: &str, matches: &ArgMatches) -> Map<String, Value> {
+    let command = Cli::command();
+    let Some(subcommand) = command.find_subcommand(name) else { return Map::new() };
+    let mut parameters = Map::new();
+    for arg in subcommand.get_arguments().filter(|arg| !arg.is_global_set()) {
+        let id = arg.get_id().as_str();
+        let Some(raw) = matches.get_raw(id) else { continue };
+        let values: Vec<String> = raw.map(|v| v.to_string_lossy().into_owned()).collect();
+        let value = match (arg.get_action().takes_values(), values.as_slice()) {
+            (false, [v]) if v == "false" => continue,
+            (false, [v]) if v == "true" => Value::Bool(true),
+            (_, [v]) => json!(v),
+            _ => json!(values),
+        };
+        parameters.insert(id.to_string(), value);
+    }
+    parameters
+}
+
+// Logs go to stderr so stdout carries only results. Levels are shown once -v asks for more than
+// the usual status lines
+fn init_logging(verbose: u8, quiet: bool) {
+    use tracing::level_filters::LevelFilter;
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::ERROR,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    tracing_subscriber::fmt()
+        .with_writer(progress::log_writer)
+        .with_max_level(level)
+        .with_ansi(std::io::IsTerminal::is_terminal(&std::io::stderr()))
+        .without_time()
+        .with_target(false)
+        .with_level(verbose > 0)
+        .init();
+}
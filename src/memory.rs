@@ -0,0 +1,69 @@
+// The --max-memory budget. Commands that hold a whole input in memory (encryption, format
+// conversion, decoded images) work out what that will take before they allocate it, and stop
+// with an error naming the input rather than have the host kill the process part way. Where a
+// command can stream instead, as rasterize does for PNG, it switches to that
+use anyhow::{Context, Result};
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::{errors, stdio};
+
+// 0 for no limit
+static LIMIT: AtomicU64 = AtomicU64::new(0);
+
+/// Caps the memory a command may plan to hold at once, in bytes. None lifts the cap
+pub fn set_limit(bytes: Option<u64>) {
+    LIMIT.store(bytes.unwrap_or(0), Ordering::Relaxed);
+}
+
+pub(crate) fn limit() -> Option<u64> {
+    Some(LIMIT.load(Ordering::Relaxed)).filter(|&n| n > 0)
+}
+
+// Errors when `bytes` are above the budget; `what` names what needs them
+pub(crate) fn reserve(bytes: u64, what: impl FnOnce() -> String) -> Result<()> {
+    match limit() {
+        Some(limit) if bytes > limit => {
+            Err(errors::MemoryLimit(format!("{} needs about {} of memory, above --max-memory {}", what(), size(bytes), size(limit))).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Reads `path` whole for a command that then holds `copies` times its size. A file is checked by
+// its size before reading; stdin, whose size is unknown, stops being read once over the budget
+pub(crate) fn read(path: &Path, copies: u64) -> Result<Vec<u8>> {
+    let what = || format!("reading {}", stdio::display(path));
+    if !stdio::is_dash(path)
+        && let Ok(meta) = std::fs::metadata(path)
+    {
+        reserve(meta.len().saturating_mul(copies), what)?;
+    }
+    read_to_end(stdio::reader(path)?, copies, what)
+}
+
+// `reader` read to the end under the same rule as stdin in `read`
+pub(crate) fn read_to_end(reader: impl Read, copies: u64, what: impl Fn() -> String) -> Result<Vec<u8>> {
+    let limit = limit();
+    let bound = limit.map_or(u64::MAX, |limit| limit / copies.max(1));
+    let mut data = Vec::new();
+    reader.take(bound.saturating_add(1)).read_to_end(&mut data).with_context(&what)?;
+    if let Some(limit) = limit
+        && data.len() as u64 > bound
+    {
+        return Err(errors::MemoryLimit(format!("{} needs more memory than --max-memory {}", what(), size(limit))).into());
+    }
+    Ok(data)
+}
+
+// Bytes in the largest binary unit that keeps a whole number in front
+pub(crate) fn size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} bytes", bytes) } else { format!("{:.1} {}", value, UNITS[unit]) }
+}
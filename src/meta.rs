@@ -0,0 +1,1105 @@
+// `meta`: what is known about a file. Filesystem attributes for anything, plus the metadata
+// embedded in images (EXIF with its GPS tags, XMP, ICC profiles, IPTC) and in MP3 audio (ID3).
+// `--strip` writes a copy without the embedded blocks, e.g. before sharing photos that carry the
+// position they were taken at. The image module decodes the blocks; the tags are read here
+use anyhow::{Context, Result, bail, ensure};
+use clap::Args;
+use serde_json::{Map, Value};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+use crate::{archive, errors, stdio};
+
+#[derive(Args)]
+#[command[name = "meta", about = "Show filesystem attributes and embedded metadata (EXIF, XMP, ICC, ID3), or strip it"]]
+pub struct MetaArgs {
+    /// File to inspect, `-` for stdin
+    input: PathBuf,
+    /// Remove EXIF, XMP, IPTC, comments and ICC profiles from JPEG, PNG and WEBP images, or ID3
+    /// tags from MP3 files. The EXIF orientation is kept, so photos still display upright
+    #[arg(long)]
+    strip: bool,
+    /// With --strip, keep the ICC profile, without which colors of wide-gamut images shift
+    #[arg(long, requires = "strip")]
+    keep_icc: bool,
+    /// With --strip, file to write, `-` for stdout. Default: the input is replaced
+    #[arg(short, long, requires = "strip")]
+    output: Option<PathBuf>,
+}
+
+/// A named group of fields, e.g. the EXIF tags of a photo
+pub struct Section {
+    pub name: &'static str,
+    pub fields: Vec<(String, Value)>,
+}
+
+impl Section {
+    fn new(name: &'static str) -> Section {
+        Section { name, fields: Vec::new() }
+    }
+
+    fn push(&mut self, key: impl Into<String>, value: impl Into<Value>) {
+        self.fields.push((key.into(), value.into()));
+    }
+}
+
+pub fn meta(a: MetaArgs) -> Result<()> {
+    if a.strip {
+        return strip_file(&a);
+    }
+    let input = stdio::input(&a.input)?;
+    let mut sections = Vec::new();
+    if !input.is_stdin() {
+        sections.push(file_section(&a.input)?);
+    }
+    if input.path().is_file() {
+        sections.extend(embedded_sections(input.path())?);
+    }
+
+    if stdio::json() {
+        let mut object = Map::new();
+        for section in sections {
+            object.insert(section.name.to_lowercase(), Value::Object(section.fields.into_iter().collect()));
+        }
+        println!("{}", serde_json::to_string_pretty(&Value::Object(object))?);
+        return Ok(());
+    }
+    for (i, section) in sections.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!("{}", section.name);
+        let width = section.fields.iter().map(|(k, _)| k.chars().count()).max().unwrap_or(0) + 1;
+        for (key, value) in &section.fields {
+            println!("  {:width$} {}", format!("{}:", key), show(value), width = width);
+        }
+    }
+    Ok(())
+}
+
+// Values as text: strings unquoted, lists joined
+fn show(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items.iter().map(show).collect::<Vec<_>>().join(", "),
+        other => other.to_string(),
+    }
+}
+
+/// Type, size, permissions, owner and timestamps of `path`, following a symbolic link
+pub fn file_section(path: &Path) -> Result<Section> {
+    let link = fs::symlink_metadata(path).with_context(|| errors::at("read", path))?;
+    let meta = fs::metadata(path).with_context(|| errors::at("read", path))?;
+    let mut section = Section::new("File");
+    section.push("Path", path.display().to_string());
+    if link.file_type().is_symlink() {
+        section.push("Link target", fs::read_link(path)?.display().to_string());
+    }
+    let kind = if meta.is_dir() {
+        "directory"
+    } else if meta.is_file() {
+        "file"
+    } else {
+        "special"
+    };
+    section.push("Type", kind);
+    section.push("Size", meta.len());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let mode = meta.mode() & 0o7777;
+        section.push("Permissions", format!("{} ({:04o})", archive::mode_string(meta.is_dir(), Some(mode)), mode));
+        section.push("Owner", meta.uid());
+        section.push("Group", meta.gid());
+        section.push("Links", meta.nlink());
+        section.push("Inode", meta.ino());
+    }
+    #[cfg(not(unix))]
+    section.push("Permissions", if meta.permissions().readonly() { "read-only" } else { "read-write" });
+    for (key, time) in [("Modified", meta.modified()), ("Accessed", meta.accessed()), ("Created", meta.created())] {
+        if let Ok(time) = time {
+            section.push(key, format_timestamp(time));
+        }
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        section.push("Changed", format_seconds(meta.ctime()));
+    }
+    Ok(section)
+}
+
+pub(crate) fn format_timestamp(time: SystemTime) -> String {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => format_seconds(d.as_secs() as i64),
+        Err(e) => format_seconds(-(e.duration().as_secs_f64().ceil() as i64)),
+    }
+}
+
+// Unix seconds as a UTC date and time
+fn format_seconds(secs: i64) -> String {
+    let (year, month, day) = archive::civil_from_days(secs.div_euclid(86_400));
+    let rem = secs.rem_euclid(86_400);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+/// The metadata embedded in an image or an ID3-tagged file, one section per kind found. Other
+/// files have none
+pub fn embedded_sections(path: &Path) -> Result<Vec<Section>> {
+    let mut file = File::open(path).with_context(|| errors::at("open", path))?;
+    let mut head = Vec::with_capacity(64);
+    (&mut file).take(64).read_to_end(&mut head).with_context(|| errors::at("read", path))?;
+    let len = file.metadata()?.len();
+    let mut tail = [0u8; 3];
+    if len >= 128 {
+        file.seek(SeekFrom::Start(len - 128))?;
+        file.read_exact(&mut tail)?;
+    }
+    drop(file);
+
+    if image::guess_format(&head).is_ok() {
+        return match crate::image::embedded_metadata(path) {
+            Ok(embedded) => Ok(image_sections(embedded)),
+            Err(e) => {
+                warn!("{}: {:#}", stdio::display(path), e);
+                Ok(Vec::new())
+            }
+        };
+    }
+    if head.starts_with(b"ID3") || &tail == b"TAG" {
+        let data = fs::read(path).with_context(|| errors::at("read", path))?;
+        let mut sections = Vec::new();
+        if let Some((section, _)) = parse_id3v2(&data) {
+            sections.push(section);
+        }
+        if let Some(section) = parse_id3v1(&data) {
+            sections.push(section);
+        }
+        return Ok(sections);
+    }
+    Ok(Vec::new())
+}
+
+fn image_sections(embedded: crate::image::Embedded) -> Vec<Section> {
+    let mut sections = Vec::new();
+    let mut image = Section::new("Image");
+    image.push("Format", embedded.format);
+    image.push("Dimensions", format!("{}x{}", embedded.dimensions.0, embedded.dimensions.1));
+    image.push("Color", format!("{:?}", embedded.color));
+    sections.push(image);
+    if let Some(exif) = &embedded.exif {
+        match parse_exif(exif) {
+            Ok(found) => sections.extend(found),
+            Err(e) => warn!("EXIF block is damaged: {:#}", e),
+        }
+    }
+    if let Some(xmp) = &embedded.xmp {
+        sections.push(parse_xmp(xmp));
+    }
+    if let Some(icc) = &embedded.icc {
+        sections.push(parse_icc(icc));
+    }
+    if let Some(iptc) = &embedded.iptc {
+        let mut section = Section::new("IPTC");
+        section.push("Size", iptc.len());
+        sections.push(section);
+    }
+    sections
+}
+
+// EXIF
+
+// A TIFF structure: a byte order mark, then chains of IFDs, tables of 12-byte tag entries
+struct Tiff<'a> {
+    data: &'a [u8],
+    little: bool,
+}
+
+struct IfdEntry<'a> {
+    tag: u16,
+    kind: u16,
+    count: usize,
+    value: &'a [u8],
+}
+
+impl<'a> Tiff<'a> {
+    // `data` may still carry the `Exif\0\0` prefix of JPEG segments
+    fn new(data: &'a [u8]) -> Result<(Tiff<'a>, usize)> {
+        let data = data.strip_prefix(b"Exif\0\0").unwrap_or(data);
+        let little = match data.get(..4) {
+            Some(b"II*\0") => true,
+            Some(b"MM\0*") => false,
+            _ => bail!("no TIFF header"),
+        };
+        let tiff = Tiff { data, little };
+        let first = tiff.u32(4).context("truncated TIFF header")? as usize;
+        Ok((tiff, first))
+    }
+
+    fn u16(&self, at: usize) -> Option<u16> {
+        self.data.get(at..at + 2).map(|b| self.read16(b))
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        self.data.get(at..at + 4).map(|b| self.read32(b))
+    }
+
+    // The first 2 or 4 bytes of `b`, in the file's byte order
+    fn read16(&self, b: &[u8]) -> u16 {
+        let b = [b[0], b[1]];
+        if self.little { u16::from_le_bytes(b) } else { u16::from_be_bytes(b) }
+    }
+
+    fn read32(&self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        if self.little { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) }
+    }
+
+    // The IFD an entry points to
+    fn offset(&self, entry: &IfdEntry) -> Option<usize> {
+        match entry.kind {
+            3 if entry.value.len() >= 2 => Some(usize::from(self.read16(entry.value))),
+            4 | 13 if entry.value.len() >= 4 => Some(self.read32(entry.value) as usize),
+            _ => None,
+        }
+    }
+
+    // Entries of the IFD at `offset`. Values of up to 4 bytes sit in the entry, larger ones at
+    // the offset it holds. Entries pointing outside the data are dropped
+    fn ifd(&self, offset: usize) -> Vec<IfdEntry<'a>> {
+        let Some(count) = self.u16(offset) else { return Vec::new() };
+        let mut entries = Vec::new();
+        for i in 0..usize::from(count) {
+            let at = offset + 2 + i * 12;
+            let (Some(tag), Some(kind), Some(count)) = (self.u16(at), self.u16(at + 2), self.u32(at + 4)) else { break };
+            let size = type_size(kind).saturating_mul(count as usize);
+            let start = if size <= 4 { at + 8 } else { self.u32(at + 8).unwrap_or(u32::MAX) as usize };
+            if let Some(value) = start.checked_add(size).and_then(|end| self.data.get(start..end)) {
+                entries.push(IfdEntry { tag, kind, count: count as usize, value });
+            }
+        }
+        entries
+    }
+
+    fn value(&self, entry: &IfdEntry) -> Value {
+        let numbers = |size: usize, read: &dyn Fn(&[u8]) -> f64| -> Value {
+            let values: Vec<Value> = entry.value.chunks_exact(size).map(|b| number(read(b))).collect();
+            if values.len() == 1 { values.into_iter().next().unwrap_or(Value::Null) } else { Value::Array(values) }
+        };
+        let u16_at = |b: &[u8]| f64::from(self.read16(b));
+        let u32_at = |b: &[u8]| self.read32(b);
+        let rational = |b: &[u8], signed: bool| {
+            let (n, d) = (u32_at(&b[..4]), u32_at(&b[4..]));
+            let (n, d) = if signed { (f64::from(n as i32), f64::from(d as i32)) } else { (f64::from(n), f64::from(d)) };
+            if d == 0.0 { 0.0 } else { n / d }
+        };
+        match entry.kind {
+            // ASCII, possibly several NUL-separated strings
+            2 => Value::String(latin1(entry.value).trim_end_matches('\0').replace('\0', ", ").trim().to_string()),
+            3 => numbers(2, &u16_at),
+            8 => numbers(2, &|b| f64::from(u16_at(b) as i16)),
+            4 => numbers(4, &|b| f64::from(u32_at(b))),
+            9 => numbers(4, &|b| f64::from(u32_at(b) as i32)),
+            5 => numbers(8, &|b| rational(b, false)),
+            10 => numbers(8, &|b| rational(b, true)),
+            6 => numbers(1, &|b| f64::from(b[0] as i8)),
+            1 if entry.count <= 8 => numbers(1, &|b| f64::from(b[0])),
+            _ => bytes_value(entry.value),
+        }
+    }
+}
+
+const fn type_size(kind: u16) -> usize {
+    match kind {
+        1 | 2 | 6 | 7 => 1,
+        3 | 8 => 2,
+        4 | 9 | 11 | 13 => 4,
+        5 | 10 | 12 => 8,
+        _ => 0,
+    }
+}
+
+// Whole numbers stay integers; fractions are rounded to what a display needs
+fn number(x: f64) -> Value {
+    if x.fract() == 0.0 && x.abs() < 1e15 {
+        Value::from(x as i64)
+    } else {
+        serde_json::Number::from_f64((x * 1e6).round() / 1e6).map_or(Value::Null, Value::Number)
+    }
+}
+
+// Text when it reads as text, hex when short, else only the length
+fn bytes_value(bytes: &[u8]) -> Value {
+    let text = bytes.strip_suffix(b"\0").unwrap_or(bytes);
+    if !text.is_empty() && text.iter().all(|b| (0x20..0x7F).contains(b) || b"\t\r\n".contains(b)) {
+        Value::String(String::from_utf8_lossy(text).trim().to_string())
+    } else if bytes.len() <= 16 {
+        Value::String(hex::encode(bytes))
+    } else {
+        Value::String(format!("({} bytes)", bytes.len()))
+    }
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| char::from(b)).collect()
+}
+
+/// The EXIF tags of a TIFF structure, as in JPEG APP1 segments, PNG eXIf and WEBP EXIF chunks:
+/// an `EXIF` section with the main and Exif IFD tags, and a `GPS` section when there is one
+pub fn parse_exif(data: &[u8]) -> Result<Vec<Section>> {
+    let (tiff, first) = Tiff::new(data)?;
+    let mut exif = Section::new("EXIF");
+    let mut gps = Section::new("GPS");
+    let mut pending = vec![(first, false)];
+    let mut seen = Vec::new();
+    while let Some((offset, is_gps)) = pending.pop() {
+        // Offsets loop back in damaged files
+        if seen.contains(&offset) || seen.len() > 8 {
+            continue;
+        }
+        seen.push(offset);
+        for entry in tiff.ifd(offset) {
+            match (is_gps, entry.tag) {
+                (false, 0x8769) => pending.extend(tiff.offset(&entry).map(|o| (o, false))),
+                (false, 0x8825) => pending.extend(tiff.offset(&entry).map(|o| (o, true))),
+                // Interoperability IFD, and the strips and tiles of TIFF files
+                (false, 0xA005 | 0x0111 | 0x0117 | 0x0144 | 0x0145) => {}
+                (false, tag) => {
+                    let name = exif_tag_name(tag).map_or_else(|| format!("Tag 0x{:04X}", tag), str::to_string);
+                    let value = match tag {
+                        0x829A => exposure_time(tiff.value(&entry)),
+                        // Starts with an 8-byte character code
+                        0x9286 => bytes_value(entry.value.get(8..).unwrap_or_default()),
+                        0x927C => Value::String(format!("({} bytes)", entry.value.len())),
+                        _ => tiff.value(&entry),
+                    };
+                    exif.push(name, value);
+                }
+                (true, tag) => {
+                    let name = gps_tag_name(tag).map_or_else(|| format!("Tag 0x{:04X}", tag), str::to_string);
+                    gps.push(name, tiff.value(&entry));
+                }
+            }
+        }
+    }
+    if let Some(position) = gps_position(&gps) {
+        gps.push("Position", position);
+    }
+    Ok([exif, gps].into_iter().filter(|s| !s.fields.is_empty()).collect())
+}
+
+// Exposure times read as the fractions cameras show, e.g. 1/250
+fn exposure_time(value: Value) -> Value {
+    match value.as_f64() {
+        Some(t) if t > 0.0 && t < 1.0 => Value::String(format!("1/{}", (1.0 / t).round())),
+        _ => value,
+    }
+}
+
+// Decimal degrees from the degrees, minutes and seconds with their N/S and E/W references
+fn gps_position(gps: &Section) -> Option<String> {
+    let get = |key: &str| gps.fields.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+    let degrees = |key: &str, reference: &str, negative: &str| -> Option<f64> {
+        let parts = get(key)?.as_array()?;
+        let [d, m, s] = parts.as_slice() else { return None };
+        let value = d.as_f64()? + m.as_f64()? / 60.0 + s.as_f64()? / 3600.0;
+        let sign = if get(reference).and_then(Value::as_str).is_some_and(|r| r.eq_ignore_ascii_case(negative)) { -1.0 } else { 1.0 };
+        Some(sign * value)
+    };
+    let latitude = degrees("GPSLatitude", "GPSLatitudeRef", "S")?;
+    let longitude = degrees("GPSLongitude", "GPSLongitudeRef", "W")?;
+    Some(format!("{:.6}, {:.6}", latitude, longitude))
+}
+
+fn exif_tag_name(tag: u16) -> Option<&'static str> {
+    Some(match tag {
+        0x0100 => "ImageWidth",
+        0x0101 => "ImageLength",
+        0x0102 => "BitsPerSample",
+        0x0103 => "Compression",
+        0x0106 => "PhotometricInterpretation",
+        0x010E => "ImageDescription",
+        0x010F => "Make",
+        0x0110 => "Model",
+        0x0112 => "Orientation",
+        0x0115 => "SamplesPerPixel",
+        0x011A => "XResolution",
+        0x011B => "YResolution",
+        0x011C => "PlanarConfiguration",
+        0x0128 => "ResolutionUnit",
+        0x0131 => "Software",
+        0x0132 => "DateTime",
+        0x013B => "Artist",
+        0x0213 => "YCbCrPositioning",
+        0x8298 => "Copyright",
+        0x829A => "ExposureTime",
+        0x829D => "FNumber",
+        0x8822 => "ExposureProgram",
+        0x8827 => "ISO",
+        0x8830 => "SensitivityType",
+        0x9000 => "ExifVersion",
+        0x9003 => "DateTimeOriginal",
+        0x9004 => "DateTimeDigitized",
+        0x9010 => "OffsetTime",
+        0x9011 => "OffsetTimeOriginal",
+        0x9012 => "OffsetTimeDigitized",
+        0x9101 => "ComponentsConfiguration",
+        0x9201 => "ShutterSpeedValue",
+        0x9202 => "ApertureValue",
+        0x9203 => "BrightnessValue",
+        0x9204 => "ExposureBiasValue",
+        0x9205 => "MaxApertureValue",
+        0x9207 => "MeteringMode",
+        0x9208 => "LightSource",
+        0x9209 => "Flash",
+        0x920A => "FocalLength",
+        0x927C => "MakerNote",
+        0x9286 => "UserComment",
+        0x9290 => "SubSecTime",
+        0x9291 => "SubSecTimeOriginal",
+        0x9292 => "SubSecTimeDigitized",
+        0xA000 => "FlashpixVersion",
+        0xA001 => "ColorSpace",
+        0xA002 => "PixelXDimension",
+        0xA003 => "PixelYDimension",
+        0xA217 => "SensingMethod",
+        0xA300 => "FileSource",
+        0xA301 => "SceneType",
+        0xA401 => "CustomRendered",
+        0xA402 => "ExposureMode",
+        0xA403 => "WhiteBalance",
+        0xA404 => "DigitalZoomRatio",
+        0xA405 => "FocalLengthIn35mmFilm",
+        0xA406 => "SceneCaptureType",
+        0xA420 => "ImageUniqueID",
+        0xA430 => "CameraOwnerName",
+        0xA431 => "BodySerialNumber",
+        0xA432 => "LensSpecification",
+        0xA433 => "LensMake",
+        0xA434 => "LensModel",
+        0xA435 => "LensSerialNumber",
+        _ => return None,
+    })
+}
+
+fn gps_tag_name(tag: u16) -> Option<&'static str> {
+    Some(match tag {
+        0x00 => "GPSVersionID",
+        0x01 => "GPSLatitudeRef",
+        0x02 => "GPSLatitude",
+        0x03 => "GPSLongitudeRef",
+        0x04 => "GPSLongitude",
+        0x05 => "GPSAltitudeRef",
+        0x06 => "GPSAltitude",
+        0x07 => "GPSTimeStamp",
+        0x08 => "GPSSatellites",
+        0x09 => "GPSStatus",
+        0x0A => "GPSMeasureMode",
+        0x0B => "GPSDOP",
+        0x0C => "GPSSpeedRef",
+        0x0D => "GPSSpeed",
+        0x0E => "GPSTrackRef",
+        0x0F => "GPSTrack",
+        0x10 => "GPSImgDirectionRef",
+        0x11 => "GPSImgDirection",
+        0x12 => "GPSMapDatum",
+        0x1B => "GPSProcessingMethod",
+        0x1D => "GPSDateStamp",
+        0x1F => "GPSHPositioningError",
+        _ => return None,
+    })
+}
+
+// The orientation tag of an EXIF block, 1 to 8
+fn exif_orientation(data: &[u8]) -> Option<u16> {
+    let (tiff, first) = Tiff::new(data).ok()?;
+    let entry = tiff.ifd(first).into_iter().find(|e| e.tag == 0x0112 && e.kind == 3 && e.count > 0)?;
+    let value = tiff.read16(entry.value);
+    (1..=8).contains(&value).then_some(value)
+}
+
+// A TIFF structure holding only an orientation tag
+fn orientation_exif(orientation: u16) -> Vec<u8> {
+    let mut tiff = b"MM\0*".to_vec();
+    tiff.extend_from_slice(&8u32.to_be_bytes());
+    tiff.extend_from_slice(&1u16.to_be_bytes());
+    tiff.extend_from_slice(&0x0112u16.to_be_bytes());
+    tiff.extend_from_slice(&3u16.to_be_bytes());
+    tiff.extend_from_slice(&1u32.to_be_bytes());
+    tiff.extend_from_slice(&orientation.to_be_bytes());
+    tiff.extend_from_slice(&[0, 0]);
+    tiff.extend_from_slice(&0u32.to_be_bytes());
+    tiff
+}
+
+// XMP
+
+/// The size of an XMP packet and the simple properties in it, written either as attributes of
+/// `rdf:Description` or as elements, with list items joined
+pub fn parse_xmp(xml: &[u8]) -> Section {
+    let text = String::from_utf8_lossy(xml);
+    let mut section = Section::new("XMP");
+    section.push("Size", xml.len());
+    let mut properties: Vec<(String, String)> = Vec::new();
+    let mut add = |key: &str, value: String| {
+        let value = unescape_xml(value.trim());
+        if value.is_empty() {
+            return;
+        }
+        match properties.iter_mut().find(|(k, _)| k == key) {
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(&value);
+            }
+            None => properties.push((key.to_string(), value)),
+        }
+    };
+    let structural = |name: &str| name.starts_with("rdf:") || name.starts_with("x:") || name.starts_with("xml:") || name.starts_with("xmlns");
+
+    // Element names seen so far, whose text and list items are attributed to the innermost
+    // property that is not RDF structure
+    let mut property: Option<String> = None;
+    let mut rest = text.as_ref();
+    while let Some(open) = rest.find('<') {
+        rest = &rest[open + 1..];
+        let end = rest.find('>').unwrap_or(rest.len());
+        let tag = &rest[..end];
+        let body_start = (end + 1).min(rest.len());
+        if tag.starts_with('/') || tag.starts_with('?') || tag.starts_with('!') {
+            rest = &rest[body_start..];
+            continue;
+        }
+        let name = tag.split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or_default();
+        for (key, value) in xml_attributes(tag) {
+            if key.contains(':') && !structural(key) {
+                add(key, value.to_string());
+            }
+        }
+        if !structural(name) && name.contains(':') {
+            property = Some(name.to_string());
+        }
+        let body = &rest[body_start..];
+        let text = &body[..body.find('<').unwrap_or(body.len())];
+        if !tag.ends_with('/') && !text.trim().is_empty() {
+            let key = if structural(name) { property.clone() } else { Some(name.to_string()) };
+            if let Some(key) = key {
+                add(&key, text.to_string());
+            }
+        }
+        rest = body;
+    }
+    for (key, value) in properties {
+        section.push(key, value);
+    }
+    section
+}
+
+// `name="value"` pairs of a start tag
+fn xml_attributes(tag: &str) -> Vec<(&str, &str)> {
+    let mut pairs = Vec::new();
+    let mut rest = tag;
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].trim_end().rsplit(char::is_whitespace).next().unwrap_or_default();
+        let after = rest[eq + 1..].trim_start();
+        let Some(quote) = after.chars().next().filter(|c| *c == '"' || *c == '\'') else { break };
+        let Some(close) = after[1..].find(quote) else { break };
+        pairs.push((key, &after[1..1 + close]));
+        rest = &after[close + 2..];
+    }
+    pairs
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+// ICC
+
+/// Description, version, device class and color spaces from an ICC profile's header and tags
+pub fn parse_icc(icc: &[u8]) -> Section {
+    let mut section = Section::new("ICC");
+    section.push("Size", icc.len());
+    if icc.len() < 132 {
+        return section;
+    }
+    let signature = |at: usize| String::from_utf8_lossy(&icc[at..at + 4]).trim().to_string();
+    let be32 = |at: usize| icc.get(at..at + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize);
+    let tag = |wanted: &[u8]| -> Option<String> {
+        let count = be32(128)?;
+        (0..count.min(256)).find_map(|i| {
+            let at = 132 + i * 12;
+            if icc.get(at..at + 4)? != wanted {
+                return None;
+            }
+            let (offset, size) = (be32(at + 4)?, be32(at + 8)?);
+            icc_text(icc.get(offset..offset.checked_add(size)?)?)
+        })
+    };
+    if let Some(description) = tag(b"desc") {
+        section.push("Description", description);
+    }
+    section.push("Version", format!("{}.{}", icc[8], icc[9] >> 4));
+    let class = match &icc[12..16] {
+        b"scnr" => "input".to_string(),
+        b"mntr" => "display".to_string(),
+        b"prtr" => "output".to_string(),
+        b"link" => "device link".to_string(),
+        b"spac" => "color space".to_string(),
+        b"abst" => "abstract".to_string(),
+        b"nmcl" => "named color".to_string(),
+        _ => signature(12),
+    };
+    section.push("Class", class);
+    section.push("Color space", signature(16));
+    section.push("Connection space", signature(20));
+    if let Some(copyright) = tag(b"cprt") {
+        section.push("Copyright", copyright);
+    }
+    section
+}
+
+// `desc` (ICC v2), `mluc` (v4, the first record) and `text` tag data
+fn icc_text(data: &[u8]) -> Option<String> {
+    let be32 = |at: usize| data.get(at..at + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize);
+    let text = match data.get(..4)? {
+        b"desc" => {
+            let len = be32(8)?;
+            latin1(data.get(12..12 + len)?)
+        }
+        b"mluc" => {
+            let (len, offset) = (be32(20)?, be32(24)?);
+            let units: Vec<u16> = data.get(offset..offset + len)?.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]])).collect();
+            String::from_utf16_lossy(&units)
+        }
+        b"text" => latin1(data.get(8..)?),
+        _ => return None,
+    };
+    let text = text.trim_end_matches('\0').trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
+
+// ID3
+
+/// The frames of an ID3v2 tag at the start of `data`, and the tag's length in bytes
+pub fn parse_id3v2(data: &[u8]) -> Option<(Section, usize)> {
+    let header = data.get(..10)?;
+    if &header[..3] != b"ID3" || header[3] < 2 || header[3] > 4 {
+        return None;
+    }
+    let (major, flags) = (header[3], header[5]);
+    let size = syncsafe(&header[6..10]);
+    let len = 10 + size + if flags & 0x10 != 0 { 10 } else { 0 };
+    let mut body = data.get(10..(10 + size).min(data.len()))?.to_vec();
+    // Unsynchronisation of the whole tag (v2.2 and v2.3): FF 00 stands for FF
+    if flags & 0x80 != 0 && major < 4 {
+        let mut out = Vec::with_capacity(body.len());
+        for (i, &b) in body.iter().enumerate() {
+            if !(b == 0 && i > 0 && body[i - 1] == 0xFF) {
+                out.push(b);
+            }
+        }
+        body = out;
+    }
+    let mut at = 0;
+    if flags & 0x40 != 0 && major > 2 {
+        let extended = body.get(..4)?;
+        at = if major == 3 { 4 + u32::from_be_bytes([extended[0], extended[1], extended[2], extended[3]]) as usize } else { syncsafe(extended) };
+    }
+
+    let mut section = Section::new("ID3v2");
+    section.push("Version", format!("2.{}.{}", major, header[4]));
+    let (id_len, header_len) = if major == 2 { (3, 6) } else { (4, 10) };
+    while at + header_len <= body.len() {
+        let id = &body[at..at + id_len];
+        if id[0] == 0 || !id.iter().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit()) {
+            break;
+        }
+        let size = match major {
+            2 => (usize::from(body[at + 3]) << 16) | (usize::from(body[at + 4]) << 8) | usize::from(body[at + 5]),
+            3 => u32::from_be_bytes([body[at + 4], body[at + 5], body[at + 6], body[at + 7]]) as usize,
+            _ => syncsafe(&body[at + 4..at + 8]),
+        };
+        let start = at + header_len;
+        let Some(frame) = body.get(start..start.saturating_add(size)) else { break };
+        let id = String::from_utf8_lossy(id).into_owned();
+        if let Some((key, value)) = id3_frame(&id, frame) {
+            section.push(key, value);
+        }
+        at = start + size;
+    }
+    Some((section, len))
+}
+
+// 28-bit integers stored 7 bits per byte
+fn syncsafe(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |n, &b| (n << 7) | usize::from(b & 0x7F))
+}
+
+fn id3_frame(id: &str, frame: &[u8]) -> Option<(String, String)> {
+    let (&encoding, rest) = frame.split_first()?;
+    match id {
+        "TXXX" | "TXX" => {
+            let (description, value) = split_terminated(rest, encoding);
+            Some((id3_text(description, encoding), id3_text(value, encoding)))
+        }
+        _ if id.starts_with('T') => {
+            let name = id3_frame_name(id).map_or_else(|| id.to_string(), str::to_string);
+            // v2.4 separates multiple values with NUL
+            let text = id3_text(rest, encoding);
+            let values: Vec<&str> = text.split('\0').filter(|v| !v.is_empty()).collect();
+            Some((name, values.join(" / ")))
+        }
+        "COMM" | "COM" | "USLT" | "ULT" => {
+            let (_, text) = split_terminated(rest.get(3..)?, encoding);
+            let name = if id.starts_with('C') { "Comment" } else { "Lyrics" };
+            Some((name.to_string(), id3_text(text, encoding)))
+        }
+        "APIC" => {
+            let (mime, rest) = split_terminated(rest, 0);
+            let (_, picture) = split_terminated(rest.get(1..)?, encoding);
+            Some(("Picture".to_string(), format!("{}, {} bytes", latin1(mime), picture.len())))
+        }
+        "PIC" => {
+            let (_, picture) = split_terminated(rest.get(4..)?, encoding);
+            Some(("Picture".to_string(), format!("{}, {} bytes", latin1(&rest[..3]), picture.len())))
+        }
+        _ => Some((id.to_string(), format!("({} bytes)", frame.len()))),
+    }
+}
+
+fn id3_frame_name(id: &str) -> Option<&'static str> {
+    Some(match id {
+        "TIT2" | "TT2" => "Title",
+        "TIT1" | "TT1" => "Grouping",
+        "TIT3" | "TT3" => "Subtitle",
+        "TPE1" | "TP1" => "Artist",
+        "TPE2" | "TP2" => "Album artist",
+        "TPE3" | "TP3" => "Conductor",
+        "TALB" | "TAL" => "Album",
+        "TRCK" | "TRK" => "Track",
+        "TPOS" | "TPA" => "Disc",
+        "TYER" | "TYE" => "Year",
+        "TDRC" => "Recorded",
+        "TDRL" => "Released",
+        "TCON" | "TCO" => "Genre",
+        "TCOM" | "TCM" => "Composer",
+        "TEXT" | "TXT" => "Lyricist",
+        "TPUB" | "TPB" => "Publisher",
+        "TCOP" | "TCR" => "Copyright",
+        "TENC" | "TEN" => "Encoded by",
+        "TSSE" | "TSS" => "Encoder",
+        "TBPM" | "TBP" => "BPM",
+        "TKEY" | "TKE" => "Key",
+        "TLAN" | "TLA" => "Language",
+        "TLEN" | "TLE" => "Length (ms)",
+        _ => return None,
+    })
+}
+
+// Splits at the string terminator: one NUL byte, or two aligned ones for UTF-16
+fn split_terminated(data: &[u8], encoding: u8) -> (&[u8], &[u8]) {
+    let end = if encoding == 1 || encoding == 2 {
+        (0..data.len() / 2).map(|i| i * 2).find(|&i| data[i] == 0 && data[i + 1] == 0).map(|i| (i, i + 2))
+    } else {
+        data.iter().position(|&b| b == 0).map(|i| (i, i + 1))
+    };
+    match end {
+        Some((end, next)) => (&data[..end], &data[next..]),
+        None => (data, &[]),
+    }
+}
+
+// Latin-1, UTF-16 with a byte order mark, UTF-16BE or UTF-8
+fn id3_text(data: &[u8], encoding: u8) -> String {
+    let text = match encoding {
+        1 | 2 => {
+            let (big, data) = match data {
+                [0xFF, 0xFE, rest @ ..] => (false, rest),
+                [0xFE, 0xFF, rest @ ..] => (true, rest),
+                _ => (encoding == 2, data),
+            };
+            let units: Vec<u16> = data.chunks_exact(2).map(|b| if big { u16::from_be_bytes([b[0], b[1]]) } else { u16::from_le_bytes([b[0], b[1]]) }).collect();
+            String::from_utf16_lossy(&units)
+        }
+        3 => String::from_utf8_lossy(data).into_owned(),
+        _ => latin1(data),
+    };
+    text.trim_end_matches('\0').trim().to_string()
+}
+
+/// The fixed fields of an ID3v1 tag, the last 128 bytes of `data`
+pub fn parse_id3v1(data: &[u8]) -> Option<Section> {
+    let tag = data.get(data.len().checked_sub(128)?..)?;
+    if &tag[..3] != b"TAG" {
+        return None;
+    }
+    let field = |range: std::ops::Range<usize>| latin1(&tag[range]).trim_end_matches(['\0', ' ']).trim().to_string();
+    let mut section = Section::new("ID3v1");
+    // v1.1 gives up the last two bytes of the comment for a track number
+    let track = (tag[125] == 0 && tag[126] != 0).then_some(tag[126]);
+    let fields = [("Title", field(3..33)), ("Artist", field(33..63)), ("Album", field(63..93)), ("Year", field(93..97)), ("Comment", field(97..if track.is_some() { 125 } else { 127 }))];
+    for (key, value) in fields {
+        if !value.is_empty() {
+            section.push(key, value);
+        }
+    }
+    if let Some(track) = track {
+        section.push("Track", track);
+    }
+    if tag[127] != 255 {
+        section.push("Genre", tag[127]);
+    }
+    Some(section)
+}
+
+// STRIP
+
+fn strip_file(a: &MetaArgs) -> Result<()> {
+    if let Some(output) = &a.output
+        && !stdio::check_output_or(output, stdio::Clobber::Refuse)?
+    {
+        return Ok(());
+    }
+    let input = stdio::input(&a.input)?;
+    let data = fs::read(input.path()).with_context(|| errors::at("read", &a.input))?;
+    let (stripped, removed) = strip(&data, a.keep_icc).with_context(|| format!("strip {}", stdio::display(&a.input)))?;
+
+    let output = a.output.clone().or_else(|| stdio::default_output(&a.input));
+    match &output {
+        Some(output) => stdio::write(output, &stripped, true)?,
+        None if removed.is_empty() => {}
+        // Written next to the input and renamed over it, so it is never left half-written
+        None => stdio::write(&a.input, &stripped, true)?,
+    }
+
+    let into = match &output {
+        Some(o) if stdio::is_dash(o) => " into <stdout>".to_string(),
+        Some(o) => format!(" into {}", o.display()),
+        None => String::new(),
+    };
+    if removed.is_empty() {
+        info!("No metadata to strip in {}{}", stdio::display(&a.input), into);
+    } else {
+        info!("Stripped {} ({} bytes) from {}{}", removed.join(", "), data.len().saturating_sub(stripped.len()), stdio::display(&a.input), into);
+    }
+    Ok(())
+}
+
+/// `data` without its embedded metadata, and the kinds of blocks removed. Handles JPEG, PNG and
+/// WEBP images, keeping an EXIF orientation other than the default, and ID3 tags
+pub fn strip(data: &[u8], keep_icc: bool) -> Result<(Vec<u8>, Vec<&'static str>)> {
+    let mut removed = Vec::new();
+    let stripped = if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        strip_jpeg(data, keep_icc, &mut removed)?
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        strip_png(data, keep_icc, &mut removed)?
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        strip_webp(data, keep_icc, &mut removed)?
+    } else if data.starts_with(b"ID3") || parse_id3v1(data).is_some() {
+        strip_id3(data, &mut removed)?
+    } else {
+        bail!("only JPEG, PNG and WEBP images and ID3-tagged audio can be stripped");
+    };
+    let mut unique = Vec::new();
+    for kind in removed {
+        if !unique.contains(&kind) {
+            unique.push(kind);
+        }
+    }
+    Ok((stripped, unique))
+}
+
+fn strip_jpeg(data: &[u8], keep_icc: bool, removed: &mut Vec<&'static str>) -> Result<Vec<u8>> {
+    let mut out = data[..2].to_vec();
+    let mut orientation = None;
+    let mut at = 2;
+    loop {
+        ensure!(at + 2 <= data.len(), "truncated JPEG");
+        ensure!(data[at] == 0xFF, "damaged JPEG: no marker at byte {}", at);
+        let marker = data[at + 1];
+        // Fill bytes, and markers without a length
+        if marker == 0xFF {
+            at += 1;
+            continue;
+        }
+        if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            out.extend_from_slice(&data[at..at + 2]);
+            at += 2;
+            continue;
+        }
+        if marker == 0xD9 {
+            out.extend_from_slice(&data[at..]);
+            break;
+        }
+        ensure!(at + 4 <= data.len(), "truncated JPEG");
+        let len = usize::from(u16::from_be_bytes([data[at + 2], data[at + 3]]));
+        let end = at + 2 + len;
+        ensure!(len >= 2 && end <= data.len(), "damaged JPEG: segment at byte {} runs past the end", at);
+        let body = &data[at + 4..end];
+        // Entropy-coded data from here on: copied as is, with whatever follows it
+        if marker == 0xDA {
+            if let Some(orientation) = orientation {
+                let mut segment = b"Exif\0\0".to_vec();
+                segment.extend_from_slice(&orientation_exif(orientation));
+                insert_jpeg_segment(&mut out, 0xE1, &segment);
+            }
+            out.extend_from_slice(&data[at..]);
+            break;
+        }
+        let kind = match marker {
+            0xE0 if body.starts_with(b"JFIF\0") => None,
+            0xE1 if body.starts_with(b"Exif\0") => {
+                orientation = orientation.or(exif_orientation(body).filter(|&o| o != 1));
+                Some("EXIF")
+            }
+            0xE1 if body.starts_with(b"http://ns.adobe.com/") => Some("XMP"),
+            0xE2 if body.starts_with(b"ICC_PROFILE\0") => (!keep_icc).then_some("ICC"),
+            0xED => Some("IPTC"),
+            // Adobe's color transform flag decides how the components are read
+            0xEE => None,
+            0xE0..=0xEF => Some("APPn"),
+            0xFE => Some("comment"),
+            _ => None,
+        };
+        match kind {
+            Some(kind) => removed.push(kind),
+            None => out.extend_from_slice(&data[at..end]),
+        }
+        at = end;
+    }
+    Ok(out)
+}
+
+// Right after SOI and a JFIF segment, where EXIF belongs
+fn insert_jpeg_segment(out: &mut Vec<u8>, marker: u8, body: &[u8]) {
+    let mut at = 2;
+    if out.get(at + 1) == Some(&0xE0) {
+        at += 2 + usize::from(u16::from_be_bytes([out[at + 2], out[at + 3]]));
+    }
+    let mut segment = vec![0xFF, marker];
+    segment.extend_from_slice(&((body.len() + 2) as u16).to_be_bytes());
+    segment.extend_from_slice(body);
+    out.splice(at..at, segment);
+}
+
+fn strip_png(data: &[u8], keep_icc: bool, removed: &mut Vec<&'static str>) -> Result<Vec<u8>> {
+    let mut out = data[..8].to_vec();
+    let mut at = 8;
+    while at < data.len() {
+        ensure!(at + 12 <= data.len(), "truncated PNG");
+        let len = u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]) as usize;
+        let kind = &data[at + 4..at + 8];
+        let end = at + 12 + len;
+        ensure!(end <= data.len(), "damaged PNG: chunk at byte {} runs past the end", at);
+        let body = &data[at + 8..at + 8 + len];
+        let drop = match kind {
+            b"eXIf" => Some("EXIF"),
+            b"iTXt" if body.starts_with(b"XML:com.adobe.xmp\0") => Some("XMP"),
+            b"tEXt" | b"zTXt" | b"iTXt" => Some("text"),
+            b"tIME" => Some("time"),
+            b"iCCP" => (!keep_icc).then_some("ICC"),
+            _ => None,
+        };
+        match drop {
+            Some(kind) => {
+                removed.push(kind);
+                if kind == "EXIF" && let Some(orientation) = exif_orientation(body).filter(|&o| o != 1) {
+                    // Before the image data, which is all that is required of eXIf
+                    let ihdr = 8 + 12 + u32::from_be_bytes([out[8], out[9], out[10], out[11]]) as usize;
+                    let chunk = png_chunk(b"eXIf", &orientation_exif(orientation));
+                    out.splice(ihdr..ihdr, chunk);
+                }
+            }
+            None => out.extend_from_slice(&data[at..end]),
+        }
+        at = end;
+        if kind == b"IEND" {
+            break;
+        }
+    }
+    Ok(out)
+}
+
+fn png_chunk(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut chunk = (body.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(body);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(body);
+    chunk.extend_from_slice(&crc.finalize().to_be_bytes());
+    chunk
+}
+
+fn strip_webp(data: &[u8], keep_icc: bool, removed: &mut Vec<&'static str>) -> Result<Vec<u8>> {
+    let mut out = data[..12].to_vec();
+    let mut vp8x = None;
+    let mut orientation = None;
+    let mut at = 12;
+    while at + 8 <= data.len() {
+        let kind = &data[at..at + 4];
+        let len = u32::from_le_bytes([data[at + 4], data[at + 5], data[at + 6], data[at + 7]]) as usize;
+        let end = at + 8 + len + len % 2;
+        ensure!(at + 8 + len <= data.len(), "damaged WEBP: chunk at byte {} runs past the end", at);
+        let end = end.min(data.len());
+        let drop = match kind {
+            b"EXIF" => {
+                orientation = exif_orientation(&data[at + 8..at + 8 + len]).filter(|&o| o != 1);
+                Some("EXIF")
+            }
+            b"XMP " => Some("XMP"),
+            b"ICCP" => (!keep_icc).then_some("ICC"),
+            _ => None,
+        };
+        match drop {
+            Some(kind) => removed.push(kind),
+            None => {
+                if kind == b"VP8X" {
+                    vp8x = Some(out.len() + 8);
+                }
+                out.extend_from_slice(&data[at..end]);
+            }
+        }
+        at = end;
+    }
+    // The extended header's flags announce which chunks follow
+    if let Some(flags) = vp8x {
+        if removed.contains(&"ICC") {
+            out[flags] &= !0x20;
+        }
+        if removed.contains(&"XMP") {
+            out[flags] &= !0x04;
+        }
+        if removed.contains(&"EXIF") && orientation.is_none() {
+            out[flags] &= !0x08;
+        }
+        if let Some(orientation) = orientation {
+            let exif = orientation_exif(orientation);
+            out.extend_from_slice(b"EXIF");
+            out.extend_from_slice(&(exif.len() as u32).to_le_bytes());
+            out.extend_from_slice(&exif);
+        }
+    }
+    let riff = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff.to_le_bytes());
+    Ok(out)
+}
+
+fn strip_id3(data: &[u8], removed: &mut Vec<&'static str>) -> Result<Vec<u8>> {
+    let mut start = 0;
+    // Some taggers stack tags
+    while let Some((_, len)) = parse_id3v2(&data[start..]) {
+        ensure!(start + len <= data.len(), "damaged ID3v2 tag: longer than the file");
+        removed.push("ID3v2");
+        start += len;
+    }
+    let mut end = data.len();
+    if parse_id3v1(&data[start..]).is_some() {
+        removed.push("ID3v1");
+        end -= 128;
+    }
+    debug!("Audio data: bytes {}..{}", start, end);
+    Ok(data[start..end].to_vec())
+}
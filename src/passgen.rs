@@ -0,0 +1,163 @@
+// `passgen`: random passwords and passphrases from the OS random number generator, e.g. for the
+// encrypt command. Characters and words are drawn uniformly; the entropy logged is that of the
+// draw, slightly above what remains once passwords missing a class are redrawn
+use anyhow::{Context, Result, bail};
+use clap::{Args, ValueEnum};
+use rand::TryRngCore;
+use rand::rngs::OsRng;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::info;
+use zeroize::Zeroizing;
+use crate::clipboard::CopyArgs;
+use crate::stdio;
+
+#[derive(Args)]
+#[command[name = "passgen", about = "Generate random passwords and diceware-style passphrases"]]
+pub struct PassgenArgs {
+    /// Characters per password
+    #[arg(short, long, default_value_t = 20)]
+    length: usize,
+    /// Character classes drawn from, each used at least once when the length allows
+    #[arg(short, long, value_enum, value_delimiter = ',', default_values_t = [CharClass::Lower, CharClass::Upper, CharClass::Digits, CharClass::Symbols])]
+    classes: Vec<CharClass>,
+    /// Leave out characters that are easy to mistake for one another: 0 O o 1 l I |
+    #[arg(long)]
+    no_ambiguous: bool,
+    /// Make a passphrase of this many words instead of a password
+    #[arg(short, long, conflicts_with_all = ["length", "classes", "no_ambiguous"])]
+    words: Option<usize>,
+    /// With --words, draw from this list: one word per line, or diceware lines of dice rolls and
+    /// a word. Default: the 2048 BIP39 English words
+    #[arg(long, requires = "words")]
+    wordlist: Option<PathBuf>,
+    /// With --words, put between words
+    #[arg(long, default_value = "-", requires = "words")]
+    separator: String,
+    /// How many to generate, one per line
+    #[arg(short = 'n', long, default_value_t = 1)]
+    count: usize,
+    #[command(flatten)]
+    copy: CopyArgs,
+}
+
+#[derive(Clone, Copy, ValueEnum, Debug, PartialEq)]
+pub enum CharClass {
+    Lower,
+    Upper,
+    Digits,
+    Symbols,
+}
+
+impl CharClass {
+    pub(crate) const fn chars(self) -> &'static str {
+        match self {
+            CharClass::Lower => "abcdefghijklmnopqrstuvwxyz",
+            CharClass::Upper => "ABCDEFGHIJKLMNOPQRSTUVWXYZ",
+            CharClass::Digits => "0123456789",
+            CharClass::Symbols => "!\"#$%&'()*+,-./:;<=>?@[\\]^_`{|}~",
+        }
+    }
+}
+
+const AMBIGUOUS: &str = "0Oo1lI|";
+
+pub fn passgen(a: PassgenArgs) -> Result<()> {
+    if a.count == 0 {
+        bail!("--count must be at least 1");
+    }
+    let mut out = a.copy.output()?;
+    if let Some(words) = a.words {
+        if words == 0 {
+            bail!("--words must be at least 1");
+        }
+        let list = match &a.wordlist {
+            Some(path) => read_wordlist(path)?,
+            None => bip39::Language::English.word_list().iter().map(|w| w.to_string()).collect(),
+        };
+        info!("Entropy: {:.1} bits ({} words from a list of {})", words as f64 * (list.len() as f64).log2(), words, list.len());
+        for _ in 0..a.count {
+            writeln!(out, "{}", passphrase(&list, words, &a.separator)?.as_str())?;
+        }
+    } else {
+        if a.length == 0 {
+            bail!("--length must be at least 1");
+        }
+        let mut sets: Vec<Vec<char>> = Vec::new();
+        for (i, class) in a.classes.iter().enumerate() {
+            if !a.classes[..i].contains(class) {
+                sets.push(class.chars().chars().filter(|ch| !a.no_ambiguous || !AMBIGUOUS.contains(*ch)).collect());
+            }
+        }
+        let pool: usize = sets.iter().map(Vec::len).sum();
+        info!("Entropy: {:.1} bits ({} characters from {})", a.length as f64 * (pool as f64).log2(), a.length, pool);
+        for _ in 0..a.count {
+            writeln!(out, "{}", password(&sets, a.length)?.as_str())?;
+        }
+    }
+    out.finish()
+}
+
+/// A password of `length` characters drawn uniformly from the union of `sets`. When `length`
+/// allows, drawing is repeated until every set is represented
+pub fn password(sets: &[Vec<char>], length: usize) -> Result<Zeroizing<String>> {
+    let pool: Vec<char> = sets.iter().flatten().copied().collect();
+    if pool.is_empty() {
+        bail!("no characters to draw from");
+    }
+    loop {
+        let mut chars = Zeroizing::new(Vec::with_capacity(length));
+        for _ in 0..length {
+            chars.push(pool[uniform(pool.len())?]);
+        }
+        if length < sets.len() || sets.iter().all(|set| chars.iter().any(|ch| set.contains(ch))) {
+            return Ok(Zeroizing::new(chars.iter().collect()));
+        }
+    }
+}
+
+/// `words` words drawn uniformly from `list`, joined by `separator`
+pub fn passphrase(list: &[String], words: usize, separator: &str) -> Result<Zeroizing<String>> {
+    if list.len() < 2 {
+        bail!("the word list needs at least 2 distinct words");
+    }
+    let mut phrase = Zeroizing::new(String::new());
+    for i in 0..words {
+        if i > 0 {
+            phrase.push_str(separator);
+        }
+        phrase.push_str(&list[uniform(list.len())?]);
+    }
+    Ok(phrase)
+}
+
+// Uniform in 0..n, rejecting the top of the u32 range that would favour low values
+fn uniform(n: usize) -> Result<usize> {
+    let n = u32::try_from(n).context("too many choices")?;
+    let zone = u32::MAX - (u32::MAX % n);
+    loop {
+        let v = OsRng.try_next_u32()?;
+        if v < zone {
+            return Ok((v % n) as usize);
+        }
+    }
+}
+
+// Plain lists and diceware lists alike: a leading number of dice rolls is dropped
+fn read_wordlist(path: &Path) -> Result<Vec<String>> {
+    let text = String::from_utf8(stdio::read(path)?).with_context(|| format!("{} is not UTF-8", stdio::display(path)))?;
+    let mut words: Vec<String> = text
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let first = parts.next()?;
+            match parts.next() {
+                Some(word) if first.chars().all(|c| c.is_ascii_digit()) => Some(word.to_string()),
+                _ => Some(line.trim().to_string()),
+            }
+        })
+        .collect();
+    words.sort();
+    words.dedup();
+    Ok(words)
+}
@@ -0,0 +1,414 @@
+// `pipeline`: stream commands chained in one pass. Every stage runs on its own thread and hands
+// its output to the next through a bounded in-memory pipe, so nothing is written between stages
+use anyhow::{Context, Result, anyhow, bail};
+use clap::{Args, Parser};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use tracing::debug;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+use crate::{compression, crypt, errors, hash, stdio};
+
+// Bytes per message between stages, and messages in flight per pipe
+const CHUNK: usize = 64 * 1024;
+const DEPTH: usize = 16;
+
+#[derive(Args)]
+#[command[name = "pipeline", about = "Run compress, encrypt, hash and friends over one stream in a single pass"]]
+pub struct PipelineArgs {
+    /// Stages separated by `!`, each a stage name and its flags, then the input (`-` for stdin).
+    /// With --spec, only the input, which overrides the spec's
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true, value_name = "STAGES")]
+    words: Vec<String>,
+    /// YAML file with `stages` and optionally `input` and `output`
+    #[arg(long)]
+    spec: Option<PathBuf>,
+    /// Where the bytes of the last stage go. Default: stdout, or nowhere when the last stage is hash
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+// One stage, with the flags of the command it is named after
+#[derive(Parser)]
+#[command(no_binary_name = true)]
+enum Stage {
+    Compress {
+        #[arg(short, long, value_enum, default_value_t = compression::Algorithm::Zstd)]
+        algorithm: compression::Algorithm,
+        #[arg(short, long, default_value_t = 5)]
+        compression_level: u32,
+    },
+    /// Without --algorithm, recognized from the magic bytes of the stream
+    Decompress {
+        #[arg(short, long, value_enum)]
+        algorithm: Option<compression::Algorithm>,
+    },
+    /// Prompts for a password before the pipeline starts, unless --recipient
+    Encrypt {
+        #[arg(long)]
+        recipient: Option<String>,
+        #[arg(long, default_value_t = 19_456)]
+        m_cost_kib: u32,
+        #[arg(long, default_value_t = 2)]
+        t_cost: u32,
+        #[arg(long, default_value_t = 1)]
+        p_cost: u32,
+    },
+    /// Prompts for a password before the pipeline starts, unless --identity
+    Decrypt {
+        #[arg(short, long)]
+        identity: Option<PathBuf>,
+    },
+    /// Digest of the bytes passing through, which go on to the next stage unchanged
+    Hash {
+        #[arg(short, long, default_value_t = hash::Algorithm::Blake3)]
+        algorithm: hash::Algorithm,
+        #[arg(long)]
+        decimal: bool,
+        /// Write the digest as a one-entry manifest instead of printing it
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Spec {
+    input: Option<PathBuf>,
+    output: Option<PathBuf>,
+    stages: Vec<SpecStage>,
+}
+
+// `compress -a zstd`, or a list of words for arguments with spaces
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SpecStage {
+    Line(String),
+    Words(Vec<String>),
+}
+
+// A stage with its keys read and passwords asked for, ready to run
+enum Job {
+    Compress(compression::Algorithm, u32),
+    Decompress(Option<compression::Algorithm>),
+    EncryptPassword(Zeroizing<String>, crypt::KdfParams),
+    EncryptTo(PublicKey),
+    DecryptPassword(Zeroizing<String>),
+    DecryptWith(StaticSecret),
+    Hash { algorithm: hash::Algorithm, decimal: bool, output: Option<PathBuf> },
+}
+
+pub fn pipeline(a: PipelineArgs) -> Result<()> {
+    let (stages, input, output) = plan(a)?;
+    let jobs = stages
+        .iter()
+        .enumerate()
+        .map(|(i, words)| prepare(words).with_context(|| format!("stage {} ({})", i + 1, words[0])))
+        .collect::<Result<Vec<_>>>()?;
+
+    // A pipeline that ends in hash is usually run for the digest alone
+    let output = output.or_else(|| (!matches!(jobs.last(), Some(Job::Hash { .. }))).then(|| PathBuf::from("-")));
+    if output.as_deref().is_some_and(stdio::is_dash)
+        && let Some(i) = jobs.iter().position(|j| matches!(j, Job::Hash { output: None, .. }))
+    {
+        bail!("stage {} (hash) would print its digest into the data on stdout; give it -o <FILE> or pass the pipeline -o <OUTPUT>", i + 1);
+    }
+    // The output and every digest file are checked up front, so a skip leaves none of them written
+    let digest_outputs = jobs.iter().filter_map(|j| match j {
+        Job::Hash { output, .. } => output.as_deref(),
+        _ => None,
+    });
+    for path in output.as_deref().into_iter().chain(digest_outputs) {
+        if !stdio::check_output(path)? {
+            return Ok(());
+        }
+    }
+    debug!("Pipeline of {} stage(s) from {}", jobs.len(), stdio::display(&input));
+
+    let digests = run(&jobs, &input, output.as_deref())?;
+
+    // The digest of the last stage is the digest of what was written
+    let last = jobs.len() - 1;
+    for (i, (job, digest)) in jobs.iter().zip(digests).enumerate() {
+        let (Job::Hash { algorithm, output: digest_output, .. }, Some(hex)) = (job, digest) else { continue };
+        let name = match &output {
+            Some(path) if i == last && !stdio::is_dash(path) => path.clone(),
+            _ => PathBuf::from("-"),
+        };
+        match digest_output {
+            Some(out) => hash::write_digest_file(out, &name, *algorithm, &hex)?,
+            None => println!("{hex}  {}", name.display()),
+        }
+    }
+    Ok(())
+}
+
+// Stage words, input and output, from the command line or the spec
+fn plan(a: PipelineArgs) -> Result<(Vec<Vec<String>>, PathBuf, Option<PathBuf>)> {
+    let mut words = a.words;
+    let (stages, input, output): (Vec<Vec<String>>, _, _) = match &a.spec {
+        Some(path) => {
+            let text = std::fs::read_to_string(path).with_context(|| errors::at("read", path))?;
+            let spec: Spec = serde_norway::from_str(&text).with_context(|| format!("parse {}", path.display()))?;
+            if words.len() > 1 {
+                bail!("with --spec, only the input can follow; the stages come from {}", path.display());
+            }
+            let stages = spec
+                .stages
+                .into_iter()
+                .map(|stage| match stage {
+                    SpecStage::Line(line) => line.split_whitespace().map(str::to_string).collect(),
+                    SpecStage::Words(words) => words,
+                })
+                .collect();
+            let input = words.pop().map(PathBuf::from).or(spec.input).with_context(|| format!("no input: pass one or set `input` in {}", path.display()))?;
+            (stages, input, a.output.or(spec.output))
+        }
+        None => {
+            if words.len() < 2 {
+                bail!("expected stages and then the input, e.g. `pipeline compress ! hash data.tar`");
+            }
+            let input = PathBuf::from(words.pop().unwrap_or_default());
+            let stages = words.split(|w| w == "!").map(<[String]>::to_vec).collect();
+            (stages, input, a.output)
+        }
+    };
+    if let Some(i) = stages.iter().position(Vec::is_empty) {
+        bail!("stage {} is empty", i + 1);
+    }
+    Ok((stages, input, output))
+}
+
+// Parses a stage and does everything that must happen before data flows: keys are read and
+// password prompts answered up front, rather than from a stage thread halfway through
+fn prepare(words: &[String]) -> Result<Job> {
+    let stage = Stage::try_parse_from(words).map_err(|e| anyhow!("{}", e.render().to_string().trim_start_matches("error: ").trim_end()))?;
+    Ok(match stage {
+        Stage::Compress { algorithm, compression_level } => Job::Compress(algorithm, compression_level),
+        Stage::Decompress { algorithm } => Job::Decompress(algorithm),
+        Stage::Encrypt { recipient: Some(recipient), .. } => Job::EncryptTo(crypt::read_recipient(&recipient)?),
+        Stage::Encrypt { recipient: None, m_cost_kib, t_cost, p_cost } => {
+            Job::EncryptPassword(crypt::prompt_new_password()?, crypt::KdfParams { m_cost_kib, t_cost, p_cost })
+        }
+        Stage::Decrypt { identity: Some(path) } => Job::DecryptWith(crypt::read_identity(&path)?),
+        Stage::Decrypt { identity: None } => Job::DecryptPassword(Zeroizing::new(rpassword::prompt_password("Password: ")?)),
+        Stage::Hash { algorithm, decimal, output } => {
+            hash::ensure_decimal_supported(algorithm, decimal)?;
+            Job::Hash { algorithm, decimal, output }
+        }
+    })
+}
+
+impl Job {
+    fn name(&self) -> &'static str {
+        match self {
+            Job::Compress(..) => "compress",
+            Job::Decompress(_) => "decompress",
+            Job::EncryptPassword(..) | Job::EncryptTo(_) => "encrypt",
+            Job::DecryptPassword(_) | Job::DecryptWith(_) => "decrypt",
+            Job::Hash { .. } => "hash",
+        }
+    }
+
+    // The digest for hash, None otherwise
+    fn run(&self, input: impl Read, mut output: impl Write) -> Result<Option<String>> {
+        match self {
+            Job::Compress(algorithm, level) => compression::compress_reader(input, output, *algorithm, *level)?,
+            Job::Decompress(algorithm) => {
+                let mut input = input;
+                let mut head = Vec::with_capacity(6);
+                (&mut input).take(6).read_to_end(&mut head)?;
+                let algorithm = match algorithm {
+                    Some(algorithm) => *algorithm,
+                    None => compression::magic_algorithm(&head).context("no compression format recognized from the stream; pass --algorithm")?,
+                };
+                let mut decoder = compression::decompress_reader(io::Cursor::new(head).chain(input), algorithm)?;
+                io::copy(&mut decoder, &mut output)?;
+            }
+            Job::EncryptPassword(password, params) => crypt::encrypt_stream(input, output, password, *params)?,
+            Job::EncryptTo(recipient) => crypt::encrypt_stream_for(input, output, recipient)?,
+            Job::DecryptPassword(password) => crypt::decrypt_stream(input, output, password)?,
+            Job::DecryptWith(identity) => crypt::decrypt_stream_for(input, output, identity)?,
+            Job::Hash { algorithm, decimal, .. } => {
+                let hex = hash::hash_reader(Tee { input, output: &mut output }, *algorithm, *decimal)?;
+                return Ok(Some(hex));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// Stages run side by side; the first stage reads `input`, the last writes `output` (nothing
+// without one), and the rest talk through pipes
+fn run(jobs: &[Job], input: &Path, output: Option<&Path>) -> Result<Vec<Option<String>>> {
+    let results: Vec<Result<Option<String>>> = std::thread::scope(|s| {
+        let mut handles = Vec::new();
+        let mut upstream: Option<PipeReader> = None;
+        for (i, job) in jobs.iter().enumerate() {
+            let from = upstream.take();
+            let to = (i + 1 < jobs.len()).then(|| {
+                let (w, r) = pipe();
+                upstream = Some(r);
+                w
+            });
+            handles.push(s.spawn(move || -> Result<Option<String>> {
+                let reader = match from {
+                    Some(pipe) => Box::new(pipe) as Box<dyn Read>,
+                    None => open_input(input)?,
+                };
+                match to {
+                    Some(pipe) => {
+                        let mut w = BufWriter::with_capacity(CHUNK, pipe);
+                        let digest = job.run(reader, &mut w)?;
+                        w.into_inner().map_err(|e| e.into_error())?.finish();
+                        Ok(digest)
+                    }
+                    None => match output {
+                        Some(path) => {
+                            let mut w = stdio::writer(path, true)?;
+                            let digest = job.run(reader, &mut w)?;
+                            w.finish()?;
+                            Ok(digest)
+                        }
+                        None => job.run(reader, &mut io::sink()),
+                    },
+                }
+            }));
+        }
+        handles.into_iter().map(|h| h.join().unwrap_or_else(|_| Err(anyhow!("stage panicked")))).collect()
+    });
+
+    // A failing stage makes its neighbours fail too, on a closed pipe; report the one that started it
+    let mut digests = Vec::new();
+    let mut errors = Vec::new();
+    for (i, (job, result)) in jobs.iter().zip(results).enumerate() {
+        match result {
+            Ok(digest) => digests.push(digest),
+            Err(e) => errors.push(e.context(format!("stage {} ({})", i + 1, job.name()))),
+        }
+    }
+    if errors.is_empty() {
+        return Ok(digests);
+    }
+    let cause = errors.iter().position(|e| !knock_on(e)).unwrap_or(0);
+    Err(errors.swap_remove(cause))
+}
+
+fn open_input(path: &Path) -> Result<Box<dyn Read>> {
+    if stdio::is_dash(path) {
+        return stdio::reader(path);
+    }
+    let file = File::open(path).with_context(|| errors::at("open", path))?;
+    let bar = crate::progress::bytes(file.metadata()?.len(), path);
+    Ok(Box::new(io::BufReader::new(bar.wrap_read(file))))
+}
+
+// Errors a stage gets because a neighbour failed first
+fn knock_on(e: &anyhow::Error) -> bool {
+    e.chain().any(|c| {
+        c.downcast_ref::<io::Error>().is_some_and(|io| {
+            io.kind() == io::ErrorKind::BrokenPipe && io.get_ref().is_some_and(|inner| inner.is::<Closed>())
+                || io.get_ref().is_some_and(|inner| inner.is::<Aborted>())
+        })
+    })
+}
+
+#[derive(Debug)]
+struct Closed;
+
+impl std::fmt::Display for Closed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the next stage stopped reading")
+    }
+}
+
+impl std::error::Error for Closed {}
+
+#[derive(Debug)]
+struct Aborted;
+
+impl std::fmt::Display for Aborted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the previous stage failed")
+    }
+}
+
+impl std::error::Error for Aborted {}
+
+// Copies everything read through it to `output`
+struct Tee<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: Read, W: Write> Read for Tee<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.input.read(buf)?;
+        self.output.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+// Chunks over a bounded channel, so a fast stage waits for a slow one. An empty chunk marks the
+// end; a writer dropped without `finish` means its stage failed, which the reader reports
+// instead of a clean end of input
+fn pipe() -> (PipeWriter, PipeReader) {
+    let (tx, rx) = sync_channel(DEPTH);
+    (PipeWriter { tx }, PipeReader { rx, chunk: Vec::new(), pos: 0, done: false })
+}
+
+struct PipeWriter {
+    tx: SyncSender<Vec<u8>>,
+}
+
+impl PipeWriter {
+    fn finish(self) {
+        let _ = self.tx.send(Vec::new());
+    }
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.tx.send(buf.to_vec()).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, Closed))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct PipeReader {
+    rx: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if self.done {
+                return Ok(0);
+            }
+            match self.rx.recv() {
+                Ok(chunk) if chunk.is_empty() => self.done = true,
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => return Err(io::Error::other(Aborted)),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,93 @@
+// --preserve: which attributes of an input its outputs carry over. Every command that writes one
+// output per input (compress, decompress, encrypt, decrypt, format and the image commands) calls
+// `stdio::finished`, and so `apply`, once the output is in place, so they all treat the flag the
+// same way
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::fs::{self, File, FileTimes};
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+use crate::stdio;
+
+// Bits of the `Attribute`s to preserve
+static PRESERVE: AtomicU8 = AtomicU8::new(0);
+
+/// An attribute of an input file that --preserve carries over to its output
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Attribute {
+    /// Modification time
+    Mtime,
+    /// Permission bits, from file inputs only
+    Perms,
+    /// Extended attributes, on Unix
+    Xattrs,
+}
+
+impl Attribute {
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Sets the attributes outputs take from their inputs. Empty for none, the default
+pub fn set(attributes: &[Attribute]) {
+    PRESERVE.store(attributes.iter().fold(0, |bits, a| bits | a.bit()), Ordering::Relaxed);
+}
+
+fn wanted(attribute: Attribute) -> bool {
+    PRESERVE.load(Ordering::Relaxed) & attribute.bit() != 0
+}
+
+// The attributes set, as --preserve takes them
+pub(crate) fn flag_value() -> Option<String> {
+    let names: Vec<String> = Attribute::value_variants()
+        .iter()
+        .filter(|&&a| wanted(a))
+        .filter_map(|a| a.to_possible_value().map(|v| v.get_name().to_string()))
+        .collect();
+    (!names.is_empty()).then(|| names.join(","))
+}
+
+// Copies the attributes asked for from `source` to the finished `target`. Nothing is copied from
+// stdin or to stdout, which have none worth keeping. Permissions go last, so a read-only source
+// does not stop the rest
+pub(crate) fn apply(source: &Path, target: &Path) -> Result<()> {
+    if PRESERVE.load(Ordering::Relaxed) == 0
+        || stdio::is_dash(source)
+        || stdio::is_spooled(source, "stdin")
+        || stdio::is_dash(target)
+        || stdio::is_spooled(target, "stdout")
+    {
+        return Ok(());
+    }
+    let meta = fs::metadata(source).with_context(|| format!("read the attributes of {}", source.display()))?;
+    if wanted(Attribute::Mtime) {
+        let modified = meta.modified().context("modification time")?;
+        File::open(target)
+            .and_then(|f| f.set_times(FileTimes::new().set_modified(modified)))
+            .with_context(|| format!("set the modification time of {}", target.display()))?;
+    }
+    #[cfg(unix)]
+    if wanted(Attribute::Xattrs) {
+        copy_xattrs(source, target)?;
+    }
+    // A directory's execute bits mean something else on a file
+    if wanted(Attribute::Perms) && meta.is_file() {
+        fs::set_permissions(target, meta.permissions()).with_context(|| format!("set the permissions of {}", target.display()))?;
+    }
+    Ok(())
+}
+
+// Attributes the filesystem or this user cannot set (security.* ones, as a rule) are warned about
+// and left out
+#[cfg(unix)]
+fn copy_xattrs(source: &Path, target: &Path) -> Result<()> {
+    let names = xattr::list(source).with_context(|| format!("list the extended attributes of {}", source.display()))?;
+    for name in names {
+        let Some(value) = xattr::get(source, &name).with_context(|| format!("read {:?} of {}", name, source.display()))? else { continue };
+        if let Err(e) = xattr::set(target, &name, &value) {
+            tracing::warn!("Could not copy {:?} to {}: {}", name, target.display(), e);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,59 @@
+// Progress bars on stderr, shared by every command. Bars are drawn only when stderr is a
+// terminal and neither --quiet nor --no-progress is given; log lines are printed above them
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressFinish, ProgressStyle};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static BARS: LazyLock<MultiProgress> = LazyLock::new(|| MultiProgress::with_draw_target(ProgressDrawTarget::stderr()));
+
+/// Turns progress bars on or off for the rest of the process. They are on by default, and
+/// never drawn when stderr is not a terminal
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Stderr for log lines, cleared out of the way of any bars being drawn, for
+/// `tracing_subscriber`'s `with_writer`
+pub fn log_writer() -> LogWriter {
+    LogWriter
+}
+
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        BARS.suspend(|| io::stderr().write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+// Bytes read from the file at `path`, e.g. wrapped around its reader with `wrap_read`
+pub(crate) fn bytes(len: u64, path: &Path) -> ProgressBar {
+    let name = path.file_name().map_or_else(|| path.display().to_string(), |n| n.to_string_lossy().into_owned());
+    add(len, "{msg} {wide_bar} {bytes}/{total_bytes} {bytes_per_sec} {eta}", &name)
+}
+
+// Files done out of a batch
+pub(crate) fn files(len: usize, what: &str) -> ProgressBar {
+    add(len as u64, "{msg} {wide_bar} {pos}/{len} files {eta}", what)
+}
+
+fn add(len: u64, template: &str, message: &str) -> ProgressBar {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return ProgressBar::hidden();
+    }
+    let style = ProgressStyle::with_template(template).unwrap_or_else(|_| ProgressStyle::default_bar());
+    // Cleared when dropped, also on the error paths that never reach finish
+    let bar = ProgressBar::new(len)
+        .with_style(style)
+        .with_message(message.to_string())
+        .with_finish(ProgressFinish::AndClear);
+    BARS.add(bar)
+}
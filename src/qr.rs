@@ -0,0 +1,1079 @@
+// `qr`: QR codes for moving keys and short secrets between machines without a network. Encoding
+// covers versions 1 to 40 at every error correction level, in numeric, alphanumeric or byte mode.
+// Decoding reads a code that is the main subject of an image, as generated or screenshotted:
+// scaled, rotated or mirrored is fine, photographed at an angle is not
+use anyhow::{Context, Result, bail};
+use clap::{Args, Subcommand, ValueEnum};
+use std::fmt::Write as _;
+use std::io::Write;
+use std::path::PathBuf;
+use tracing::{debug, info};
+use crate::{errors, stdio};
+
+#[derive(Args)]
+#[command[name = "qr", about = "Encode text or small files as QR codes and decode them from images"]]
+pub struct QrArgs {
+    #[command(subcommand)]
+    command: QrCommand,
+}
+
+#[derive(Subcommand)]
+enum QrCommand {
+    /// Draw a QR code as an image (PNG, SVG or any raster format by extension), or on the terminal
+    Encode(EncodeArgs),
+    /// Read the QR code in an image and print its contents
+    Decode(DecodeArgs),
+}
+
+#[derive(Args)]
+struct EncodeArgs {
+    /// Text to encode
+    #[arg(required_unless_present = "input")]
+    text: Option<String>,
+    /// Encode the bytes of this file instead, `-` for stdin
+    #[arg(short, long, conflicts_with = "text")]
+    input: Option<PathBuf>,
+    /// Image to write: .svg, .png or another raster format, `-` for PNG on stdout. Default: drawn
+    /// on the terminal, for a dark background
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Error correction level: l (7% of the code can be lost), m (15%), q (25%) or h (30%)
+    #[arg(short, long, value_enum, default_value_t = EcLevel::M)]
+    ec_level: EcLevel,
+    /// Pixels per module in raster output, user units in SVG
+    #[arg(short, long, default_value_t = 8)]
+    scale: u32,
+    /// Quiet zone around the code, in modules
+    #[arg(long, default_value_t = 4)]
+    border: u32,
+}
+
+#[derive(Args)]
+struct DecodeArgs {
+    /// Image holding the code, `-` for stdin
+    image: PathBuf,
+    /// Write the decoded bytes to this file instead of printing them as text
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum, Debug, PartialEq)]
+pub enum EcLevel {
+    L,
+    M,
+    Q,
+    H,
+}
+
+impl EcLevel {
+    const ALL: [EcLevel; 4] = [EcLevel::L, EcLevel::M, EcLevel::Q, EcLevel::H];
+
+    const fn ordinal(self) -> usize {
+        match self {
+            EcLevel::L => 0,
+            EcLevel::M => 1,
+            EcLevel::Q => 2,
+            EcLevel::H => 3,
+        }
+    }
+
+    // As written in the format information
+    const fn format_bits(self) -> u32 {
+        match self {
+            EcLevel::L => 1,
+            EcLevel::M => 0,
+            EcLevel::Q => 3,
+            EcLevel::H => 2,
+        }
+    }
+}
+
+pub fn qr(a: QrArgs) -> Result<()> {
+    match a.command {
+        QrCommand::Encode(a) => encode_command(a),
+        QrCommand::Decode(a) => decode_command(a),
+    }
+}
+
+fn encode_command(a: EncodeArgs) -> Result<()> {
+    let data = match (&a.text, &a.input) {
+        (_, Some(path)) => stdio::read(path)?,
+        (Some(text), None) => text.as_bytes().to_vec(),
+        (None, None) => bail!("nothing to encode"),
+    };
+    if a.scale == 0 {
+        bail!("--scale must be at least 1");
+    }
+    let code = QrCode::encode(&data, a.ec_level)?;
+    debug!("Version {} ({}x{} modules), level {:?}, {} bytes", code.version, code.size, code.size, a.ec_level, data.len());
+
+    let Some(output) = &a.output else {
+        print!("{}", code.to_terminal(a.border as usize));
+        return Ok(());
+    };
+    if !stdio::check_output(output)? {
+        return Ok(());
+    }
+    if output.extension().is_some_and(|e| e.eq_ignore_ascii_case("svg")) {
+        stdio::write(output, code.to_svg(a.scale, a.border).as_bytes(), false)?;
+    } else {
+        let out = stdio::output(output, "png", true)?;
+        stdio::write_atomic(out.path(), |path| Ok(code.to_image(a.scale, a.border).save(path)?))
+            .with_context(|| errors::at("write", output))?;
+        out.finish()?;
+    }
+    info!("Wrote QR code: {} (version {}, {} bytes)", stdio::display(output), code.version, data.len());
+    Ok(())
+}
+
+fn decode_command(a: DecodeArgs) -> Result<()> {
+    if let Some(path) = &a.output
+        && !stdio::check_output(path)?
+    {
+        return Ok(());
+    }
+    let input = stdio::input(&a.image)?;
+    let image = crate::image::open_image(input.path(), true)?.to_luma8();
+    let data = decode(&image).with_context(|| format!("no readable QR code in {}", stdio::display(&a.image)))?;
+    match &a.output {
+        Some(path) => stdio::write(path, &data, true)?,
+        None => {
+            let mut out = std::io::stdout().lock();
+            writeln!(out, "{}", String::from_utf8_lossy(&data))?;
+            out.flush()?;
+        }
+    }
+    Ok(())
+}
+
+// ENCODING
+/// A QR code symbol, `size` modules across
+pub struct QrCode {
+    version: usize,
+    size: usize,
+    modules: Vec<bool>,
+    // Finder, timing and alignment patterns and the format and version areas, left out of the data
+    function: Vec<bool>,
+}
+
+impl QrCode {
+    /// The smallest code holding `data` at `ec_level`, in the most compact mode that covers all of it
+    pub fn encode(data: &[u8], ec_level: EcLevel) -> Result<QrCode> {
+        let mode = Mode::of(data);
+        let payload = mode.encode(data);
+        let version = (1..=40)
+            .find(|&v| data.len() < 1 << mode.count_bits(v) && 4 + mode.count_bits(v) + payload.len() <= data_codewords(v, ec_level) * 8)
+            .with_context(|| format!("{} bytes do not fit in a QR code at level {:?}", data.len(), ec_level))?;
+
+        let mut bits = Vec::new();
+        push_bits(&mut bits, mode.indicator(), 4);
+        push_bits(&mut bits, data.len() as u32, mode.count_bits(version));
+        bits.extend(payload);
+        let capacity = data_codewords(version, ec_level) * 8;
+        let terminator = (capacity - bits.len()).min(4);
+        push_bits(&mut bits, 0, terminator);
+        let fill = (8 - bits.len() % 8) % 8;
+        push_bits(&mut bits, 0, fill);
+        for pad in [0xEC, 0x11].into_iter().cycle() {
+            if bits.len() >= capacity {
+                break;
+            }
+            push_bits(&mut bits, pad, 8);
+        }
+        let codewords: Vec<u8> = bits.chunks(8).map(|byte| byte.iter().fold(0u8, |acc, &b| acc << 1 | u8::from(b))).collect();
+
+        let mut code = QrCode::blank(version);
+        let all = add_ecc_and_interleave(&codewords, version, ec_level);
+        for (i, (x, y)) in code.data_positions().into_iter().enumerate() {
+            if i < all.len() * 8 {
+                code.modules[y * code.size + x] = all[i / 8] >> (7 - i % 8) & 1 != 0;
+            }
+        }
+
+        // Masks are their own inverse, so each is tried by applying it twice
+        let mut best = (u32::MAX, 0);
+        for mask in 0..8 {
+            code.apply_mask(mask);
+            code.draw_format(ec_level, mask);
+            best = best.min((code.penalty(), mask));
+            code.apply_mask(mask);
+        }
+        code.apply_mask(best.1);
+        code.draw_format(ec_level, best.1);
+        Ok(code)
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn version(&self) -> usize {
+        self.version
+    }
+
+    /// Whether the module at column `x`, row `y` is dark
+    pub fn module(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    // Function patterns drawn, format area reserved
+    fn blank(version: usize) -> QrCode {
+        let size = version * 4 + 17;
+        let mut code = QrCode { version, size, modules: vec![false; size * size], function: vec![false; size * size] };
+        for i in 0..size {
+            code.set_function(6, i, i % 2 == 0);
+            code.set_function(i, 6, i % 2 == 0);
+        }
+        for (x, y) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            for dy in -4i64..=4 {
+                for dx in -4i64..=4 {
+                    let (xx, yy) = (x as i64 + dx, y as i64 + dy);
+                    if (0..size as i64).contains(&xx) && (0..size as i64).contains(&yy) {
+                        let dist = dx.abs().max(dy.abs());
+                        code.set_function(xx as usize, yy as usize, dist != 2 && dist != 4);
+                    }
+                }
+            }
+        }
+        let centers = alignment_centers(version);
+        for (i, &y) in centers.iter().enumerate() {
+            for (j, &x) in centers.iter().enumerate() {
+                // Not where they would overlap the finders
+                let last = centers.len() - 1;
+                if (i, j) == (0, 0) || (i, j) == (0, last) || (i, j) == (last, 0) {
+                    continue;
+                }
+                for dy in -2i64..=2 {
+                    for dx in -2i64..=2 {
+                        code.set_function((x as i64 + dx) as usize, (y as i64 + dy) as usize, dx.abs().max(dy.abs()) != 1);
+                    }
+                }
+            }
+        }
+        code.draw_format(EcLevel::L, 0);
+        if version >= 7 {
+            let bits = version_bits(version);
+            for i in 0..18 {
+                let dark = bits >> i & 1 != 0;
+                let (a, b) = (size - 11 + i % 3, i / 3);
+                code.set_function(a, b, dark);
+                code.set_function(b, a, dark);
+            }
+        }
+        code
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.function[y * self.size + x] = true;
+    }
+
+    fn draw_format(&mut self, ec_level: EcLevel, mask: u8) {
+        let bits = format_bits(ec_level, mask);
+        for (i, (x, y)) in format_positions(self.size).into_iter().enumerate() {
+            self.set_function(x, y, bits >> (i % 15) & 1 != 0);
+        }
+        // Always dark
+        self.set_function(8, self.size - 8, true);
+    }
+
+    // Non-function modules in the zigzag order codeword bits are placed in: two columns at a
+    // time from the right, alternately upwards and downwards, skipping the vertical timing line
+    fn data_positions(&self) -> Vec<(usize, usize)> {
+        let mut positions = Vec::new();
+        let mut right = self.size as i64 - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            for vert in 0..self.size {
+                for j in 0..2 {
+                    let x = (right - j) as usize;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward { self.size - 1 - vert } else { vert };
+                    if !self.function[y * self.size + x] {
+                        positions.push((x, y));
+                    }
+                }
+            }
+            right -= 2;
+        }
+        positions
+    }
+
+    fn apply_mask(&mut self, mask: u8) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if !self.function[y * self.size + x] && mask_bit(mask, x, y) {
+                    self.modules[y * self.size + x] ^= true;
+                }
+            }
+        }
+    }
+
+    // The standard's penalty score: long runs, 2x2 blocks, finder lookalikes and imbalance
+    fn penalty(&self) -> u32 {
+        const FINDER_LIKE: [bool; 11] = [true, false, true, true, true, false, true, false, false, false, false];
+        let n = self.size;
+        let mut penalty = 0;
+        for vertical in [false, true] {
+            for i in 0..n {
+                let line: Vec<bool> = (0..n).map(|j| if vertical { self.module(i, j) } else { self.module(j, i) }).collect();
+                let mut run = 1;
+                for j in 1..=n {
+                    if j < n && line[j] == line[j - 1] {
+                        run += 1;
+                    } else {
+                        if run >= 5 {
+                            penalty += 3 + (run - 5);
+                        }
+                        run = 1;
+                    }
+                }
+                for window in line.windows(11) {
+                    if window == FINDER_LIKE || window.iter().rev().eq(FINDER_LIKE.iter()) {
+                        penalty += 40;
+                    }
+                }
+            }
+        }
+        for y in 0..n - 1 {
+            for x in 0..n - 1 {
+                let c = self.module(x, y);
+                if c == self.module(x + 1, y) && c == self.module(x, y + 1) && c == self.module(x + 1, y + 1) {
+                    penalty += 3;
+                }
+            }
+        }
+        let dark = self.modules.iter().filter(|&&m| m).count();
+        let total = n * n;
+        let k = (dark * 20).abs_diff(total * 10).div_ceil(total).saturating_sub(1);
+        penalty + k as u32 * 10
+    }
+
+    /// Black on white with a quiet zone of `border` modules, `scale` pixels per module
+    pub fn to_image(&self, scale: u32, border: u32) -> image::GrayImage {
+        let side = (self.size as u32 + border * 2) * scale;
+        image::GrayImage::from_fn(side, side, |px, py| {
+            let (x, y) = ((px / scale) as i64 - border as i64, (py / scale) as i64 - border as i64);
+            let dark = (0..self.size as i64).contains(&x) && (0..self.size as i64).contains(&y) && self.module(x as usize, y as usize);
+            image::Luma([if dark { 0 } else { 255 }])
+        })
+    }
+
+    /// One path of unit squares, crisp at any size
+    pub fn to_svg(&self, scale: u32, border: u32) -> String {
+        let side = self.size as u32 + border * 2;
+        let mut path = String::new();
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if self.module(x, y) {
+                    let _ = write!(path, "M{},{}h1v1h-1z", x as u32 + border, y as u32 + border);
+                }
+            }
+        }
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{w}\" viewBox=\"0 0 {side} {side}\" shape-rendering=\"crispEdges\">\n\
+             <rect width=\"{side}\" height=\"{side}\" fill=\"#fff\"/>\n<path d=\"{path}\" fill=\"#000\"/>\n</svg>\n",
+            w = side * scale,
+        )
+    }
+
+    // Two rows per line with half blocks. Light modules are drawn, so on a dark terminal the
+    // code reads dark on light as scanners expect
+    fn to_terminal(&self, border: usize) -> String {
+        let side = self.size + border * 2;
+        let light = |x: usize, y: usize| {
+            let (x, y) = (x as i64 - border as i64, y as i64 - border as i64);
+            !((0..self.size as i64).contains(&x) && (0..self.size as i64).contains(&y) && self.module(x as usize, y as usize))
+        };
+        let mut out = String::new();
+        for y in (0..side).step_by(2) {
+            for x in 0..side {
+                let top = light(x, y);
+                let bottom = y + 1 < side && light(x, y + 1);
+                out.push(match (top, bottom) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' ',
+                });
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Numeric,
+    Alphanumeric,
+    Byte,
+}
+
+const ALPHANUMERIC: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ $%*+-./:";
+
+impl Mode {
+    fn of(data: &[u8]) -> Mode {
+        if data.iter().all(u8::is_ascii_digit) {
+            Mode::Numeric
+        } else if data.iter().all(|b| ALPHANUMERIC.contains(b)) {
+            Mode::Alphanumeric
+        } else {
+            Mode::Byte
+        }
+    }
+
+    const fn indicator(self) -> u32 {
+        match self {
+            Mode::Numeric => 1,
+            Mode::Alphanumeric => 2,
+            Mode::Byte => 4,
+        }
+    }
+
+    // Width of the character count, which grows with the version
+    const fn count_bits(self, version: usize) -> usize {
+        let i = if version <= 9 { 0 } else if version <= 26 { 1 } else { 2 };
+        match self {
+            Mode::Numeric => [10, 12, 14][i],
+            Mode::Alphanumeric => [9, 11, 13][i],
+            Mode::Byte => [8, 16, 16][i],
+        }
+    }
+
+    fn encode(self, data: &[u8]) -> Vec<bool> {
+        let mut bits = Vec::new();
+        match self {
+            Mode::Numeric => {
+                for group in data.chunks(3) {
+                    let value = group.iter().fold(0, |acc, d| acc * 10 + u32::from(d - b'0'));
+                    push_bits(&mut bits, value, group.len() * 3 + 1);
+                }
+            }
+            Mode::Alphanumeric => {
+                let index = |b: &u8| ALPHANUMERIC.iter().position(|c| c == b).unwrap_or(0) as u32;
+                for pair in data.chunks(2) {
+                    match pair {
+                        [a, b] => push_bits(&mut bits, index(a) * 45 + index(b), 11),
+                        [a] => push_bits(&mut bits, index(a), 6),
+                        _ => unreachable!(),
+                    }
+                }
+            }
+            Mode::Byte => {
+                for &b in data {
+                    push_bits(&mut bits, u32::from(b), 8);
+                }
+            }
+        }
+        bits
+    }
+}
+
+fn push_bits(bits: &mut Vec<bool>, value: u32, len: usize) {
+    for i in (0..len).rev() {
+        bits.push(value >> i & 1 != 0);
+    }
+}
+
+// Error correction codewords per block, and blocks, by level and version (index 0 unused)
+const ECC_PER_BLOCK: [[u8; 41]; 4] = [
+    [0, 7, 10, 15, 20, 26, 18, 20, 24, 30, 18, 20, 24, 26, 30, 22, 24, 28, 30, 28, 28, 28, 28, 30, 30, 26, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30],
+    [0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26, 30, 22, 22, 24, 24, 28, 28, 26, 26, 26, 26, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28],
+    [0, 13, 22, 18, 26, 18, 24, 18, 22, 20, 24, 28, 26, 24, 20, 30, 24, 28, 28, 26, 30, 28, 30, 30, 30, 30, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30],
+    [0, 17, 28, 22, 16, 22, 28, 26, 26, 24, 28, 24, 28, 22, 24, 24, 30, 28, 28, 26, 28, 30, 24, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30],
+];
+const BLOCKS: [[u8; 41]; 4] = [
+    [0, 1, 1, 1, 1, 1, 2, 2, 2, 2, 4, 4, 4, 4, 4, 6, 6, 6, 6, 7, 8, 8, 9, 9, 10, 12, 12, 12, 13, 14, 15, 16, 17, 18, 19, 19, 20, 21, 22, 24, 25],
+    [0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5, 5, 8, 9, 9, 10, 10, 11, 13, 14, 16, 17, 17, 18, 20, 21, 23, 25, 26, 28, 29, 31, 33, 35, 37, 38, 40, 43, 45, 47, 49],
+    [0, 1, 1, 2, 2, 4, 4, 6, 6, 8, 8, 8, 10, 12, 16, 12, 17, 16, 18, 21, 20, 23, 23, 25, 27, 29, 34, 34, 35, 38, 40, 43, 45, 48, 51, 53, 56, 59, 62, 65, 68],
+    [0, 1, 1, 2, 4, 4, 4, 5, 6, 8, 8, 11, 11, 16, 16, 18, 16, 19, 21, 25, 25, 25, 34, 30, 32, 35, 37, 40, 42, 45, 48, 51, 54, 57, 60, 63, 66, 70, 74, 77, 81],
+];
+
+// Modules left for codewords once the function patterns are drawn
+fn raw_data_modules(version: usize) -> usize {
+    let mut result = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let aligns = version / 7 + 2;
+        result -= (25 * aligns - 10) * aligns - 55;
+        if version >= 7 {
+            result -= 36;
+        }
+    }
+    result
+}
+
+fn data_codewords(version: usize, ec_level: EcLevel) -> usize {
+    let e = ec_level.ordinal();
+    raw_data_modules(version) / 8 - ECC_PER_BLOCK[e][version] as usize * BLOCKS[e][version] as usize
+}
+
+// Block layout: (blocks, ECC per block, short blocks, data codewords of a short block). Long
+// blocks carry one data codeword more
+fn block_layout(version: usize, ec_level: EcLevel) -> (usize, usize, usize, usize) {
+    let e = ec_level.ordinal();
+    let (blocks, ecc) = (BLOCKS[e][version] as usize, ECC_PER_BLOCK[e][version] as usize);
+    let raw = raw_data_modules(version) / 8;
+    (blocks, ecc, blocks - raw % blocks, raw / blocks - ecc)
+}
+
+// Data split into blocks, each followed by its ECC, then read out column by column
+fn add_ecc_and_interleave(data: &[u8], version: usize, ec_level: EcLevel) -> Vec<u8> {
+    let (blocks, ecc, short, short_data) = block_layout(version, ec_level);
+    let divisor = rs_divisor(ecc);
+    let mut split = Vec::with_capacity(blocks);
+    let mut k = 0;
+    for i in 0..blocks {
+        let len = short_data + usize::from(i >= short);
+        let block = &data[k..k + len];
+        k += len;
+        split.push((block.to_vec(), rs_remainder(block, &divisor)));
+    }
+    let mut result = Vec::with_capacity(raw_data_modules(version) / 8);
+    for i in 0..=short_data {
+        for (block, _) in &split {
+            if let Some(&b) = block.get(i) {
+                result.push(b);
+            }
+        }
+    }
+    for i in 0..ecc {
+        for (_, ecc) in &split {
+            result.push(ecc[i]);
+        }
+    }
+    result
+}
+
+fn alignment_centers(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let aligns = version / 7 + 2;
+    let step = if version == 32 { 26 } else { (version * 4 + aligns * 2 + 1) / (aligns * 2 - 2) * 2 };
+    let mut centers: Vec<usize> = (0..aligns - 1).map(|i| version * 4 + 10 - i * step).collect();
+    centers.push(6);
+    centers.reverse();
+    centers
+}
+
+// 15 bits, BCH protected and masked so they are never all light
+fn format_bits(ec_level: EcLevel, mask: u8) -> u32 {
+    let data = ec_level.format_bits() << 3 | u32::from(mask);
+    let mut rem = data;
+    for _ in 0..10 {
+        rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+    }
+    (data << 10 | rem) ^ 0x5412
+}
+
+// Both copies of the format bits, bit i of the first copy at index i and of the second at 15 + i
+fn format_positions(size: usize) -> Vec<(usize, usize)> {
+    let mut first: Vec<(usize, usize)> = (0..=5).map(|i| (8, i)).collect();
+    first.extend([(8, 7), (8, 8), (7, 8)]);
+    first.extend((9..15).map(|i| (14 - i, 8)));
+    let mut second: Vec<(usize, usize)> = (0..8).map(|i| (size - 1 - i, 8)).collect();
+    second.extend((8..15).map(|i| (8, size - 15 + i)));
+    first.extend(second);
+    first
+}
+
+fn version_bits(version: usize) -> u32 {
+    let mut rem = version as u32;
+    for _ in 0..12 {
+        rem = (rem << 1) ^ ((rem >> 11) * 0x1F25);
+    }
+    (version as u32) << 12 | rem
+}
+
+fn mask_bit(mask: u8, x: usize, y: usize) -> bool {
+    match mask {
+        0 => (x + y).is_multiple_of(2),
+        1 => y.is_multiple_of(2),
+        2 => x.is_multiple_of(3),
+        3 => (x + y).is_multiple_of(3),
+        4 => (x / 3 + y / 2).is_multiple_of(2),
+        5 => x * y % 2 + x * y % 3 == 0,
+        6 => (x * y % 2 + x * y % 3).is_multiple_of(2),
+        _ => ((x + y) % 2 + x * y % 3).is_multiple_of(2),
+    }
+}
+
+// REED-SOLOMON over GF(256) with the polynomial 0x11D, generator roots 2^0 .. 2^(n-1)
+fn gf_mul(x: u8, y: u8) -> u8 {
+    let mut z: u8 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x1D);
+        z ^= ((y >> i) & 1) * x;
+    }
+    z
+}
+
+// Powers of 2 and their logarithms, for the decoder's many inversions
+const GF_EXP: [u8; 255] = {
+    let mut table = [0u8; 255];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        table[i] = x as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11D;
+        }
+        i += 1;
+    }
+    table
+};
+const GF_LOG: [u8; 256] = {
+    let mut table = [0u8; 256];
+    let mut i = 0;
+    while i < 255 {
+        table[GF_EXP[i] as usize] = i as u8;
+        i += 1;
+    }
+    table
+};
+
+fn gf_pow2(e: usize) -> u8 {
+    GF_EXP[e % 255]
+}
+
+fn gf_inv(x: u8) -> u8 {
+    GF_EXP[(255 - GF_LOG[x as usize] as usize) % 255]
+}
+
+fn rs_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0u8; degree];
+    result[degree - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_mul(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_mul(root, 2);
+    }
+    result
+}
+
+fn rs_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0u8; divisor.len()];
+    for &b in data {
+        let factor = b ^ result.remove(0);
+        result.push(0);
+        for (r, &d) in result.iter_mut().zip(divisor) {
+            *r ^= gf_mul(d, factor);
+        }
+    }
+    result
+}
+
+// Corrects up to ecc/2 wrong codewords of `block` in place: syndromes, Berlekamp-Massey for the
+// error locator, Chien search for the positions and Forney for the values
+fn rs_correct(block: &mut [u8], ecc: usize) -> Result<usize> {
+    let n = block.len();
+    // r(x) with block[0] as the highest power, evaluated at 2^i
+    let eval = |poly: &[u8], x: u8| poly.iter().fold(0u8, |acc, &c| gf_mul(acc, x) ^ c);
+    let syndromes: Vec<u8> = (0..ecc).map(|i| eval(block, gf_pow2(i))).collect();
+    if syndromes.iter().all(|&s| s == 0) {
+        return Ok(0);
+    }
+
+    // Polynomials from here on are lowest power first
+    let mut locator = vec![1u8];
+    let mut previous = vec![1u8];
+    let (mut errors, mut shift, mut last) = (0usize, 1usize, 1u8);
+    for k in 0..ecc {
+        let mut delta = syndromes[k];
+        for i in 1..=errors.min(locator.len() - 1) {
+            delta ^= gf_mul(locator[i], syndromes[k - i]);
+        }
+        if delta == 0 {
+            shift += 1;
+            continue;
+        }
+        let coef = gf_mul(delta, gf_inv(last));
+        let mut next = locator.clone();
+        next.resize(next.len().max(previous.len() + shift), 0);
+        for (i, &p) in previous.iter().enumerate() {
+            next[i + shift] ^= gf_mul(coef, p);
+        }
+        if 2 * errors <= k {
+            previous = std::mem::replace(&mut locator, next);
+            errors = k + 1 - errors;
+            last = delta;
+            shift = 1;
+        } else {
+            locator = next;
+            shift += 1;
+        }
+    }
+    if errors * 2 > ecc {
+        bail!("too many errors to correct");
+    }
+
+    let eval_low = |poly: &[u8], x: u8| poly.iter().rev().fold(0u8, |acc, &c| gf_mul(acc, x) ^ c);
+    // Omega = S * Lambda mod x^ecc, and Lambda' (odd terms only in characteristic 2)
+    let mut omega = vec![0u8; ecc];
+    for (i, &s) in syndromes.iter().enumerate() {
+        for (j, &l) in locator.iter().enumerate() {
+            if i + j < ecc {
+                omega[i + j] ^= gf_mul(s, l);
+            }
+        }
+    }
+    let derivative: Vec<u8> = (1..locator.len()).map(|i| if i % 2 == 1 { locator[i] } else { 0 }).collect();
+
+    let mut found = 0;
+    for (position, value) in block.iter_mut().enumerate() {
+        let x = gf_pow2(n - 1 - position);
+        let x_inv = gf_inv(x);
+        if eval_low(&locator, x_inv) != 0 {
+            continue;
+        }
+        let denominator = eval_low(&derivative, x_inv);
+        if denominator == 0 {
+            bail!("uncorrectable block");
+        }
+        *value ^= gf_mul(x, gf_mul(eval_low(&omega, x_inv), gf_inv(denominator)));
+        found += 1;
+    }
+    if found != errors || (0..ecc).any(|i| eval(block, gf_pow2(i)) != 0) {
+        bail!("uncorrectable block");
+    }
+    Ok(found)
+}
+
+// DECODING
+/// The bytes held by the QR code in `image`
+pub fn decode(image: &image::GrayImage) -> Result<Vec<u8>> {
+    let grid = Binary::new(image);
+    let [tl, tr, bl] = find_finders(&grid).context("no finder patterns found")?;
+    let module = (tl.module + tr.module + bl.module) / 3.0;
+    let across = ((tl.distance(&tr) + tl.distance(&bl)) / 2.0 / module + 7.0 - 17.0) / 4.0;
+    let guess = across.round().clamp(1.0, 40.0) as usize;
+    let mut last = None;
+    for version in [guess, guess + 1, guess.saturating_sub(1)] {
+        if !(1..=40).contains(&version) {
+            continue;
+        }
+        // A mirrored code, as seen from the back or through a front camera, reads transposed
+        for (tr, bl) in [(tr, bl), (bl, tr)] {
+            match read_symbol(&grid, version, tl, tr, bl) {
+                Ok(data) => return Ok(data),
+                Err(e) => {
+                    debug!("Version {}: {:#}", version, e);
+                    last = Some(e);
+                }
+            }
+        }
+    }
+    Err(last.unwrap_or_else(|| anyhow::anyhow!("no plausible version")))
+}
+
+fn read_symbol(grid: &Binary, version: usize, tl: Finder, tr: Finder, bl: Finder) -> Result<Vec<u8>> {
+    let blank = QrCode::blank(version);
+    let size = blank.size;
+    // Finder centers sit 3.5 modules in from their corners
+    let span = (size - 7) as f64;
+    let (ux, uy) = ((tr.x - tl.x) / span, (tr.y - tl.y) / span);
+    let (vx, vy) = ((bl.x - tl.x) / span, (bl.y - tl.y) / span);
+    let sample = |x: usize, y: usize| {
+        let (mx, my) = (x as f64 - 3.0, y as f64 - 3.0);
+        grid.dark(tl.x + mx * ux + my * vx, tl.y + mx * uy + my * vy)
+    };
+
+    // Nearest valid format word over both copies
+    let positions = format_positions(size);
+    let read: Vec<u32> = [&positions[..15], &positions[15..]]
+        .iter()
+        .map(|copy| copy.iter().enumerate().fold(0, |acc, (i, &(x, y))| acc | u32::from(sample(x, y)) << i))
+        .collect();
+    let (distance, ec_level, mask) = EcLevel::ALL
+        .iter()
+        .flat_map(|&level| (0..8).map(move |mask| (level, mask)))
+        .map(|(level, mask)| {
+            let bits = format_bits(level, mask);
+            (read.iter().map(|r| (r ^ bits).count_ones()).min().unwrap_or(15), level, mask)
+        })
+        .min_by_key(|(d, _, _)| *d)
+        .context("no format information")?;
+    if distance > 3 {
+        bail!("unreadable format information");
+    }
+
+    let mut raw = vec![0u8; raw_data_modules(version) / 8];
+    for (i, (x, y)) in blank.data_positions().into_iter().enumerate().take(raw.len() * 8) {
+        if sample(x, y) ^ mask_bit(mask, x, y) {
+            raw[i / 8] |= 1 << (7 - i % 8);
+        }
+    }
+
+    // Undo the interleaving, then correct each block
+    let (blocks, ecc, short, short_data) = block_layout(version, ec_level);
+    let mut split: Vec<Vec<u8>> = (0..blocks).map(|i| Vec::with_capacity(short_data + 1 + ecc + usize::from(i >= short))).collect();
+    let mut k = 0;
+    for i in 0..=short_data {
+        for (j, block) in split.iter_mut().enumerate() {
+            if i < short_data || j >= short {
+                block.push(raw[k]);
+                k += 1;
+            }
+        }
+    }
+    for _ in 0..ecc {
+        for block in split.iter_mut() {
+            block.push(raw[k]);
+            k += 1;
+        }
+    }
+    let mut data = Vec::new();
+    let mut corrected = 0;
+    for block in &mut split {
+        corrected += rs_correct(block, ecc)?;
+        data.extend_from_slice(&block[..block.len() - ecc]);
+    }
+    debug!("Version {}, level {:?}, mask {}, {} codeword(s) corrected", version, ec_level, mask, corrected);
+    parse_segments(&data, version)
+}
+
+fn parse_segments(data: &[u8], version: usize) -> Result<Vec<u8>> {
+    let mut reader = BitReader { data, pos: 0 };
+    let mut out = Vec::new();
+    while reader.remaining() >= 4 {
+        let mode = match reader.read(4)? {
+            0 => break,
+            1 => Mode::Numeric,
+            2 => Mode::Alphanumeric,
+            4 => Mode::Byte,
+            // ECI designator: the character set is not acted on, UTF-8 is assumed when printing
+            7 => {
+                let first = reader.read(8)?;
+                if first & 0x80 != 0 {
+                    reader.read(if first & 0x40 == 0 { 8 } else { 16 })?;
+                }
+                continue;
+            }
+            other => bail!("unsupported segment mode {}", other),
+        };
+        let count = reader.read(mode.count_bits(version))? as usize;
+        match mode {
+            Mode::Numeric => {
+                let mut left = count;
+                while left > 0 {
+                    let digits = left.min(3);
+                    let value = reader.read(digits * 3 + 1)?;
+                    out.extend(format!("{:0width$}", value, width = digits).bytes());
+                    left -= digits;
+                }
+            }
+            Mode::Alphanumeric => {
+                let mut left = count;
+                while left > 0 {
+                    if left >= 2 {
+                        let value = reader.read(11)? as usize;
+                        out.push(*ALPHANUMERIC.get(value / 45).context("invalid alphanumeric data")?);
+                        out.push(*ALPHANUMERIC.get(value % 45).context("invalid alphanumeric data")?);
+                        left -= 2;
+                    } else {
+                        let value = reader.read(6)? as usize;
+                        out.push(*ALPHANUMERIC.get(value).context("invalid alphanumeric data")?);
+                        left -= 1;
+                    }
+                }
+            }
+            Mode::Byte => {
+                for _ in 0..count {
+                    out.push(reader.read(8)? as u8);
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn remaining(&self) -> usize {
+        self.data.len() * 8 - self.pos
+    }
+
+    fn read(&mut self, len: usize) -> Result<u32> {
+        if len > self.remaining() {
+            bail!("truncated segment");
+        }
+        let mut value = 0;
+        for _ in 0..len {
+            value = value << 1 | u32::from(self.data[self.pos / 8] >> (7 - self.pos % 8) & 1);
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+}
+
+// The image thresholded halfway between its darkest and lightest pixel
+struct Binary {
+    width: usize,
+    height: usize,
+    dark: Vec<bool>,
+}
+
+impl Binary {
+    fn new(image: &image::GrayImage) -> Binary {
+        let (min, max) = image.pixels().fold((255u8, 0u8), |(lo, hi), p| (lo.min(p[0]), hi.max(p[0])));
+        let threshold = (u16::from(min) + u16::from(max)) / 2;
+        Binary {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            dark: image.pixels().map(|p| u16::from(p[0]) <= threshold && min != max).collect(),
+        }
+    }
+
+    fn at(&self, x: i64, y: i64) -> bool {
+        x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height && self.dark[y as usize * self.width + x as usize]
+    }
+
+    fn dark(&self, x: f64, y: f64) -> bool {
+        self.at(x.floor() as i64, y.floor() as i64)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Finder {
+    x: f64,
+    y: f64,
+    module: f64,
+    hits: usize,
+}
+
+impl Finder {
+    fn distance(&self, other: &Finder) -> f64 {
+        (self.x - other.x).hypot(self.y - other.y)
+    }
+}
+
+// Dark, light, dark, light, dark runs in the proportions 1:1:3:1:1
+fn finder_ratio(counts: &[usize; 5]) -> Option<f64> {
+    let total: usize = counts.iter().sum();
+    if total < 7 {
+        return None;
+    }
+    let module = total as f64 / 7.0;
+    let slack = module * 0.5;
+    let ok = counts.iter().zip([1.0, 1.0, 3.0, 1.0, 1.0]).all(|(&c, w)| (c as f64 - w * module).abs() <= w * slack);
+    ok.then_some(module)
+}
+
+// Runs through `center` along a line: the center of the middle run and the module size
+fn cross_check(dark: impl Fn(i64) -> bool, center: i64, len: i64) -> Option<(f64, f64)> {
+    if !dark(center) {
+        return None;
+    }
+    let mut counts = [0usize; 5];
+    let mut i = center;
+    for (slot, want) in [(2, true), (1, false), (0, true)] {
+        while i >= 0 && dark(i) == want {
+            counts[slot] += 1;
+            i -= 1;
+        }
+    }
+    let mut j = center + 1;
+    for (slot, want) in [(2, true), (3, false), (4, true)] {
+        while j < len && dark(j) == want {
+            counts[slot] += 1;
+            j += 1;
+        }
+    }
+    let module = finder_ratio(&counts)?;
+    let middle = (j - counts[4] as i64 - counts[3] as i64) as f64 - counts[2] as f64 / 2.0;
+    Some((middle, module))
+}
+
+// Candidates from every row, confirmed down their column and across again, then the three that
+// best form the corners of a square
+fn find_finders(grid: &Binary) -> Option<[Finder; 3]> {
+    let mut found: Vec<Finder> = Vec::new();
+    for y in 0..grid.height as i64 {
+        let mut runs: Vec<(usize, usize)> = Vec::new(); // (start, length) alternating from the first dark run
+        let mut x = 0;
+        while x < grid.width as i64 {
+            let start = x;
+            let dark = grid.at(x, y);
+            while x < grid.width as i64 && grid.at(x, y) == dark {
+                x += 1;
+            }
+            if dark || !runs.is_empty() {
+                runs.push((start as usize, (x - start) as usize));
+            }
+        }
+        for w in runs.windows(5).step_by(2) {
+            let counts = [w[0].1, w[1].1, w[2].1, w[3].1, w[4].1];
+            if finder_ratio(&counts).is_none() {
+                continue;
+            }
+            let cx = w[2].0 as i64 + w[2].1 as i64 / 2;
+            let Some((cy, vertical)) = cross_check(|i| grid.at(cx, i), y, grid.height as i64) else { continue };
+            let Some((cx, module)) = cross_check(|i| grid.at(i, cy as i64), cx, grid.width as i64) else { continue };
+            // The same rings on the diagonal, where data rarely lines up by chance. Steps there
+            // advance a pixel on each axis, so runs count the same as across
+            let (px, py) = (cx as i64, cy as i64);
+            let diagonal = |i: i64| grid.at(px - py.min(px) + i, py - py.min(px) + i);
+            let Some((_, slanted)) = cross_check(diagonal, py.min(px), (grid.width + grid.height) as i64) else { continue };
+            if (vertical - module).abs() > module * 0.3 || (slanted - module).abs() > module * 0.3 {
+                continue;
+            }
+            match found.iter_mut().find(|f| (f.x - cx).abs() < f.module && (f.y - cy).abs() < f.module) {
+                Some(f) => {
+                    let n = f.hits as f64;
+                    f.x = (f.x * n + cx) / (n + 1.0);
+                    f.y = (f.y * n + cy) / (n + 1.0);
+                    f.module = (f.module * n + module) / (n + 1.0);
+                    f.hits += 1;
+                }
+                None => found.push(Finder { x: cx, y: cy, module, hits: 1 }),
+            }
+        }
+    }
+    found.sort_by_key(|f| std::cmp::Reverse(f.hits));
+    found.truncate(16);
+
+    let mut best: Option<(f64, [Finder; 3])> = None;
+    for i in 0..found.len() {
+        for j in i + 1..found.len() {
+            for k in j + 1..found.len() {
+                let (a, b, c) = (found[i], found[j], found[k]);
+                // The corner opposite the longest side is the top left
+                let [tl, p, q] = if b.distance(&c) >= a.distance(&b).max(a.distance(&c)) {
+                    [a, b, c]
+                } else if a.distance(&c) >= a.distance(&b) {
+                    [b, a, c]
+                } else {
+                    [c, a, b]
+                };
+                let (l1, l2, hyp) = (tl.distance(&p), tl.distance(&q), p.distance(&q));
+                let modules = [a.module, b.module, c.module];
+                let (lo, hi) = modules.iter().fold((f64::MAX, 0f64), |(lo, hi), &m| (lo.min(m), hi.max(m)));
+                if l1.min(l2) < hi * 7.0 {
+                    continue;
+                }
+                let score = (l1 - l2).abs() / l1.max(l2) + (hyp - l1.hypot(l2)).abs() / hyp + (hi - lo) / hi;
+                // With y pointing down, top right then bottom left turns clockwise
+                let cross = (p.x - tl.x) * (q.y - tl.y) - (p.y - tl.y) * (q.x - tl.x);
+                let corners = if cross > 0.0 { [tl, p, q] } else { [tl, q, p] };
+                if best.as_ref().is_none_or(|(s, _)| score < *s) {
+                    best = Some((score, corners));
+                }
+            }
+        }
+    }
+    best.filter(|(score, _)| *score < 0.5).map(|(_, corners)| corners)
+}
+
@@ -1,21 +1,50 @@
 use anyhow::{Context, Result, bail};
 use clap::{Args, ValueEnum};
-use rayon::prelude::*;
 use std::{
     ffi::OsStr,
     fs,
+    io::{Read, Write},
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
 };
-use walkdir::WalkDir;
+use tracing::{info, warn};
+
+use crate::batch::{self, BatchArgs};
+use crate::watch::{self, WatchArgs, Watching};
+use crate::{memory, stdio};
+
+// Memory per output pixel: the pixmap and the image it is copied into
+const BYTES_PER_PIXEL: u64 = 8;
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
 pub enum OutputFormat {
     Png,
     Bmp,
+    Pdf,
+    Ico,
+    Icns,
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Bmp => "bmp",
+            OutputFormat::Pdf => "pdf",
+            OutputFormat::Ico => "ico",
+            OutputFormat::Icns => "icns",
+        }
+    }
+
+    // One file bundling every --sizes entry (or the canonical icon sizes)
+    fn is_icon(self) -> bool {
+        matches!(self, OutputFormat::Ico | OutputFormat::Icns)
+    }
 }
 
 #[derive(Args)]
-#[command[name = "rasterize", about = "Rasterize SVG images to PNG or BMP"]]
+#[command[name = "rasterize", about = "Rasterize SVG images to PNG, BMP, ICO or ICNS, or export them to PDF"]]
 pub struct RasterizeArgs {
     input: PathBuf,
     /// Output input
@@ -33,32 +62,117 @@ pub struct RasterizeArgs {
     /// Scale factor (applied after width/height)
     #[arg(short, long, default_value_t = 1.0)]
     scale: f32,
-    /// Render recursively
-    #[arg(short, long)]
-    recursive: bool,
-    /// Number of worker threads for batch mode (0 = use rayon default)
-    #[arg(long, default_value_t = 0)]
-    threads: usize,
-    /// Overwrite existing files
-    #[arg(long, default_value_t = false)]
-    overwrite: bool,
+    /// Pixels per inch used to size PDF pages (96 = CSS pixels, so mm/in sizes are kept)
+    #[arg(long, default_value_t = 96.0)]
+    dpi: f32,
+    #[command(flatten)]
+    batch: BatchArgs,
+    #[command(flatten)]
+    watch: WatchArgs,
+    /// Load every font in this directory, in addition to system fonts (repeatable)
+    #[arg(long)]
+    font_dir: Vec<PathBuf>,
+    /// Load this font file, in addition to system fonts (repeatable)
+    #[arg(long)]
+    font_file: Vec<PathBuf>,
+    /// Font family for text without a font-family
+    #[arg(long)]
+    default_font_family: Option<String>,
+    /// Fill behind the drawing: RRGGBB, RRGGBBAA or transparent
+    #[arg(long, default_value = "transparent")]
+    background: String,
+    /// Render only the area covered by the element with this id
+    #[arg(long)]
+    export_id: Option<String>,
+    /// Render only this region, in the SVG's user units: x,y,w,h
+    #[arg(long, conflicts_with = "export_id")]
+    viewbox: Option<String>,
+    /// Render a square image per size, named {stem}_{size}.{ext}: 16,32,64 (for ico/icns: the sizes in the bundle)
+    #[arg(long, value_delimiter = ',', conflicts_with_all = ["width", "height"])]
+    sizes: Vec<u32>,
+    /// Render density variants of the output, named {stem}@2x.{ext} (1 keeps the plain name): 1,2,3
+    #[arg(long, value_delimiter = ',', conflicts_with = "sizes")]
+    scales: Vec<f32>,
+    /// Refuse outputs with more pixels than this (the pixmap needs 4 bytes per pixel)
+    #[arg(long, default_value_t = 100_000_000)]
+    max_pixels: u64,
+    /// PNG only: render large outputs in strips of at most --max-pixels, streamed to the file
+    #[arg(long)]
+    tiled: bool,
+    /// Batch mode: keep rendering after a file fails (default stops at the first failure)
+    #[arg(short = 'k', long)]
+    keep_going: bool,
+    /// Batch mode: how to print the end-of-batch summary
+    #[arg(long, value_enum, default_value_t = SummaryFormat::Text)]
+    summary: SummaryFormat,
+    /// Batch mode: do not draw the progress bar (it is also hidden when stderr is not a terminal)
+    #[arg(long)]
+    no_progress: bool,
+    /// Batch mode: name outputs from {stem}, {width}, {height} and {ext}, e.g. "{stem}-{width}x{height}.{ext}"
+    #[arg(long)]
+    name_template: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum SummaryFormat {
+    Text,
+    Json,
+}
+
+// One file written from an SVG and the size it is rendered at
+struct Variant {
+    output: PathBuf,
+    width: Option<u32>,
+    height: Option<u32>,
+    scale: f32,
 }
 
 pub fn rasterize(a: RasterizeArgs) -> Result<()> {
-    if a.threads > 0 {
-        rayon::ThreadPoolBuilder::new()
-            .num_threads(a.threads)
-            .build_global()
-            .ok();
+    let background = crate::image::parse_hex_rgba(&a.background)?;
+    if background[3] != 0 && matches!(a.format, OutputFormat::Pdf) {
+        warn!("--background is not applied to PDF output");
+    }
+    if (a.export_id.is_some() || a.viewbox.is_some()) && matches!(a.format, OutputFormat::Pdf) {
+        bail!("--export-id and --viewbox are not supported for PDF output");
+    }
+    if a.sizes.contains(&0) {
+        bail!("--sizes must be greater than 0");
+    }
+    if a.scale <= 0.0 || a.scales.iter().any(|&s| s <= 0.0) {
+        bail!("--scale and --scales must be greater than 0");
+    }
+    if let Some(template) = &a.name_template {
+        check_template(template)?;
+    }
+    if a.format.is_icon() {
+        check_icon_args(&a)?;
+    }
+    if a.max_pixels == 0 {
+        bail!("--max-pixels must be greater than 0");
+    }
+    if a.tiled && !matches!(a.format, OutputFormat::Png) {
+        bail!("--tiled is only supported for PNG output");
+    }
+    let watching = a.watch.start(&a.input, a.batch.recursive)?;
+    let fontdb = load_fonts(&a.font_dir, &a.font_file, a.default_font_family.as_deref())?;
+
+    if stdio::is_dash(&a.input) {
+        if !a.sizes.is_empty() {
+            bail!("--sizes names its outputs after the input file, which stdin does not have");
+        }
+        let input = stdio::input(&a.input)?;
+        let output = stdio::output(a.output.as_deref().unwrap_or(Path::new("-")), a.format.extension(), true)?;
+        rasterize_single(input.path(), Some(output.path()), &a, &fontdb)?;
+        return output.finish();
     }
 
     let input_meta = fs::metadata(&a.input)
         .with_context(|| format!("Failed to read input metadata: {}", a.input.display()))?;
 
     if input_meta.is_file() {
-        rasterize_single(&a.input, a.output.as_deref(), &a)?;
+        rasterize_single(&a.input, a.output.as_deref(), &a, &fontdb)?;
     } else if input_meta.is_dir() {
-        rasterize_batch(&a.input, a.output.as_deref(), &a)?;
+        rasterize_batch(&a.input, a.output.as_deref(), &a, &fontdb, watching)?;
     } else {
         bail!(
             "Input is neither a file nor a directory: {}",
@@ -69,22 +183,111 @@ pub fn rasterize(a: RasterizeArgs) -> Result<()> {
     Ok(())
 }
 
-fn rasterize_single(input: &Path, output: Option<&Path>, a: &RasterizeArgs) -> Result<()> {
+// Loaded once and shared by every file of a batch
+fn load_fonts(font_dirs: &[PathBuf], font_files: &[PathBuf], default_family: Option<&str>) -> Result<Arc<usvg::fontdb::Database>> {
+    let mut db = usvg::fontdb::Database::new();
+    db.load_system_fonts();
+    for dir in font_dirs {
+        if !dir.is_dir() {
+            bail!("Font directory not found: {}", dir.display());
+        }
+        db.load_fonts_dir(dir);
+    }
+    for file in font_files {
+        db.load_font_file(file)
+            .with_context(|| format!("Load font: {}", file.display()))?;
+    }
+
+    if let Some(family) = default_family {
+        let known = db
+            .faces()
+            .any(|face| face.families.iter().any(|(name, _)| name.eq_ignore_ascii_case(family)));
+        if !known {
+            warn!("Font family '{}' is not installed or loaded, text will use a fallback", family);
+        }
+    }
+    Ok(Arc::new(db))
+}
+
+fn rasterize_single(input: &Path, output: Option<&Path>, a: &RasterizeArgs, fontdb: &Arc<usvg::fontdb::Database>) -> Result<()> {
     ensure_svg(input)?;
+    if a.name_template.is_some() {
+        bail!("--name-template applies to directory input, use --output to name a single file");
+    }
 
-    let output_path = resolve_output(input, output, a.format)?;
-    if output_path.exists() && !a.overwrite {
-        bail!("Output exists (use --overwrite: {}", output_path.display());
+    let variants = if a.sizes.is_empty() || a.format.is_icon() {
+        let output = resolve_output(input, output, a.format)?;
+        scale_variants(output, a)
+    } else {
+        let dir = match output {
+            Some(out) if out.extension().is_some() && !out.is_dir() => {
+                bail!("With --sizes, --output must be a directory: {}", out.display())
+            }
+            Some(out) => out.to_path_buf(),
+            None => input.parent().map(Path::to_path_buf).unwrap_or_default(),
+        };
+        size_variants(input, &dir, a)
+    };
+
+    let variants = writable(variants, stdio::Clobber::Refuse)?;
+    if variants.is_empty() {
+        return Ok(());
     }
-    if let Some(parent) = output_path.parent() {
-        fs::create_dir_all(parent).with_context(|| format!("Create dir: {}", parent.display()))?;
+    for variant in &variants {
+        if let Some(parent) = variant.output.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Create dir: {}", parent.display()))?;
+        }
     }
 
-    render_svg(input, &output_path, a)?;
-    Ok(())
+    render_svg(input, &variants, a, fontdb)
+}
+
+// The variants whose outputs may be written, as the overwrite flags say, or as `default` without
+// one: a single SVG refuses an existing output, a batch skips it
+fn writable(variants: Vec<Variant>, default: stdio::Clobber) -> Result<Vec<Variant>> {
+    let mut kept = Vec::with_capacity(variants.len());
+    for variant in variants {
+        if stdio::check_output_or(&variant.output, default)? {
+            kept.push(variant);
+        }
+    }
+    Ok(kept)
+}
+
+fn size_variants(input: &Path, dir: &Path, a: &RasterizeArgs) -> Vec<Variant> {
+    let stem = input.file_stem().and_then(OsStr::to_str).unwrap_or("output");
+    a.sizes
+        .iter()
+        .map(|&size| Variant {
+            output: dir.join(format!("{stem}_{size}.{}", a.format.extension())),
+            width: Some(size),
+            height: Some(size),
+            scale: a.scale,
+        })
+        .collect()
 }
 
-fn rasterize_batch(input: &Path, output: Option<&Path>, a: &RasterizeArgs) -> Result<()> {
+// `output` itself, or one @Nx file next to it per --scales entry
+fn scale_variants(output: PathBuf, a: &RasterizeArgs) -> Vec<Variant> {
+    if a.scales.is_empty() {
+        return vec![Variant { output, width: a.width, height: a.height, scale: a.scale }];
+    }
+    let stem = output.file_stem().and_then(OsStr::to_str).unwrap_or("output").to_string();
+    a.scales
+        .iter()
+        .map(|&factor| {
+            let suffix = if factor == 1.0 { String::new() } else { format!("@{factor}x") };
+            Variant {
+                output: output.with_file_name(format!("{stem}{suffix}.{}", a.format.extension())),
+                width: a.width,
+                height: a.height,
+                scale: a.scale * factor,
+            }
+        })
+        .collect()
+}
+
+fn rasterize_batch(input: &Path, output: Option<&Path>, a: &RasterizeArgs, fontdb: &Arc<usvg::fontdb::Database>, watching: Option<Watching>) -> Result<()> {
     let output_dir = match output {
         Some(path) => path.to_path_buf(),
         None => input.join("rasterized"),
@@ -92,60 +295,153 @@ fn rasterize_batch(input: &Path, output: Option<&Path>, a: &RasterizeArgs) -> Re
     fs::create_dir_all(&output_dir)
         .with_context(|| format!("Create dir: {}", output_dir.display()))?;
 
-    let mut walker = WalkDir::new(input);
-    if !a.recursive {
-        walker = walker.max_depth(1);
-    }
-
-    let svgs: Vec<PathBuf> = walker
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .map(|e| e.into_path())
-        .filter(|p| p.is_file() && is_svg(p))
-        .collect();
+    let svgs = a.batch.collect(input, is_svg)?;
 
-    svgs.par_iter().try_for_each(|svg_path| -> Result<()> {
-        let relative_path = svg_path.strip_prefix(input).unwrap_or(svg_path.as_path());
+    let render = |svg_path: &Path| -> Result<()> {
+        let relative_path = svg_path.strip_prefix(input).unwrap_or(svg_path);
 
         let output_path = output_dir
             .join(relative_path)
-            .with_extension(match a.format {
-                OutputFormat::Png => "png",
-                OutputFormat::Bmp => "bmp",
-            });
+            .with_extension(a.format.extension());
 
-        if output_path.exists() && !a.overwrite {
+        let mut variants = if a.sizes.is_empty() || a.format.is_icon() {
+            scale_variants(output_path, a)
+        } else {
+            size_variants(svg_path, output_path.parent().unwrap_or(&output_dir), a)
+        };
+        // Templated names are only known once the SVG is parsed, render_svg checks those
+        if a.name_template.is_none() {
+            variants = writable(variants, stdio::Clobber::Skip)?;
+        }
+        if variants.is_empty() {
             return Ok(());
         }
 
-        if let Some(parent) = output_path.parent() {
-            fs::create_dir_all(parent)
-                .with_context(|| format!("Create dir: {}", parent.display()))?;
+        for variant in &variants {
+            if let Some(parent) = variant.output.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("Create dir: {}", parent.display()))?;
+            }
         }
 
-        render_svg(svg_path, &output_path, a)?;
-        Ok(())
-    })?;
+        render_svg(svg_path, &variants, a, fontdb)
+    };
 
-    Ok(())
+    if a.no_progress {
+        crate::progress::set_enabled(false);
+    }
+    let first = render_all(&svgs, a, &render);
+
+    // Renders land in the output directory or are not SVGs, either way they are not picked up again
+    let source = |path: &Path| is_svg(path) && !path.starts_with(&output_dir);
+    watch::follow(watching, first, input, &a.batch.walk, source, |changed, _| render_all(changed, a, &render))
 }
 
-fn render_svg(input: &Path, output: &Path, a: &RasterizeArgs) -> Result<()> {
-    let data = fs::read(input).with_context(|| format!("Read SVG: {}", input.display()))?;
+// One batch behind the progress bar, closed by the summary
+fn render_all(svgs: &[PathBuf], a: &RasterizeArgs, render: &(impl Fn(&Path) -> Result<()> + Sync)) -> Result<()> {
+    let started = Instant::now();
+    let report = batch::process(svgs, a.batch.threads, a.keep_going, "Rendering", render)?;
+    let elapsed = started.elapsed().as_secs_f64();
+    let done = report.succeeded.len() + report.failed.len();
+    let rate = if elapsed > 0.0 { done as f64 / elapsed } else { 0.0 };
+
+    match a.summary {
+        SummaryFormat::Text => {
+            report.log_failures();
+            println!("{} in {:.2}s ({:.1} files/s)", report.counts(), elapsed, rate);
+            if report.skipped > 0 {
+                println!("Stopped at the first failure, use --keep-going to render the rest");
+            }
+        }
+        SummaryFormat::Json => {
+            let summary = serde_json::json!({
+                "succeeded": report.succeeded,
+                "failed": report.failed.iter()
+                    .map(|(path, e)| serde_json::json!({ "path": path, "error": format!("{:#}", e) }))
+                    .collect::<Vec<_>>(),
+                "skipped": report.skipped,
+                "elapsed_secs": elapsed,
+                "files_per_sec": rate,
+            });
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+        }
+    }
+    report.into_result()
+}
 
-    let mut options = usvg::Options::default();
-    options.resources_dir = input.parent().map(|p| p.to_path_buf());
+// Parses once and writes every variant
+fn render_svg(input: &Path, variants: &[Variant], a: &RasterizeArgs, fontdb: &Arc<usvg::fontdb::Database>) -> Result<()> {
+    let data = read_svg(input)?;
 
-    std::sync::Arc::make_mut(&mut options.fontdb).load_system_fonts();
+    let mut options = usvg::Options {
+        resources_dir: input.parent().map(|p| p.to_path_buf()),
+        fontdb: fontdb.clone(),
+        ..Default::default()
+    };
+    if let Some(family) = &a.default_font_family {
+        options.font_family = family.clone();
+    }
 
     let tree = usvg::Tree::from_data(&data, &options)
         .with_context(|| format!("Parse SVG: {}", input.display()))?;
 
-    let size = tree.size();
+    // Area of the drawing to render, in the pixels of tree.size()
+    let region = match (&a.export_id, &a.viewbox) {
+        (Some(id), _) => {
+            let node = tree
+                .node_by_id(id)
+                .with_context(|| format!("No element with id '{}' in {}", id, input.display()))?;
+            node.abs_layer_bounding_box()
+                .with_context(|| format!("Element '{}' has nothing to render", id))?
+                .to_rect()
+        }
+        (None, Some(viewbox)) => user_region(&data, tree.size(), viewbox)?,
+        (None, None) => tree.size().to_rect(0.0, 0.0).context("Empty SVG size")?,
+    };
+    if a.dpi <= 0.0 {
+        bail!("--dpi must be greater than 0");
+    }
+
+    let size = usvg::Size::from_wh(region.width(), region.height()).context("Empty render region")?;
+    for variant in variants {
+        let output = match &a.name_template {
+            Some(template) => {
+                let (width, height) = if a.format.is_icon() {
+                    let largest = icon_sizes(a).last().copied().unwrap_or_default();
+                    (largest, largest)
+                } else {
+                    output_size(size, variant)
+                };
+                let name = expand_template(template, input, width, height, a.format.extension());
+                let output = variant.output.with_file_name(name);
+                if !stdio::check_output_or(&output, stdio::Clobber::Skip)? {
+                    continue;
+                }
+                if let Some(parent) = output.parent() {
+                    fs::create_dir_all(parent).with_context(|| format!("Create dir: {}", parent.display()))?;
+                }
+                output
+            }
+            None => variant.output.clone(),
+        };
+        let rendered = stdio::write_atomic(&output, |output| {
+            if a.format.is_icon() {
+                render_icon(&tree, region, output, a)
+            } else {
+                render_variant(&tree, region, variant, output, a)
+            }
+        });
+        rendered.with_context(|| format!("Render {}", input.display()))?;
+    }
+    Ok(())
+}
+
+// Pixel size of a variant: --width/--height (keeping the aspect when only one is given), then the scale
+fn output_size(size: usvg::Size, variant: &Variant) -> (u32, u32) {
     let mut width = size.width().ceil() as u32;
     let mut height = size.height().ceil() as u32;
 
-    match (a.width, a.height) {
+    match (variant.width, variant.height) {
         (Some(w), Some(h)) => {
             width = w;
             height = h;
@@ -156,18 +452,112 @@ fn render_svg(input: &Path, output: &Path, a: &RasterizeArgs) -> Result<()> {
             height = (w as f32 * aspect).round().max(1.0) as u32;
         }
         (None, Some(h)) => {
-            let aspect = (width as f32) / (h as f32);
+            let aspect = (width as f32) / (height as f32);
             height = h;
             width = (h as f32 * aspect).round().max(1.0) as u32;
         }
         (None, None) => {}
     }
+    width = (width as f32 * variant.scale).round().max(1.0) as u32;
+    height = (height as f32 * variant.scale).round().max(1.0) as u32;
+    (width, height)
+}
 
-    let mut pixmap = tiny_skia::Pixmap::new(width, height)
-        .with_context(|| format!("Allocate Pixmap {}x{}", width, height))?;
+fn render_variant(tree: &usvg::Tree, region: tiny_skia::Rect, variant: &Variant, output: &Path, a: &RasterizeArgs) -> Result<()> {
+    let size = usvg::Size::from_wh(region.width(), region.height()).context("Empty render region")?;
+    let (width, height) = output_size(size, variant);
 
-    let source_width = size.width() as f32;
-    let source_height = size.height() as f32;
+    if let OutputFormat::Pdf = a.format {
+        // The page is the SVG at its own aspect, sized so it spans width x height pixels at --dpi
+        let scale = match (variant.width, variant.height) {
+            (Some(w), Some(h)) => (w as f32 / size.width()).min(h as f32 / size.height()),
+            (Some(w), None) => w as f32 / size.width(),
+            (None, Some(h)) => h as f32 / size.height(),
+            (None, None) => 1.0,
+        } * variant.scale;
+        let page = svg2pdf::PageOptions { dpi: a.dpi / scale };
+        let pdf = svg2pdf::to_pdf(tree, svg2pdf::ConversionOptions::default(), page)
+            .map_err(|e| anyhow::anyhow!("Convert to PDF: {}", e))?;
+        fs::write(output, pdf).with_context(|| format!("Write PDF: {}", output.display()))?;
+        return Ok(());
+    }
+
+    let pixels = width as u64 * height as u64;
+    if a.tiled && matches!(a.format, OutputFormat::Png) {
+        return render_png_strips(tree, region, width, height, output, a);
+    }
+    if pixels > a.max_pixels {
+        bail!(
+            "Output is {}x{} = {} pixels, above --max-pixels {} (raise it, or use --tiled for PNG)",
+            width, height, pixels, a.max_pixels
+        );
+    }
+    if memory::limit().is_some_and(|limit| pixels.saturating_mul(BYTES_PER_PIXEL) > limit) {
+        if matches!(a.format, OutputFormat::Png) {
+            info!("Rendering {}x{} in strips to stay within --max-memory", width, height);
+            return render_png_strips(tree, region, width, height, output, a);
+        }
+        memory::reserve(pixels.saturating_mul(BYTES_PER_PIXEL), || format!("rendering {}x{}", width, height))?;
+    }
+
+    let img = render_pixels(tree, region, width, height, a)?;
+
+    match a.format {
+        OutputFormat::Png => img
+            .save_with_format(output, image::ImageFormat::Png)
+            .with_context(|| format!("Write PNG: {}", output.display()))?,
+        OutputFormat::Bmp => img
+            .save_with_format(output, image::ImageFormat::Bmp)
+            .with_context(|| format!("Write BMP: {}", output.display()))?,
+        OutputFormat::Pdf | OutputFormat::Ico | OutputFormat::Icns => unreachable!("written by render_svg"),
+    }
+
+    Ok(())
+}
+
+// Renders strips of at most --max-pixels, or what --max-memory holds, and streams them into the
+// PNG, so only one strip is in memory
+fn render_png_strips(tree: &usvg::Tree, region: tiny_skia::Rect, width: u32, height: u32, output: &Path, a: &RasterizeArgs) -> Result<()> {
+    let budget = memory::limit().map_or(a.max_pixels, |limit| a.max_pixels.min(limit / BYTES_PER_PIXEL));
+    let rows = (budget / width as u64).min(height as u64) as u32;
+    if rows == 0 {
+        bail!("A single {}px row is above --max-pixels {} or --max-memory", width, a.max_pixels);
+    }
+
+    let file = fs::File::create(output).with_context(|| format!("Create: {}", output.display()))?;
+    let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().with_context(|| format!("Write PNG: {}", output.display()))?;
+    let mut stream = writer.stream_writer()?;
+
+    let mut top = 0;
+    while top < height {
+        let strip = rows.min(height - top);
+        let img = render_rows(tree, region, width, height, top, strip, a)?;
+        stream
+            .write_all(img.as_raw())
+            .with_context(|| format!("Write PNG: {}", output.display()))?;
+        top += strip;
+    }
+    stream.finish().with_context(|| format!("Write PNG: {}", output.display()))?;
+    Ok(())
+}
+
+// `region` fitted into width x height, centered, over --background
+fn render_pixels(tree: &usvg::Tree, region: tiny_skia::Rect, width: u32, height: u32, a: &RasterizeArgs) -> Result<image::RgbaImage> {
+    render_rows(tree, region, width, height, 0, height, a)
+}
+
+// Rows top..top + rows of the width x height render
+fn render_rows(tree: &usvg::Tree, region: tiny_skia::Rect, width: u32, height: u32, top: u32, rows: u32, a: &RasterizeArgs) -> Result<image::RgbaImage> {
+    let mut pixmap = tiny_skia::Pixmap::new(width, rows)
+        .with_context(|| format!("Allocate Pixmap {}x{}", width, rows))?;
+    let [r, g, b, alpha] = crate::image::parse_hex_rgba(&a.background)?;
+    pixmap.fill(tiny_skia::Color::from_rgba8(r, g, b, alpha));
+
+    let source_width = region.width();
+    let source_height = region.height();
 
     let target_width = width as f32;
     let target_height = height as f32;
@@ -179,32 +569,429 @@ fn render_svg(input: &Path, output: &Path, a: &RasterizeArgs) -> Result<()> {
     let transform_x = (target_width - source_width * scale) * 0.5;
     let transform_y = (target_height - source_height * scale) * 0.5;
 
-    let transform = tiny_skia::Transform::from_scale(scale, scale).post_translate(transform_x, transform_y);
+    let transform = tiny_skia::Transform::from_translate(-region.x(), -region.y())
+        .post_scale(scale, scale)
+        .post_translate(transform_x, transform_y - top as f32);
 
-    resvg::render(&tree, transform, &mut pixmap.as_mut());
+    resvg::render(tree, transform, &mut pixmap.as_mut());
 
-    let rgba = pixmap.data().to_vec();
-    let img = image::RgbaImage::from_raw(width, height, rgba)
-        .with_context(|| "pixmap -> image buffer - conversion failed")?;
+    // tiny-skia stores premultiplied alpha
+    let rgba = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|p| {
+            let c = p.demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        })
+        .collect();
+    image::RgbaImage::from_raw(width, rows, rgba)
+        .context("pixmap -> image buffer - conversion failed")
+}
+
+const ICO_SIZES: [u32; 7] = [16, 24, 32, 48, 64, 128, 256];
 
+// PNG-backed ICNS entries: pixel size and OSType, @2x types share the render of the same size
+const ICNS_TYPES: [(u32, &[u8; 4]); 11] = [
+    (16, b"icp4"),
+    (32, b"icp5"),
+    (32, b"ic11"),
+    (64, b"icp6"),
+    (64, b"ic12"),
+    (128, b"ic07"),
+    (256, b"ic08"),
+    (256, b"ic13"),
+    (512, b"ic09"),
+    (512, b"ic14"),
+    (1024, b"ic10"),
+];
+
+fn check_icon_args(a: &RasterizeArgs) -> Result<()> {
+    if a.width.is_some() || a.height.is_some() || !a.scales.is_empty() || a.scale != 1.0 {
+        bail!("--width, --height, --scale and --scales do not apply to {} output, use --sizes to pick the icon sizes", a.format.extension());
+    }
     match a.format {
-        OutputFormat::Png => img
-            .save_with_format(output, image::ImageFormat::Png)
-            .with_context(|| format!("Write PNG: {}", output.display()))?,
-        OutputFormat::Bmp => img
-            .save_with_format(output, image::ImageFormat::Bmp)
-            .with_context(|| format!("Write BMP: {}", output.display()))?,
+        OutputFormat::Ico => {
+            if let Some(size) = a.sizes.iter().find(|&&size| size > 256) {
+                bail!("ICO icons are at most 256px, got {}", size);
+            }
+        }
+        OutputFormat::Icns => {
+            if let Some(size) = a.sizes.iter().find(|&&size| !ICNS_TYPES.iter().any(|&(s, _)| s == size)) {
+                bail!("ICNS has no {}px icon, pick from 16, 32, 64, 128, 256, 512, 1024", size);
+            }
+        }
+        _ => {}
     }
+    Ok(())
+}
+
+// Ascending, without duplicates
+fn icon_sizes(a: &RasterizeArgs) -> Vec<u32> {
+    let mut sizes = match (a.sizes.is_empty(), a.format) {
+        (false, _) => a.sizes.clone(),
+        (true, OutputFormat::Icns) => ICNS_TYPES.iter().map(|&(size, _)| size).collect(),
+        (true, _) => ICO_SIZES.to_vec(),
+    };
+    sizes.sort_unstable();
+    sizes.dedup();
+    sizes
+}
+
+fn render_icon(tree: &usvg::Tree, region: tiny_skia::Rect, output: &Path, a: &RasterizeArgs) -> Result<()> {
+    let sizes = icon_sizes(a);
+    let images = sizes
+        .iter()
+        .map(|&size| render_pixels(tree, region, size, size, a))
+        .collect::<Result<Vec<_>>>()?;
+
+    let bytes = match a.format {
+        OutputFormat::Ico => {
+            let frames = images
+                .iter()
+                .map(|img| image::codecs::ico::IcoFrame::as_png(img.as_raw(), img.width(), img.height(), image::ExtendedColorType::Rgba8))
+                .collect::<image::ImageResult<Vec<_>>>()?;
+            let mut ico = Vec::new();
+            image::codecs::ico::IcoEncoder::new(&mut ico).encode_images(&frames)?;
+            ico
+        }
+        OutputFormat::Icns => {
+            let mut body = Vec::new();
+            for (size, img) in sizes.iter().zip(&images) {
+                let mut png = Vec::new();
+                img.write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)?;
+                for (_, ostype) in ICNS_TYPES.iter().filter(|&&(s, _)| s == *size) {
+                    body.extend_from_slice(*ostype);
+                    body.extend_from_slice(&(png.len() as u32 + 8).to_be_bytes());
+                    body.extend_from_slice(&png);
+                }
+            }
+            let mut icns = b"icns".to_vec();
+            icns.extend_from_slice(&(body.len() as u32 + 8).to_be_bytes());
+            icns.extend_from_slice(&body);
+            icns
+        }
+        _ => unreachable!("not an icon format"),
+    };
+    fs::write(output, bytes).with_context(|| format!("Write {}: {}", a.format.extension().to_uppercase(), output.display()))
+}
 
+const TEMPLATE_FIELDS: [&str; 4] = ["stem", "width", "height", "ext"];
+
+fn check_template(template: &str) -> Result<()> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            bail!("Unclosed '{{' in --name-template '{}'", template);
+        };
+        let field = &rest[start + 1..start + len];
+        if !TEMPLATE_FIELDS.contains(&field) {
+            bail!("Unknown field '{{{}}}' in --name-template, expected one of {{stem}}, {{width}}, {{height}}, {{ext}}", field);
+        }
+        rest = &rest[start + len + 1..];
+    }
+    if !template.contains("{stem}") {
+        warn!("--name-template has no {{stem}}, files with the same size will overwrite each other");
+    }
+    if Path::new(template).components().any(|c| !matches!(c, std::path::Component::Normal(_))) {
+        bail!("--name-template must be a relative path without '..': {}", template);
+    }
     Ok(())
 }
 
-fn resolve_output(input: &Path, output: Option<&Path>, format: OutputFormat) -> Result<PathBuf> {
-    let extension = match format {
-        OutputFormat::Png => "png",
-        OutputFormat::Bmp => "bmp",
+fn expand_template(template: &str, input: &Path, width: u32, height: u32, ext: &str) -> String {
+    let stem = input.file_stem().and_then(OsStr::to_str).unwrap_or("output");
+    template
+        .replace("{stem}", stem)
+        .replace("{width}", &width.to_string())
+        .replace("{height}", &height.to_string())
+        .replace("{ext}", ext)
+}
+
+// Maps x,y,w,h in the root viewBox coordinates onto the canvas, the same way usvg places
+// the viewBox (preserveAspectRatio included)
+fn user_region(data: &[u8], size: usvg::Size, viewbox: &str) -> Result<tiny_skia::Rect> {
+    let [x, y, w, h] = parse_rect(viewbox).with_context(|| format!("Invalid --viewbox '{}', expected x,y,w,h", viewbox))?;
+    if w <= 0.0 || h <= 0.0 {
+        bail!("--viewbox width and height must be greater than 0");
+    }
+
+    let text = std::str::from_utf8(data).context("SVG is not UTF-8")?;
+    let options = usvg::roxmltree::ParsingOptions { allow_dtd: true, ..Default::default() };
+    let doc = usvg::roxmltree::Document::parse_with_options(text, options).context("Parse SVG")?;
+    let root = doc.root_element();
+
+    let mut ts = tiny_skia::Transform::identity();
+    if let Some([vx, vy, vw, vh]) = root.attribute("viewBox").and_then(parse_rect)
+        && vw > 0.0
+        && vh > 0.0
+    {
+        let (sx, sy) = (size.width() / vw, size.height() / vh);
+        let aspect = root.attribute("preserveAspectRatio").unwrap_or("xMidYMid meet");
+        ts = if aspect.trim_start().starts_with("none") {
+            tiny_skia::Transform::from_row(sx, 0.0, 0.0, sy, -vx * sx, -vy * sy)
+        } else {
+            let s = if aspect.contains("slice") { sx.max(sy) } else { sx.min(sy) };
+            let align = |min: &str, max: &str| if aspect.contains(min) { 0.0 } else if aspect.contains(max) { 1.0 } else { 0.5 };
+            let tx = -vx * s + (size.width() - vw * s) * align("xMin", "xMax");
+            let ty = -vy * s + (size.height() - vh * s) * align("YMin", "YMax");
+            tiny_skia::Transform::from_row(s, 0.0, 0.0, s, tx, ty)
+        };
+    }
+
+    tiny_skia::Rect::from_xywh(x, y, w, h)
+        .and_then(|r| r.transform(ts))
+        .context("Invalid --viewbox region")
+}
+
+// "x,y,w,h", also accepts the whitespace separated form of the viewBox attribute
+fn parse_rect(s: &str) -> Option<[f32; 4]> {
+    let values: Vec<f32> = s
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.parse().ok())
+        .collect::<Option<_>>()?;
+    values.try_into().ok()
+}
+
+#[derive(Args)]
+#[command[name = "svg-optimize", about = "Simplify and minify SVGs by rewriting them through usvg"]]
+pub struct OptimizeArgs {
+    input: PathBuf,
+    /// Output file, or directory for batch mode (default: {stem}.min.svg next to the input, or <input>/optimized)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Decimal places kept in coordinates and transforms
+    #[arg(short, long, default_value_t = 3)]
+    precision: u8,
+    /// Indent the output instead of writing it on one line
+    #[arg(long)]
+    pretty: bool,
+    /// Keep ids that nothing in the file references
+    #[arg(long)]
+    keep_ids: bool,
+    /// Prefix for the ids of gradients, clip paths and other definitions
+    #[arg(long)]
+    id_prefix: Option<String>,
+    /// Keep <text> elements instead of converting them to paths
+    #[arg(long)]
+    preserve_text: bool,
+    #[command(flatten)]
+    batch: BatchArgs,
+}
+
+pub fn optimize(a: OptimizeArgs) -> Result<()> {
+    let fontdb = load_fonts(&[], &[], None)?;
+
+    if stdio::is_dash(&a.input) || a.output.as_deref().is_some_and(stdio::is_dash) {
+        if let Some(output) = &a.output
+            && !stdio::check_output(output)?
+        {
+            return Ok(());
+        }
+        let input = stdio::input(&a.input)?;
+        ensure_svg(input.path())?;
+        let output = stdio::output(a.output.as_deref().unwrap_or(Path::new("-")), "svg", false)?;
+        let (before, after) = optimize_svg(input.path(), output.path(), &a, &fontdb)?;
+        info!("{} -> {} ({})", stdio::display(&a.input), stdio::display(output.path()), size_change(before, after));
+        return output.finish();
+    }
+
+    let input_meta = fs::metadata(&a.input)
+        .with_context(|| format!("Failed to read input metadata: {}", a.input.display()))?;
+
+    if input_meta.is_file() {
+        ensure_svg(&a.input)?;
+        let output = match &a.output {
+            Some(out) if out.is_dir() || out.extension().is_none() => out.join(optimized_name(&a.input)),
+            Some(out) => out.clone(),
+            None => a.input.with_file_name(optimized_name(&a.input)),
+        };
+        if !stdio::check_output_or(&output, stdio::Clobber::Refuse)? {
+            return Ok(());
+        }
+        let (before, after) = optimize_svg(&a.input, &output, &a, &fontdb)?;
+        info!("{} -> {} ({})", a.input.display(), output.display(), size_change(before, after));
+    } else if input_meta.is_dir() {
+        let output_dir = match &a.output {
+            Some(path) => path.clone(),
+            None => a.input.join("optimized"),
+        };
+        let svgs = a.batch.collect(&a.input, |p| is_svg(p) && !p.starts_with(&output_dir))?;
+
+        batch::run(&svgs, a.batch.threads, |svg_path| {
+            let relative_path = svg_path.strip_prefix(&a.input).unwrap_or(svg_path);
+            let output_path = output_dir.join(relative_path).with_extension("svg");
+            if !stdio::check_output_or(&output_path, stdio::Clobber::Skip)? {
+                return Ok(());
+            }
+            let (before, after) = optimize_svg(svg_path, &output_path, &a, &fontdb)?;
+            info!("{} ({})", output_path.display(), size_change(before, after));
+            Ok(())
+        })?;
+    } else {
+        bail!(
+            "Input is neither a file nor a directory: {}",
+            a.input.display()
+        );
+    }
+
+    Ok(())
+}
+
+fn optimized_name(input: &Path) -> String {
+    let stem = input.file_stem().and_then(OsStr::to_str).unwrap_or("output");
+    format!("{stem}.min.svg")
+}
+
+// Returns the input and output sizes in bytes
+fn optimize_svg(input: &Path, output: &Path, a: &OptimizeArgs, fontdb: &Arc<usvg::fontdb::Database>) -> Result<(usize, usize)> {
+    let data = read_svg(input)?;
+
+    let options = usvg::Options {
+        resources_dir: input.parent().map(|p| p.to_path_buf()),
+        fontdb: fontdb.clone(),
+        ..Default::default()
+    };
+    let tree = usvg::Tree::from_data(&data, &options)
+        .with_context(|| format!("Parse SVG: {}", input.display()))?;
+
+    let indent = if a.pretty { usvg::Indent::Spaces(2) } else { usvg::Indent::None };
+    let write_options = usvg::WriteOptions {
+        id_prefix: a.id_prefix.clone(),
+        preserve_text: a.preserve_text,
+        coordinates_precision: a.precision,
+        transforms_precision: a.precision,
+        indent,
+        ..Default::default()
+    };
+    let mut svg = tree.to_string(&write_options);
+    if !a.keep_ids {
+        svg = strip_unused_ids(&svg);
+    }
+    let svg = collapse_transforms(svg, a.precision);
+
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Create dir: {}", parent.display()))?;
+    }
+    stdio::write(output, svg.as_bytes(), false).with_context(|| format!("Write SVG: {}", output.display()))?;
+    Ok((data.len(), svg.len()))
+}
+
+// Drops id="..." attributes that no url(#id) or href="#id" in the document points at.
+// Works on usvg's own output, which always uses double quotes and one space before attributes.
+fn strip_unused_ids(svg: &str) -> String {
+    const ATTR: &str = " id=\"";
+    let referenced = |id: &str| {
+        svg.contains(&format!("#{id})")) || svg.contains(&format!("\"#{id}\""))
     };
 
+    let mut out = String::with_capacity(svg.len());
+    let mut rest = svg;
+    while let Some(start) = rest.find(ATTR) {
+        let value_start = start + ATTR.len();
+        let Some(len) = rest[value_start..].find('"') else {
+            break;
+        };
+        let end = value_start + len + 1;
+        out.push_str(&rest[..start]);
+        if referenced(&rest[value_start..value_start + len]) {
+            out.push_str(&rest[start..end]);
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// Folds a <g> that only carries a transform into its single child <g>, repeatedly,
+// so chains of nested translate/scale groups end up as one matrix
+fn collapse_transforms(svg: String, precision: u8) -> String {
+    let mut svg = svg;
+    while let Some((range, replacement)) = next_collapse(&svg, precision) {
+        svg.replace_range(range, &replacement);
+    }
+    svg
+}
+
+// The byte range of the outer group and what replaces it
+fn next_collapse(svg: &str, precision: u8) -> Option<(std::ops::Range<usize>, String)> {
+    let doc = usvg::roxmltree::Document::parse(svg).ok()?;
+    for outer in doc.descendants().filter(|n| n.has_tag_name("g")) {
+        let Some(outer_transform) = only_transform(outer) else {
+            continue;
+        };
+        let mut children = outer
+            .children()
+            .filter(|n| !(n.is_text() && n.text().is_some_and(|t| t.trim().is_empty())));
+        let (Some(inner), None) = (children.next(), children.next()) else {
+            continue;
+        };
+        if !inner.has_tag_name("g") {
+            continue;
+        }
+        let combined = outer_transform.pre_concat(
+            inner.attribute("transform").and_then(parse_matrix).unwrap_or_default(),
+        );
+        let value = format!(
+            "matrix({})",
+            [combined.sx, combined.ky, combined.kx, combined.sy, combined.tx, combined.ty]
+                .iter()
+                .map(|v| format_number(*v, precision))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+
+        let inner_range = inner.range();
+        let mut inner_text = svg[inner_range.clone()].to_string();
+        match inner.attributes().find(|attr| attr.name() == "transform") {
+            Some(attr) => {
+                let value_range = attr.range_value();
+                inner_text.replace_range(
+                    value_range.start - inner_range.start..value_range.end - inner_range.start,
+                    &value,
+                );
+            }
+            None => inner_text.insert_str(2, &format!(" transform=\"{value}\"")),
+        }
+        return Some((outer.range(), inner_text));
+    }
+    None
+}
+
+fn only_transform(node: usvg::roxmltree::Node) -> Option<usvg::Transform> {
+    let mut attributes = node.attributes();
+    match (attributes.next(), attributes.next()) {
+        (Some(attr), None) if attr.name() == "transform" => parse_matrix(attr.value()),
+        _ => None,
+    }
+}
+
+// usvg always writes transforms as matrix(a b c d e f)
+fn parse_matrix(s: &str) -> Option<usvg::Transform> {
+    let inner = s.trim().strip_prefix("matrix(")?.strip_suffix(')')?;
+    let values: Vec<f32> = inner
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.parse().ok())
+        .collect::<Option<_>>()?;
+    let [a, b, c, d, e, f]: [f32; 6] = values.try_into().ok()?;
+    Some(usvg::Transform::from_row(a, b, c, d, e, f))
+}
+
+fn format_number(value: f32, precision: u8) -> String {
+    let s = format!("{:.*}", precision as usize, value);
+    let s = if s.contains('.') { s.trim_end_matches('0').trim_end_matches('.') } else { &s };
+    if s == "-0" { "0".to_string() } else { s.to_string() }
+}
+
+fn size_change(before: usize, after: usize) -> String {
+    let percent = if before == 0 { 0.0 } else { (after as f64 - before as f64) / before as f64 * 100.0 };
+    format!("{} -> {} bytes, {:+.1}%", before, after, percent)
+}
+
+fn resolve_output(input: &Path, output: Option<&Path>, format: OutputFormat) -> Result<PathBuf> {
+    let extension = format.extension();
+
     let default_output = input.with_extension(extension);
 
     let Some(out) = output else {
@@ -230,9 +1017,23 @@ fn resolve_output(input: &Path, output: Option<&Path>, format: OutputFormat) ->
     Ok(out.to_path_buf())
 }
 
+// SVG source, gunzipped when it is an .svgz (or any gzip stream)
+fn read_svg(input: &Path) -> Result<Vec<u8>> {
+    let data = fs::read(input).with_context(|| format!("Read SVG: {}", input.display()))?;
+    if !data.starts_with(&[0x1f, 0x8b]) {
+        return Ok(data);
+    }
+    let mut svg = Vec::with_capacity(data.len() * 4);
+    flate2::read::GzDecoder::new(data.as_slice())
+        .read_to_end(&mut svg)
+        .with_context(|| format!("Decompress SVGZ: {}", input.display()))?;
+    Ok(svg)
+}
+
 fn ensure_svg(input: &Path) -> Result<()> {
-    if !is_svg(input) {
-        bail!("Not an .svg file: {}", input.display());
+    // Stdin has no extension to go by, its content has to parse instead
+    if !is_svg(input) && !stdio::is_spooled(input, "stdin") {
+        bail!("Not an .svg or .svgz file: {}", input.display());
     }
     Ok(())
 }
@@ -241,6 +1042,6 @@ fn is_svg(input: &Path) -> bool {
     input
         .extension()
         .and_then(|e| e.to_str())
-        .map(|e| e.eq_ignore_ascii_case("svg"))
+        .map(|e| e.eq_ignore_ascii_case("svg") || e.eq_ignore_ascii_case("svgz"))
         .unwrap_or(false)
 }
@@ -0,0 +1,493 @@
+// `serve`: a directory over plain HTTP/1.1, read-only, e.g. to hand build artifacts to another
+// machine on the LAN. Every response carries a Content-Digest (RFC 9530) computed by the hash
+// module, so the receiving side can check what arrived; responses are compressed when asked to
+// and the client accepts it. One thread per connection, one request per connection
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use clap::{Args, ValueEnum};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+use crate::compression;
+use crate::hash::{self, Algorithm};
+use crate::{archive, errors, stdio};
+
+#[derive(Args)]
+#[command[name = "serve", about = "Serve a directory read-only over HTTP with Content-Digest headers"]]
+pub struct ServeArgs {
+    /// Directory to serve
+    #[arg(default_value = ".")]
+    dir: PathBuf,
+    /// Port to listen on, 0 for any free one
+    #[arg(short, long, default_value_t = 8080)]
+    port: u16,
+    /// Address to listen on: 0.0.0.0 (or ::) shares with the local network
+    #[arg(short, long, default_value = "127.0.0.1")]
+    bind: IpAddr,
+    /// Compress responses with the first of these the client accepts, e.g. `zstd,gzip`
+    #[arg(short, long, value_enum, value_delimiter = ',')]
+    compress: Vec<Encoding>,
+    /// Compression level. Default: 3 for zstd, 6 for gzip, 4 for br
+    #[arg(short, long, requires = "compress")]
+    level: Option<u32>,
+    /// Also serve and list files and directories whose names start with a dot
+    #[arg(long)]
+    hidden: bool,
+}
+
+/// A content coding of HTTP responses
+#[derive(Clone, Copy, ValueEnum, Debug, PartialEq)]
+pub enum Encoding {
+    Zstd,
+    Gzip,
+    Br,
+}
+
+impl Encoding {
+    const fn token(self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+            Encoding::Br => "br",
+        }
+    }
+
+    const fn algorithm(self) -> compression::Algorithm {
+        match self {
+            Encoding::Zstd => compression::Algorithm::Zstd,
+            Encoding::Gzip => compression::Algorithm::Gzip,
+            Encoding::Br => compression::Algorithm::Brotli,
+        }
+    }
+
+    const fn default_level(self) -> u32 {
+        match self {
+            Encoding::Zstd => 3,
+            Encoding::Gzip => 6,
+            Encoding::Br => 4,
+        }
+    }
+}
+
+// Already compressed, where another pass only costs time
+const COMPRESSED: &[&str] = &[
+    "7z", "avif", "br", "bz2", "gif", "gz", "jpeg", "jpg", "lz4", "mp3", "mp4", "ogg", "png", "sz", "webm", "webp", "woff2", "xz", "zip", "zst",
+];
+
+// Kept as is in links: unreserved URI characters and the path separator
+const PATH: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~').remove(b'/');
+
+struct Server {
+    root: PathBuf,
+    compress: Vec<Encoding>,
+    level: Option<u32>,
+    hidden: bool,
+    digests: Mutex<HashMap<PathBuf, CachedDigest>>,
+}
+
+// SHA-256 of a file, valid while its size and modification time are unchanged
+struct CachedDigest {
+    len: u64,
+    modified: SystemTime,
+    digest: Vec<u8>,
+}
+
+pub fn serve(a: ServeArgs) -> Result<()> {
+    let root = fs::canonicalize(&a.dir).with_context(|| errors::at("open", &a.dir))?;
+    if !root.is_dir() {
+        bail!("{} is not a directory", a.dir.display());
+    }
+    let listener = TcpListener::bind(SocketAddr::new(a.bind, a.port)).with_context(|| format!("listen on {}:{}", a.bind, a.port))?;
+    let address = listener.local_addr()?;
+    info!("Serving {} on http://{}/", root.display(), address);
+    if a.bind.is_unspecified() {
+        info!("Reachable from other machines; Ctrl-C stops it");
+    }
+    let server = Arc::new(Server { root, compress: a.compress, level: a.level, hidden: a.hidden, digests: Mutex::new(HashMap::new()) });
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("accept: {}", e);
+                continue;
+            }
+        };
+        let server = Arc::clone(&server);
+        std::thread::spawn(move || {
+            let peer = stream.peer_addr().map_or_else(|_| "?".to_string(), |p| p.to_string());
+            if let Err(e) = server.handle(stream, &peer) {
+                debug!("{}: {:#}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+struct Request {
+    method: String,
+    target: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+// A response before its body: status, headers, and the body's source
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Body,
+}
+
+enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    // A file, this many bytes from where it is positioned
+    File(File, u64),
+    // A compressed copy
+    Spooled(Spool),
+}
+
+// A temporary file, removed on drop
+struct Spool {
+    file: File,
+    path: PathBuf,
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Response {
+    fn text(status: u16, message: &str) -> Response {
+        Response { status, headers: vec![("Content-Type", "text/plain; charset=utf-8".to_string())], body: Body::Bytes(format!("{}\n", message).into_bytes()) }
+    }
+}
+
+impl Server {
+    fn handle(&self, mut stream: TcpStream, peer: &str) -> Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+        let request = match read_request(&mut stream) {
+            Ok(request) => request,
+            Err(e) => {
+                send(&mut stream, Response::text(400, "Bad request"), false)?;
+                return Err(e);
+            }
+        };
+        let head = request.method == "HEAD";
+        let response = if request.method == "GET" || head {
+            self.respond(&request).unwrap_or_else(|e| {
+                warn!("{} {}: {:#}", request.method, request.target, e);
+                Response::text(500, "Internal server error")
+            })
+        } else {
+            let mut response = Response::text(405, "Method not allowed");
+            response.headers.push(("Allow", "GET, HEAD".to_string()));
+            response
+        };
+        info!("{} {} {} {}", peer, request.method, request.target, response.status);
+        send(&mut stream, response, head)
+    }
+
+    fn respond(&self, request: &Request) -> Result<Response> {
+        let path = request.target.split(['?', '#']).next().unwrap_or_default();
+        let Some(path) = percent_decode_str(path).decode_utf8().ok().filter(|p| p.starts_with('/')) else {
+            return Ok(Response::text(400, "Bad request"));
+        };
+        // Only plain names below the root, and no hidden ones unless asked for
+        let mut local = self.root.clone();
+        for component in Path::new(path.as_ref()).components() {
+            match component {
+                Component::RootDir => {}
+                Component::Normal(name) if self.hidden || !name.to_string_lossy().starts_with('.') => local.push(name),
+                _ => return Ok(Response::text(404, "Not found")),
+            }
+        }
+        // Symbolic links are followed only where they stay inside the root
+        let Ok(local) = fs::canonicalize(&local) else { return Ok(Response::text(404, "Not found")) };
+        if !local.starts_with(&self.root) {
+            return Ok(Response::text(404, "Not found"));
+        }
+
+        if local.is_dir() {
+            if !path.ends_with('/') {
+                let mut response = Response::text(301, "Moved permanently");
+                response.headers.push(("Location", format!("{}/", utf8_percent_encode(&path, PATH))));
+                return Ok(response);
+            }
+            let index = local.join("index.html");
+            if !index.is_file() {
+                return self.listing(&local, &path);
+            }
+            return self.file(request, &index);
+        }
+        self.file(request, &local)
+    }
+
+    fn file(&self, request: &Request, path: &Path) -> Result<Response> {
+        let meta = fs::metadata(path)?;
+        let len = meta.len();
+        let digest = self.digest(path, len, meta.modified()?)?;
+        let etag = format!("\"{}\"", hex::encode(&digest[..16]));
+        let modified = http_date(meta.modified()?);
+        let mut headers = vec![
+            ("Content-Type", content_type(path).to_string()),
+            ("Last-Modified", modified.clone()),
+            ("ETag", etag.clone()),
+            ("Accept-Ranges", "bytes".to_string()),
+        ];
+        if !self.compress.is_empty() {
+            headers.push(("Vary", "Accept-Encoding".to_string()));
+        }
+        if request.header("If-None-Match").is_some_and(|tags| tags.split(',').any(|t| t.trim() == etag || t.trim() == "*")) {
+            return Ok(Response { status: 304, headers, body: Body::Empty });
+        }
+
+        // A single byte range of the file as stored. Repr-Digest still covers the whole file. An
+        // If-Range that no longer matches asks for the whole file instead
+        let current = request.header("If-Range").is_none_or(|v| v.trim() == etag || v.trim() == modified);
+        if let Some(range) = request.header("Range").filter(|_| current) {
+            let Some((start, end)) = parse_range(range, len) else {
+                let mut response = Response::text(416, "Range not satisfiable");
+                response.headers.push(("Content-Range", format!("bytes */{}", len)));
+                return Ok(response);
+            };
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(start))?;
+            let part = sha256(file.try_clone()?.take(end - start + 1))?;
+            file.seek(SeekFrom::Start(start))?;
+            headers.push(("Content-Range", format!("bytes {}-{}/{}", start, end, len)));
+            headers.push(("Content-Digest", structured_digest(&part)));
+            headers.push(("Repr-Digest", structured_digest(&digest)));
+            return Ok(Response { status: 206, headers, body: Body::File(file, end - start + 1) });
+        }
+
+        let extension = path.extension().map(|e| e.to_string_lossy().to_ascii_lowercase()).unwrap_or_default();
+        let encoding = if COMPRESSED.contains(&extension.as_str()) || len < 256 { None } else { self.negotiate(request.header("Accept-Encoding")) };
+        if let Some(encoding) = encoding {
+            let (spooled, file) = stdio::temp_file("serve")?;
+            let mut spool = Spool { file, path: spooled };
+            let level = self.level.unwrap_or(encoding.default_level());
+            compression::compress_reader(File::open(path)?, &mut spool.file, encoding.algorithm(), level)?;
+            let compressed = spool.file.metadata()?.len();
+            // Sent as stored when compression does not pay off
+            if compressed < len {
+                spool.file.seek(SeekFrom::Start(0))?;
+                let encoded = sha256(&mut spool.file)?;
+                spool.file.seek(SeekFrom::Start(0))?;
+                headers.push(("Content-Encoding", encoding.token().to_string()));
+                headers.push(("Content-Digest", structured_digest(&encoded)));
+                headers.push(("Repr-Digest", structured_digest(&encoded)));
+                debug!("{}: {} to {} bytes with {}", path.display(), len, compressed, encoding.token());
+                return Ok(Response { status: 200, headers, body: Body::Spooled(spool) });
+            }
+        }
+        headers.push(("Content-Digest", structured_digest(&digest)));
+        headers.push(("Repr-Digest", structured_digest(&digest)));
+        Ok(Response { status: 200, headers, body: Body::File(File::open(path)?, len) })
+    }
+
+    // The server's first choice among the codings the client accepts
+    fn negotiate(&self, accept: Option<&str>) -> Option<Encoding> {
+        let accepted: Vec<&str> = accept?
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                let token = parts.next()?.trim();
+                let refused = parts.any(|p| p.trim().strip_prefix("q=").and_then(|q| q.trim().parse::<f32>().ok()) == Some(0.0));
+                (!refused).then_some(token)
+            })
+            .collect();
+        self.compress.iter().copied().find(|e| accepted.iter().any(|t| t.eq_ignore_ascii_case(e.token()) || *t == "*"))
+    }
+
+    fn digest(&self, path: &Path, len: u64, modified: SystemTime) -> Result<Vec<u8>> {
+        if let Some(cached) = self.digests.lock().expect("digest cache poisoned").get(path)
+            && cached.len == len
+            && cached.modified == modified
+        {
+            return Ok(cached.digest.clone());
+        }
+        let digest = sha256(File::open(path)?)?;
+        self.digests.lock().expect("digest cache poisoned").insert(path.to_path_buf(), CachedDigest { len, modified, digest: digest.clone() });
+        Ok(digest)
+    }
+
+    fn listing(&self, dir: &Path, path: &str) -> Result<Response> {
+        let mut entries: Vec<(bool, String, u64)> = fs::read_dir(dir)?
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let name = e.file_name().to_string_lossy().into_owned();
+                let meta = fs::metadata(e.path()).ok()?;
+                (self.hidden || !name.starts_with('.')).then_some((meta.is_dir(), name, meta.len()))
+            })
+            .collect();
+        entries.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        let title = escape_html(path);
+        let mut html = format!("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title></head>\n<body><h1>{title}</h1>\n<ul>\n");
+        if path != "/" {
+            html.push_str("<li><a href=\"../\">../</a></li>\n");
+        }
+        for (is_dir, name, size) in entries {
+            let slash = if is_dir { "/" } else { "" };
+            let href = utf8_percent_encode(&name, PATH).to_string().replace('/', "%2F");
+            let size = if is_dir { String::new() } else { format!(" ({} bytes)", size) };
+            html.push_str(&format!("<li><a href=\"{}{}\">{}{}</a>{}</li>\n", href, slash, escape_html(&name), slash, size));
+        }
+        html.push_str("</ul>\n</body></html>\n");
+        Ok(Response { status: 200, headers: vec![("Content-Type", "text/html; charset=utf-8".to_string())], body: Body::Bytes(html.into_bytes()) })
+    }
+}
+
+fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    // Headers beyond 16 KiB are not read
+    let mut reader = BufReader::new(stream.take(16 * 1024));
+    let mut line = String::new();
+    reader.read_line(&mut line).context("read request line")?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next()) else { bail!("malformed request line") };
+    if !version.starts_with("HTTP/1.") {
+        bail!("unsupported protocol {}", version);
+    }
+    let mut request = Request { method: method.to_string(), target: target.to_string(), headers: Vec::new() };
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).context("read headers")? == 0 {
+            bail!("headers cut short");
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').context("malformed header")?;
+        request.headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    Ok(request)
+}
+
+fn send(stream: &mut TcpStream, mut response: Response, head: bool) -> Result<()> {
+    let len = match &response.body {
+        Body::Empty => 0,
+        Body::Bytes(bytes) => bytes.len() as u64,
+        Body::File(_, len) => *len,
+        Body::Spooled(spool) => spool.file.metadata()?.len(),
+    };
+    let mut out = io::BufWriter::new(stream);
+    write!(out, "HTTP/1.1 {} {}\r\n", response.status, reason(response.status))?;
+    for (name, value) in &response.headers {
+        write!(out, "{}: {}\r\n", name, value)?;
+    }
+    if response.status != 304 {
+        write!(out, "Content-Length: {}\r\n", len)?;
+    }
+    write!(out, "Connection: close\r\n\r\n")?;
+    if !head {
+        match &mut response.body {
+            Body::Empty => {}
+            Body::Bytes(bytes) => out.write_all(bytes)?,
+            Body::File(file, len) => {
+                io::copy(&mut file.take(*len), &mut out)?;
+            }
+            Body::Spooled(spool) => {
+                io::copy(&mut spool.file, &mut out)?;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+const fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        416 => "Range Not Satisfiable",
+        _ => "Internal Server Error",
+    }
+}
+
+// `bytes=a-b`, `bytes=a-` and `bytes=-n`, as inclusive offsets within `len`
+fn parse_range(header: &str, len: u64) -> Option<(u64, u64)> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') || len == 0 {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => (len.saturating_sub(suffix.parse::<u64>().ok().filter(|&n| n > 0)?), len - 1),
+        (start, "") => (start.parse().ok()?, len - 1),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(len - 1)),
+    };
+    (start <= end && start < len).then_some((start, end))
+}
+
+fn sha256(r: impl Read) -> Result<Vec<u8>> {
+    Ok(hex::decode(hash::hash_reader(r, Algorithm::Sha256, false)?)?)
+}
+
+// RFC 9530 dictionary member, the digest as a byte sequence
+fn structured_digest(digest: &[u8]) -> String {
+    format!("sha-256=:{}:", base64::engine::general_purpose::STANDARD.encode(digest))
+}
+
+// IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let days = (secs / 86_400) as i64;
+    let (year, month, day) = archive::civil_from_days(days);
+    let rem = secs % 86_400;
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT", DAYS[(days % 7) as usize], day, MONTHS[month as usize - 1], year, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().map(|e| e.to_string_lossy().to_ascii_lowercase()).unwrap_or_default().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "txt" | "log" | "sha256" | "blake3" | "md5" | "sha1" => "text/plain; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/vnd.microsoft.icon",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "zst" => "application/zstd",
+        "tar" => "application/x-tar",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
@@ -0,0 +1,187 @@
+// `shred`: overwrite files with random data several times, then remove them, e.g. plain text
+// once its encrypted copy is written. Overwriting in place only reaches the old blocks on
+// filesystems that rewrite files where they lie: copy-on-write filesystems (btrfs, ZFS, APFS),
+// journals that hold data and SSD wear levelling can all keep earlier copies elsewhere
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use rand::TryRngCore;
+use rand::rngs::OsRng;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, IsTerminal, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+use walkdir::WalkDir;
+use crate::{batch, errors};
+
+#[derive(Args)]
+#[command[name = "shred", about = "Overwrite files with random data, then delete them"]]
+pub struct ShredArgs {
+    /// Files to shred, or directories with --recursive
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+    /// Passes of random data over each file
+    #[arg(short = 'n', long, default_value_t = 3)]
+    passes: u32,
+    /// Finish with a pass of zeros, which hides that the file was shredded
+    #[arg(short, long)]
+    zero: bool,
+    /// Shred everything under directories, then remove them
+    #[arg(short, long)]
+    recursive: bool,
+    /// Do not ask before shredding a directory
+    #[arg(short, long)]
+    yes: bool,
+    /// Overwrite only, leaving the files in place
+    #[arg(long)]
+    keep: bool,
+}
+
+/// How files are overwritten before they are removed
+#[derive(Clone, Copy, Debug)]
+pub struct ShredOptions {
+    pub passes: u32,
+    pub zero: bool,
+    pub keep: bool,
+}
+
+impl Default for ShredOptions {
+    fn default() -> Self {
+        ShredOptions { passes: 3, zero: false, keep: false }
+    }
+}
+
+pub fn shred(a: ShredArgs) -> Result<()> {
+    if a.passes == 0 && !a.zero {
+        bail!("nothing to overwrite with: --passes is 0 and --zero is not set");
+    }
+    let options = ShredOptions { passes: a.passes, zero: a.zero, keep: a.keep };
+    for path in &a.paths {
+        let meta = fs::symlink_metadata(path).with_context(|| errors::at("read", path))?;
+        if meta.is_dir() && !a.recursive {
+            bail!("{} is a directory, use --recursive", path.display());
+        }
+    }
+
+    // Directories are confirmed all together before anything is touched
+    let dirs: Vec<&PathBuf> = a.paths.iter().filter(|p| p.is_dir() && !p.is_symlink()).collect();
+    if !dirs.is_empty() && !a.yes {
+        let files: usize = dirs.iter().map(|d| WalkDir::new(d).into_iter().flatten().filter(|e| !e.file_type().is_dir()).count()).sum();
+        let names: Vec<String> = dirs.iter().map(|d| d.display().to_string()).collect();
+        confirm(&format!("Shred {} file(s) in {}{}?", files, names.join(", "), if a.keep { "" } else { " and remove them" }))?;
+    }
+
+    let mut count = 0;
+    for path in &a.paths {
+        if path.is_dir() && !path.is_symlink() {
+            count += shred_tree(path, options)?;
+        } else {
+            shred_file(path, options)?;
+            count += 1;
+        }
+    }
+    info!("Shredded {} file(s){}", count, if a.keep { ", kept in place" } else { "" });
+    Ok(())
+}
+
+// Asks on the terminal; without one there is nobody to ask, so --yes is required
+fn confirm(question: &str) -> Result<()> {
+    if !io::stdin().is_terminal() {
+        bail!("refusing to shred directories without confirmation; pass --yes");
+    }
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    if !matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes") {
+        bail!("cancelled");
+    }
+    Ok(())
+}
+
+/// Overwrites the file at `path` and, unless `keep`, removes it. A symbolic link is removed
+/// without touching what it points to
+pub fn shred_file(path: &Path, options: ShredOptions) -> Result<()> {
+    let meta = fs::symlink_metadata(path).with_context(|| errors::at("read", path))?;
+    if meta.file_type().is_symlink() {
+        if !options.keep {
+            fs::remove_file(path).with_context(|| format!("remove {}", path.display()))?;
+        }
+        return Ok(());
+    }
+    if !meta.is_file() {
+        bail!("{} is not a regular file", path.display());
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if meta.nlink() > 1 {
+            warn!("{} has {} hard links; their shared contents are overwritten too", path.display(), meta.nlink());
+        }
+    }
+
+    let len = meta.len();
+    let mut file = OpenOptions::new().write(true).open(path).with_context(|| errors::at("open", path))?;
+    let mut buf = vec![0u8; 1024 * 1024];
+    let passes = options.passes + u32::from(options.zero);
+    for pass in 0..passes {
+        let random = pass < options.passes;
+        debug!("{}: pass {}/{} ({})", path.display(), pass + 1, passes, if random { "random" } else { "zeros" });
+        if !random {
+            buf.fill(0);
+        }
+        file.seek(SeekFrom::Start(0))?;
+        let mut left = len;
+        while left > 0 {
+            let n = left.min(buf.len() as u64) as usize;
+            if random {
+                OsRng.try_fill_bytes(&mut buf[..n])?;
+            }
+            file.write_all(&buf[..n]).with_context(|| format!("overwrite {}", path.display()))?;
+            left -= n as u64;
+        }
+        // Each pass reaches the disk before the next one replaces it in the page cache
+        file.sync_all().with_context(|| errors::at("sync", path))?;
+    }
+    if options.keep {
+        return Ok(());
+    }
+
+    // Emptied and renamed first, so neither the size nor the name outlives the contents
+    file.set_len(0)?;
+    file.sync_all()?;
+    drop(file);
+    let hidden = path.with_file_name(random_name()?);
+    fs::rename(path, &hidden).with_context(|| format!("rename {}", path.display()))?;
+    fs::remove_file(&hidden).with_context(|| format!("remove {}", path.display()))?;
+    Ok(())
+}
+
+/// Shreds every file under `dir`, then removes its directories unless `keep`. Returns the number
+/// of files shredded
+pub fn shred_tree(dir: &Path, options: ShredOptions) -> Result<usize> {
+    let files: Vec<PathBuf> = WalkDir::new(dir)
+        .into_iter()
+        .map(|e| e.map(|e| e.into_path()).with_context(|| format!("walk {}", dir.display())))
+        .filter(|p| p.as_ref().map_or(true, |p| p.is_symlink() || !p.is_dir()))
+        .collect::<Result<_>>()?;
+    // A directory is only removed once all of its files are gone
+    let report = batch::process(&files, None, true, "Shredding", |path| shred_file(path, options))?;
+    report.log_failures();
+    report.into_result()?;
+    if !options.keep {
+        // Deepest first, so each directory is empty when it is removed
+        for entry in WalkDir::new(dir).contents_first(true) {
+            let entry = entry.with_context(|| format!("walk {}", dir.display()))?;
+            if entry.file_type().is_dir() {
+                fs::remove_dir(entry.path()).with_context(|| format!("remove {}", entry.path().display()))?;
+            }
+        }
+    }
+    Ok(files.len())
+}
+
+fn random_name() -> Result<String> {
+    let mut bytes = [0u8; 8];
+    OsRng.try_fill_bytes(&mut bytes)?;
+    Ok(format!(".{}", hex::encode(bytes)))
+}
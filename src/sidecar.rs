@@ -0,0 +1,182 @@
+// --sidecar: a `<output>.jj.meta` JSON file next to every output, saying what made it. It names
+// the tool version, the command and its parameters, and the source and output with their sizes
+// and BLAKE3 digests, so `verify-sidecar` can later tell whether either has changed since
+use anyhow::{Context, Result, anyhow, bail};
+use clap::{Args, ValueEnum};
+use serde_json::{Map, Value, json};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::SystemTime;
+use tracing::debug;
+use crate::hash::{self, Algorithm};
+use crate::{errors, meta, stdio};
+
+const EXTENSION: &str = "jj.meta";
+const ALGORITHM: Algorithm = Algorithm::Blake3;
+
+static INVOCATION: OnceLock<Invocation> = OnceLock::new();
+
+/// The command a run was given and its parameters by name, recorded in every sidecar it writes
+pub struct Invocation {
+    pub command: String,
+    pub parameters: Map<String, Value>,
+}
+
+/// Turns sidecars on for this run
+pub fn enable(invocation: Invocation) {
+    let _ = INVOCATION.set(invocation);
+}
+
+pub(crate) fn enabled() -> bool {
+    INVOCATION.get().is_some()
+}
+
+#[derive(Args)]
+#[command[name = "verify-sidecar", about = "Check outputs and their sources against the .jj.meta sidecars written with --sidecar"]]
+pub struct VerifyArgs {
+    /// Outputs, or their .jj.meta files
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    /// Also fail when a source is no longer there to compare, instead of only checking the output
+    #[arg(long)]
+    require_source: bool,
+}
+
+// Where the sidecar of `output` goes
+fn sidecar_path(output: &Path) -> PathBuf {
+    let mut name = output.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(EXTENSION);
+    output.with_file_name(name)
+}
+
+// Writes the sidecar of the finished `output`, made from `source`, when --sidecar is on. A sidecar
+// left from an earlier run is replaced along with its output, as it would describe the old one
+pub(crate) fn write(source: &Path, output: &Path) -> Result<()> {
+    let Some(invocation) = INVOCATION.get() else { return Ok(()) };
+    if stdio::is_dash(output) || stdio::is_spooled(output, "stdout") {
+        return Ok(());
+    }
+    let dir = output.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let source = if stdio::is_dash(source) || stdio::is_spooled(source, "stdin") {
+        Value::Null
+    } else {
+        let mut entry = describe(source)?;
+        entry.insert("path".into(), json!(relative(dir, source).to_string_lossy().replace('\\', "/")));
+        Value::Object(entry)
+    };
+    let mut target = describe(output)?;
+    target.insert("path".into(), json!(output.file_name().unwrap_or_default().to_string_lossy()));
+    let sidecar = json!({
+        "tool": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "command": invocation.command,
+        "parameters": invocation.parameters,
+        "created": meta::format_timestamp(SystemTime::now()),
+        "algorithm": ALGORITHM.to_string(),
+        "source": source,
+        "output": target,
+    });
+    let path = sidecar_path(output);
+    stdio::write(&path, format!("{}\n", serde_json::to_string_pretty(&sidecar)?).as_bytes(), false)?;
+    debug!("Wrote {}", path.display());
+    Ok(())
+}
+
+// Size and digest of a file; a directory source, as encrypt takes, is only marked as one
+fn describe(path: &Path) -> Result<Map<String, Value>> {
+    let meta = fs::metadata(path).with_context(|| errors::at("read", path))?;
+    let mut entry = Map::new();
+    if meta.is_dir() {
+        entry.insert("directory".into(), json!(true));
+    } else {
+        entry.insert("size".into(), json!(meta.len()));
+        entry.insert("hash".into(), json!(hash::hash_file(path, ALGORITHM, false)?));
+    }
+    Ok(entry)
+}
+
+// `path` as seen from `dir`, so a sidecar still finds its source when both move together. Falls
+// back to the absolute path across drives
+fn relative(dir: &Path, path: &Path) -> PathBuf {
+    let (Ok(dir), Ok(path)) = (fs::canonicalize(dir), fs::canonicalize(path)) else { return path.to_path_buf() };
+    let from: Vec<Component> = dir.components().collect();
+    let to: Vec<Component> = path.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    if common == 0 {
+        return path;
+    }
+    let mut rel: PathBuf = from[common..].iter().map(|_| Component::ParentDir).collect();
+    rel.extend(&to[common..]);
+    rel
+}
+
+pub fn verify(a: VerifyArgs) -> Result<()> {
+    let mut results = Vec::new();
+    let mut failed = Vec::new();
+    for file in &a.files {
+        let (output, sidecar) = match file.to_string_lossy().strip_suffix(&format!(".{}", EXTENSION)) {
+            Some(output) => (PathBuf::from(output), file.clone()),
+            None => (file.clone(), sidecar_path(file)),
+        };
+        match verify_one(&output, &sidecar, a.require_source) {
+            Ok(note) => {
+                if !stdio::json() {
+                    println!("OK  {}{}", output.display(), note.as_ref().map(|n| format!(" ({})", n)).unwrap_or_default());
+                }
+                results.push(json!({ "output": output.display().to_string(), "status": "ok", "note": note }));
+            }
+            Err(e) => {
+                if !stdio::json() {
+                    println!("FAILED  {}: {:#}", output.display(), e);
+                }
+                results.push(json!({ "output": output.display().to_string(), "status": "failed", "error": format!("{:#}", e) }));
+                failed.push((output, e));
+            }
+        }
+    }
+    if stdio::json() {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    }
+    if !failed.is_empty() {
+        return Err(errors::Batch(failed).into());
+    }
+    Ok(())
+}
+
+// Checks one output against its sidecar. Ok carries a note on what could not be checked
+fn verify_one(output: &Path, sidecar: &Path, require_source: bool) -> Result<Option<String>> {
+    let text = fs::read_to_string(sidecar).with_context(|| errors::at("read", sidecar))?;
+    let record: Value = serde_json::from_str(&text).with_context(|| format!("parse {}", sidecar.display()))?;
+    let algorithm = record["algorithm"]
+        .as_str()
+        .and_then(|name| Algorithm::from_str(name, true).ok())
+        .ok_or_else(|| anyhow!("{} names no known hash algorithm", sidecar.display()))?;
+
+    let expected = record["output"]["hash"].as_str().context("sidecar has no output hash")?;
+    let got = hash::hash_file(output, algorithm, false)?;
+    if !hash::eq_hex(&got, expected) {
+        bail!("output changed: expected {}, got {}", expected, got);
+    }
+
+    let source = &record["source"];
+    let Some(rel) = source["path"].as_str() else { return Ok(Some("source was stdin".into())) };
+    let dir = sidecar.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let path = dir.join(rel);
+    if !path.exists() {
+        if require_source {
+            bail!("source {} not found", path.display());
+        }
+        return Ok(Some("source not found".into()));
+    }
+    if source["directory"].as_bool() == Some(true) {
+        return Ok(Some("source is a directory".into()));
+    }
+    let expected = source["hash"].as_str().context("sidecar has no source hash")?;
+    let got = hash::hash_file(&path, algorithm, false)?;
+    if !hash::eq_hex(&got, expected) {
+        bail!("source {} changed: expected {}, got {}", path.display(), expected, got);
+    }
+    Ok(None)
+}
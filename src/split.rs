@@ -0,0 +1,275 @@
+// `split` and `join`: a large file cut into numbered parts of a fixed size, e.g. an encrypted
+// archive headed over a channel with a size limit, and put back together. Split writes a hash
+// manifest next to the parts, with a digest per part and one for the whole file, which join
+// checks before and after reassembling
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+use crate::batch;
+use crate::hash::{self, Algorithm};
+use crate::{errors, stdio};
+
+#[derive(Args)]
+#[command[name = "split", about = "Split a large file into numbered parts with a checksum manifest"]]
+pub struct SplitArgs {
+    /// File to split, `-` for stdin
+    input: PathBuf,
+    /// Bytes per part: a number with an optional K, M, G or T suffix (powers of 1024), or KB,
+    /// MB, GB, TB (powers of 1000)
+    #[arg(short, long, value_parser = parse_size)]
+    size: u64,
+    /// Directory for the parts and manifest. Default: the input's directory
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Name the parts and manifest are derived from. Default: the input's file name, required
+    /// for stdin
+    #[arg(short, long)]
+    name: Option<String>,
+    /// Digest written to the manifest
+    #[arg(short, long, default_value_t = Algorithm::Blake3)]
+    algorithm: Algorithm,
+}
+
+#[derive(Args)]
+#[command[name = "join", about = "Reassemble the parts listed in a split manifest and verify them"]]
+pub struct JoinArgs {
+    /// Manifest written by `split`, e.g. backup.tar.zst.blake3
+    manifest: PathBuf,
+    /// File to write. Default: the original name, in the manifest's directory
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+pub fn split(a: SplitArgs) -> Result<()> {
+    if a.size == 0 {
+        bail!("--size must be at least 1 byte");
+    }
+    let name = match &a.name {
+        Some(name) => name.clone(),
+        None if stdio::is_dash(&a.input) => bail!("--name is needed to split stdin"),
+        None => a.input.file_name().context("input has no file name")?.to_string_lossy().into_owned(),
+    };
+    if Path::new(&name).file_name() != Some(name.as_ref()) {
+        bail!("--name must be a plain file name");
+    }
+    let dir = match &a.output {
+        Some(dir) => dir.clone(),
+        None if stdio::is_dash(&a.input) => PathBuf::from("."),
+        None => a.input.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new(".")).to_path_buf(),
+    };
+    fs::create_dir_all(&dir).with_context(|| errors::at("create", &dir))?;
+
+    // Part numbers padded to the widest, so parts list in order. Stdin's length is unknown
+    let len = if stdio::is_dash(&a.input) { None } else { Some(fs::metadata(&a.input).with_context(|| errors::at("read", &a.input))?.len()) };
+    let digits = len.map_or(3, |len| len.div_ceil(a.size).to_string().len().max(3));
+    let manifest = dir.join(format!("{}.{}", name, a.algorithm));
+    if !stdio::check_output_or(&manifest, stdio::Clobber::Refuse)? {
+        return Ok(());
+    }
+
+    let input = stdio::reader(&a.input)?;
+    let bar = crate::progress::bytes(len.unwrap_or(0), &a.input);
+    let mut splitter = Splitter { input: bar.wrap_read(input), size: a.size, dir: &dir, name: &name, digits, replace: stdio::clobber() == Some(stdio::Clobber::Replace), parts: Vec::new(), current: None, written: 0 };
+    let whole = hash::hash_reader(&mut splitter, a.algorithm, false).with_context(|| format!("split {}", stdio::display(&a.input)))?;
+    splitter.finish()?;
+    bar.finish_and_clear();
+    let parts = splitter.parts;
+    if parts.is_empty() {
+        bail!("{} is empty", stdio::display(&a.input));
+    }
+
+    // Parts are hashed back from disk, which also catches a short write
+    let digests = batch::map(&parts, None, false, "Hashing", |part| hash::hash_file(part, a.algorithm, false))?;
+    let mut entries = BTreeMap::new();
+    entries.insert(PathBuf::from(&name), whole);
+    for (part, digest) in parts.iter().zip(digests) {
+        let Some(digest) = digest else { continue };
+        let digest = digest.with_context(|| format!("hash {}", part.display()))?;
+        entries.insert(PathBuf::from(part.file_name().unwrap_or_default()), digest);
+    }
+    hash::write_manifest(&manifest, "", a.algorithm, &entries)?;
+    info!("Split {} into {} part(s) of up to {} bytes in {}", stdio::display(&a.input), parts.len(), a.size, dir.display());
+    Ok(())
+}
+
+// Passes the input through while writing it out as consecutive parts
+struct Splitter<'a, R> {
+    input: R,
+    size: u64,
+    dir: &'a Path,
+    name: &'a str,
+    digits: usize,
+    // Parts left over without their manifest are replaced only with --force; skipping one of them
+    // would leave a set that does not join
+    replace: bool,
+    parts: Vec<PathBuf>,
+    current: Option<stdio::AtomicFile>,
+    written: u64,
+}
+
+impl<R: Read> Read for Splitter<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.input.read(buf)?;
+        let mut rest = &buf[..n];
+        while !rest.is_empty() {
+            if self.current.is_none() || self.written == self.size {
+                self.next_part()?;
+            }
+            let take = rest.len().min((self.size - self.written) as usize);
+            self.current.as_mut().expect("part opened above").write_all(&rest[..take])?;
+            self.written += take as u64;
+            rest = &rest[take..];
+        }
+        Ok(n)
+    }
+}
+
+impl<R> Splitter<'_, R> {
+    fn next_part(&mut self) -> io::Result<()> {
+        self.finish().map_err(io_error)?;
+        let path = self.dir.join(format!("{}.{:0width$}", self.name, self.parts.len() + 1, width = self.digits));
+        if !self.replace && fs::symlink_metadata(&path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("Output exists (use --force): {}", path.display())));
+        }
+        debug!("Writing {}", path.display());
+        self.current = Some(stdio::AtomicFile::create(&path).map_err(io_error)?);
+        self.parts.push(path);
+        self.written = 0;
+        Ok(())
+    }
+
+    // Each part goes into place once it is full, synced like every other output
+    fn finish(&mut self) -> Result<()> {
+        if let Some(part) = self.current.take() {
+            part.commit()?;
+        }
+        Ok(())
+    }
+}
+
+// An error from the stdio helpers as one `Read` can return, keeping the kind of the I/O error
+// under it for the exit code
+fn io_error(e: anyhow::Error) -> io::Error {
+    let kind = e.chain().find_map(|cause| cause.downcast_ref::<io::Error>()).map_or(io::ErrorKind::Other, io::Error::kind);
+    io::Error::new(kind, format!("{:#}", e))
+}
+
+pub fn join(a: JoinArgs) -> Result<()> {
+    stdio::ensure_not_dash(&a.manifest, "join, whose parts are found next to the manifest")?;
+    let (algorithm, entries) = hash::read_manifest(&a.manifest)?;
+    let dir = a.manifest.parent().map(Path::to_path_buf).unwrap_or_default();
+
+    // The whole file is the entry every part is named after
+    let whole = entries
+        .keys()
+        .find(|name| entries.keys().filter(|k| k != name).all(|k| part_number(k, name).is_some()))
+        .filter(|_| entries.len() > 1)
+        .context("manifest does not list the parts of a split file")?
+        .clone();
+    if Path::new(&whole).file_name() != Some(whole.as_ref()) {
+        bail!("manifest names a file outside its directory: {}", whole);
+    }
+    let mut parts: Vec<(u64, PathBuf, &String)> = entries
+        .iter()
+        .filter_map(|(k, digest)| part_number(k, &whole).map(|n| (n, dir.join(k), digest)))
+        .collect();
+    parts.sort_by_key(|(n, _, _)| *n);
+    if let Some(gap) = parts.iter().enumerate().find(|(i, (n, _, _))| *n != *i as u64 + 1) {
+        bail!("part {} is missing from the manifest", gap.0 + 1);
+    }
+    let missing: Vec<String> = parts.iter().filter(|(_, p, _)| !p.is_file()).map(|(_, p, _)| p.display().to_string()).collect();
+    if !missing.is_empty() {
+        bail!("missing part(s): {}", missing.join(", "));
+    }
+
+    // Every part checked first, so a damaged one is named before anything is written
+    let digests = batch::map(&parts, None, true, "Verifying", |(_, part, _)| hash::hash_file(part, algorithm, false))?;
+    let mut bad = Vec::new();
+    for ((_, part, expected), got) in parts.iter().zip(digests) {
+        let got = got.context("part skipped")?.with_context(|| format!("hash {}", part.display()))?;
+        if !got.eq_ignore_ascii_case(expected.trim()) {
+            bad.push(part.display().to_string());
+        }
+    }
+    if !bad.is_empty() {
+        bail!("damaged part(s), digest mismatch: {}", bad.join(", "));
+    }
+
+    let output = a.output.clone().unwrap_or_else(|| dir.join(&whole));
+    if !stdio::check_output_or(&output, stdio::Clobber::Refuse)? {
+        return Ok(());
+    }
+    let total: u64 = parts.iter().map(|(_, p, _)| fs::metadata(p).map_or(0, |m| m.len())).sum();
+    let mut input: Box<dyn Read> = Box::new(io::empty());
+    for (_, part, _) in &parts {
+        let file = File::open(part).with_context(|| errors::at("open", part))?;
+        input = Box::new(input.chain(file));
+    }
+    let bar = crate::progress::bytes(total, &output);
+    let mut out = stdio::writer(&output, true)?;
+    let got = hash::hash_reader(Tee { input: bar.wrap_read(input), out: &mut out }, algorithm, false)?;
+    bar.finish_and_clear();
+
+    // The parts were each intact, so a mismatch here means the manifest lists the wrong ones. The
+    // joined file is dropped unfinished, so it never shows up under its name
+    if !got.eq_ignore_ascii_case(entries[&whole].trim()) {
+        bail!("joined file does not match the manifest's digest for {}", whole);
+    }
+    out.finish()?;
+    let shown = if stdio::is_dash(&output) { "<stdout>".to_string() } else { output.display().to_string() };
+    info!("Joined {} part(s) into {} ({} bytes, {} verified)", parts.len(), shown, total, algorithm);
+    Ok(())
+}
+
+// `key` as `<whole>.<number>`, the part's number
+fn part_number(key: &str, whole: &str) -> Option<u64> {
+    let digits = key.strip_prefix(whole)?.strip_prefix('.')?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+// Copies what is read from `input` to `out`
+struct Tee<R, W> {
+    input: R,
+    out: W,
+}
+
+impl<R: Read, W: Write> Read for Tee<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.input.read(buf)?;
+        self.out.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+/// A byte count such as `2G`, `500MB` or `1.5GiB`: K, M, G and T (and KiB, MiB, ...) are powers
+/// of 1024, KB, MB, GB and TB powers of 1000
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("invalid size '{}'", s))?;
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KIB" => 1 << 10,
+        "M" | "MIB" => 1 << 20,
+        "G" | "GIB" => 1 << 30,
+        "T" | "TIB" => 1 << 40,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        _ => return Err(format!("unknown size unit '{}' in '{}'", unit.trim(), s)),
+    };
+    let bytes = number * multiplier as f64;
+    if !bytes.is_finite() || bytes >= u64::MAX as f64 {
+        return Err(format!("size '{}' is too large", s));
+    }
+    Ok(bytes.round() as u64)
+}
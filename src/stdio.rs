@@ -0,0 +1,447 @@
+// `-` as a path: stdin for inputs, stdout for outputs. Commands that stream use `reader` and
+// `writer` directly; commands built around paths get a spooled temporary file instead, which is
+// filled from stdin up front, or copied to stdout once the command has written it. Whether an
+// existing output file may be replaced is decided here too, by `check_output`, for every command
+use anyhow::{Context, Result, bail};
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use tracing::info;
+use crate::{errors, http, memory, preserve, sidecar};
+
+static FORCE_TTY: AtomicBool = AtomicBool::new(false);
+static JSON: AtomicBool = AtomicBool::new(false);
+static CLOBBER: AtomicU8 = AtomicU8::new(UNSET);
+static STDIN_TAKEN: AtomicBool = AtomicBool::new(false);
+static SPOOLED: AtomicUsize = AtomicUsize::new(0);
+
+// CLOBBER before any of --force, --no-clobber and --skip-existing is given
+const UNSET: u8 = u8::MAX;
+
+// Largest URL body `remote_input` downloads when no --max-memory is set
+const URL_SPOOL_LIMIT: u64 = 1 << 30;
+
+/// Allows binary output on a terminal stdout, which is refused otherwise
+pub fn set_force_tty(force: bool) {
+    FORCE_TTY.store(force, Ordering::Relaxed);
+}
+
+/// Results as JSON on stdout where a command has a JSON form, and failures as JSON on stderr
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub(crate) fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// What happens to an output file that already exists
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Clobber {
+    /// An error, for that file only in batches. With --no-clobber, and the default of commands
+    /// whose outputs are hard to recreate, such as keys and extracted files
+    Refuse,
+    /// Replaced. With --force, and the default of most commands
+    Replace,
+    /// Left alone and its input skipped without an error. With --skip-existing, and the default
+    /// of the batch modes of rasterize and svg-optimize
+    Skip,
+}
+
+// Set from the command line, for the whole run. Without it each output keeps its command's default
+pub fn set_clobber(policy: Clobber) {
+    CLOBBER.store(policy as u8, Ordering::Relaxed);
+}
+
+// The policy set with `set_clobber`, if any
+pub(crate) fn clobber() -> Option<Clobber> {
+    match CLOBBER.load(Ordering::Relaxed) {
+        0 => Some(Clobber::Refuse),
+        1 => Some(Clobber::Replace),
+        2 => Some(Clobber::Skip),
+        _ => None,
+    }
+}
+
+// Whether to go on and write `path`: true when it is stdout, does not exist or may be replaced,
+// false when it exists and is to be skipped. Errors when it exists and replacing is refused.
+// Called before any work is done for an output, so skipped files cost nothing. Existing outputs
+// are replaced unless a flag says otherwise; `check_output_or` is for those that are not
+pub(crate) fn check_output(path: &Path) -> Result<bool> {
+    check_output_or(path, Clobber::Replace)
+}
+
+// `check_output` for an output whose command treats an existing file as `default` does. A
+// symlink counts as existing even when dangling: the output is renamed over the link itself,
+// never written through it
+pub(crate) fn check_output_or(path: &Path, default: Clobber) -> Result<bool> {
+    if is_dash(path) || is_spooled(path, "stdout") || fs::symlink_metadata(path).is_err() {
+        return Ok(true);
+    }
+    match clobber().unwrap_or(default) {
+        Clobber::Replace => Ok(true),
+        Clobber::Skip => {
+            info!("Skipped {}: output exists", path.display());
+            Ok(false)
+        }
+        Clobber::Refuse => Err(errors::OutputExists(path.to_path_buf()).into()),
+    }
+}
+
+// Called by commands that write one output per input once `target` is in place: it takes the
+// attributes --preserve asks for from `source`, and gets its --sidecar
+pub(crate) fn finished(source: &Path, target: &Path) -> Result<()> {
+    preserve::apply(source, target)?;
+    sidecar::write(source, target)
+}
+
+pub(crate) fn is_dash(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+// `-` for the default output of a command reading stdin, which has no name to derive one from
+pub(crate) fn default_output(input: &Path) -> Option<PathBuf> {
+    is_dash(input).then(|| PathBuf::from("-"))
+}
+
+// For commands that only handle a single file
+pub(crate) fn ensure_not_dash(path: &Path, what: &str) -> Result<()> {
+    if is_dash(path) {
+        bail!("'-' cannot be used with {}", what);
+    }
+    Ok(())
+}
+
+pub(crate) fn reader(path: &Path) -> Result<Box<dyn Read>> {
+    if is_dash(path) {
+        take_stdin()?;
+        return Ok(Box::new(io::stdin().lock()));
+    }
+    let file = File::open(path).with_context(|| errors::at("open", path))?;
+    Ok(Box::new(io::BufReader::new(file)))
+}
+
+pub(crate) fn read(path: &Path) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader(path)?.read_to_end(&mut data).with_context(|| errors::at("read", path))?;
+    Ok(data)
+}
+
+// Stdout, or an `AtomicFile` that `finish` moves into place. `binary` output is refused on a
+// terminal unless --force-tty
+pub(crate) fn writer(path: &Path, binary: bool) -> Result<Writer> {
+    if is_dash(path) {
+        check_tty(binary)?;
+        return Ok(Writer::Stdout(io::BufWriter::new(io::stdout().lock())));
+    }
+    Ok(Writer::File(AtomicFile::create(path)?))
+}
+
+pub(crate) fn write(path: &Path, bytes: &[u8], binary: bool) -> Result<()> {
+    let mut w = writer(path, binary)?;
+    w.write_all(bytes).with_context(|| errors::at("write", path))?;
+    w.finish()
+}
+
+/// Output of `writer`. A file is only there under its name once `finish` succeeds
+pub(crate) enum Writer {
+    Stdout(io::BufWriter<io::StdoutLock<'static>>),
+    File(AtomicFile),
+}
+
+impl Writer {
+    pub(crate) fn finish(self) -> Result<()> {
+        match self {
+            Writer::Stdout(mut out) => out.flush().context("write stdout"),
+            Writer::File(file) => file.commit(),
+        }
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Writer::Stdout(out) => out.write(buf),
+            Writer::File(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Stdout(out) => out.flush(),
+            Writer::File(file) => file.flush(),
+        }
+    }
+}
+
+/// A file written under a temporary name in its target's directory, then synced to disk and
+/// renamed over the target by `commit`, so an interrupted run never leaves a truncated output
+/// behind. Dropped without `commit`, the temporary file is removed; a run killed by a signal
+/// does not unwind, so its `.jj-tmp-<pid>-…` file stays behind for the user to delete
+pub(crate) struct AtomicFile {
+    file: io::BufWriter<File>,
+    temp: PathBuf,
+    target: PathBuf,
+    committed: bool,
+    private: bool,
+}
+
+impl AtomicFile {
+    pub(crate) fn create(path: &Path) -> Result<AtomicFile> {
+        let temp = atomic_temp(path);
+        let file = create_new(&temp)?;
+        Ok(AtomicFile { file: io::BufWriter::new(file), temp, target: path.to_path_buf(), committed: false, private: false })
+    }
+
+    // For secrets: the temporary file is readable by the owner only from the moment it exists,
+    // and keeps that mode instead of taking the permissions of a file it replaces
+    pub(crate) fn create_private(path: &Path) -> Result<AtomicFile> {
+        let temp = atomic_temp(path);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options.open(&temp).with_context(|| errors::at("create", &temp))?;
+        Ok(AtomicFile { file: io::BufWriter::new(file), temp, target: path.to_path_buf(), committed: false, private: true })
+    }
+
+    // Where the data is until `commit`, for reading it back before it goes live
+    pub(crate) fn path(&self) -> &Path {
+        &self.temp
+    }
+
+    // The open file, e.g. to set its times; flush first for buffered writes to land before
+    pub(crate) fn file(&self) -> &File {
+        self.file.get_ref()
+    }
+
+    pub(crate) fn commit(mut self) -> Result<()> {
+        self.file.flush().with_context(|| errors::at("write", &self.target))?;
+        self.file.get_ref().sync_all().with_context(|| errors::at("sync", &self.target))?;
+        if self.private {
+            rename(&self.temp, &self.target)?;
+        } else {
+            replace(&self.temp, &self.target)?;
+        }
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+// For encoders that write by path: `write` gets a temporary name next to `path` that ends in the
+// same file name, so formats picked by extension still are, and its result is moved into place
+// as by `AtomicFile`. Stdout's spooled files are temporary already and written directly
+pub(crate) fn write_atomic<T>(path: &Path, write: impl FnOnce(&Path) -> Result<T>) -> Result<T> {
+    if is_spooled(path, "stdout") {
+        return write(path);
+    }
+    let temp = atomic_temp(path);
+    let result = write(&temp).and_then(|value| {
+        File::open(&temp).and_then(|f| f.sync_all()).with_context(|| errors::at("sync", path))?;
+        replace(&temp, path)?;
+        Ok(value)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+// Hidden, unique to this process and ending in the target's name
+fn atomic_temp(path: &Path) -> PathBuf {
+    let n = SPOOLED.fetch_add(1, Ordering::Relaxed);
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!(".jj-tmp-{}-{}-{}", std::process::id(), n, name))
+}
+
+// Renames `temp` over `target`, keeping the permissions of a file it replaces, then syncs the
+// directory so the rename itself survives a crash
+pub(crate) fn replace(temp: &Path, target: &Path) -> Result<()> {
+    if let Ok(meta) = fs::metadata(target) {
+        let _ = fs::set_permissions(temp, meta.permissions());
+    }
+    rename(temp, target)
+}
+
+fn rename(temp: &Path, target: &Path) -> Result<()> {
+    fs::rename(temp, target).with_context(|| errors::at("replace", target))?;
+    #[cfg(unix)]
+    {
+        let dir = target.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let _ = File::open(dir).and_then(|d| d.sync_all());
+    }
+    Ok(())
+}
+
+// How a path shows up in messages, naming stdin and stdout rather than their spooled files
+pub(crate) fn display(path: &Path) -> String {
+    if is_dash(path) || is_spooled(path, "stdin") {
+        "<stdin>".to_string()
+    } else if is_spooled(path, "stdout") {
+        "<stdout>".to_string()
+    } else {
+        path.display().to_string()
+    }
+}
+
+// Whether `path` is one of this process's spooled files, `kind` being "stdin" or "stdout"
+pub(crate) fn is_spooled(path: &Path, kind: &str) -> bool {
+    let prefix = format!("jj_toolkit-{}-{}-", kind, std::process::id());
+    path.parent() == Some(std::env::temp_dir().as_path()) && path.file_name().is_some_and(|n| n.to_string_lossy().starts_with(&prefix))
+}
+
+/// An input path, or stdin (or a URL's body) copied to a temporary file that is removed on drop
+pub(crate) struct Input {
+    path: PathBuf,
+    spooled: bool,
+}
+
+impl Input {
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn is_stdin(&self) -> bool {
+        self.spooled
+    }
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        if self.spooled {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+pub(crate) fn input(path: &Path) -> Result<Input> {
+    if !is_dash(path) {
+        return Ok(Input { path: path.to_path_buf(), spooled: false });
+    }
+    let spool = spool_path("stdin", "");
+    let mut file = create_new(&spool)?;
+    let input = Input { path: spool, spooled: true };
+    take_stdin()?;
+    io::copy(&mut io::stdin().lock(), &mut file).context("read stdin")?;
+    Ok(input)
+}
+
+// `input`, or for a URL the response body downloaded to a temporary file, for read-only commands
+// that need a file to seek in. The download stops at --max-memory, as the command is about to
+// read what it holds, or at URL_SPOOL_LIMIT without one
+pub(crate) fn remote_input(path: &Path) -> Result<Input> {
+    let Some(url) = http::url(path) else { return input(path) };
+    let response = http::get(url, 0, None)?;
+    let (limit, what) = match memory::limit() {
+        Some(limit) => (limit, "by --max-memory"),
+        None => (URL_SPOOL_LIMIT, "for a URL input"),
+    };
+    let too_large = || errors::MemoryLimit(format!("{} is larger than the {} allowed {}; save it with fetch first", url, memory::size(limit), what));
+    if response.len().is_some_and(|len| len > limit) {
+        return Err(too_large().into());
+    }
+    let spool = spool_path("url", "");
+    let mut file = create_new(&spool)?;
+    let input = Input { path: spool, spooled: true };
+    let copied = io::copy(&mut response.body.take(limit + 1), &mut file).with_context(|| format!("download {}", url))?;
+    if copied > limit {
+        return Err(too_large().into());
+    }
+    Ok(input)
+}
+
+/// An output path, or a temporary file that `finish` copies to stdout
+pub(crate) struct Output {
+    path: PathBuf,
+    spooled: bool,
+}
+
+impl Output {
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn finish(self) -> Result<()> {
+        if self.spooled {
+            let mut stdout = io::stdout().lock();
+            io::copy(&mut File::open(&self.path)?, &mut stdout).context("write stdout")?;
+            stdout.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        if self.spooled {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+// `extension` keeps format detection from the output name working for the spooled file
+pub(crate) fn output(path: &Path, extension: &str, binary: bool) -> Result<Output> {
+    if !is_dash(path) {
+        return Ok(Output { path: path.to_path_buf(), spooled: false });
+    }
+    check_tty(binary)?;
+    let spool = spool_path("stdout", extension);
+    create_new(&spool)?;
+    Ok(Output { path: spool, spooled: true })
+}
+
+// A new file in the temp dir, open for reading and writing, named like the spooled ones
+pub(crate) fn temp_file(kind: &str) -> Result<(PathBuf, File)> {
+    let path = spool_path(kind, "");
+    let file = fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path).with_context(|| errors::at("create", &path))?;
+    Ok((path, file))
+}
+
+// Never through an existing file or symlink in the shared temp dir
+fn create_new(path: &Path) -> Result<File> {
+    fs::OpenOptions::new().write(true).create_new(true).open(path).with_context(|| errors::at("create", path))
+}
+
+fn check_tty(binary: bool) -> Result<()> {
+    if binary && io::stdout().is_terminal() && !FORCE_TTY.load(Ordering::Relaxed) {
+        bail!("refusing to write binary data to a terminal; redirect stdout or pass --force-tty");
+    }
+    Ok(())
+}
+
+// Stdin can only be consumed once, so a second `-` input is an error rather than empty
+fn take_stdin() -> Result<()> {
+    if STDIN_TAKEN.swap(true, Ordering::Relaxed) {
+        bail!("'-' can stand for stdin only once");
+    }
+    Ok(())
+}
+
+fn spool_path(kind: &str, extension: &str) -> PathBuf {
+    let n = SPOOLED.fetch_add(1, Ordering::Relaxed);
+    let mut name = format!("jj_toolkit-{}-{}-{}", kind, std::process::id(), n);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(extension);
+    }
+    std::env::temp_dir().join(name)
+}
@@ -1,96 +1,444 @@
+use std::collections::HashMap;
 use std::fs;
-use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result, anyhow};
-use clap::Args;
-use image::{ImageBuffer, Rgba};
+use anyhow::{Context, Result, anyhow, bail};
+use clap::{Args, ValueEnum};
+use image::{ImageBuffer, ImageFormat, Rgba};
+use rand::rngs::ChaCha20Rng;
+use rand::{Rng, SeedableRng};
+
+use crate::stdio;
 
 #[derive(Args)]
-#[command[name = "stegano-embed", about = "Embed data into a PNG or BMP image using LSB steganography"]]
+#[command[name = "stegano-embed", about = "Embed data into a PNG, BMP, TIFF or WebP image using LSB steganography"]]
 pub struct EmbedArgs {
-    /// Input image path
-    input: PathBuf,
-    /// Output image path
+    /// Input image path, or a directory of covers when --file is a directory
+    #[arg(required_unless_present = "covers", conflicts_with = "covers")]
+    input: Option<PathBuf>,
+    /// Output image path (with --split or a directory of payloads: output directory, default
+    /// <COVERS>/embedded)
     #[arg(short, long)]
     output: Option<PathBuf>,
-    /// Message to embed
+    /// Message to embed; `-` reads the payload from stdin
     #[arg(short, long, conflicts_with = "file")]
     message: Option<String>,
-    /// File to embed
+    /// File to embed, or a directory of files to embed one per cover; `-` reads stdin
     #[arg(short, long, conflicts_with = "message")]
     file: Option<PathBuf>,
+    /// Walk the payload and cover directories recursively
+    #[arg(short, long)]
+    recursive: bool,
+    /// How payloads in a directory find their cover
+    #[arg(long, value_enum, default_value_t = Pairing::Name)]
+    pair: Pairing,
+    /// Scatter the bits over the image in an order derived from this key. Extraction needs the same key
+    #[arg(short, long)]
+    key: Option<String>,
+    /// Channels that carry data; alpha LSBs stand out in opaque images
+    #[arg(long, value_enum, default_value_t = Channels::Rgb)]
+    channels: Channels,
+    /// Directory of cover images to stripe the payload across, in file name order
+    #[arg(long, requires = "split")]
+    covers: Option<PathBuf>,
+    /// Split the payload over as many --covers as it needs
+    #[arg(long, requires = "covers")]
+    split: bool,
+    /// Embed into the quantized DCT coefficients of a baseline JPEG cover; the output stays a JPEG
+    #[arg(long, conflicts_with_all = ["covers", "channels"])]
+    dct: bool,
 }
 
 #[derive(Args)]
-#[command[name = "stegano-extract", about = "Extract data embedded in a PNG or BMP image using LSB steganography"]]
+#[command[name = "stegano-extract", about = "Extract data embedded in a PNG, BMP, TIFF or WebP image using LSB steganography"]]
 pub struct ExtractArgs {
-    /// Input image path
+    /// Input image path, a directory holding every part of a split payload, or the output
+    /// directory of a batch embed
     input: PathBuf,
-    /// Optional output file. If omitted, prints as UTF-8 text.
+    /// Optional output file, `-` for the raw bytes on stdout. If omitted, an embedded file is
+    /// restored under its original name in the current directory, and a message is printed as
+    /// UTF-8 text.
     #[arg(short, long)]
     output: Option<PathBuf>,
+    /// Key the data was embedded with
+    #[arg(short, long)]
+    key: Option<String>,
+    /// Channels the data was embedded in (ignored for JPEGs, which carry data in DCT coefficients)
+    #[arg(long, value_enum, default_value_t = Channels::Rgb)]
+    channels: Channels,
 }
 
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Channels {
+    Rgb,
+    Rgba,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Pairing {
+    /// The cover with the same relative path, minus the extension
+    Name,
+    /// Covers in name order, starting over when they run out
+    RoundRobin,
+}
+
+/// Written next to the outputs of a batch embed; `stegano-extract <DIR>` reads it back
+const MANIFEST: &str = "manifest.json";
+
 pub fn embed(a: EmbedArgs) -> Result<()> {
-    // Load image
-    let img =
-        image::open(&a.input).with_context(|| format!("failed to load image {:?}", a.input))?;
-    let mut img = img.to_rgba8();
+    if let Some(dir) = a.file.as_ref().filter(|f| f.is_dir()) {
+        if a.dct {
+            bail!("--dct embeds into a single JPEG cover, not a directory of them");
+        }
+        return embed_batch(&a, dir);
+    }
 
     // Get payload bytes
-    let payload: Vec<u8> = if let Some(msg) = a.message {
-        msg.into_bytes()
-    } else if let Some(path) = a.file {
-        fs::read(&path).with_context(|| format!("failed to read file {:?}", path))?
+    let stdin = a.message.as_deref() == Some("-") || a.file.as_deref() == Some(Path::new("-"));
+    let (payload, meta) = if stdin {
+        (stdio::read(Path::new("-")).context("failed to read payload from stdin")?, Meta::default())
+    } else if let Some(msg) = &a.message {
+        (msg.clone().into_bytes(), Meta::for_message())
+    } else if let Some(path) = &a.file {
+        let bytes = fs::read(path).with_context(|| format!("failed to read file {:?}", path))?;
+        (bytes, Meta::for_file(path))
     } else {
         return Err(anyhow!("You must provide either --message or --file"));
     };
 
-    // Build bitstream
     if payload.len() > u32::MAX as usize {
         return Err(anyhow!("Payload too large"));
     }
 
-    let mut data = Vec::with_capacity(4 + payload.len());
-    let len = payload.len() as u32;
-    data.extend_from_slice(&len.to_be_bytes());
-    data.extend_from_slice(&payload);
+    if let Some(covers) = &a.covers {
+        return embed_split(&a, covers, &payload, &meta);
+    }
+    let cover = stdio::input(a.input.as_ref().context("missing input image")?)?;
+    let input = cover.path();
+    let part = Part { index: 0, count: 1, total_crc: crc32fast::hash(&payload), meta, data: payload };
+    // A cover from stdin goes back out on stdout, as a PNG unless it is a --dct JPEG
+    let output = a.output.clone().or_else(|| cover.is_stdin().then(|| PathBuf::from("-")));
+    let output = output.map(|o| stdio::output(&o, if a.dct { "jpg" } else { "png" }, true)).transpose()?;
+    let output_path = output.as_ref().map(|o| o.path());
+    if a.dct {
+        embed_jpeg(&a, input, output_path, &part)?;
+        return output.map_or(Ok(()), |o| o.finish());
+    }
 
-    embed_data(&mut img, &data).with_context(|| "failed to embed data into the image")?;
+    // Load image
+    let img =
+        crate::image::open_image(input, false).with_context(|| format!("failed to load image {:?}", input))?;
+    let mut img = img.to_rgba8();
+
+    let positions = BitPositions::new(img.as_raw().len() / 4, a.channels, a.key.as_deref());
+    embed_data(&mut img, &part.encode()?, positions).with_context(|| "failed to embed data into the image")?;
 
     // Save image
-    if let Some(path) = &a.output {
-        img.save(path)
-            .with_context(|| format!("failed to save image to {:?}", path))?;
-    } else {
-        let mut out = a.input.clone();
-        let mut name = a.input.file_stem().unwrap().to_str().unwrap().to_string();
-        name += "_embedded";
+    let out = match output_path {
+        Some(path) => {
+            carrier_format(path)?;
+            path.to_path_buf()
+        }
+        None => embedded_path(input, &input.with_file_name(format!("{}_embedded", file_stem(input)))),
+    };
+    if !stdio::check_output(&out)? {
+        return Ok(());
+    }
+    save_carrier(&img, &out)?;
+    output.map_or(Ok(()), |o| o.finish())
+}
+
+// The coefficients are rewritten as they were apart from their LSBs, with no pixel round trip
+fn embed_jpeg(a: &EmbedArgs, input: &Path, output: Option<&Path>, part: &Part) -> Result<()> {
+    let data = fs::read(input).with_context(|| format!("failed to read image {:?}", input))?;
+    let mut jpeg = crate::jpeg::read(&data).with_context(|| format!("--dct needs a baseline JPEG cover, {:?} is not", input))?;
+    let out = match output {
+        Some(path) => path.to_path_buf(),
+        None => {
+            let ext = input.extension().map(|e| e.to_string_lossy().into_owned()).unwrap_or_else(|| "jpg".to_string());
+            input.with_file_name(format!("{}_embedded.{}", file_stem(input), ext))
+        }
+    };
+    if ImageFormat::from_path(&out).ok() != Some(ImageFormat::Jpeg) {
+        bail!("{:?} must be a .jpg or .jpeg file with --dct", out);
+    }
+    if !stdio::check_output(&out)? {
+        return Ok(());
+    }
+
+    let slots = dct_slots(&jpeg);
+    let mut lsbs: Vec<u8> = slots.iter().map(|&(c, b, k)| (jpeg.components[c].blocks[b][k] & 1) as u8).collect();
+    let positions = BitPositions::flat(lsbs.len(), a.key.as_deref());
+    embed_data(&mut lsbs, &part.encode()?, positions).with_context(|| "failed to embed data into the JPEG")?;
+    for (&(c, b, k), bit) in slots.iter().zip(lsbs) {
+        let coef = &mut jpeg.components[c].blocks[b][k];
+        *coef = (*coef & !1) | bit as i16;
+    }
+    stdio::write(&out, &crate::jpeg::write(&jpeg)?, true).with_context(|| format!("failed to save image to {:?}", out))
+}
 
-        out.set_file_name(name);
-        out.set_extension(a.input.extension().unwrap());
-        img.save(&out)
-            .with_context(|| format!("failed to save image to {:?}", out))?;
+/// Coefficients that carry a bit, as (component, block, index): AC values other than 0 and 1, as
+/// in jsteg. Zeros would show in the run lengths, and setting the LSB only ever swaps 2 with 3
+/// and -1 with -2, so extraction finds this same set again. -1023 is left out too, since clearing
+/// its LSB gives -1024, which baseline JPEG has no code for.
+fn dct_slots(jpeg: &crate::jpeg::Jpeg) -> Vec<(usize, usize, usize)> {
+    let mut slots = Vec::new();
+    for (c, component) in jpeg.components.iter().enumerate() {
+        for (b, block) in component.blocks.iter().enumerate() {
+            for (k, &coef) in block.iter().enumerate().skip(1) {
+                if coef != 0 && coef != 1 && coef != -1023 {
+                    slots.push((c, b, k));
+                }
+            }
+        }
     }
+    slots
+}
 
+// Each cover takes as much of the payload as it can hold, until all of it is placed
+fn embed_split(a: &EmbedArgs, covers: &Path, payload: &[u8], meta: &Meta) -> Result<()> {
+    let out_dir = match &a.output {
+        Some(dir) => dir.clone(),
+        None => covers.join("embedded"),
+    };
+    let mut plan = Vec::new();
+    let mut remaining = payload.len();
+    for cover in list_images(covers)? {
+        if remaining == 0 {
+            break;
+        }
+        let (width, height) = image::ImageReader::open(&cover)
+            .and_then(|r| r.with_guessed_format())
+            .with_context(|| format!("failed to open image {:?}", cover))?
+            .into_dimensions()
+            .with_context(|| format!("failed to read image size of {:?}", cover))?;
+        let slots = BitPositions::new(width as usize * height as usize, a.channels, None).capacity;
+        let room = (slots / 8).saturating_sub(HEADER_LEN + meta.len());
+        if room == 0 {
+            continue;
+        }
+        let take = room.min(remaining);
+        plan.push((cover, take));
+        remaining -= take;
+    }
+    if remaining > 0 {
+        bail!("Covers in {:?} are too small, {} more bytes do not fit", covers, remaining);
+    }
+    if plan.len() > u16::MAX as usize {
+        bail!("Payload would need more than {} covers", u16::MAX);
+    }
+
+    fs::create_dir_all(&out_dir).with_context(|| format!("failed to create {:?}", out_dir))?;
+    let total_crc = crc32fast::hash(payload);
+    let mut offset = 0;
+    for (index, (cover, take)) in plan.iter().enumerate() {
+        let img = crate::image::open_image(cover, false).with_context(|| format!("failed to load image {:?}", cover))?;
+        let mut img = img.to_rgba8();
+        let part = Part {
+            index: index as u16,
+            count: plan.len() as u16,
+            total_crc,
+            meta: meta.clone(),
+            data: payload[offset..offset + take].to_vec(),
+        };
+        offset += take;
+
+        let positions = BitPositions::new(img.as_raw().len() / 4, a.channels, a.key.as_deref());
+        embed_data(&mut img, &part.encode()?, positions)
+            .with_context(|| format!("failed to embed data into {:?}", cover))?;
+        let out = embedded_path(cover, &out_dir.join(file_stem(cover)));
+        save_carrier(&img, &out)?;
+        println!("Part {}/{}: {} bytes in {:?}", index + 1, plan.len(), take, out);
+    }
     Ok(())
 }
 
+// Every payload file goes into its own cover; the manifest records where each one went
+fn embed_batch(a: &EmbedArgs, payload_dir: &Path) -> Result<()> {
+    let covers_dir = a.input.as_ref().context("missing input directory of covers")?;
+    if !covers_dir.is_dir() {
+        bail!("{:?} must be a directory of covers when --file is a directory", covers_dir);
+    }
+    let out_dir = match &a.output {
+        Some(dir) => dir.clone(),
+        None => covers_dir.join("embedded"),
+    };
+    let payloads = walk_files(payload_dir, a.recursive, &out_dir)?;
+    let covers: Vec<PathBuf> =
+        walk_files(covers_dir, a.recursive, &out_dir)?.into_iter().filter(|p| ImageFormat::from_path(p).is_ok()).collect();
+    if payloads.is_empty() {
+        bail!("No files to embed in {:?}", payload_dir);
+    }
+    if covers.is_empty() {
+        bail!("No cover images in {:?}", covers_dir);
+    }
+
+    // Pair up front so nothing is written when a payload has no cover
+    let by_name: HashMap<PathBuf, &PathBuf> =
+        covers.iter().map(|c| (relative(c, covers_dir).with_extension(""), c)).collect();
+    let mut pairs: HashMap<PathBuf, (PathBuf, PathBuf)> = HashMap::new();
+    let mut outputs = HashMap::new();
+    for (i, payload) in payloads.iter().enumerate() {
+        let rel = relative(payload, payload_dir);
+        let cover = match a.pair {
+            Pairing::Name => match by_name.get(&rel.with_extension("")) {
+                Some(cover) => (*cover).clone(),
+                None => bail!("No cover in {:?} is named like {:?}", covers_dir, rel),
+            },
+            Pairing::RoundRobin => covers[i % covers.len()].clone(),
+        };
+        let out = embedded_path(&cover, &out_dir.join(&rel));
+        if let Some(other) = outputs.insert(out.clone(), payload) {
+            bail!("{:?} and {:?} would both be written to {:?}", other, payload, out);
+        }
+        pairs.insert(payload.clone(), (cover, out));
+    }
+
+    let report = crate::batch::process(&payloads, None, true, "Embedding", |payload| {
+        let (cover, out) = &pairs[payload];
+        if !stdio::check_output(out)? {
+            return Ok(());
+        }
+        let data = fs::read(payload).with_context(|| format!("failed to read file {:?}", payload))?;
+        if data.len() > u32::MAX as usize {
+            bail!("Payload too large");
+        }
+        let img = crate::image::open_image(cover, false).with_context(|| format!("failed to load image {:?}", cover))?;
+        let mut img = img.to_rgba8();
+        let part = Part { index: 0, count: 1, total_crc: crc32fast::hash(&data), meta: Meta::for_file(payload), data };
+        let positions = BitPositions::new(img.as_raw().len() / 4, a.channels, a.key.as_deref());
+        embed_data(&mut img, &part.encode()?, positions)
+            .with_context(|| format!("failed to embed data into {:?}", cover))?;
+        if let Some(parent) = out.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("failed to create {:?}", parent))?;
+        }
+        save_carrier(&img, out)
+    })?;
+    report.log_failures();
+    println!("{}", report.counts());
+
+    let entries: Vec<serde_json::Value> = report
+        .succeeded
+        .iter()
+        .map(|payload| {
+            let (cover, out) = &pairs[payload];
+            serde_json::json!({
+                "payload": slash_path(&relative(payload, payload_dir)),
+                "cover": slash_path(cover),
+                "output": slash_path(&relative(out, &out_dir)),
+            })
+        })
+        .collect();
+    if !entries.is_empty() {
+        let manifest = serde_json::json!({ "version": 1, "entries": entries });
+        let path = out_dir.join(MANIFEST);
+        if stdio::check_output(&path)? {
+            stdio::write(&path, serde_json::to_string_pretty(&manifest)?.as_bytes(), false)
+                .with_context(|| format!("failed to write manifest {:?}", path))?;
+            println!("Manifest written to {:?}", path);
+        }
+    }
+    report.into_result()
+}
+
+// Files under `dir`, sorted, leaving out an output directory nested inside it
+fn walk_files(dir: &Path, recursive: bool, exclude: &Path) -> Result<Vec<PathBuf>> {
+    crate::batch::collect(dir, &Default::default(), recursive, |p| !p.starts_with(exclude))
+}
+
+fn relative(path: &Path, base: &Path) -> PathBuf {
+    path.strip_prefix(base).unwrap_or(path).to_path_buf()
+}
+
+// Manifest paths use `/` on every platform
+fn slash_path(path: &Path) -> String {
+    path.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+fn file_stem(path: &Path) -> String {
+    path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_else(|| "output".to_string())
+}
+
+// `base` with the input's extension, or .png when the input is not a lossless carrier
+fn embedded_path(input: &Path, base: &Path) -> PathBuf {
+    let mut out = base.to_path_buf();
+    match input.extension() {
+        Some(ext) if carrier_format(input).is_ok() => {
+            out.set_extension(ext);
+        }
+        _ => {
+            println!("{:?} is not a lossless carrier, writing PNG", input);
+            out.set_extension("png");
+        }
+    }
+    out
+}
+
+fn save_carrier(img: &ImageBuffer<Rgba<u8>, Vec<u8>>, out: &Path) -> Result<()> {
+    let format = carrier_format(out)?;
+    stdio::write_atomic(out, |temp| Ok(img.save_with_format(temp, format)?))
+        .with_context(|| format!("failed to save image to {:?}", out))
+}
+
+// Image files directly inside `dir`, sorted by name
+fn list_images(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut images: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("failed to read directory {:?}", dir))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && ImageFormat::from_path(p).is_ok())
+        .collect();
+    images.sort();
+    Ok(images)
+}
+
 pub fn extract(a: ExtractArgs) -> Result<()> {
-    // Load image
-    let img =
-        image::open(&a.input).with_context(|| format!("failed to load image {:?}", a.input))?;
-    let img = img.to_rgba8();
+    let to_stdout = a.output.as_deref().is_some_and(stdio::is_dash);
+    let input = stdio::input(&a.input)?;
+    if a.input.join(MANIFEST).is_file() {
+        if to_stdout {
+            bail!("A batch embed holds several payloads, --output must be a directory");
+        }
+        return extract_batch(&a);
+    }
+    let (extracted, meta) = if a.input.is_dir() {
+        extract_split(&a)?
+    } else {
+        let part = extract_part(input.path(), &a).with_context(|| "failed to extract data")?;
+        if part.count > 1 {
+            bail!(
+                "{:?} holds part {} of {} of a split payload, pass the directory with every part",
+                stdio::display(&a.input), part.index + 1, part.count
+            );
+        }
+        (part.data, part.meta)
+    };
 
-    let extracted = extract_data(&img).with_context(|| "failed to extract data")?;
+    if to_stdout {
+        return stdio::write(Path::new("-"), &extracted, true).context("failed to write to stdout");
+    }
+
+    // Files come back under their own name, next to where the command runs
+    let restored = match (&a.output, &meta.name) {
+        (None, Some(name)) => {
+            Some(PathBuf::from(Path::new(name).file_name().context("invalid embedded file name")?))
+        }
+        _ => None,
+    };
 
-    if let Some(path) = a.output {
-        let mut f =
-            fs::File::create(&path).with_context(|| format!("failed to create file {:?}", path))?;
-        f.write_all(&extracted)
-            .with_context(|| format!("failed to write to file {:?}", path))?;
-        println!("Extracted {} bytes to {:?}", extracted.len(), path);
+    // A restored name was not chosen by the user, so an existing file there is not replaced
+    // unless --force says so
+    let default = if restored.is_some() { stdio::Clobber::Refuse } else { stdio::Clobber::Replace };
+    if let Some(path) = a.output.or(restored) {
+        if !stdio::check_output_or(&path, default)? {
+            return Ok(());
+        }
+        // Atomically, so a link planted at an embedded name is replaced rather than written through
+        stdio::write(&path, &extracted, true).with_context(|| format!("failed to write to file {:?}", path))?;
+        match &meta.mime {
+            Some(mime) => println!("Extracted {} bytes ({}) to {:?}", extracted.len(), mime, path),
+            None => println!("Extracted {} bytes to {:?}", extracted.len(), path),
+        }
     } else {
         // Try to parse as UTF-8; else show length
         match String::from_utf8(extracted.clone()) {
@@ -105,75 +453,564 @@ pub fn extract(a: ExtractArgs) -> Result<()> {
     Ok(())
 }
 
-/// Embed data bytes into the image using 1 bit per channel LSB.
-fn embed_data(img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, data: &[u8]) -> Result<()> {
-    let buffer = img.as_mut();
+#[derive(Args)]
+#[command[name = "stegano-detect", about = "Estimate how likely an image carries LSB-embedded data"]]
+pub struct DetectArgs {
+    /// Input image path
+    input: PathBuf,
+}
+
+pub fn detect(a: DetectArgs) -> Result<()> {
+    let input = stdio::input(&a.input)?;
+    let img = crate::image::open_image(input.path(), false)
+        .with_context(|| format!("failed to load image {}", stdio::display(&a.input)))?;
+    let img = img.to_rgb8();
+    let samples = img.as_raw();
+    if samples.len() < 3 * 64 {
+        bail!("Image too small to analyse");
+    }
+
+    println!("File: {}", stdio::display(&a.input));
+    println!("Chi-square (probability that value pairs 2k/2k+1 were equalized by embedding):");
+    let names = ["R", "G", "B"];
+    let mut chi = [0.0; 3];
+    for (channel, name) in names.iter().enumerate() {
+        chi[channel] = chi_square_probability(samples.iter().skip(channel).step_by(3));
+        println!("  {}: {:.3}", name, chi[channel]);
+    }
+    // A sequential embedding equalizes the pairs only in the part it covers
+    let extent = (1..=20)
+        .map(|step| step * 5)
+        .take_while(|percent| {
+            let end = samples.len() * percent / 100;
+            chi_square_probability(samples[..end].iter()) > 0.95
+        })
+        .last()
+        .unwrap_or(0);
+    println!("  Sequential embedding covers about {}% of the image", extent);
+
+    println!("Sample pair analysis (estimated share of LSBs carrying data):");
+    let mut spa = [0.0; 3];
+    for (channel, name) in names.iter().enumerate() {
+        spa[channel] = sample_pair_rate(&img, channel);
+        println!("  {}: {:.3}", name, spa[channel]);
+    }
+
+    let rate = spa.iter().copied().fold(0.0, f64::max);
+    let verdict = if rate > 0.2 || extent >= 10 {
+        "likely"
+    } else if rate > 0.05 || chi.iter().any(|&p| p > 0.95) {
+        "possible"
+    } else {
+        "unlikely"
+    };
+    println!("LSB embedding: {} (up to {:.0}% of capacity)", verdict, rate.max(extent as f64 / 100.0) * 100.0);
+    Ok(())
+}
+
+// Westfeld-Pfitzmann: LSB embedding pulls the counts of 2k and 2k+1 together, so the
+// histogram fits the "pairs are equal" model. Returns the upper tail of the chi-square fit
+fn chi_square_probability<'a>(values: impl Iterator<Item = &'a u8>) -> f64 {
+    let mut histogram = [0u64; 256];
+    for &v in values {
+        histogram[v as usize] += 1;
+    }
+    let mut chi = 0.0;
+    let mut pairs = 0;
+    for pair in histogram.chunks(2) {
+        let expected = (pair[0] + pair[1]) as f64 / 2.0;
+        if expected > 0.0 {
+            chi += (pair[0] as f64 - expected).powi(2) / expected;
+            pairs += 1;
+        }
+    }
+    if pairs < 2 {
+        return 0.0;
+    }
+    upper_gamma((pairs - 1) as f64 / 2.0, chi / 2.0)
+}
+
+// Dumitrescu-Wu-Wang sample pair analysis over horizontally adjacent samples: solves
+// (g/2)p^2 + (2x - n)p + (y - x) = 0 for the embedding rate p
+fn sample_pair_rate(img: &image::RgbImage, channel: usize) -> f64 {
+    let (mut x, mut y, mut g, mut n) = (0f64, 0f64, 0f64, 0f64);
+    for row in img.rows() {
+        let values: Vec<i32> = row.map(|p| p[channel] as i32).collect();
+        for pair in values.windows(2) {
+            let (u, v) = (pair[0], pair[1]);
+            n += 1.0;
+            if (v % 2 == 0 && u < v) || (v % 2 == 1 && u > v) {
+                x += 1.0;
+            }
+            if (v % 2 == 0 && u > v) || (v % 2 == 1 && u < v) {
+                y += 1.0;
+            }
+            if u / 2 == v / 2 {
+                g += 1.0;
+            }
+        }
+    }
+    if g == 0.0 {
+        return 0.0;
+    }
+    let (qa, qb, qc) = (g / 2.0, 2.0 * x - n, y - x);
+    let discriminant = qb * qb - 4.0 * qa * qc;
+    if discriminant < 0.0 {
+        return 0.0;
+    }
+    let roots = [(-qb + discriminant.sqrt()) / (2.0 * qa), (-qb - discriminant.sqrt()) / (2.0 * qa)];
+    roots.iter().copied().fold(f64::INFINITY, |best, r| if r.abs() < best.abs() { r } else { best }).clamp(0.0, 1.0)
+}
+
+// Regularized upper incomplete gamma Q(a, x), as in Numerical Recipes' gammq
+fn upper_gamma(a: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 1.0;
+    }
+    let ln_prefix = -x + a * x.ln() - ln_gamma(a);
+    if x < a + 1.0 {
+        // Series for P(a, x)
+        let mut term = 1.0 / a;
+        let mut sum = term;
+        let mut n = a;
+        for _ in 0..500 {
+            n += 1.0;
+            term *= x / n;
+            sum += term;
+            if term.abs() < sum.abs() * 1e-12 {
+                break;
+            }
+        }
+        1.0 - sum * ln_prefix.exp()
+    } else {
+        // Lentz's continued fraction for Q(a, x)
+        let tiny = 1e-300;
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / tiny;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for i in 1..500 {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            if d.abs() < tiny {
+                d = tiny;
+            }
+            c = b + an / c;
+            if c.abs() < tiny {
+                c = tiny;
+            }
+            d = 1.0 / d;
+            let delta = d * c;
+            h *= delta;
+            if (delta - 1.0).abs() < 1e-12 {
+                break;
+            }
+        }
+        h * ln_prefix.exp()
+    }
+}
+
+// Lanczos approximation
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [
+        76.180_091_729_471_46,
+        -86.505_320_329_416_77,
+        24.014_098_240_830_91,
+        -1.231_739_572_450_155,
+        0.001_208_650_973_866_179,
+        -0.000_005_395_239_384_953,
+    ];
+    let mut y = x;
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let mut series = 1.000_000_000_190_015;
+    for c in COEFFICIENTS {
+        y += 1.0;
+        series += c / y;
+    }
+    -tmp + (2.506_628_274_631_000_5 * series / x).ln()
+}
+
+/// Output formats that keep every channel byte as written. WebP is always encoded lossless
+fn carrier_format(path: &Path) -> Result<ImageFormat> {
+    let format = ImageFormat::from_path(path).with_context(|| format!("unknown image format for {:?}", path))?;
+    match format {
+        ImageFormat::Png | ImageFormat::Bmp | ImageFormat::Tiff | ImageFormat::WebP => Ok(format),
+        ImageFormat::Jpeg => {
+            bail!("{:?} is a lossy format and would destroy the embedded data, use PNG, BMP, TIFF or WebP, or --dct to embed into a JPEG cover", path)
+        }
+        ImageFormat::Avif | ImageFormat::Gif => {
+            bail!("{:?} is a lossy format and would destroy the embedded data, use PNG, BMP, TIFF or WebP", path)
+        }
+        _ => bail!("{:?} is not a supported carrier, use PNG, BMP, TIFF or WebP", path),
+    }
+}
+
+fn extract_part(input: &Path, a: &ExtractArgs) -> Result<Part> {
+    let data = fs::read(input).with_context(|| format!("failed to read image {:?}", input))?;
+    if data.starts_with(&[0xFF, 0xD8]) {
+        let jpeg = crate::jpeg::read(&data).with_context(|| format!("failed to read JPEG {:?}", input))?;
+        let lsbs: Vec<u8> =
+            dct_slots(&jpeg).iter().map(|&(c, b, k)| (jpeg.components[c].blocks[b][k] & 1) as u8).collect();
+        let positions = BitPositions::flat(lsbs.len(), a.key.as_deref());
+        return extract_data(&lsbs, positions);
+    }
+    let img = crate::image::open_image(input, false).with_context(|| format!("failed to load image {:?}", input))?;
+    let img = img.to_rgba8();
+    let positions = BitPositions::new(img.as_raw().len() / 4, a.channels, a.key.as_deref());
+    extract_data(&img, positions)
+}
+
+// Images without data are ignored; the parts found must form exactly one complete payload
+fn extract_split(a: &ExtractArgs) -> Result<(Vec<u8>, Meta)> {
+    let mut parts: Vec<Part> = list_images(&a.input)?
+        .iter()
+        .filter_map(|path| extract_part(path, a).ok())
+        .collect();
+    let Some(first) = parts.first() else {
+        bail!("No embedded data found in {:?} (wrong --key or --channels?)", a.input);
+    };
+    let (total_crc, count) = (first.total_crc, first.count);
+    if parts.iter().any(|p| p.total_crc != total_crc || p.count != count) {
+        bail!("{:?} holds parts of more than one payload", a.input);
+    }
+    parts.sort_by_key(|p| p.index);
+    parts.dedup_by_key(|p| p.index);
+    let missing: Vec<String> = (0..count)
+        .filter(|i| !parts.iter().any(|p| p.index == *i))
+        .map(|i| (i + 1).to_string())
+        .collect();
+    if !missing.is_empty() {
+        bail!("Missing part(s) {} of {}", missing.join(", "), count);
+    }
+
+    let meta = parts[0].meta.clone();
+    let payload: Vec<u8> = parts.into_iter().flat_map(|p| p.data).collect();
+    if crc32fast::hash(&payload) != total_crc {
+        bail!("Payload corrupted: checksum of the reassembled parts does not match");
+    }
+    Ok((payload, meta))
+}
 
-    let capacity_bits = buffer.len();
+// Restores every payload listed in the manifest of a batch embed, under its relative path
+fn extract_batch(a: &ExtractArgs) -> Result<()> {
+    let manifest_path = a.input.join(MANIFEST);
+    let manifest: serde_json::Value = serde_json::from_slice(
+        &fs::read(&manifest_path).with_context(|| format!("failed to read manifest {:?}", manifest_path))?,
+    )
+    .with_context(|| format!("failed to parse manifest {:?}", manifest_path))?;
+    let invalid = || anyhow!("Invalid manifest {:?}", manifest_path);
+    let mut targets = HashMap::new();
+    for entry in manifest["entries"].as_array().ok_or_else(invalid)? {
+        let (Some(payload), Some(output)) = (entry["payload"].as_str(), entry["output"].as_str()) else {
+            return Err(invalid());
+        };
+        // Manifests are data from elsewhere; they must not point outside the output directory
+        let payload = PathBuf::from(payload);
+        if !payload.components().all(|c| matches!(c, std::path::Component::Normal(_))) {
+            bail!("Manifest entry {:?} escapes the output directory", payload);
+        }
+        targets.insert(a.input.join(output), payload);
+    }
+    let out_dir = match &a.output {
+        Some(dir) => dir.clone(),
+        None => a.input.join("extracted"),
+    };
+
+    let mut carriers: Vec<PathBuf> = targets.keys().cloned().collect();
+    carriers.sort();
+    crate::batch::run(&carriers, None, |carrier| {
+        let part = extract_part(carrier, a)?;
+        if part.count > 1 {
+            bail!("holds part {} of {} of a split payload", part.index + 1, part.count);
+        }
+        let out = out_dir.join(&targets[carrier]);
+        if !stdio::check_output_or(&out, stdio::Clobber::Refuse)? {
+            return Ok(());
+        }
+        if let Some(parent) = out.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("failed to create {:?}", parent))?;
+        }
+        stdio::write(&out, &part.data, true).with_context(|| format!("failed to write to file {:?}", out))
+    })
+}
+
+/// Start of every embedded stream. Version 3: magic, version, part index and count, CRC32 of
+/// the whole payload, this part's length and CRC32, then the lengths of the file name and MIME
+/// type that follow. The part CRC32 covers name, MIME and data.
+/// Version 2 has no name/MIME and version 1 no part fields either; both are still read.
+const MAGIC: &[u8; 4] = b"JJSG";
+const VERSION: u8 = 3;
+const HEADER_LEN: usize = 4 + 1 + 2 + 2 + 4 + 4 + 4 + 2 + 1;
+const HEADER_LEN_V2: usize = 4 + 1 + 2 + 2 + 4 + 4 + 4;
+const HEADER_LEN_V1: usize = 4 + 1 + 4 + 4;
+
+/// What the payload was: the embedded file's name, and its MIME type
+#[derive(Clone, Default)]
+struct Meta {
+    name: Option<String>,
+    mime: Option<String>,
+}
+
+impl Meta {
+    fn for_file(path: &Path) -> Self {
+        // Only the last component; extraction must never write outside its directory
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned());
+        let mime = mime_type(path).to_string();
+        Meta { name, mime: Some(mime) }
+    }
+
+    fn for_message() -> Self {
+        Meta { name: None, mime: Some("text/plain; charset=utf-8".to_string()) }
+    }
+
+    // Bytes after the fixed header
+    fn encode(&self) -> Vec<u8> {
+        let name = self.name.as_deref().unwrap_or("").as_bytes();
+        let mime = self.mime.as_deref().unwrap_or("").as_bytes();
+        [name, mime].concat()
+    }
+
+    fn len(&self) -> usize {
+        self.encode().len()
+    }
+}
+
+/// One embedded stream: a whole payload, or part `index` of `count` of one split over covers
+struct Part {
+    index: u16,
+    count: u16,
+    total_crc: u32,
+    meta: Meta,
+    data: Vec<u8>,
+}
+
+impl Part {
+    fn encode(&self) -> Result<Vec<u8>> {
+        let name_len = self.meta.name.as_deref().map_or(0, str::len);
+        let mime_len = self.meta.mime.as_deref().map_or(0, str::len);
+        let Ok(name_len) = u16::try_from(name_len) else {
+            bail!("File name too long to embed");
+        };
+        let Ok(mime_len) = u8::try_from(mime_len) else {
+            bail!("MIME type too long to embed");
+        };
+        let meta = self.meta.encode();
+
+        let mut out = Vec::with_capacity(HEADER_LEN + meta.len() + self.data.len());
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&self.index.to_be_bytes());
+        out.extend_from_slice(&self.count.to_be_bytes());
+        out.extend_from_slice(&self.total_crc.to_be_bytes());
+        out.extend_from_slice(&(self.data.len() as u32).to_be_bytes());
+        out.extend_from_slice(&crc32fast::hash(&[meta.as_slice(), &self.data].concat()).to_be_bytes());
+        out.extend_from_slice(&name_len.to_be_bytes());
+        out.push(mime_len);
+        out.extend_from_slice(&meta);
+        out.extend_from_slice(&self.data);
+        Ok(out)
+    }
+}
+
+// By extension; only what is needed to label common payloads
+fn mime_type(path: &Path) -> &'static str {
+    let ext = path.extension().map(|e| e.to_string_lossy().to_ascii_lowercase()).unwrap_or_default();
+    match ext.as_str() {
+        "txt" | "md" => "text/plain",
+        "html" | "htm" => "text/html",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "zst" => "application/zstd",
+        "7z" => "application/x-7z-compressed",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "doc" => "application/msword",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Byte offsets in the RGBA buffer that carry the bits, one per bit.
+///
+/// Slots are the usable channels (RGB or RGBA) of every pixel in order; `order` walks them
+/// and `next` turns each slot into its byte offset. JPEG slots are one byte each.
+struct BitPositions {
+    order: SlotOrder,
+    per_pixel: usize,
+    stride: usize,
+    capacity: usize,
+}
+
+impl BitPositions {
+    fn new(pixels: usize, channels: Channels, key: Option<&str>) -> Self {
+        let per_pixel = match channels {
+            Channels::Rgb => 3,
+            Channels::Rgba => 4,
+        };
+        let capacity = pixels * per_pixel;
+        BitPositions { order: SlotOrder::new(capacity, key), per_pixel, stride: 4, capacity }
+    }
+
+    fn flat(len: usize, key: Option<&str>) -> Self {
+        BitPositions { order: SlotOrder::new(len, key), per_pixel: 1, stride: 1, capacity: len }
+    }
+}
+
+impl Iterator for BitPositions {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let slot = self.order.next()?;
+        Some(slot / self.per_pixel * self.stride + slot % self.per_pixel)
+    }
+}
+
+/// Sequential from the first slot, or a key-seeded permutation of all of them.
+///
+/// The permutation is a Fisher-Yates shuffle run lazily: step `i` swaps slot `i` with a random
+/// slot in `i..len`, and only the slots touched so far are remembered, so memory follows the
+/// payload size rather than the image size.
+enum SlotOrder {
+    Sequential(std::ops::Range<usize>),
+    Keyed {
+        rng: Box<ChaCha20Rng>,
+        next: usize,
+        len: usize,
+        swapped: HashMap<usize, usize>,
+    },
+}
+
+impl SlotOrder {
+    fn new(len: usize, key: Option<&str>) -> Self {
+        match key {
+            None => SlotOrder::Sequential(0..len),
+            Some(key) => {
+                let seed = blake3::derive_key("jj_toolkit stegano 2025 bit order", key.as_bytes());
+                SlotOrder::Keyed {
+                    rng: Box::new(ChaCha20Rng::from_seed(seed)),
+                    next: 0,
+                    len,
+                    swapped: HashMap::new(),
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for SlotOrder {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        match self {
+            SlotOrder::Sequential(range) => range.next(),
+            SlotOrder::Keyed { rng, next, len, swapped } => {
+                if *next >= *len {
+                    return None;
+                }
+                let i = *next;
+                // u64 keeps the sequence the same on 32 and 64-bit targets
+                let j = rng.random_range(i as u64..*len as u64) as usize;
+                let at_j = swapped.get(&j).copied().unwrap_or(j);
+                let at_i = swapped.remove(&i).unwrap_or(i);
+                if j != i {
+                    swapped.insert(j, at_i);
+                }
+                *next += 1;
+                Some(at_j)
+            }
+        }
+    }
+}
+
+/// Embed data bytes into the image using 1 bit per channel LSB.
+fn embed_data(buffer: &mut [u8], data: &[u8], mut positions: BitPositions) -> Result<()> {
+    let capacity_bits = positions.capacity;
     let required_bits = data.len() * 8;
 
     if required_bits > capacity_bits {
         return Err(anyhow!("Embedded data too large, data's {required_bits} bits, need to be < {capacity_bits} bits"));
     }
 
-    let mut bit_idx = 0usize;
-
     for &byte in data {
         for bit_pos in (0..8).rev() {
             let bit = (byte >> bit_pos) & 1;
-            let idx = bit_idx;
+            let idx = positions.next().context("ran out of channel bytes")?;
             let org = buffer[idx];
             // Set LSB to `bit`
             let new = (org & 0xFE) | bit;
             buffer[idx] = new;
-
-            bit_idx += 1;
         }
     }
 
     Ok(())
 }
 
-fn extract_data(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> Result<Vec<u8>> {
-    let buffer = img.as_raw();
-
-    let capacity_bits = buffer.len();
-    if capacity_bits < 32 {
-        return Err(anyhow!("Image too small to contain length prefix"));
+fn extract_data(buffer: &[u8], mut positions: BitPositions) -> Result<Part> {
+    let capacity_bits = positions.capacity;
+    if capacity_bits < HEADER_LEN_V1 * 8 {
+        return Err(anyhow!("Image too small to contain a header"));
     }
 
-    let mut bit_idx = 0usize;
-
-    // Read length
-    let mut len_bytes = [0u8; 4];
-    for byte in &mut len_bytes {
-        let mut val = 0u8;
-        for _ in 0..8 {
-            let idx = bit_idx;
-            let bit = buffer[idx] & 1;
-            val = (val << 1) | bit;
-            bit_idx += 1;
+    let mut read_bytes = |count: usize| -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mut val = 0u8;
+            for _ in 0..8 {
+                let idx = positions.next().context("ran out of channel bytes")?;
+                let bit = buffer[idx] & 1;
+                val = (val << 1) | bit;
+            }
+            out.push(val);
         }
-        *byte = val;
-    }
-    let payload_len = u32::from_be_bytes(len_bytes) as usize;
+        Ok(out)
+    };
 
-    let required_bits = 32 + payload_len * 8;
-    if required_bits > capacity_bits {
-        return Err(anyhow!("Encoded length ({payload_len} bytes) exceeds image capacity"));
+    let prefix = read_bytes(5)?;
+    if &prefix[..4] != MAGIC {
+        bail!("No embedded data found (wrong --key or --channels?)");
     }
+    let version = prefix[4];
+    let fields = match version {
+        1 => read_bytes(HEADER_LEN_V1 - 5)?,
+        2 => read_bytes(HEADER_LEN_V2 - 5)?,
+        3 => read_bytes(HEADER_LEN - 5)?,
+        version => bail!("Unsupported embedded data version {}", version),
+    };
+    let be_u32 = |at: usize| u32::from_be_bytes(fields[at..at + 4].try_into().unwrap());
+    let be_u16 = |at: usize| u16::from_be_bytes(fields[at..at + 2].try_into().unwrap());
+    let (index, count, total_crc, payload_len, checksum) = if version == 1 {
+        (0, 1, be_u32(4), be_u32(0) as usize, be_u32(4))
+    } else {
+        (be_u16(0), be_u16(2), be_u32(4), be_u32(8) as usize, be_u32(12))
+    };
+    let (name_len, mime_len) = if version == 3 { (be_u16(16) as usize, fields[18] as usize) } else { (0, 0) };
 
-    let mut out = Vec::with_capacity(payload_len);
-    for _ in 0..payload_len {
-        let mut val = 0u8;
-        for _ in 0..8 {
-            let idx = bit_idx;
-            let bit = buffer[idx] & 1;
-            val = (val << 1) | bit;
-            bit_idx += 1;
-        }
-        out.push(val);
+    let header_len = 5 + fields.len() + name_len + mime_len;
+    let required_bits = (header_len + payload_len) * 8;
+    if required_bits > capacity_bits || index >= count {
+        bail!("Payload corrupted: header is inconsistent with the image");
     }
 
-    Ok(out)
-}
\ No newline at end of file
+    let meta_bytes = read_bytes(name_len + mime_len)?;
+    let data = read_bytes(payload_len)?;
+    if crc32fast::hash(&[meta_bytes.as_slice(), &data].concat()) != checksum {
+        bail!("Payload corrupted: checksum mismatch");
+    }
+    let text = |bytes: &[u8]| (!bytes.is_empty()).then(|| String::from_utf8_lossy(bytes).into_owned());
+    let meta = Meta { name: text(&meta_bytes[..name_len]), mime: text(&meta_bytes[name_len..]) };
+    Ok(Part { index, count, total_crc, meta, data })
+}
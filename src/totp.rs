@@ -0,0 +1,259 @@
+// `totp`: RFC 6238 one-time codes, the six digits authenticator apps show, from a base32 secret or
+// an otpauth:// URI. A secret can be saved encrypted with the crypt module's format, to a
+// password or to a recipient, so it does not have to sit in plain text or shell history
+use anyhow::{Context, Result, bail, ensure};
+use clap::{Args, ValueEnum};
+use hmac::{Hmac, KeyInit, Mac};
+use percent_encoding::{NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use std::io::{BufRead, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+use zeroize::Zeroizing;
+use crate::clipboard::CopyArgs;
+use crate::crypt;
+use crate::stdio;
+
+#[derive(Args)]
+#[command[name = "totp", about = "Current time-based one-time password (RFC 6238) for a secret or otpauth:// URI"]]
+pub struct TotpArgs {
+    /// Base32 secret as shown next to a QR code, `-` to read it from stdin
+    #[arg(short, long, required_unless_present_any = ["uri", "file"], conflicts_with_all = ["uri", "file"])]
+    secret: Option<String>,
+    /// otpauth://totp/... URI, as held by the QR codes sites show, `-` to read it from stdin
+    #[arg(short, long, conflicts_with = "file")]
+    uri: Option<String>,
+    /// Read the secret from a file written with --save
+    #[arg(short, long)]
+    file: Option<PathBuf>,
+    /// With --file, the identity to decrypt it with when it was saved for a --recipient
+    #[arg(short, long, requires = "file")]
+    identity: Option<PathBuf>,
+    /// Encrypt the secret and its settings to this file instead of printing a code
+    #[arg(long, conflicts_with = "file")]
+    save: Option<PathBuf>,
+    /// With --save, encrypt to an X25519 recipient instead of a password: an `age1...` key or a
+    /// file holding one
+    #[arg(long, requires = "save")]
+    recipient: Option<String>,
+    /// Digits per code. Default: from the URI, else 6
+    #[arg(short, long)]
+    digits: Option<u32>,
+    /// Seconds each code is valid for. Default: from the URI, else 30
+    #[arg(short, long)]
+    period: Option<u64>,
+    /// HMAC algorithm. Default: from the URI, else sha1
+    #[arg(short, long, value_enum)]
+    algorithm: Option<TotpAlgorithm>,
+    /// Code for this Unix time instead of now
+    #[arg(long, value_name = "SECONDS")]
+    at: Option<u64>,
+    #[command(flatten)]
+    copy: CopyArgs,
+}
+
+#[derive(Clone, Copy, ValueEnum, Debug, PartialEq)]
+pub enum TotpAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl TotpAlgorithm {
+    const fn uri_name(self) -> &'static str {
+        match self {
+            TotpAlgorithm::Sha1 => "SHA1",
+            TotpAlgorithm::Sha256 => "SHA256",
+            TotpAlgorithm::Sha512 => "SHA512",
+        }
+    }
+}
+
+/// A TOTP secret and the settings codes are derived with
+pub struct Totp {
+    pub secret: Zeroizing<Vec<u8>>,
+    pub algorithm: TotpAlgorithm,
+    pub digits: u32,
+    pub period: u64,
+    /// Account and issuer, from an otpauth:// URI
+    pub label: Option<String>,
+    pub issuer: Option<String>,
+}
+
+impl Totp {
+    /// Defaults of authenticator apps: SHA-1, 6 digits, 30 seconds
+    pub fn new(secret: &[u8]) -> Totp {
+        Totp { secret: Zeroizing::new(secret.to_vec()), algorithm: TotpAlgorithm::Sha1, digits: 6, period: 30, label: None, issuer: None }
+    }
+
+    /// A secret in base32, case, spaces and padding ignored
+    pub fn from_base32(text: &str) -> Result<Totp> {
+        Ok(Totp::new(&decode_base32(text)?))
+    }
+
+    /// An `otpauth://totp/<label>?secret=...` URI, with its optional issuer, algorithm, digits
+    /// and period
+    pub fn from_uri(uri: &str) -> Result<Totp> {
+        let rest = uri.trim().strip_prefix("otpauth://").context("not an otpauth:// URI")?;
+        let (kind, rest) = rest.split_once('/').context("otpauth URI without a label")?;
+        if !kind.eq_ignore_ascii_case("totp") {
+            bail!("only time-based (totp) URIs are supported, not {}", kind);
+        }
+        let (label, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let mut secret = None;
+        let mut totp = Totp::new(&[]);
+        let label = percent_decode_str(label).decode_utf8_lossy().into_owned();
+        totp.label = (!label.is_empty()).then_some(label);
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = Zeroizing::new(percent_decode_str(value).decode_utf8_lossy().into_owned());
+            match key.to_ascii_lowercase().as_str() {
+                "secret" => secret = Some(decode_base32(&value)?),
+                "issuer" => totp.issuer = Some(value.to_string()),
+                "algorithm" => {
+                    totp.algorithm = match value.to_ascii_uppercase().as_str() {
+                        "SHA1" => TotpAlgorithm::Sha1,
+                        "SHA256" => TotpAlgorithm::Sha256,
+                        "SHA512" => TotpAlgorithm::Sha512,
+                        other => bail!("unsupported algorithm {} in the URI", other),
+                    }
+                }
+                "digits" => totp.digits = value.parse().context("invalid digits in the URI")?,
+                "period" => totp.period = value.parse().context("invalid period in the URI")?,
+                _ => {}
+            }
+        }
+        totp.secret = Zeroizing::new(secret.context("otpauth URI without a secret")?.to_vec());
+        Ok(totp)
+    }
+
+    /// The otpauth:// URI holding this secret and its settings
+    pub fn to_uri(&self) -> Zeroizing<String> {
+        let label = self.label.as_deref().unwrap_or("jj_toolkit");
+        let mut uri = format!(
+            "otpauth://totp/{}?secret={}&algorithm={}&digits={}&period={}",
+            utf8_percent_encode(label, NON_ALPHANUMERIC),
+            data_encoding::BASE32_NOPAD.encode(&self.secret),
+            self.algorithm.uri_name(),
+            self.digits,
+            self.period
+        );
+        if let Some(issuer) = &self.issuer {
+            uri.push_str(&format!("&issuer={}", utf8_percent_encode(issuer, NON_ALPHANUMERIC)));
+        }
+        Zeroizing::new(uri)
+    }
+
+    /// The code for Unix time `time`
+    pub fn code_at(&self, time: u64) -> Result<String> {
+        ensure!((1..=10).contains(&self.digits), "digits must be between 1 and 10");
+        ensure!(self.period > 0, "period must be at least 1 second");
+        ensure!(!self.secret.is_empty(), "the secret is empty");
+        let counter = (time / self.period).to_be_bytes();
+        let mac = match self.algorithm {
+            TotpAlgorithm::Sha1 => hmac::<sha1::Sha1>(&self.secret, &counter),
+            TotpAlgorithm::Sha256 => hmac::<sha2::Sha256>(&self.secret, &counter),
+            TotpAlgorithm::Sha512 => hmac::<sha2::Sha512>(&self.secret, &counter),
+        }?;
+        // Dynamic truncation: 31 bits at the offset named by the last nibble
+        let offset = (mac[mac.len() - 1] & 0x0F) as usize;
+        let value = u32::from_be_bytes([mac[offset] & 0x7F, mac[offset + 1], mac[offset + 2], mac[offset + 3]]);
+        let code = u64::from(value) % 10u64.pow(self.digits);
+        Ok(format!("{:0width$}", code, width = self.digits as usize))
+    }
+
+    /// Seconds the code for `time` stays valid
+    pub fn remaining_at(&self, time: u64) -> u64 {
+        self.period - time % self.period
+    }
+}
+
+fn hmac<D: hmac::EagerHash>(key: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+    let mut mac = <Hmac<D> as KeyInit>::new_from_slice(key).map_err(|_| anyhow::anyhow!("invalid HMAC key"))?;
+    mac.update(message);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+fn decode_base32(text: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let normalized = Zeroizing::new(text.chars().filter(|c| !c.is_whitespace() && *c != '=' && *c != '-').collect::<String>().to_ascii_uppercase());
+    let secret = data_encoding::BASE32_NOPAD.decode(normalized.as_bytes()).context("the secret is not valid base32")?;
+    Ok(Zeroizing::new(secret))
+}
+
+pub fn totp(a: TotpArgs) -> Result<()> {
+    let mut totp = if let Some(secret) = &a.secret {
+        Totp::from_base32(&read_arg(secret)?)?
+    } else if let Some(uri) = &a.uri {
+        Totp::from_uri(&read_arg(uri)?)?
+    } else if let Some(path) = &a.file {
+        load(path, a.identity.as_deref())?
+    } else {
+        bail!("one of --secret, --uri or --file is required");
+    };
+    if let Some(digits) = a.digits {
+        totp.digits = digits;
+    }
+    if let Some(period) = a.period {
+        totp.period = period;
+    }
+    if let Some(algorithm) = a.algorithm {
+        totp.algorithm = algorithm;
+    }
+    // Checked before anything is saved
+    let now = match a.at {
+        Some(at) => at,
+        None => SystemTime::now().duration_since(UNIX_EPOCH).context("system clock before 1970")?.as_secs(),
+    };
+    let code = totp.code_at(now)?;
+
+    if let Some(path) = &a.save {
+        if a.copy.copy {
+            bail!("--copy takes a code, which --save does not print");
+        }
+        if !stdio::check_output(path)? {
+            return Ok(());
+        }
+        save(&totp, path, a.recipient.as_deref())?;
+        info!("Saved TOTP secret: {}", path.display());
+        return Ok(());
+    }
+    let mut out = a.copy.output()?;
+    writeln!(out, "{}", code)?;
+    out.finish()?;
+    info!("Valid for {} more second(s)", totp.remaining_at(now));
+    Ok(())
+}
+
+// `-` for a line from stdin, which keeps the secret out of shell history
+fn read_arg(arg: &str) -> Result<Zeroizing<String>> {
+    if !stdio::is_dash(Path::new(arg)) {
+        return Ok(Zeroizing::new(arg.to_string()));
+    }
+    let mut line = Zeroizing::new(String::new());
+    std::io::BufReader::new(stdio::reader(Path::new("-"))?).read_line(&mut line).context("read stdin")?;
+    Ok(Zeroizing::new(line.trim().to_string()))
+}
+
+fn save(totp: &Totp, path: &Path, recipient: Option<&str>) -> Result<()> {
+    let uri = totp.to_uri();
+    let mut w = stdio::writer(path, true)?;
+    match recipient {
+        Some(recipient) => crypt::encrypt_stream_for(Cursor::new(uri.as_bytes()), &mut w, &crypt::read_recipient(recipient)?)?,
+        None => crypt::encrypt_stream(Cursor::new(uri.as_bytes()), &mut w, &crypt::prompt_new_password()?, crypt::KdfParams::default())?,
+    }
+    w.finish()
+}
+
+fn load(path: &Path, identity: Option<&Path>) -> Result<Totp> {
+    let r = stdio::reader(path)?;
+    let mut uri = Zeroizing::new(Vec::new());
+    match identity {
+        Some(identity) => crypt::decrypt_stream_for(r, &mut *uri, &crypt::read_identity(identity)?)?,
+        None => {
+            let password = Zeroizing::new(rpassword::prompt_password("Password: ")?);
+            crypt::decrypt_stream(r, &mut *uri, &password)?
+        }
+    }
+    let uri = std::str::from_utf8(&uri).with_context(|| format!("{} does not hold a TOTP secret", path.display()))?;
+    Totp::from_uri(uri).with_context(|| format!("{} does not hold a TOTP secret", path.display()))
+}
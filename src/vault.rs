@@ -0,0 +1,296 @@
+// `vault`: small named secrets, such as passwords, API tokens and recovery codes, kept together in
+// one file encrypted with the crypt module's format, to a password or to a recipient. It does what
+// pass does without a directory of gpg files. A change decrypts the whole vault and writes it back
+// sealed the same way, with the password and Argon2id costs it was opened with or to the public
+// half of the identity, and the new file only replaces the old one once it is complete. Changes
+// hold a lock on `<vault>.lock` from reading the vault to replacing it, so two at once do not
+// both start from the same contents
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use clap::{Args, Subcommand, ValueEnum};
+use serde_json::{Map, Value, json};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+use x25519_dalek::PublicKey;
+use zeroize::Zeroizing;
+use crate::clipboard::CopyArgs;
+use crate::passgen::{self, CharClass};
+use crate::{crypt, errors, stdio};
+
+// Version of the JSON document inside the encryption
+const VERSION: u64 = 1;
+
+#[derive(Args)]
+#[command[name = "vault", about = "Keep small named secrets in one password- or identity-encrypted file"]]
+pub struct VaultArgs {
+    #[command(subcommand)]
+    command: VaultCommand,
+}
+
+#[derive(Subcommand)]
+enum VaultCommand {
+    /// Create an empty vault, asking for its password unless --recipient
+    Init(InitArgs),
+    /// Store a secret under a name. It is asked for unless --stdin or --generate
+    Add(AddArgs),
+    /// Print a secret
+    Get(GetArgs),
+    /// Print the names of the secrets in order
+    List(OpenArgs),
+    /// Remove a secret
+    Rm(NameArgs),
+}
+
+#[derive(Args)]
+struct InitArgs {
+    /// Vault file to create
+    vault: PathBuf,
+    /// Encrypt to an X25519 recipient instead of a password: an `age1...` key, or a file holding
+    /// one such as the .pub of `keygen --for-crypt`
+    #[arg(long)]
+    recipient: Option<String>,
+}
+
+/// A vault and what opens it
+#[derive(Args)]
+struct OpenArgs {
+    /// Vault file
+    vault: PathBuf,
+    /// Identity of a vault created with --recipient, e.g. the .key of `keygen --for-crypt`
+    #[arg(short, long)]
+    identity: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct NameArgs {
+    #[command(flatten)]
+    open: OpenArgs,
+    /// Name of the secret, e.g. "mail/work"
+    name: String,
+}
+
+#[derive(Args)]
+struct GetArgs {
+    #[command(flatten)]
+    entry: NameArgs,
+    #[command(flatten)]
+    copy: CopyArgs,
+}
+
+#[derive(Args)]
+struct AddArgs {
+    #[command(flatten)]
+    entry: NameArgs,
+    /// Read the secret from stdin, all of it but a final newline
+    #[arg(long)]
+    stdin: bool,
+    /// Store a random password of this many characters from every class `passgen` draws from
+    #[arg(long, value_name = "LENGTH", conflicts_with = "stdin")]
+    generate: Option<usize>,
+    /// Replace the secret stored under the name, which is refused otherwise
+    #[arg(long)]
+    replace: bool,
+}
+
+// What seals the vault again after a change
+enum Key {
+    Password(Zeroizing<String>, crypt::KdfParams),
+    Recipient(PublicKey),
+}
+
+// A decrypted vault
+struct Vault {
+    secrets: BTreeMap<String, Zeroizing<String>>,
+    key: Key,
+}
+
+pub fn vault(a: VaultArgs) -> Result<()> {
+    match a.command {
+        VaultCommand::Init(a) => init(a),
+        VaultCommand::Add(a) => add(a),
+        VaultCommand::Get(a) => get(a),
+        VaultCommand::List(a) => list(a),
+        VaultCommand::Rm(a) => rm(a),
+    }
+}
+
+fn init(a: InitArgs) -> Result<()> {
+    stdio::ensure_not_dash(&a.vault, "vault")?;
+    if !stdio::check_output_or(&a.vault, stdio::Clobber::Refuse)? {
+        return Ok(());
+    }
+    let key = match &a.recipient {
+        Some(recipient) => Key::Recipient(crypt::read_recipient(recipient)?),
+        None => Key::Password(crypt::prompt_new_password()?, crypt::KdfParams::default()),
+    };
+    Vault { secrets: BTreeMap::new(), key }.save(&a.vault)?;
+    // Only its owner has any business reading it, encrypted or not
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&a.vault, std::fs::Permissions::from_mode(0o600)).with_context(|| errors::at("set the permissions of", &a.vault))?;
+    }
+    info!("Created vault {}", a.vault.display());
+    Ok(())
+}
+
+fn add(a: AddArgs) -> Result<()> {
+    let AddArgs { entry: NameArgs { open, name }, stdin, generate, replace } = a;
+    check_name(&name)?;
+    let _lock = lock(&open.vault)?;
+    let mut vault = Vault::open(&open)?;
+    if vault.secrets.contains_key(&name) && !replace {
+        bail!("{} is already in {}; pass --replace to change it", name, open.vault.display());
+    }
+    let secret = match generate {
+        Some(length) => {
+            ensure!(length > 0, "--generate must be at least 1");
+            let sets: Vec<Vec<char>> = CharClass::value_variants().iter().map(|class| class.chars().chars().collect()).collect();
+            passgen::password(&sets, length)?
+        }
+        None if stdin => read_stdin()?,
+        None => prompt_secret()?,
+    };
+    ensure!(!secret.is_empty(), "the secret is empty");
+    vault.secrets.insert(name.clone(), secret);
+    vault.save(&open.vault)?;
+    info!("Stored {} in {}", name, open.vault.display());
+    Ok(())
+}
+
+fn get(a: GetArgs) -> Result<()> {
+    let GetArgs { entry: NameArgs { open, name }, copy } = a;
+    let vault = Vault::open(&open)?;
+    let secret = vault.secrets.get(&name).ok_or_else(|| missing(&name, &open.vault))?;
+    let mut out = copy.output()?;
+    writeln!(out, "{}", secret.as_str())?;
+    out.finish()
+}
+
+fn list(a: OpenArgs) -> Result<()> {
+    let vault = Vault::open(&a)?;
+    let mut out = stdio::writer(Path::new("-"), false)?;
+    if stdio::json() {
+        writeln!(out, "{}", json!(vault.secrets.keys().collect::<Vec<_>>()))?;
+    } else {
+        for name in vault.secrets.keys() {
+            writeln!(out, "{}", name)?;
+        }
+    }
+    out.finish()
+}
+
+fn rm(a: NameArgs) -> Result<()> {
+    let _lock = lock(&a.open.vault)?;
+    let mut vault = Vault::open(&a.open)?;
+    if vault.secrets.remove(&a.name).is_none() {
+        return Err(missing(&a.name, &a.open.vault));
+    }
+    vault.save(&a.open.vault)?;
+    info!("Removed {} from {}", a.name, a.open.vault.display());
+    Ok(())
+}
+
+impl Vault {
+    fn open(a: &OpenArgs) -> Result<Vault> {
+        stdio::ensure_not_dash(&a.vault, "vault")?;
+        let sealed = std::fs::read(&a.vault).with_context(|| errors::at("read", &a.vault))?;
+        let mut plain = Zeroizing::new(Vec::new());
+        let key = match &a.identity {
+            Some(path) => {
+                let identity = crypt::read_identity(path)?;
+                crypt::decrypt_stream_for(&sealed[..], &mut *plain, &identity)?;
+                Key::Recipient(PublicKey::from(&identity))
+            }
+            None => {
+                let Some(params) = crypt::kdf_params(&sealed[..])? else {
+                    bail!("this vault is encrypted to a recipient; pass its identity with --identity");
+                };
+                let password = Zeroizing::new(rpassword::prompt_password("Password: ")?);
+                crypt::decrypt_stream(&sealed[..], &mut *plain, &password)?;
+                Key::Password(password, params)
+            }
+        };
+        let secrets = parse(&plain).with_context(|| format!("{} is not a vault", a.vault.display()))?;
+        Ok(Vault { secrets, key })
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let secrets: Map<String, Value> = self.secrets.iter().map(|(name, secret)| (name.clone(), json!(secret.as_str()))).collect();
+        let plain = Zeroizing::new(serde_json::to_vec(&json!({ "vault": VERSION, "secrets": secrets }))?);
+        let mut w = stdio::writer(path, true)?;
+        match &self.key {
+            Key::Password(password, params) => crypt::encrypt_stream(&plain[..], &mut w, password, *params)?,
+            Key::Recipient(recipient) => crypt::encrypt_stream_for(&plain[..], &mut w, recipient)?,
+        }
+        w.finish()
+    }
+}
+
+// An advisory lock on the file beside `vault`, held until the returned file is dropped. Not the
+// vault itself: `save` puts a new file in its place, which a waiting change would then miss
+fn lock(vault: &Path) -> Result<File> {
+    stdio::ensure_not_dash(vault, "vault")?;
+    // No lock file beside a vault that is not there
+    std::fs::metadata(vault).with_context(|| errors::at("read", vault))?;
+    let mut name = vault.file_name().context("vault has no file name")?.to_os_string();
+    name.push(".lock");
+    let path = vault.with_file_name(name);
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(&path).with_context(|| errors::at("create", &path))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) => {
+            info!("Waiting for another change to {}", vault.display());
+            file.lock().with_context(|| errors::at("lock", &path))?;
+        }
+        Err(std::fs::TryLockError::Error(e)) => return Err(e).with_context(|| errors::at("lock", &path)),
+    }
+    Ok(file)
+}
+
+fn parse(plain: &[u8]) -> Result<BTreeMap<String, Zeroizing<String>>> {
+    let document: Value = serde_json::from_slice(plain)?;
+    match document.get("vault").and_then(Value::as_u64) {
+        Some(VERSION) => {}
+        Some(version) => bail!("vault version {} is newer than this build reads", version),
+        None => bail!("no vault version"),
+    }
+    let secrets = document.get("secrets").and_then(Value::as_object).context("no secrets")?;
+    secrets
+        .iter()
+        .map(|(name, secret)| Ok((name.clone(), Zeroizing::new(secret.as_str().with_context(|| format!("{} is not a string", name))?.to_string()))))
+        .collect()
+}
+
+// Names are listed one per line, so they have no line breaks or other control characters
+fn check_name(name: &str) -> Result<()> {
+    ensure!(!name.is_empty(), "the name is empty");
+    ensure!(!name.chars().any(char::is_control), "the name has control characters");
+    Ok(())
+}
+
+fn missing(name: &str, vault: &Path) -> anyhow::Error {
+    anyhow!(io::Error::new(io::ErrorKind::NotFound, format!("{} is not in {}", name, vault.display())))
+}
+
+fn read_stdin() -> Result<Zeroizing<String>> {
+    let mut secret = Zeroizing::new(String::new());
+    stdio::reader(Path::new("-"))?.read_to_string(&mut secret).context("read stdin")?;
+    let len = secret.strip_suffix('\n').map_or(secret.len(), |s| s.strip_suffix('\r').unwrap_or(s).len());
+    secret.truncate(len);
+    Ok(secret)
+}
+
+// Asked twice until both entries match, like a new password
+fn prompt_secret() -> Result<Zeroizing<String>> {
+    loop {
+        let secret = Zeroizing::new(rpassword::prompt_password("Secret: ")?);
+        let confirm = Zeroizing::new(rpassword::prompt_password("Repeat secret: ")?);
+        if secret == confirm {
+            return Ok(secret);
+        }
+        eprintln!("Secrets do not match. Please try again.");
+    }
+}
@@ -0,0 +1,141 @@
+// `--watch`: after its first pass over a directory, a command keeps running and processes files
+// as they are added or changed, a drop folder. Events are collected per path and handed over once
+// a file has gone unchanged for the settle time, so half-copied files are not picked up
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+use walkdir::WalkDir;
+use crate::batch::{Selector, WalkArgs};
+
+/// Keeps processing a directory input as files appear
+#[derive(Args, Default)]
+pub struct WatchArgs {
+    /// After processing the directory, keep watching it and process files as they are added or
+    /// changed, until interrupted
+    #[arg(long)]
+    pub(crate) watch: bool,
+    /// With --watch, milliseconds a file must go unchanged before it is processed
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    pub(crate) settle: u64,
+}
+
+impl WatchArgs {
+    // Called before the first pass, so a bad input fails up front and files dropped in while the
+    // pass runs are not missed
+    pub(crate) fn start(&self, input: &Path, recursive: bool) -> Result<Option<Watching>> {
+        if !self.watch {
+            return Ok(None);
+        }
+        if !input.is_dir() {
+            bail!("--watch needs a directory input");
+        }
+        // Events name canonical paths; the command gets them back under `input` as it was given
+        let canonical = std::fs::canonicalize(input).with_context(|| format!("resolve {}", input.display()))?;
+        let (tx, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx).context("start watching")?;
+        let mode = if recursive { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+        watcher.watch(&canonical, mode).with_context(|| format!("watch {}", input.display()))?;
+        Ok(Some(Watching { _watcher: watcher, events, canonical, recursive, settle: Duration::from_millis(self.settle) }))
+    }
+}
+
+// A watch set up by `WatchArgs::start`, events queued until `follow` handles them
+pub(crate) struct Watching {
+    // Watches for as long as it is kept
+    _watcher: notify::RecommendedWatcher,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+    canonical: PathBuf,
+    recursive: bool,
+    settle: Duration,
+}
+
+// `first` is the result of the command's pass over the directory. Without a watch it is the
+// command's result; with one, a failed pass is logged and the directory watched regardless.
+// `keep` is the command's file filter, which must turn down its own outputs when they land under
+// `root`, and `on_change` gets the selected files that changed and those that are gone. The
+// outputs of changed files are stale, so from here on they are replaced whatever the overwrite
+// flags say
+pub(crate) fn follow(
+    watching: Option<Watching>,
+    first: Result<()>,
+    root: &Path,
+    walk: &WalkArgs,
+    keep: impl Fn(&Path) -> bool,
+    on_change: impl FnMut(&[PathBuf], &[PathBuf]) -> Result<()>,
+) -> Result<()> {
+    let Some(watching) = watching else { return first };
+    if let Err(e) = first {
+        error!("{:#}", e);
+    }
+    crate::stdio::set_clobber(crate::stdio::Clobber::Replace);
+    let selector = Selector::new(root, walk, watching.recursive)?;
+    watching.run(root, &selector, keep, on_change)
+}
+
+impl Watching {
+    fn run(
+        self,
+        root: &Path,
+        selector: &Selector,
+        keep: impl Fn(&Path) -> bool,
+        mut on_change: impl FnMut(&[PathBuf], &[PathBuf]) -> Result<()>,
+    ) -> Result<()> {
+        info!("Watching {} for new and changed files (Ctrl-C to stop)", root.display());
+
+        let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+        loop {
+            let wait = pending.values().min().map_or(Duration::from_secs(3600), |last| (*last + self.settle).saturating_duration_since(Instant::now()));
+            match self.events.recv_timeout(wait) {
+                Ok(Ok(event)) => {
+                    // Reads, including this command's own, and metadata changes are not new content
+                    if matches!(event.kind, EventKind::Access(_) | EventKind::Modify(notify::event::ModifyKind::Metadata(_))) {
+                        continue;
+                    }
+                    for path in event.paths {
+                        if let Ok(rel) = path.strip_prefix(&self.canonical) {
+                            pending.insert(root.join(rel), Instant::now());
+                        }
+                    }
+                }
+                Ok(Err(e)) => warn!("Watch: {}", e),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => bail!("stopped watching {}", root.display()),
+            }
+
+            let now = Instant::now();
+            let mut ready: Vec<PathBuf> = pending.iter().filter(|(_, last)| now.duration_since(**last) >= self.settle).map(|(path, _)| path.clone()).collect();
+            if ready.is_empty() {
+                continue;
+            }
+            ready.sort();
+            let mut changed = Vec::new();
+            let mut removed = Vec::new();
+            for path in ready {
+                pending.remove(&path);
+                if self.recursive && path.is_dir() {
+                    // A folder moved in whole: its files may predate the watch on it
+                    changed.extend(WalkDir::new(&path).sort_by_file_name().into_iter().flatten().map(|e| e.into_path()).filter(|p| selector.selects(p) && keep(p)));
+                } else if selector.selects(&path) {
+                    if keep(&path) {
+                        changed.push(path);
+                    }
+                } else if !path.exists() && selector.matches(&path) {
+                    removed.push(path);
+                }
+            }
+            changed.dedup();
+            if changed.is_empty() && removed.is_empty() {
+                continue;
+            }
+            debug!("{} changed and {} removed file(s) in {}", changed.len(), removed.len(), root.display());
+            if let Err(e) = on_change(&changed, &removed) {
+                error!("{:#}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,1093 @@
+use std::fs;
+use std::path::PathBuf;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+mod common;
+use common::{ok, pipe, run, scratch};
+
+#[test]
+fn logs_go_to_stderr_and_follow_verbosity() {
+    let dir = scratch("verbosity");
+    fs::create_dir_all(dir.join("docs")).unwrap();
+    fs::write(dir.join("docs/a.txt"), "abc").unwrap();
+    let docs = dir.join("docs");
+    let manifest = dir.join("docs.blake3");
+    let manifest = manifest.to_str().unwrap();
+
+    let out = ok(&["hash", "-d", docs.to_str().unwrap(), "-o", manifest]);
+    assert!(out.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Wrote manifest"));
+
+    let out = ok(&["-q", "hash", "-d", docs.to_str().unwrap(), "-o", manifest]);
+    assert!(out.stderr.is_empty(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    // Global flags also work after the subcommand; -v adds leveled debug lines
+    let out = ok(&["hash", "-d", docs.to_str().unwrap(), "-o", manifest, "-v"]);
+    let log = String::from_utf8_lossy(&out.stderr);
+    assert!(log.contains("DEBUG") && log.contains("docs/a.txt"), "{log}");
+
+    // Progress bars never reach a pipe, and can be turned off on a terminal
+    let out = ok(&["--no-progress", "hash", "-d", docs.to_str().unwrap(), "-o", manifest]);
+    assert_eq!(String::from_utf8_lossy(&out.stderr).trim(), format!("Wrote manifest: {}", manifest));
+
+    // Results stay on stdout whatever the verbosity
+    let out = ok(&["-q", "hash", docs.join("a.txt").to_str().unwrap()]);
+    assert!(String::from_utf8_lossy(&out.stdout).contains("a.txt"));
+}
+
+#[test]
+fn dash_reads_stdin_and_writes_stdout() {
+    let text = b"piped through every command\n".repeat(50);
+
+    let hashed = pipe(&["hash", "-", "-a", "sha256"], &text);
+    assert!(String::from_utf8_lossy(&hashed.stdout).ends_with("  -\n"));
+
+    // A stdin input defaults to stdout, and the status lines stay on stderr
+    let compressed = pipe(&["compress", "-", "-a", "zstd"], &text);
+    assert!(compressed.status.success(), "{}", String::from_utf8_lossy(&compressed.stderr));
+    assert!(compressed.stdout.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]));
+    assert!(String::from_utf8_lossy(&compressed.stderr).contains("<stdin> -> <stdout>"));
+    let restored = pipe(&["decompress", "-"], &compressed.stdout);
+    assert_eq!(restored.stdout, text);
+
+    let packed = pipe(&["format", "-", "-f", "msgpack"], br#"{"a":[1,2]}"#);
+    assert!(packed.status.success(), "{}", String::from_utf8_lossy(&packed.stderr));
+    let json = pipe(&["format", "-", "--from", "msgpack", "-f", "json", "--compact"], &packed.stdout);
+    assert_eq!(String::from_utf8_lossy(&json.stdout), r#"{"a":[1,2]}"#);
+
+    let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="8" height="4"><rect width="8" height="4"/></svg>"#;
+    let png = pipe(&["rasterize", "-"], svg);
+    assert!(png.stdout.starts_with(b"\x89PNG"), "{}", String::from_utf8_lossy(&png.stderr));
+    let scaled = pipe(&["image-scale", "-", "-p", "50"], &png.stdout);
+    let info = pipe(&["image-info", "-"], &scaled.stdout);
+    assert!(String::from_utf8_lossy(&info.stdout).contains("Dimensions:  4x2"));
+
+    // There is only one stdin to go around
+    let twice = pipe(&["format-diff", "-", "-"], b"{}");
+    assert!(!twice.status.success());
+    assert!(String::from_utf8_lossy(&twice.stderr).contains("only once"));
+}
+
+#[test]
+fn directory_walks_share_globs_depth_and_symlink_flags() {
+    let dir = scratch("walk");
+    let tree = dir.join("tree");
+    fs::create_dir_all(tree.join("sub/deep")).unwrap();
+    for file in ["a.txt", "b.log", "sub/c.txt", "sub/deep/d.txt"] {
+        fs::write(tree.join(file), file).unwrap();
+    }
+    fs::create_dir_all(dir.join("elsewhere")).unwrap();
+    fs::write(dir.join("elsewhere/e.txt"), "e").unwrap();
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(tree.join("a.txt"), tree.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(dir.join("elsewhere"), tree.join("linked")).unwrap();
+    }
+
+    let out = dir.join("out");
+    let args = ["compress", tree.to_str().unwrap(), "-r", "-o", out.to_str().unwrap()];
+    ok(&[&args[..], &["--include", "**.txt", "--max-depth", "2", "-t", "2"]].concat());
+    assert!(out.join("a.txt.zst").is_file() && out.join("sub/c.txt.zst").is_file());
+    assert!(!out.join("b.log.zst").exists() && !out.join("sub/deep").exists());
+    #[cfg(unix)]
+    assert!(out.join("link.txt.zst").is_file() && !out.join("linked").exists());
+
+    let manifest = dir.join("tree.blake3");
+    let hash = |extra: &[&str]| {
+        ok(&[&["hash", "-d", tree.to_str().unwrap(), "-o", manifest.to_str().unwrap()], extra].concat());
+        fs::read_to_string(&manifest).unwrap()
+    };
+    let listed = hash(&["--exclude", "*.log"]);
+    assert!(listed.contains("tree/sub/deep/d.txt") && !listed.contains("b.log"), "{listed}");
+    // Linked files are listed as they always were; linked directories only when followed
+    #[cfg(unix)]
+    {
+        assert!(listed.contains("tree/link.txt") && !listed.contains("e.txt"), "{listed}");
+        assert!(hash(&["--follow-symlinks"]).contains("tree/linked/e.txt"));
+    }
+}
+
+#[test]
+fn respect_gitignore_skips_what_git_would() {
+    let dir = scratch("gitignore");
+    let checkout = dir.join("checkout");
+    let tree = checkout.join("src");
+    for file in ["a.txt", "x.log", "keep.log", "target/t.txt", "build/b.txt", "sub/build/c.txt", "sub/secret.txt", "sub/ok.txt"] {
+        let path = tree.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, file).unwrap();
+    }
+    fs::create_dir_all(checkout.join(".git/info")).unwrap();
+    fs::write(checkout.join(".git/info/exclude"), "a.txt\n").unwrap();
+    // Rules above the input apply too, and deeper files and later lines win
+    fs::write(checkout.join(".gitignore"), "*.log\ntarget/\n").unwrap();
+    fs::write(tree.join(".gitignore"), "!keep.log\n/build\n").unwrap();
+    fs::write(tree.join("sub/.gitignore"), "secret.txt\n").unwrap();
+
+    let manifest = dir.join("src.blake3");
+    let hash = |extra: &[&str]| {
+        ok(&[&["hash", "-d", tree.to_str().unwrap(), "-o", manifest.to_str().unwrap()], extra].concat());
+        fs::read_to_string(&manifest).unwrap()
+    };
+    let listed = hash(&["--respect-gitignore"]);
+    for kept in ["keep.log", "sub/build/c.txt", "sub/ok.txt", ".gitignore"] {
+        assert!(listed.contains(&format!("src/{kept}")), "{kept} missing from {listed}");
+    }
+    for ignored in ["a.txt", "x.log", "target/t.txt", "src/build/b.txt", "secret.txt"] {
+        assert!(!listed.contains(ignored), "{ignored} in {listed}");
+    }
+    assert!(hash(&[]).contains("src/target/t.txt"));
+
+    // Inside the checkout its .git directory is never picked up
+    let out = dir.join("out");
+    ok(&["compress", checkout.to_str().unwrap(), "-r", "-o", out.to_str().unwrap(), "--respect-gitignore"]);
+    assert!(out.join("src/sub/ok.txt.zst").is_file() && out.join(".gitignore.zst").is_file());
+    assert!(!out.join(".git").exists() && !out.join("src/target").exists());
+}
+
+#[test]
+fn pipeline_streams_through_stages_and_reverses() {
+    let dir = scratch("pipeline");
+    let data = dir.join("data.txt");
+    fs::write(&data, "nightly rows\n".repeat(5000)).unwrap();
+    let key = dir.join("k");
+    ok(&["keygen", "-a", "x25519", "--for-crypt", key.to_str().unwrap()]);
+    let public = format!("{}.pub", key.display());
+    let identity = format!("{}.key", key.display());
+
+    let backup = dir.join("backup.jj");
+    let out = ok(&[
+        "pipeline", "-o", backup.to_str().unwrap(),
+        "compress", "--algorithm", "zstd", "!", "encrypt", "--recipient", &public, "!", "hash", "--algorithm", "blake3",
+        data.to_str().unwrap(),
+    ]);
+    // The digest of the last stage is the digest of the file written
+    let digest = String::from_utf8(out.stdout).unwrap();
+    let direct = String::from_utf8(ok(&["hash", backup.to_str().unwrap()]).stdout).unwrap();
+    assert_eq!(digest, direct);
+
+    let spec = dir.join("restore.yaml");
+    fs::write(&spec, format!("stages:\n  - [decrypt, --identity, '{}']\n  - decompress\n", identity)).unwrap();
+    let out = ok(&["pipeline", "--spec", spec.to_str().unwrap(), backup.to_str().unwrap()]);
+    assert_eq!(out.stdout, fs::read(&data).unwrap());
+
+    // The stage that failed is reported, not the ones starved by it
+    let out = pipe(&["pipeline", "decompress", "-a", "zstd", "!", "hash", "-"], b"not zstd");
+    let err = String::from_utf8_lossy(&out.stderr);
+    assert!(!out.status.success() && err.contains("stage 1 (decompress)"), "{err}");
+}
+
+#[test]
+fn watch_processes_files_dropped_in_later() {
+    let dir = scratch("watch");
+    let inbox = dir.join("inbox");
+    let outbox = dir.join("outbox");
+    fs::create_dir_all(&inbox).unwrap();
+    fs::write(inbox.join("early.txt"), "there before the watch").unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_jj_toolkit"))
+        .args(["compress", inbox.to_str().unwrap(), "-r", "-o", outbox.to_str().unwrap(), "--watch", "--settle", "100"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let wait_for = |path: PathBuf| {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(20);
+        while !path.is_file() && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        path.is_file()
+    };
+
+    let first = wait_for(outbox.join("early.txt.zst"));
+    fs::create_dir_all(inbox.join("sub")).unwrap();
+    fs::write(inbox.join("sub/late.txt"), "dropped in while watching").unwrap();
+    let later = wait_for(outbox.join("sub/late.txt.zst"));
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert!(first && later);
+
+    // Watching needs a directory to watch
+    let file = inbox.join("early.txt");
+    let out = pipe(&["compress", file.to_str().unwrap(), "--watch"], b"");
+    assert!(!out.status.success() && String::from_utf8_lossy(&out.stderr).contains("--watch needs a directory"));
+}
+
+#[test]
+fn archive_round_trips_tar_and_zip_and_refuses_traversal() {
+    let dir = scratch("archive");
+    let tree = dir.join("tree");
+    fs::create_dir_all(tree.join("bin")).unwrap();
+    fs::write(tree.join("notes.txt"), "kept\n".repeat(100)).unwrap();
+    fs::write(tree.join("bin/run.sh"), "#!/bin/sh\n").unwrap();
+    fs::write(tree.join("skip.log"), "left out").unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(tree.join("bin/run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    for name in ["tree.zip", "tree.tar.zst", "tree.tgz"] {
+        let archive = dir.join(name);
+        ok(&["archive", "create", archive.to_str().unwrap(), tree.to_str().unwrap(), "--exclude", "*.log"]);
+        let listed = String::from_utf8(ok(&["archive", "list", archive.to_str().unwrap()]).stdout).unwrap();
+        assert!(listed.contains("tree/bin/run.sh") && !listed.contains("skip.log"), "{listed}");
+
+        let out = dir.join(format!("out-{name}"));
+        ok(&["archive", "extract", archive.to_str().unwrap(), "-o", out.to_str().unwrap(), "--include", "tree/bin/*"]);
+        assert_eq!(fs::read(out.join("tree/bin/run.sh")).unwrap(), b"#!/bin/sh\n");
+        assert!(!out.join("tree/notes.txt").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(out.join("tree/bin/run.sh")).unwrap().permissions().mode() & 0o777, 0o755, "{name}");
+        }
+
+        // Existing files are kept unless asked
+        let again = pipe(&["archive", "extract", archive.to_str().unwrap(), "-o", out.to_str().unwrap()], b"");
+        assert!(!again.status.success() && String::from_utf8_lossy(&again.stderr).contains("--force"));
+        ok(&["archive", "extract", archive.to_str().unwrap(), "-o", out.to_str().unwrap(), "--overwrite"]);
+        assert_eq!(fs::read(out.join("tree/notes.txt")).unwrap(), fs::read(tree.join("notes.txt")).unwrap());
+    }
+
+    // An entry renamed to climb out of the output directory is refused
+    let evil = dir.join("AA");
+    fs::create_dir_all(&evil).unwrap();
+    fs::write(evil.join("evil.txt"), "escaped").unwrap();
+    let zip = dir.join("evil.zip");
+    ok(&["archive", "create", zip.to_str().unwrap(), evil.to_str().unwrap()]);
+    // Same length, so only the names in the local and central headers change
+    let mut bytes = fs::read(&zip).unwrap();
+    let mut renamed = 0;
+    for i in 0..bytes.len() - 2 {
+        if &bytes[i..i + 3] == b"AA/" {
+            bytes[i..i + 2].copy_from_slice(b"..");
+            renamed += 1;
+        }
+    }
+    assert_eq!(renamed, 2);
+    fs::write(&zip, bytes).unwrap();
+    let inside = dir.join("inside");
+    let out = pipe(&["archive", "extract", zip.to_str().unwrap(), "-o", inside.to_str().unwrap()], b"");
+    assert!(!out.status.success() && String::from_utf8_lossy(&out.stderr).contains("outside the output directory"));
+    assert!(!dir.join("evil.txt").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn archive_extract_drops_special_bits_and_replaces_links() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = scratch("archive_links");
+    let tree = dir.join("tree");
+    fs::create_dir_all(&tree).unwrap();
+    fs::write(tree.join("tool"), "#!/bin/sh\n").unwrap();
+    fs::set_permissions(tree.join("tool"), fs::Permissions::from_mode(0o6755)).unwrap();
+    let victim = dir.join("victim.txt");
+
+    for name in ["tree.zip", "tree.tgz"] {
+        let archive = dir.join(name);
+        ok(&["archive", "create", archive.to_str().unwrap(), tree.to_str().unwrap()]);
+        let out = dir.join(format!("out-{name}"));
+        ok(&["archive", "extract", archive.to_str().unwrap(), "-o", out.to_str().unwrap()]);
+        let tool = out.join("tree/tool");
+        assert_eq!(fs::metadata(&tool).unwrap().permissions().mode() & 0o7777, 0o755, "{name}");
+
+        // A link planted where a file is replaced is itself replaced, not written through
+        fs::write(&victim, "untouched").unwrap();
+        fs::remove_file(&tool).unwrap();
+        std::os::unix::fs::symlink(&victim, &tool).unwrap();
+        ok(&["archive", "extract", archive.to_str().unwrap(), "-o", out.to_str().unwrap(), "--force"]);
+        assert_eq!(fs::read(&victim).unwrap(), b"untouched", "{name}");
+        assert!(!fs::symlink_metadata(&tool).unwrap().is_symlink());
+        assert_eq!(fs::read(&tool).unwrap(), b"#!/bin/sh\n");
+    }
+
+    // A central directory said to be larger than the file is refused before it is read
+    let zip = dir.join("tree.zip");
+    let mut bytes = fs::read(&zip).unwrap();
+    let end = bytes.len() - 22;
+    bytes[end + 12..end + 16].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+    fs::write(&zip, bytes).unwrap();
+    let out = pipe(&["archive", "list", zip.to_str().unwrap()], b"");
+    assert!(!out.status.success() && String::from_utf8_lossy(&out.stderr).contains("truncated central directory"));
+}
+
+#[test]
+fn corrupt_zip_entries_leave_nothing_behind() {
+    let dir = scratch("archive_corrupt");
+    let tree = dir.join("tree");
+    fs::create_dir_all(&tree).unwrap();
+    fs::write(tree.join("data.txt"), "0123456789".repeat(1000)).unwrap();
+    let zip = dir.join("tree.zip");
+    ok(&["archive", "create", zip.to_str().unwrap(), tree.to_str().unwrap()]);
+    let original = fs::read(&zip).unwrap();
+    let central = (0..original.len() - 4).find(|&i| original[i..i + 4] == *b"PK\x01\x02").unwrap();
+
+    // An entry that inflates to more than its declared size, as a zip bomb does, and one whose
+    // CRC does not match
+    for (field, value, message) in [(24, 100u32, "size mismatch"), (16, 0xDEAD_BEEF, "CRC mismatch")] {
+        let mut bytes = original.clone();
+        bytes[central + field..central + field + 4].copy_from_slice(&value.to_le_bytes());
+        fs::write(&zip, bytes).unwrap();
+        let out_dir = dir.join(format!("out-{field}"));
+        let out = pipe(&["archive", "extract", zip.to_str().unwrap(), "-o", out_dir.to_str().unwrap()], b"");
+        assert!(!out.status.success() && String::from_utf8_lossy(&out.stderr).contains(message), "{}", String::from_utf8_lossy(&out.stderr));
+        let left: Vec<_> = fs::read_dir(out_dir.join("tree")).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert!(left.is_empty(), "{left:?}");
+    }
+}
+
+#[test]
+fn passgen_draws_from_the_requested_classes_and_lists() {
+    let out = ok(&["passgen", "-l", "24", "-c", "digits,upper", "-n", "5"]);
+    let lines: Vec<String> = String::from_utf8(out.stdout).unwrap().lines().map(String::from).collect();
+    assert_eq!(lines.len(), 5);
+    for line in &lines {
+        assert_eq!(line.len(), 24);
+        assert!(line.chars().all(|c| c.is_ascii_digit() || c.is_ascii_uppercase()), "{line}");
+        assert!(line.chars().any(|c| c.is_ascii_digit()) && line.chars().any(|c| c.is_ascii_uppercase()));
+    }
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Entropy:"));
+
+    let phrase = String::from_utf8(ok(&["passgen", "-w", "6", "--separator", "."]).stdout).unwrap();
+    assert_eq!(phrase.trim().split('.').count(), 6);
+
+    // Diceware lines keep only their word
+    let dir = scratch("passgen");
+    let list = dir.join("dice.txt");
+    fs::write(&list, "11111\tapple\n11112\tbanana\n11113\tcherry\n").unwrap();
+    let out = ok(&["passgen", "-w", "4", "--wordlist", list.to_str().unwrap()]);
+    let phrase = String::from_utf8(out.stdout).unwrap();
+    assert!(phrase.trim().split('-').all(|w| ["apple", "banana", "cherry"].contains(&w)), "{phrase}");
+    assert!(String::from_utf8_lossy(&out.stderr).contains("list of 3"));
+}
+
+#[test]
+fn qr_round_trips_text_and_bytes_through_images() {
+    let dir = scratch("qr");
+    let png = dir.join("code.png");
+    let text = "otpauth://totp/jj?secret=JBSWY3DPEHPK3PXP&issuer=jj_toolkit";
+    ok(&["qr", "encode", text, "-o", png.to_str().unwrap(), "-e", "q"]);
+    let out = ok(&["qr", "decode", png.to_str().unwrap()]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), format!("{text}\n"));
+
+    // Rotated, then resampled to a fractional module size and saved lossy
+    let turned = dir.join("turned.png");
+    let small = dir.join("small.jpg");
+    ok(&["image-transform", png.to_str().unwrap(), "--rotate", "90", "-o", turned.to_str().unwrap()]);
+    ok(&["image-scale", turned.to_str().unwrap(), "-p", "45", "-o", small.to_str().unwrap()]);
+    assert_eq!(String::from_utf8(ok(&["qr", "decode", small.to_str().unwrap()]).stdout).unwrap(), format!("{text}\n"));
+
+    // Binary payloads come back byte for byte
+    let key = dir.join("key.bin");
+    let bytes: Vec<u8> = (0..=255u8).rev().collect();
+    fs::write(&key, &bytes).unwrap();
+    let back = dir.join("back.bin");
+    ok(&["qr", "encode", "-i", key.to_str().unwrap(), "-o", png.to_str().unwrap(), "-s", "3"]);
+    ok(&["qr", "decode", png.to_str().unwrap(), "-o", back.to_str().unwrap()]);
+    assert_eq!(fs::read(&back).unwrap(), bytes);
+
+    let svg = dir.join("code.svg");
+    ok(&["qr", "encode", "12345", "-o", svg.to_str().unwrap()]);
+    assert!(fs::read_to_string(&svg).unwrap().starts_with("<svg"));
+    let terminal = String::from_utf8(ok(&["qr", "encode", "12345"]).stdout).unwrap();
+    // 21 modules and a border of 4 on either side, two rows per line
+    assert_eq!(terminal.lines().count(), 15);
+}
+
+#[test]
+fn split_and_join_verify_the_parts() {
+    let dir = scratch("split");
+    let input = dir.join("data.bin");
+    let bytes: Vec<u8> = (0..250_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    fs::write(&input, &bytes).unwrap();
+    let parts = dir.join("parts");
+    ok(&["split", input.to_str().unwrap(), "-s", "100K", "-o", parts.to_str().unwrap()]);
+    let mut names: Vec<String> = fs::read_dir(&parts).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+    names.sort();
+    assert_eq!(names, ["data.bin.001", "data.bin.002", "data.bin.003", "data.bin.blake3"]);
+    assert_eq!(fs::metadata(parts.join("data.bin.001")).unwrap().len(), 102_400);
+
+    let manifest = parts.join("data.bin.blake3");
+    ok(&["join", manifest.to_str().unwrap()]);
+    assert_eq!(fs::read(parts.join("data.bin")).unwrap(), bytes);
+
+    // A damaged part is named and nothing is written
+    let mut part = fs::read(parts.join("data.bin.002")).unwrap();
+    part[10] ^= 1;
+    fs::write(parts.join("data.bin.002"), part).unwrap();
+    let joined = dir.join("joined.bin");
+    let out = pipe(&["join", manifest.to_str().unwrap(), "-o", joined.to_str().unwrap()], b"");
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("data.bin.002"));
+    assert!(!joined.exists());
+
+    // Parts are refused where they exist, and with --force replace a link instead of writing
+    // through it
+    let again = pipe(&["split", input.to_str().unwrap(), "-s", "100K", "-o", parts.to_str().unwrap()], b"");
+    assert!(!again.status.success());
+    #[cfg(unix)]
+    {
+        let victim = dir.join("victim.txt");
+        fs::write(&victim, "untouched").unwrap();
+        fs::remove_file(parts.join("data.bin.001")).unwrap();
+        std::os::unix::fs::symlink(&victim, parts.join("data.bin.001")).unwrap();
+        ok(&["split", input.to_str().unwrap(), "-s", "100K", "-o", parts.to_str().unwrap(), "--force"]);
+        assert_eq!(fs::read(&victim).unwrap(), b"untouched");
+        assert!(!fs::symlink_metadata(parts.join("data.bin.001")).unwrap().is_symlink());
+        assert_eq!(fs::read(parts.join("data.bin.001")).unwrap(), &bytes[..102_400]);
+    }
+}
+
+#[test]
+fn shred_overwrites_and_removes_only_what_it_is_given() {
+    let dir = scratch("shred");
+    let file = dir.join("secret.txt");
+    fs::write(&file, "attack at dawn").unwrap();
+    let target = dir.join("target.txt");
+    fs::write(&target, "still here").unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&target, dir.join("link")).unwrap();
+
+    let kept = dir.join("kept.txt");
+    fs::write(&kept, "attack at dawn").unwrap();
+    ok(&["shred", "--keep", kept.to_str().unwrap()]);
+    let overwritten = fs::read(&kept).unwrap();
+    assert_eq!(overwritten.len(), 14);
+    assert_ne!(overwritten, b"attack at dawn");
+
+    ok(&["shred", "-z", file.to_str().unwrap()]);
+    assert!(!file.exists());
+    #[cfg(unix)]
+    {
+        ok(&["shred", dir.join("link").to_str().unwrap()]);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "still here");
+    }
+
+    // Directories need --recursive, and --yes when there is no terminal to ask on
+    let tree = dir.join("tree");
+    fs::create_dir_all(tree.join("sub")).unwrap();
+    fs::write(tree.join("sub/a"), "a").unwrap();
+    assert!(!pipe(&["shred", tree.to_str().unwrap()], b"").status.success());
+    assert!(!pipe(&["shred", "-r", tree.to_str().unwrap()], b"y\n").status.success());
+    assert!(tree.join("sub/a").exists());
+    ok(&["shred", "-r", "--yes", tree.to_str().unwrap()]);
+    assert!(!tree.exists());
+}
+
+#[test]
+fn totp_matches_the_rfc_vectors_and_reads_saved_secrets() {
+    // RFC 6238 appendix B, SHA-1 with the ASCII secret "12345678901234567890"
+    let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+    for (at, code) in [("59", "94287082"), ("1111111109", "07081804"), ("20000000000", "65353130")] {
+        let out = ok(&["totp", "-s", secret, "-d", "8", "--at", at]);
+        assert_eq!(String::from_utf8(out.stdout).unwrap(), format!("{code}\n"));
+    }
+    let uri = format!("otpauth://totp/ACME%20Co:jj?secret={}&issuer=ACME%20Co&digits=8", secret.to_lowercase());
+    let out = ok(&["totp", "-u", &uri, "--at", "59"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "94287082\n");
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Valid for 1 more second"));
+
+    // Saved to a recipient, settings included
+    let dir = scratch("totp");
+    let id = dir.join("id");
+    ok(&["keygen", "-a", "x25519", "--for-crypt", id.to_str().unwrap()]);
+    let saved = dir.join("acme.jj");
+    let recipient = dir.join("id.pub");
+    ok(&["totp", "-u", &uri, "--save", saved.to_str().unwrap(), "--recipient", recipient.to_str().unwrap()]);
+    assert!(!fs::read(&saved).unwrap().windows(secret.len()).any(|w| w == secret.as_bytes()));
+    let out = ok(&["totp", "-f", saved.to_str().unwrap(), "-i", dir.join("id.key").to_str().unwrap(), "--at", "1111111109"]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "07081804\n");
+}
+
+#[test]
+fn vault_stores_lists_and_removes_secrets() {
+    let dir = scratch("vault");
+    let id = dir.join("id");
+    ok(&["keygen", "-a", "x25519", "--for-crypt", id.to_str().unwrap()]);
+    let key = dir.join("id.key");
+    let vault = dir.join("secrets.jj");
+    let v = vault.to_str().unwrap();
+    let i = key.to_str().unwrap();
+    ok(&["vault", "init", v, "--recipient", dir.join("id.pub").to_str().unwrap()]);
+
+    let added = pipe(&["vault", "add", v, "mail/work", "-i", i, "--stdin"], b"hunter2 and more\n");
+    assert!(added.status.success(), "{}", String::from_utf8_lossy(&added.stderr));
+    ok(&["vault", "add", v, "api", "-i", i, "--generate", "32"]);
+    assert!(!fs::read(&vault).unwrap().windows(7).any(|w| w == b"hunter2"));
+
+    let out = ok(&["vault", "get", v, "mail/work", "-i", i]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "hunter2 and more\n");
+    assert_eq!(ok(&["vault", "get", v, "api", "-i", i]).stdout.len(), 33);
+    let out = ok(&["vault", "list", v, "-i", i]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "api\nmail/work\n");
+
+    // Names are not overwritten by accident, and a recipient vault needs its identity
+    assert!(!pipe(&["vault", "add", v, "api", "-i", i, "--stdin"], b"x").status.success());
+    let out = pipe(&["vault", "list", v], b"");
+    assert!(String::from_utf8_lossy(&out.stderr).contains("--identity"));
+
+    ok(&["vault", "rm", v, "api", "-i", i]);
+    let out = ok(&["--json", "vault", "list", v, "-i", i]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "[\"mail/work\"]\n");
+    assert_eq!(pipe(&["vault", "get", v, "api", "-i", i], b"").status.code(), Some(66));
+
+    // Changes made at the same time wait for each other rather than each starting from the same vault
+    let adds: Vec<_> = (0..6)
+        .map(|n| std::thread::spawn({
+            let (v, i) = (v.to_string(), i.to_string());
+            move || run(&["vault", "add", &v, &format!("n{n}"), "-i", &i, "--generate", "16"])
+        }))
+        .collect();
+    for add in adds {
+        let out = add.join().unwrap();
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    }
+    let out = ok(&["vault", "list", v, "-i", i]);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "mail/work\nn0\nn1\nn2\nn3\nn4\nn5\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn copy_puts_results_on_the_clipboard_and_clears_it() {
+    use std::os::unix::fs::PermissionsExt;
+
+    // An xclip that keeps the clipboard in a file
+    let dir = scratch("clipboard");
+    let board = dir.join("board");
+    let xclip = dir.join("xclip");
+    fs::write(&xclip, format!("#!/bin/sh\ncase \"$*\" in *-o*) cat '{0}' ;; *) cat > '{0}' ;; esac\n", board.display())).unwrap();
+    fs::set_permissions(&xclip, fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!("{}:{}", dir.display(), std::env::var("PATH").unwrap_or_default());
+    let copy = |args: &[&str]| {
+        let out = Command::new(env!("CARGO_BIN_EXE_jj_toolkit")).args(args).env("PATH", &path).env("DISPLAY", ":0").env_remove("WAYLAND_DISPLAY").output().unwrap();
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        assert!(out.stdout.is_empty());
+        fs::read_to_string(&board).unwrap()
+    };
+
+    let data = dir.join("data.txt");
+    fs::write(&data, "rows").unwrap();
+    let line = String::from_utf8(ok(&["hash", data.to_str().unwrap()]).stdout).unwrap();
+    assert_eq!(copy(&["hash", data.to_str().unwrap(), "--copy"]), line.split_whitespace().next().unwrap());
+    assert_eq!(copy(&["totp", "-s", "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", "--at", "59", "--copy"]), "287082");
+    assert_eq!(copy(&["passgen", "-l", "12", "-n", "2", "--copy"]).lines().map(str::len).collect::<Vec<_>>(), [12, 12]);
+
+    // Cleared once the time is up, unless something else was copied meanwhile
+    copy(&["passgen", "--copy", "--clear-after", "1"]);
+    copy(&["passgen", "--copy", "--clear-after", "1"]);
+    // Nothing about the secret is on the timer's command line, where other users could read it
+    #[cfg(target_os = "linux")]
+    for entry in fs::read_dir("/proc").unwrap().flatten() {
+        let Ok(cmdline) = fs::read(entry.path().join("cmdline")) else { continue };
+        let args: Vec<&[u8]> = cmdline.split(|&b| b == 0).filter(|a| !a.is_empty()).collect();
+        if args.iter().any(|a| *a == b"clipboard-clear") {
+            assert_eq!(&args[1..], [&b"clipboard-clear"[..], b"--after", b"1"]);
+        }
+    }
+    std::thread::sleep(std::time::Duration::from_secs(3));
+    assert_eq!(fs::read_to_string(&board).unwrap(), "");
+    copy(&["passgen", "--copy", "--clear-after", "1"]);
+    fs::write(&board, "copied since").unwrap();
+    std::thread::sleep(std::time::Duration::from_secs(3));
+    assert_eq!(fs::read_to_string(&board).unwrap(), "copied since");
+
+    assert!(!pipe(&["hash", "-d", dir.to_str().unwrap(), "--copy"], b"").status.success());
+}
+
+#[test]
+fn meta_reads_exif_and_id3_and_strips_them() {
+    let dir = scratch("meta");
+    let png = dir.join("code.png");
+    let jpg = dir.join("photo.jpg");
+    ok(&["qr", "encode", "meta", "-o", png.to_str().unwrap()]);
+    ok(&["image-convert", png.to_str().unwrap(), "-f", "jpeg", "-o", jpg.to_str().unwrap()]);
+
+    // Big-endian EXIF: IFD0 with a make and orientation 6, pointing to a GPS IFD at 50°30'N 4°W
+    let mut tiff = b"MM\0*\0\0\0\x08\0\x03".to_vec();
+    tiff.extend_from_slice(b"\x01\x0f\0\x02\0\0\0\x04Sun\0");
+    tiff.extend_from_slice(b"\x01\x12\0\x03\0\0\0\x01\0\x06\0\0");
+    tiff.extend_from_slice(b"\x88\x25\0\x04\0\0\0\x01\0\0\0\x32\0\0\0\0");
+    tiff.extend_from_slice(b"\0\x03\0\x02\0\x05\0\0\0\x03\0\0\0\x5c\0\x03\0\x02\0\0\0\x02W\0\0\0");
+    tiff.extend_from_slice(b"\0\x04\0\x05\0\0\0\x03\0\0\0\x74\0\0\0\0");
+    for (n, d) in [(50u32, 1u32), (30, 1), (0, 1), (4, 1), (0, 1), (0, 1)] {
+        tiff.extend_from_slice(&n.to_be_bytes());
+        tiff.extend_from_slice(&d.to_be_bytes());
+    }
+    let bytes = fs::read(&jpg).unwrap();
+    let mut photo = bytes[..2].to_vec();
+    photo.extend_from_slice(&[0xFF, 0xE1]);
+    photo.extend_from_slice(&(tiff.len() as u16 + 8).to_be_bytes());
+    photo.extend_from_slice(b"Exif\0\0");
+    photo.extend_from_slice(&tiff);
+    photo.extend_from_slice(&bytes[2..]);
+    fs::write(&jpg, &photo).unwrap();
+
+    let out = ok(&["meta", jpg.to_str().unwrap(), "--json"]);
+    let json: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(json["file"]["Size"], photo.len());
+    assert_eq!(json["image"]["Format"], "JPEG");
+    assert_eq!(json["exif"]["Make"], "Sun");
+    assert_eq!(json["exif"]["Orientation"], 6);
+    assert_eq!(json["gps"]["Position"], "50.500000, -4.000000");
+
+    // Stripped in place, the orientation alone survives
+    ok(&["meta", jpg.to_str().unwrap(), "--strip"]);
+    let json: serde_json::Value = serde_json::from_slice(&ok(&["meta", jpg.to_str().unwrap(), "--json"]).stdout).unwrap();
+    assert_eq!(json["exif"], serde_json::json!({ "Orientation": 6 }));
+    assert!(json.get("gps").is_none());
+    ok(&["image-info", jpg.to_str().unwrap()]);
+
+    // ID3v2.3 title frame and an ID3v1 tail around the audio
+    let mp3 = dir.join("song.mp3");
+    let audio = [0xFF, 0xFB, 0x90, 0x00].repeat(64);
+    let mut song = b"ID3\x03\0\0\0\0\0\x10TIT2\0\0\0\x06\0\0\0Songs".to_vec();
+    song.extend_from_slice(&audio);
+    let mut v1 = b"TAG".to_vec();
+    v1.extend_from_slice(&[b"Old".as_slice(), &[0; 27]].concat());
+    v1.resize(128, 0);
+    v1[127] = 255;
+    song.extend_from_slice(&v1);
+    fs::write(&mp3, &song).unwrap();
+    let text = String::from_utf8(ok(&["meta", mp3.to_str().unwrap()]).stdout).unwrap();
+    assert!(text.contains("Title:   Songs") && text.contains("Title: Old"), "{text}");
+    let stripped = dir.join("stripped.mp3");
+    ok(&["meta", mp3.to_str().unwrap(), "--strip", "-o", stripped.to_str().unwrap()]);
+    assert_eq!(fs::read(&stripped).unwrap(), audio);
+    assert!(!pipe(&["meta", mp3.to_str().unwrap(), "--strip", "-o", stripped.to_str().unwrap()], b"").status.success());
+
+    let out = pipe(&["meta", png.to_str().unwrap(), "--strip", "-o", "-"], b"");
+    assert!(out.status.success() && out.stdout.starts_with(b"\x89PNG"));
+    assert!(!pipe(&["meta", "--strip", dir.join("code.svg").to_str().unwrap()], b"").status.success());
+}
+
+#[test]
+fn serve_sends_files_with_their_digests() {
+    use base64::Engine;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpStream;
+
+    let dir = scratch("serve");
+    let text: String = (0..4000).map(|i| format!("line {i}\n")).collect();
+    fs::write(dir.join("notes.txt"), &text).unwrap();
+    fs::write(dir.join(".secret"), "hidden").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_jj_toolkit"))
+        .args(["serve", dir.to_str().unwrap(), "-p", "0", "-c", "gzip"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut log = BufReader::new(child.stderr.take().unwrap());
+    let mut line = String::new();
+    log.read_line(&mut line).unwrap();
+    let address = line.trim().rsplit("http://").next().unwrap().trim_end_matches('/').to_string();
+
+    // Status line and headers, then the body
+    let get = |path: &str, extra: &str| -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(&address).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: test\r\n{extra}\r\n").unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        (String::from_utf8(response[..split].to_vec()).unwrap(), response[split + 4..].to_vec())
+    };
+    let digest_header = |head: &str| head.lines().find_map(|l| l.strip_prefix("Content-Digest: ")).unwrap().to_string();
+
+    let (head, body) = get("/notes.txt", "");
+    assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+    assert_eq!(body, text.as_bytes());
+    let hex = String::from_utf8(ok(&["hash", "-a", "sha256", dir.join("notes.txt").to_str().unwrap()]).stdout).unwrap();
+    let digest = hex::decode(hex.split_whitespace().next().unwrap()).unwrap();
+    assert_eq!(digest_header(&head), format!("sha-256=:{}:", base64::engine::general_purpose::STANDARD.encode(&digest)));
+
+    // Compressed on request, with the digest of what was sent
+    let (head, body) = get("/notes.txt", "Accept-Encoding: gzip\r\n");
+    assert!(head.contains("Content-Encoding: gzip") && body.len() < text.len(), "{head}");
+    let mut unpacked = String::new();
+    flate2::read::GzDecoder::new(&body[..]).read_to_string(&mut unpacked).unwrap();
+    assert_eq!(unpacked, text);
+    assert!(digest_header(&head).starts_with("sha-256=:"));
+
+    let (head, body) = get("/notes.txt", "Range: bytes=5-10\r\n");
+    assert!(head.starts_with("HTTP/1.1 206") && head.contains("Content-Range: bytes 5-10/"), "{head}");
+    assert_eq!(body, &text.as_bytes()[5..=10]);
+
+    let (head, body) = get("/", "");
+    assert!(String::from_utf8(body).unwrap().contains("href=\"notes.txt\""), "{head}");
+    assert!(get("/.secret", "").0.starts_with("HTTP/1.1 404"));
+    assert!(get("/../", "").0.starts_with("HTTP/1.1 404"));
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[test]
+fn bench_reports_hash_and_compression_results() {
+    let dir = scratch("bench");
+    let sample = dir.join("sample.txt");
+    fs::write(&sample, "the quick brown fox jumps over the lazy dog\n".repeat(2000)).unwrap();
+
+    let out = ok(&["bench", "--only", "hash,compression", "-i", sample.to_str().unwrap(), "-s", "32K", "-c", "1,3", "--json"]);
+    let report: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(report["data"]["bytes"], 32 * 1024);
+    assert_eq!(report["hash"].as_array().unwrap().len(), 6);
+    // Two levels for each of the five levelled algorithms, one run each for lz4 and snappy
+    let compression = report["compression"].as_array().unwrap();
+    assert_eq!(compression.len(), 12);
+    assert!(compression.iter().all(|r| r["ratio"].as_f64().unwrap() > 1.0));
+    assert!(report.get("argon2").is_none() && report.get("image_resize").is_none());
+}
+
+#[test]
+fn existing_outputs_are_refused_skipped_or_replaced() {
+    let dir = scratch("clobber");
+    let input = dir.join("notes.txt");
+    fs::write(&input, "fresh notes\n".repeat(100)).unwrap();
+    let output = dir.join("notes.txt.zst");
+    fs::write(&output, "older").unwrap();
+    let compress = |flags: &[&str]| pipe(&[&["compress", input.to_str().unwrap(), "-o", output.to_str().unwrap()], flags].concat(), b"");
+
+    let refused = compress(&["--no-clobber"]);
+    assert!(!refused.status.success() && String::from_utf8_lossy(&refused.stderr).contains("Output exists"));
+    assert!(compress(&["--skip-existing"]).status.success());
+    assert_eq!(fs::read(&output).unwrap(), b"older");
+
+    // The last of the three flags wins
+    assert!(!compress(&["--force", "--no-clobber"]).status.success());
+    assert!(compress(&["--no-clobber", "--force"]).status.success());
+    assert_eq!(pipe(&["decompress", output.to_str().unwrap(), "-o", "-"], b"").stdout, fs::read(&input).unwrap());
+    // --overwrite is the same flag as --force
+    assert!(compress(&["--overwrite"]).status.success());
+
+    // Without a flag each command keeps its own default: compress replaces, split refuses
+    fs::write(&output, "older").unwrap();
+    assert!(compress(&[]).status.success());
+    assert_ne!(fs::read(&output).unwrap(), b"older");
+    let parts = dir.join("parts");
+    let split = || pipe(&["split", input.to_str().unwrap(), "-s", "512", "-o", parts.to_str().unwrap()], b"");
+    assert!(split().status.success());
+    let again = split();
+    assert!(!again.status.success() && String::from_utf8_lossy(&again.stderr).contains("Output exists"));
+}
+
+#[test]
+fn failed_writes_leave_existing_outputs_untouched() {
+    let dir = scratch("atomic");
+    let input = dir.join("log.txt");
+    fs::write(&input, "line of the log\n".repeat(5000)).unwrap();
+    let packed = dir.join("log.txt.zst");
+    ok(&["compress", input.to_str().unwrap(), "-o", packed.to_str().unwrap()]);
+    let mut truncated = fs::read(&packed).unwrap();
+    truncated.truncate(truncated.len() / 2);
+    fs::write(&packed, truncated).unwrap();
+
+    let output = dir.join("restored.txt");
+    fs::write(&output, "previous").unwrap();
+    let out = pipe(&["decompress", packed.to_str().unwrap(), "-o", output.to_str().unwrap(), "--force"], b"");
+    assert!(!out.status.success());
+    assert_eq!(fs::read(&output).unwrap(), b"previous");
+    let names: Vec<String> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+    assert!(names.iter().all(|n| !n.starts_with(".jj-tmp")), "{:?}", names);
+}
+
+#[test]
+fn run_follows_job_dependencies_and_reports_each_job() {
+    let dir = scratch("jobs");
+    fs::create_dir_all(dir.join("logs")).unwrap();
+    for name in ["a.log", "b.log"] {
+        fs::write(dir.join("logs").join(name), "request served\n".repeat(200)).unwrap();
+    }
+    let file = dir.join("jobs.yaml");
+    fs::write(
+        &file,
+        "jobs:
+  - name: digest
+    command: hash
+    inputs: packed
+    options: { directory: true, output: packed.blake3 }
+    needs: pack
+  - name: pack
+    command: compress
+    inputs: [logs]
+    options: { recursive: true, algorithm: zstd, output: packed }
+  - name: broken
+    command: decompress
+    inputs: missing.zst
+  - name: after-broken
+    command: hash
+    inputs: logs/a.log
+    needs: [broken]
+",
+    )
+    .unwrap();
+
+    // Paths are relative to the job file, and a job starts only after the jobs it needs
+    let plan = pipe(&["run", file.to_str().unwrap(), "--dry-run", "--only", "digest"], b"");
+    assert_eq!(
+        String::from_utf8_lossy(&plan.stdout),
+        "pack: compress logs --algorithm=zstd --output=packed --recursive\ndigest: hash packed --directory --output=packed.blake3\n"
+    );
+    let report = dir.join("report.json");
+    let out = pipe(&["run", file.to_str().unwrap(), "--report", report.to_str().unwrap()], b"");
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("1 of 4 jobs failed"));
+    assert!(dir.join("packed/a.log.zst").exists() && dir.join("packed.blake3").exists());
+
+    let report: serde_json::Value = serde_json::from_slice(&fs::read(&report).unwrap()).unwrap();
+    let status: Vec<(&str, &str)> = report["jobs"].as_array().unwrap().iter().map(|j| (j["name"].as_str().unwrap(), j["status"].as_str().unwrap())).collect();
+    assert_eq!(status, [("pack", "ok"), ("digest", "ok"), ("broken", "failed"), ("after-broken", "skipped")]);
+    assert!(report["jobs"][2]["error"].as_str().unwrap().contains("missing.zst"));
+
+    fs::write(dir.join("cycle.yaml"), "jobs:\n  - { name: a, command: hash, needs: b }\n  - { name: b, command: hash, needs: a }\n").unwrap();
+    let cycle = pipe(&["run", dir.join("cycle.yaml").to_str().unwrap()], b"");
+    assert!(String::from_utf8_lossy(&cycle.stderr).contains("cycle: a -> b -> a"));
+}
+
+#[test]
+fn max_memory_refuses_whole_inputs_above_it() {
+    let dir = scratch("max_memory");
+    let rows: Vec<String> = (0..5000).map(|i| format!("{{\"id\":{},\"name\":\"row {}\"}}", i, i)).collect();
+    let json = dir.join("rows.json");
+    fs::write(&json, format!("{{\"rows\":[{}]}}", rows.join(","))).unwrap();
+
+    let refused = pipe(&["format", json.to_str().unwrap(), "-f", "bson", "--max-memory", "64K"], b"");
+    assert!(!refused.status.success());
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("above --max-memory 64.0 KiB"));
+    assert!(!dir.join("rows.bson").exists());
+    ok(&["format", json.to_str().unwrap(), "-f", "bson", "--max-memory", "16M"]);
+
+    // Stdin has no size to check up front, so reading it stops at the limit. Kept below a pipe
+    // buffer, so the test can write all of it before the command exits
+    let stdin = format!("[{}]", rows[..1000].join(","));
+    let streamed = pipe(&["format", "-", "--from", "json", "-f", "json", "--max-memory", "64K"], stdin.as_bytes());
+    assert!(String::from_utf8_lossy(&streamed.stderr).contains("needs more memory than --max-memory"));
+
+    let key = dir.join("k");
+    ok(&["keygen", key.to_str().unwrap(), "--for-crypt", "--algorithm", "x25519"]);
+    let recipient = dir.join("k.pub");
+    let encrypted = pipe(&["encrypt", json.to_str().unwrap(), "--recipient", recipient.to_str().unwrap(), "--max-memory", "64K"], b"");
+    assert!(String::from_utf8_lossy(&encrypted.stderr).contains("encrypting"));
+    assert!(!dir.join("rows.jj").exists());
+}
+
+#[test]
+fn preserve_carries_input_attributes_to_outputs() {
+    let dir = scratch("preserve");
+    let input = dir.join("notes.txt");
+    fs::write(&input, "kept as it was\n").unwrap();
+    let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+    fs::File::options().write(true).open(&input).unwrap().set_times(fs::FileTimes::new().set_modified(old)).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&input, fs::Permissions::from_mode(0o640)).unwrap();
+    }
+
+    let plain = dir.join("plain.zst");
+    ok(&["compress", input.to_str().unwrap(), "-o", plain.to_str().unwrap()]);
+    assert_ne!(fs::metadata(&plain).unwrap().modified().unwrap(), old);
+
+    // Bare --preserve is mtime and perms
+    let kept = dir.join("kept.zst");
+    ok(&["compress", input.to_str().unwrap(), "-o", kept.to_str().unwrap(), "--preserve"]);
+    assert_eq!(fs::metadata(&kept).unwrap().modified().unwrap(), old);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&kept).unwrap().permissions().mode() & 0o777, 0o640);
+    }
+
+    let restored = dir.join("restored.txt");
+    ok(&["decompress", kept.to_str().unwrap(), "-o", restored.to_str().unwrap(), "--preserve=mtime"]);
+    assert_eq!(fs::metadata(&restored).unwrap().modified().unwrap(), old);
+}
+
+#[test]
+fn json_reports_failures_with_their_kind_and_exit_code() {
+    let dir = scratch("json_errors");
+    let missing = dir.join("missing.txt");
+    let failure = |out: &Output| -> serde_json::Value { serde_json::from_str(String::from_utf8_lossy(&out.stderr).lines().last().unwrap()).unwrap() };
+
+    let out = pipe(&["--json", "hash", missing.to_str().unwrap()], b"");
+    assert_eq!(out.status.code(), Some(66));
+    let e = failure(&out);
+    assert_eq!(e["kind"], "not_found");
+    assert_eq!(e["path"], missing.to_str().unwrap());
+    assert_eq!(e["exit_code"], 66);
+    assert!(e["causes"][0].as_str().unwrap().contains("No such file"), "{e}");
+
+    // Same exit code without --json, as text
+    let out = pipe(&["hash", missing.to_str().unwrap()], b"");
+    assert_eq!(out.status.code(), Some(66));
+    assert!(String::from_utf8_lossy(&out.stderr).starts_with("Error: open"));
+
+    let input = dir.join("notes.txt");
+    fs::write(&input, "notes\n").unwrap();
+    let output = dir.join("notes.txt.zst");
+    ok(&["compress", input.to_str().unwrap(), "-o", output.to_str().unwrap()]);
+    let out = pipe(&["compress", input.to_str().unwrap(), "-o", output.to_str().unwrap(), "--json", "--no-clobber"], b"");
+    assert_eq!(out.status.code(), Some(73));
+    assert_eq!(failure(&out)["kind"], "output_exists");
+
+    // A batch lists each file that failed
+    fs::create_dir_all(dir.join("images")).unwrap();
+    fs::write(dir.join("images/a.png"), "not an image").unwrap();
+    let out = pipe(&["image-convert", dir.join("images").to_str().unwrap(), "-r", "-f", "bmp", "--json"], b"");
+    assert_eq!(out.status.code(), Some(1));
+    let e = failure(&out);
+    assert_eq!(e["kind"], "batch");
+    assert_eq!(e["failures"][0]["path"], dir.join("images/a.png").to_str().unwrap());
+
+    let out = pipe(&["--json", "hash", "--no-such-flag"], b"");
+    assert_eq!(out.status.code(), Some(2));
+    assert_eq!(failure(&out)["kind"], "usage");
+}
+
+#[test]
+fn sidecars_record_outputs_and_catch_changes() {
+    let dir = scratch("sidecar");
+    let input = dir.join("notes.txt");
+    fs::write(&input, "kept track of\n").unwrap();
+    fs::create_dir_all(dir.join("out")).unwrap();
+    let output = dir.join("out/notes.txt.zst");
+    ok(&["compress", input.to_str().unwrap(), "-o", output.to_str().unwrap(), "-a", "brotli", "--sidecar"]);
+
+    let sidecar: serde_json::Value = serde_json::from_slice(&fs::read(dir.join("out/notes.txt.zst.jj.meta")).unwrap()).unwrap();
+    assert_eq!(sidecar["command"], "compress");
+    assert_eq!(sidecar["parameters"]["algorithm"], "brotli");
+    assert_eq!(sidecar["source"]["path"], "../notes.txt");
+    assert_eq!(sidecar["output"]["size"], fs::metadata(&output).unwrap().len());
+
+    let out = ok(&["verify-sidecar", output.to_str().unwrap()]);
+    assert!(String::from_utf8_lossy(&out.stdout).starts_with("OK"));
+
+    // A changed source fails, and so does a gone one with --require-source
+    fs::write(&input, "changed since\n").unwrap();
+    let out = pipe(&["verify-sidecar", output.to_str().unwrap()], b"");
+    assert!(!out.status.success() && String::from_utf8_lossy(&out.stdout).contains("changed"));
+    fs::remove_file(&input).unwrap();
+    assert!(ok(&["verify-sidecar", output.to_str().unwrap()]).status.success());
+    assert!(!pipe(&["verify-sidecar", output.to_str().unwrap(), "--require-source"], b"").status.success());
+
+    fs::write(&output, "tampered").unwrap();
+    assert!(!pipe(&["verify-sidecar", output.to_str().unwrap()], b"").status.success());
+
+    // No sidecar without the flag
+    let plain = dir.join("plain.txt");
+    fs::write(&plain, "plain").unwrap();
+    ok(&["compress", plain.to_str().unwrap(), "-o", dir.join("plain.txt.zst").to_str().unwrap()]);
+    assert!(!dir.join("plain.txt.zst.jj.meta").exists());
+}
+
+#[test]
+fn fetch_resumes_and_checks_the_digest() {
+    use std::io::{BufRead, BufReader};
+
+    let dir = scratch("fetch");
+    let served = dir.join("served");
+    fs::create_dir_all(&served).unwrap();
+    let text: String = (0..5000).map(|i| format!("row {i}\n")).collect();
+    fs::write(served.join("rows.txt"), &text).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_jj_toolkit")).args(["serve", served.to_str().unwrap(), "-p", "0"]).stderr(Stdio::piped()).spawn().unwrap();
+    // Kept open, as the server logs every request
+    let mut log = BufReader::new(child.stderr.take().unwrap());
+    let mut line = String::new();
+    log.read_line(&mut line).unwrap();
+    let url = format!("{}rows.txt", line.trim().rsplit(' ').next().unwrap());
+    let hex = String::from_utf8(ok(&["hash", "-a", "sha256", served.join("rows.txt").to_str().unwrap()]).stdout).unwrap();
+    let sha256 = hex.split_whitespace().next().unwrap();
+
+    let output = dir.join("rows.txt");
+    ok(&["fetch", &url, "-o", output.to_str().unwrap(), "--sha256", sha256]);
+    assert_eq!(fs::read_to_string(&output).unwrap(), text);
+
+    // A partial download is picked up where it stopped
+    let resumed = dir.join("resumed.txt");
+    fs::write(dir.join("resumed.txt.part"), &text[..1000]).unwrap();
+    let out = ok(&["fetch", &url, "-o", resumed.to_str().unwrap(), "--sha256", sha256]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Resuming"));
+    assert_eq!(fs::read_to_string(&resumed).unwrap(), text);
+    assert!(!dir.join("resumed.txt.part").exists());
+
+    // Without a validator or a digest a partial download is started over, not joined
+    let unchecked = dir.join("unchecked.txt");
+    fs::write(dir.join("unchecked.txt.part"), "stale bytes of something else\n").unwrap();
+    let out = ok(&["fetch", &url, "-o", unchecked.to_str().unwrap()]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("starting over"));
+    assert_eq!(fs::read_to_string(&unchecked).unwrap(), text);
+
+    // A validator that still matches resumes; one for a file that changed since gets it whole
+    let etag = format!("\"{}\"", sha256[..32].to_ascii_lowercase());
+    for (name, validator, resumes) in [("same.txt", etag.as_str(), true), ("changed.txt", "\"0123\"", false)] {
+        let target = dir.join(name);
+        let part = if resumes { text[..1000].to_string() } else { "old version\n".to_string() };
+        fs::write(dir.join(format!("{name}.part")), part).unwrap();
+        fs::write(dir.join(format!("{name}.part.validator")), format!("{url}\n{validator}\n")).unwrap();
+        let out = ok(&["fetch", &url, "-o", target.to_str().unwrap()]);
+        assert_eq!(String::from_utf8_lossy(&out.stderr).contains("Resuming"), resumes, "{name}");
+        assert_eq!(fs::read_to_string(&target).unwrap(), text, "{name}");
+        assert!(!dir.join(format!("{name}.part.validator")).exists());
+    }
+
+    // A malformed digest is refused before anything is downloaded
+    let out = pipe(&["fetch", &url, "-o", dir.join("bad.txt").to_str().unwrap(), "--sha256", "abc"], b"");
+    assert_eq!(out.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("expected 64 hex digits"));
+    assert!(!dir.join("bad.txt.part").exists());
+
+    // A wrong digest leaves nothing behind
+    let wrong = dir.join("wrong.txt");
+    let out = pipe(&["fetch", &url, "-o", wrong.to_str().unwrap(), "--sha256", &"0".repeat(64)], b"");
+    assert_eq!(out.status.code(), Some(65), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(!wrong.exists() && !dir.join("wrong.txt.part").exists());
+
+    // The digest can come from a manifest written by hash
+    let manifest = dir.join("rows.sha256");
+    ok(&["hash", "-a", "sha256", served.join("rows.txt").to_str().unwrap(), "-o", manifest.to_str().unwrap()]);
+    let out = ok(&["fetch", &url, "-o", "-", "--manifest", manifest.to_str().unwrap()]);
+    assert_eq!(out.stdout, text.as_bytes());
+
+    let missing = pipe(&["fetch", &url.replace("rows.txt", "gone.txt"), "-o", dir.join("gone.txt").to_str().unwrap()], b"");
+    assert_eq!(missing.status.code(), Some(66));
+    let other = pipe(&["fetch", "ftp://example.com/a.txt", "-o", "-"], b"");
+    assert!(String::from_utf8_lossy(&other.stderr).contains("not an http:// or https:// URL"));
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[test]
+fn urls_work_as_inputs_to_read_only_commands() {
+    use std::io::{BufRead, BufReader};
+
+    let dir = scratch("url_inputs");
+    let served = dir.join("served");
+    fs::create_dir_all(&served).unwrap();
+    let png = served.join("code.png");
+    ok(&["qr", "encode", "hello", "-o", png.to_str().unwrap()]);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_jj_toolkit")).args(["serve", served.to_str().unwrap(), "-p", "0"]).stderr(Stdio::piped()).spawn().unwrap();
+    let mut log = BufReader::new(child.stderr.take().unwrap());
+    let mut line = String::new();
+    log.read_line(&mut line).unwrap();
+    let url = format!("{}code.png", line.trim().rsplit(' ').next().unwrap());
+
+    let local = String::from_utf8(ok(&["hash", png.to_str().unwrap()]).stdout).unwrap();
+    let remote = String::from_utf8(ok(&["hash", &url]).stdout).unwrap();
+    assert_eq!(local.split_whitespace().next(), remote.split_whitespace().next());
+
+    let info = String::from_utf8(ok(&["image-info", &url]).stdout).unwrap();
+    assert!(info.contains(&url) && info.contains("PNG"), "{}", info);
+
+    // Spooled only up to --max-memory
+    let capped = pipe(&["image-info", &url, "--max-memory", "64"], b"");
+    assert_eq!(capped.status.code(), Some(69), "{}", String::from_utf8_lossy(&capped.stderr));
+
+    // decompress --test streams the body through the decoder
+    let packed = served.join("code.png.zst");
+    ok(&["compress", png.to_str().unwrap(), "-o", packed.to_str().unwrap()]);
+    let tested = ok(&["decompress", "--test", &url.replace("code.png", "code.png.zst")]);
+    assert!(String::from_utf8_lossy(&tested.stderr).contains("OK"));
+
+    let missing = pipe(&["hash", &url.replace("code.png", "gone.png")], b"");
+    assert_eq!(missing.status.code(), Some(66));
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
@@ -0,0 +1,123 @@
+use std::fs;
+use std::path::PathBuf;
+
+mod common;
+use common::{run, scratch};
+
+// A container of `notes.txt` with the bytes of its header that follow the name
+fn container(dir: &std::path::Path) -> (PathBuf, Vec<u8>, usize) {
+    let input = dir.join("notes.txt");
+    let original: Vec<u8> = (0..20_000u32).flat_map(|i| (i % 251).to_le_bytes()).collect();
+    fs::write(&input, &original).unwrap();
+    let packed = dir.join("notes.jjz");
+    let out = run(&["compress", "--container", input.to_str().unwrap(), "-o", packed.to_str().unwrap()]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    // magic | version | algorithm | name len | name
+    let fields = 6 + 1 + 1 + 2 + "notes.txt".len();
+    (packed, original, fields)
+}
+
+#[test]
+fn container_round_trips_name_size_and_mtime() {
+    let dir = scratch("round_trip");
+    let (packed, original, _) = container(&dir);
+    let mtime = fs::metadata(dir.join("notes.txt")).unwrap().modified().unwrap();
+    let restored = dir.join("out");
+    fs::create_dir(&restored).unwrap();
+    let out = run(&["decompress", packed.to_str().unwrap(), "-o", restored.join("notes.txt").to_str().unwrap()]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(fs::read(restored.join("notes.txt")).unwrap(), original);
+    assert_eq!(fs::metadata(restored.join("notes.txt")).unwrap().modified().unwrap(), mtime);
+}
+
+#[test]
+fn corrupted_container_header_fails_cleanly() {
+    let dir = scratch("header");
+    let (packed, _, fields) = container(&dir);
+    let mut bytes = fs::read(&packed).unwrap();
+    // mtime nanos past a second, then secs past what SystemTime holds
+    for (at, value) in [(fields + 16, &[0xFF; 4][..]), (fields + 8, &[0xFF; 8][..])] {
+        let mut crafted = bytes.clone();
+        crafted[at..at + value.len()].copy_from_slice(value);
+        fs::write(&packed, &crafted).unwrap();
+        let out = run(&["decompress", packed.to_str().unwrap(), "-o", dir.join("restored").to_str().unwrap()]);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(65), "{}", stderr);
+        assert!(stderr.contains("invalid container"), "{}", stderr);
+        assert!(!stderr.contains("panicked"), "{}", stderr);
+        assert!(!dir.join("restored").exists());
+    }
+
+    bytes.truncate(fields + 10);
+    fs::write(&packed, &bytes).unwrap();
+    let out = run(&["decompress", packed.to_str().unwrap(), "-o", dir.join("restored").to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(65), "{}", String::from_utf8_lossy(&out.stderr));
+
+    // In a directory too, rather than being taken for a file without a container
+    let batch = dir.join("batch");
+    fs::create_dir_all(&batch).unwrap();
+    fs::copy(&packed, batch.join("notes.jjz")).unwrap();
+    let out = run(&["decompress", "-r", batch.to_str().unwrap(), "-o", dir.join("restored").to_str().unwrap()]);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert_eq!(out.status.code(), Some(65), "{}", stderr);
+    assert!(stderr.contains("invalid container header") && stderr.contains("notes.jjz"), "{}", stderr);
+}
+
+#[test]
+fn checksum_mismatch_leaves_no_output() {
+    let dir = scratch("checksum");
+    let (packed, _, fields) = container(&dir);
+    let mut bytes = fs::read(&packed).unwrap();
+    // First byte of the recorded digest
+    bytes[fields + 20] ^= 0xFF;
+    fs::write(&packed, &bytes).unwrap();
+    let out = run(&["decompress", packed.to_str().unwrap(), "-o", dir.join("restored").to_str().unwrap()]);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(!out.status.success());
+    assert!(stderr.contains("checksum mismatch"), "{}", stderr);
+    assert!(!dir.join("restored").exists());
+}
+
+#[test]
+fn test_mode_decodes_without_writing() {
+    let dir = scratch("test_mode");
+    let (packed, _, fields) = container(&dir);
+    let out = run(&["decompress", "--test", packed.to_str().unwrap()]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("OK"));
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+    let mut bytes = fs::read(&packed).unwrap();
+    bytes[fields + 20] ^= 0xFF;
+    fs::write(&packed, &bytes).unwrap();
+    let out = run(&["decompress", "--test", packed.to_str().unwrap()]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("checksum mismatch"));
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+}
+
+#[test]
+fn dry_run_writes_nothing_and_notes_overwrites() {
+    let dir = scratch("dry_run");
+    let tree = dir.join("tree");
+    fs::create_dir_all(tree.join("sub")).unwrap();
+    fs::write(tree.join("notes.txt"), "notes\n".repeat(100)).unwrap();
+    fs::write(tree.join("sub/log.txt"), "log\n".repeat(100)).unwrap();
+    let out_dir = dir.join("out");
+    fs::create_dir(&out_dir).unwrap();
+    fs::write(out_dir.join("notes.txt.zst"), "older").unwrap();
+
+    let out = run(&["compress", "-r", tree.to_str().unwrap(), "-o", out_dir.to_str().unwrap(), "--dry-run"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let plan = String::from_utf8_lossy(&out.stdout);
+    let lines: Vec<&str> = plan.lines().collect();
+    assert_eq!(lines.len(), 2, "{plan}");
+    assert!(lines.iter().any(|l| l.contains("notes.txt.zst") && l.ends_with("(overwrites existing file)")), "{plan}");
+    assert!(lines.iter().any(|l| l.contains("log.txt.zst") && !l.contains("overwrites")), "{plan}");
+    assert_eq!(fs::read(out_dir.join("notes.txt.zst")).unwrap(), b"older");
+    assert!(!out_dir.join("sub").exists());
+
+    // The note follows the overwrite flags
+    let out = run(&["compress", "-r", tree.to_str().unwrap(), "-o", out_dir.to_str().unwrap(), "--dry-run", "--no-clobber"]);
+    assert!(String::from_utf8_lossy(&out.stdout).contains("(refused, output exists)"));
+    assert_eq!(fs::read_dir(&out_dir).unwrap().count(), 1);
+}
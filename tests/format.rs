@@ -0,0 +1,344 @@
+use std::fs;
+use std::process::Output;
+
+mod common;
+use common::{run, scratch, stdout};
+
+// Stdout of a command that is expected to fail
+fn stdout_lossy(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stdout).into_owned()
+}
+
+#[test]
+fn msgpack_round_trips_through_json() {
+    let dir = scratch("msgpack");
+    // {"id": 7, "ok": true, "ratio": 1.5, "tags": ["a", "b"]}
+    let payload = b"\x84\xa2id\x07\xa2ok\xc3\xa5ratio\xcb\x3f\xf8\x00\x00\x00\x00\x00\x00\xa4tags\x92\xa1a\xa1b";
+    let input = dir.join("payload.msgpack");
+    fs::write(&input, payload).unwrap();
+
+    stdout(&run(&["format", "-f", "json", input.to_str().unwrap()]));
+    let json: serde_json::Value = serde_json::from_slice(&fs::read(dir.join("payload.json")).unwrap()).unwrap();
+    assert_eq!(json, serde_json::json!({ "id": 7, "tags": ["a", "b"], "ok": true, "ratio": 1.5 }));
+
+    let back = dir.join("back.mpk");
+    stdout(&run(&["format", "-f", "msgpack", dir.join("payload.json").to_str().unwrap(), "-o", back.to_str().unwrap()]));
+    assert_eq!(fs::read(&back).unwrap(), payload);
+}
+
+#[test]
+fn jsonc_config_normalizes_to_strict_json() {
+    let dir = scratch("jsonc");
+    let input = dir.join("settings.jsonc");
+    fs::write(&input, "// editor settings\n{\n  \"tabs\": false, /* spaces */\n  \"rulers\": [80, 120,],\n}\n").unwrap();
+    stdout(&run(&["format", "-f", "json", input.to_str().unwrap()]));
+    let json: serde_json::Value = serde_json::from_slice(&fs::read(dir.join("settings.json")).unwrap()).unwrap();
+    assert_eq!(json, serde_json::json!({ "tabs": false, "rulers": [80, 120] }));
+
+    // --from overrides the extension
+    let config = dir.join("app.conf");
+    fs::write(&config, "{ retries: 3, }").unwrap();
+    let out = dir.join("app.json");
+    stdout(&run(&["format", "--from", "json5", "-f", "json", config.to_str().unwrap(), "-o", out.to_str().unwrap()]));
+    assert!(fs::read_to_string(&out).unwrap().contains("\"retries\": 3"));
+}
+
+#[test]
+fn json_layout_follows_compact_and_indent() {
+    let dir = scratch("layout");
+    let input = dir.join("in.json");
+    fs::write(&input, r#"{"a": [1, 2]}"#).unwrap();
+    let convert = |flags: &[&str]| {
+        let out = dir.join("out.json");
+        let mut args = vec!["format", "-f", "json", input.to_str().unwrap(), "-o", out.to_str().unwrap()];
+        args.extend_from_slice(flags);
+        stdout(&run(&args));
+        fs::read_to_string(&out).unwrap()
+    };
+    assert_eq!(convert(&[]), "{\n  \"a\": [\n    1,\n    2\n  ]\n}");
+    assert_eq!(convert(&["--compact"]), r#"{"a":[1,2]}"#);
+    assert_eq!(convert(&["--indent", "4"]), "{\n    \"a\": [\n        1,\n        2\n    ]\n}");
+
+    let bson = run(&["format", "-f", "bson", "--compact", input.to_str().unwrap()]);
+    assert!(String::from_utf8_lossy(&bson.stderr).contains("JSON output only"));
+}
+
+#[test]
+fn validate_reports_error_positions() {
+    let dir = scratch("validate");
+    let good = dir.join("good.json");
+    fs::write(&good, r#"{"a": 1}"#).unwrap();
+    let bad = dir.join("bad.json");
+    fs::write(&bad, "{\n  \"a\": 1,\n  \"b\": [1 2]\n}\n").unwrap();
+    let truncated = dir.join("cut.msgpack");
+    fs::write(&truncated, b"\x82\xa1a\x01\xa1b").unwrap();
+
+    let out = run(&["format-validate", good.to_str().unwrap(), bad.to_str().unwrap(), truncated.to_str().unwrap()]);
+    assert!(!out.status.success());
+    let report = String::from_utf8_lossy(&out.stdout);
+    assert!(report.contains("good.json: OK (JSON)"), "{report}");
+    assert!(report.contains("bad.json:3:11: byte 22: invalid JSON: expected `,` or `]`"), "{report}");
+    assert!(report.contains("cut.msgpack:byte 6: invalid MessagePack"), "{report}");
+    assert!(String::from_utf8_lossy(&out.stderr).contains("2 of 3 files are invalid"));
+    assert!(!dir.join("good.bson").exists());
+}
+
+#[test]
+fn schema_violations_stop_the_conversion() {
+    let dir = scratch("schema");
+    let schema = dir.join("schema.json");
+    fs::write(&schema, r#"{"type": "object", "required": ["port"], "properties": {"port": {"type": "integer", "maximum": 65535}, "tags": {"type": "array", "items": {"type": "string"}}}}"#).unwrap();
+    let bad = dir.join("bad.json");
+    fs::write(&bad, r#"{"port": 70000, "tags": ["a", 1]}"#).unwrap();
+    let out = run(&["format", "--schema", schema.to_str().unwrap(), bad.to_str().unwrap()]);
+    let err = String::from_utf8_lossy(&out.stderr);
+    assert!(err.contains("2 violations"), "{err}");
+    assert!(err.contains("/port: 70000 is greater than the maximum of 65535"), "{err}");
+    assert!(err.contains("/tags/1: 1 is not of type \"string\""), "{err}");
+    assert!(!dir.join("bad.bson").exists());
+
+    let good = dir.join("good.json");
+    fs::write(&good, r#"{"port": 443}"#).unwrap();
+    stdout(&run(&["format", "--schema", schema.to_str().unwrap(), good.to_str().unwrap()]));
+    assert!(dir.join("good.bson").is_file());
+}
+
+#[test]
+fn directory_input_mirrors_the_tree() {
+    let dir = scratch("batch");
+    let input = dir.join("in");
+    fs::create_dir_all(input.join("nested/drafts")).unwrap();
+    fs::write(input.join("a.json"), r#"{"a": 1}"#).unwrap();
+    fs::write(input.join("nested/b.jsonc"), "{ b: 2, }").unwrap();
+    fs::write(input.join("nested/broken.json"), "{").unwrap();
+    fs::write(input.join("nested/drafts/c.json"), "{}").unwrap();
+    fs::write(input.join("notes.txt"), "not a format").unwrap();
+    let output = dir.join("out");
+
+    let out = run(&["format", "-r", input.to_str().unwrap(), "-f", "msgpack", "-o", output.to_str().unwrap(), "--exclude", "nested/drafts/*"]);
+    assert!(!out.status.success());
+    let report = String::from_utf8_lossy(&out.stdout);
+    assert!(report.contains("Processed 3 file(s): 2 succeeded, 1 failed"), "{report}");
+    assert!(String::from_utf8_lossy(&out.stderr).contains("broken.json"));
+    assert!(output.join("a.msgpack").is_file());
+    assert!(output.join("nested/b.msgpack").is_file());
+    assert!(!output.join("nested/drafts").exists());
+
+    let flat = run(&["format", input.to_str().unwrap()]);
+    assert!(String::from_utf8_lossy(&flat.stderr).contains("Use -r/--recursive"));
+}
+
+#[test]
+fn content_decides_without_a_known_extension() {
+    let dir = scratch("sniff");
+    let write = |name: &str, data: &[u8]| {
+        let path = dir.join(name);
+        fs::write(&path, data).unwrap();
+        path.to_str().unwrap().to_string()
+    };
+    let json = write("capture-1", b"  {\"id\": 1}");
+    let json5 = write("capture-2", b"// exported\n{ id: 2, }");
+    let msgpack = write("capture-3", b"\x81\xa2id\x03");
+    let report = stdout(&run(&["format-validate", &json, &json5, &msgpack]));
+    assert!(report.contains("capture-1: OK (JSON)"), "{report}");
+    assert!(report.contains("capture-2: OK (JSON5)"), "{report}");
+    assert!(report.contains("capture-3: OK (MessagePack)"), "{report}");
+
+    // A BSON document, recognized by its length prefix
+    let bson = dir.join("capture-4");
+    stdout(&run(&["format", &json, "-o", bson.to_str().unwrap()]));
+    stdout(&run(&["format", "-f", "json", bson.to_str().unwrap(), "-o", dir.join("back.json").to_str().unwrap()]));
+    assert!(fs::read_to_string(dir.join("back.json")).unwrap().contains("\"id\": 1"));
+
+    let yaml = write("capture-5", b"name: api\nport: 80\n");
+    let out = run(&["format", "-f", "json", &yaml]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("looks like YAML"));
+}
+
+#[test]
+fn diff_reports_changes_by_path_across_formats() {
+    let dir = scratch("diff");
+    let old = dir.join("old.json");
+    fs::write(&old, r#"{"name": "api", "port": 80, "tags": ["a", "b"], "db": {"host": "x"}}"#).unwrap();
+    let new_source = dir.join("new.json5");
+    fs::write(&new_source, r#"{ db: { host: "y" }, port: 443, name: "api", tags: ["a"], debug: true }"#).unwrap();
+    let new = dir.join("new.msgpack");
+    stdout(&run(&["format", "-f", "msgpack", new_source.to_str().unwrap(), "-o", new.to_str().unwrap()]));
+
+    let report = stdout(&run(&["format-diff", old.to_str().unwrap(), new.to_str().unwrap()]));
+    assert_eq!(report, "~ /db/host: \"x\" -> \"y\"\n+ /debug: true\n~ /port: 80 -> 443\n- /tags/1: \"b\"\n4 change(s)");
+
+    let json: serde_json::Value = serde_json::from_str(&stdout(&run(&["format-diff", "--json", old.to_str().unwrap(), new.to_str().unwrap()]))).unwrap();
+    assert_eq!(json[1], serde_json::json!({ "op": "added", "path": "/debug", "new": true }));
+
+    // Reordered keys are not a change
+    let reordered = dir.join("reordered.json");
+    fs::write(&reordered, r#"{"db": {"host": "x"}, "tags": ["a", "b"], "port": 80, "name": "api"}"#).unwrap();
+    assert_eq!(stdout(&run(&["format-diff", old.to_str().unwrap(), reordered.to_str().unwrap()])), "No differences");
+}
+
+#[test]
+fn merge_layers_overrides_with_each_array_strategy() {
+    let dir = scratch("merge");
+    let base = dir.join("base.json");
+    fs::write(&base, r#"{"replicas": 1, "env": {"LOG": "info", "REGION": "eu"}, "containers": [{"name": "api", "image": "api:1", "port": 80}, {"name": "cron", "image": "cron:1"}]}"#).unwrap();
+    let prod = dir.join("prod.jsonc");
+    fs::write(&prod, r#"{ replicas: 3, env: { LOG: "warn" }, containers: [{ name: "api", image: "api:2" }, { name: "proxy", image: "proxy:1" }], }"#).unwrap();
+    let merge = |strategy: &str| -> serde_json::Value {
+        serde_json::from_str(&stdout(&run(&["format-merge", base.to_str().unwrap(), prod.to_str().unwrap(), "--arrays", strategy]))).unwrap()
+    };
+
+    let replaced = merge("replace");
+    assert_eq!(replaced["replicas"], 3);
+    assert_eq!(replaced["env"], serde_json::json!({ "LOG": "warn", "REGION": "eu" }));
+    assert_eq!(replaced["containers"].as_array().unwrap().len(), 2);
+    assert_eq!(merge("append")["containers"].as_array().unwrap().len(), 4);
+    assert_eq!(
+        merge("merge-by-key")["containers"],
+        serde_json::json!([
+            { "name": "api", "image": "api:2", "port": 80 },
+            { "name": "cron", "image": "cron:1" },
+            { "name": "proxy", "image": "proxy:1" },
+        ])
+    );
+
+    let binary = run(&["format-merge", base.to_str().unwrap(), prod.to_str().unwrap(), "-f", "bson"]);
+    assert!(String::from_utf8_lossy(&binary.stderr).contains("pass --output"));
+}
+
+// Length-delimited protobuf field
+fn field(number: u8, bytes: &[u8]) -> Vec<u8> {
+    let mut out = vec![number << 3 | 2, bytes.len() as u8];
+    out.extend_from_slice(bytes);
+    out
+}
+
+#[test]
+fn protobuf_decodes_with_a_descriptor_set() {
+    let dir = scratch("protobuf");
+    // shop.proto: message Order { uint32 id = 1; string item = 2; repeated string tags = 3; }
+    let order_field = |name: &str, number: u8, label: u8, kind: u8| {
+        [field(1, name.as_bytes()), vec![3 << 3, number, 4 << 3, label, 5 << 3, kind]].concat()
+    };
+    let message = [
+        field(1, b"Order"),
+        field(2, &order_field("id", 1, 1, 13)),
+        field(2, &order_field("item", 2, 1, 9)),
+        field(2, &order_field("tags", 3, 3, 9)),
+    ]
+    .concat();
+    let file = [field(1, b"shop.proto"), field(2, b"shop"), field(4, &message), field(12, b"proto3")].concat();
+    let set = dir.join("shop.desc");
+    fs::write(&set, field(1, &file)).unwrap();
+
+    let payload = [&[0x08, 42][..], &field(2, b"tea"), &field(3, b"hot"), &field(3, b"green")].concat();
+    let input = dir.join("order.pb");
+    fs::write(&input, &payload).unwrap();
+    let proto = ["--proto-descriptor", set.to_str().unwrap(), "--proto-message", "shop.Order"];
+
+    let json = dir.join("order.json");
+    stdout(&run(&[&["format", "--from", "protobuf", "-f", "json", input.to_str().unwrap()][..], &proto].concat()));
+    let value: serde_json::Value = serde_json::from_slice(&fs::read(&json).unwrap()).unwrap();
+    assert_eq!(value, serde_json::json!({ "id": 42, "item": "tea", "tags": ["hot", "green"] }));
+
+    let back = dir.join("back.pb");
+    stdout(&run(&[&["format", "-f", "protobuf", json.to_str().unwrap(), "-o", back.to_str().unwrap()][..], &proto].concat()));
+    assert_eq!(fs::read(&back).unwrap(), payload);
+
+    // Without the descriptor there is nothing to decode against
+    assert!(!run(&["format", "--from", "protobuf", "-f", "json", input.to_str().unwrap()]).status.success());
+    let unknown = run(&["format", "--from", "protobuf", "-f", "json", input.to_str().unwrap(), "--proto-descriptor", set.to_str().unwrap(), "--proto-message", "shop.Cart"]);
+    assert!(String::from_utf8_lossy(&unknown.stderr).contains("No message \"shop.Cart\""));
+}
+
+#[test]
+fn parquet_and_arrow_rows_read_as_json_and_ndjson() {
+    use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray};
+    use std::sync::Arc;
+
+    let dir = scratch("columnar");
+    let batch = RecordBatch::try_from_iter([
+        ("city", Arc::new(StringArray::from(vec![Some("Oslo"), Some("Lima"), None])) as ArrayRef),
+        ("temp", Arc::new(Float64Array::from(vec![4.5, 19.0, -2.25])) as ArrayRef),
+    ])
+    .unwrap();
+    let parquet_file = dir.join("weather.parquet");
+    let mut writer = parquet::arrow::ArrowWriter::try_new(fs::File::create(&parquet_file).unwrap(), batch.schema(), None).unwrap();
+    writer.write(&batch).unwrap();
+    writer.close().unwrap();
+    // Feather v2 without its extension, to be found by its magic
+    let arrow_file = dir.join("weather.data");
+    let mut writer = arrow_ipc::writer::FileWriter::try_new(fs::File::create(&arrow_file).unwrap(), &batch.schema()).unwrap();
+    writer.write(&batch).unwrap();
+    writer.finish().unwrap();
+
+    let rows = serde_json::json!([
+        { "city": "Oslo", "temp": 4.5 },
+        { "city": "Lima", "temp": 19.0 },
+        { "city": null, "temp": -2.25 },
+    ]);
+    stdout(&run(&["format", "-f", "json", parquet_file.to_str().unwrap()]));
+    let json: serde_json::Value = serde_json::from_slice(&fs::read(dir.join("weather.json")).unwrap()).unwrap();
+    assert_eq!(json, rows);
+
+    let ndjson = dir.join("weather.ndjson");
+    stdout(&run(&["format", "-f", "ndjson", arrow_file.to_str().unwrap(), "-o", ndjson.to_str().unwrap()]));
+    let text = fs::read_to_string(&ndjson).unwrap();
+    assert_eq!(text.lines().count(), 3);
+    assert_eq!(text.lines().next().unwrap(), r#"{"city":"Oslo","temp":4.5}"#);
+
+    // NDJSON reads back as the same array
+    let back = dir.join("back.json");
+    stdout(&run(&["format", "-f", "json", ndjson.to_str().unwrap(), "-o", back.to_str().unwrap()]));
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(&fs::read(&back).unwrap()).unwrap(), rows);
+
+    let refused = run(&["format", "-f", "parquet", back.to_str().unwrap()]);
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("read-only"));
+}
+
+#[test]
+fn bincode_output_carries_a_checked_header() {
+    let dir = scratch("bincode");
+    let input = dir.join("entry.json");
+    fs::write(&input, r#"{"name": "probe", "readings": [1, 2, 3]}"#).unwrap();
+    let bin = dir.join("entry.bin");
+    stdout(&run(&["format", "-f", "bincode", input.to_str().unwrap()]));
+    let bytes = fs::read(&bin).unwrap();
+    assert_eq!(&bytes[..8], b"JJBC\x01\x01\x00\x02");
+
+    // The header makes the file recognizable without its extension
+    let renamed = dir.join("entry.dat");
+    fs::copy(&bin, &renamed).unwrap();
+    let back = dir.join("back.json");
+    stdout(&run(&["format", "-f", "json", renamed.to_str().unwrap(), "-o", back.to_str().unwrap()]));
+    let json: serde_json::Value = serde_json::from_slice(&fs::read(&back).unwrap()).unwrap();
+    assert_eq!(json, serde_json::json!({ "name": "probe", "readings": [1, 2, 3] }));
+
+    // Headerless bincode and a newer schema both fail instead of decoding
+    let stale = dir.join("stale.bin");
+    fs::write(&stale, &bytes[8..]).unwrap();
+    let out = run(&["format", "-f", "json", stale.to_str().unwrap()]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("no jj_toolkit BINCODE header"));
+    let mut newer = bytes.clone();
+    newer[5] = 2;
+    fs::write(&stale, &newer).unwrap();
+    let out = run(&["format-validate", stale.to_str().unwrap()]);
+    assert!(stdout_lossy(&out).contains("byte 5: invalid BINCODE: unsupported schema 2 (expected 1)"));
+}
+
+#[test]
+fn forged_bincode_lengths_fail_instead_of_aborting() {
+    let dir = scratch("forged");
+    // A string that claims 2^60 bytes, with none of them there
+    let forged = dir.join("forged.bin");
+    let mut bytes = b"JJBC\x01\x01\x00\x02\x05\xFD".to_vec();
+    bytes.extend_from_slice(&(0x10u64 << 56).to_le_bytes());
+    fs::write(&forged, &bytes).unwrap();
+
+    let out = run(&["format", "-f", "json", forged.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(1), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Failed to deserialize"));
+    let out = run(&["format-validate", forged.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(1), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(stdout_lossy(&out).contains("invalid BINCODE"));
+}
@@ -0,0 +1,315 @@
+use std::fs;
+use std::path::Path;
+
+mod common;
+use common::{run, s, scratch, stderr};
+
+// Baseline JPEG with a size in whole MCUs, so it can be transformed losslessly
+fn write_jpeg(path: &Path, width: u32, height: u32) {
+    image::RgbImage::from_fn(width, height, |x, y| image::Rgb([(x * 3) as u8, (y * 4) as u8, ((x + y) * 2) as u8]))
+        .save(path)
+        .unwrap();
+}
+
+#[test]
+fn jpeg_rotation_is_lossless_and_reversible() {
+    let dir = scratch("jpeg_rotate");
+    let original = dir.join("photo.jpg");
+    write_jpeg(&original, 64, 48);
+
+    let turned = dir.join("turned.jpg");
+    let log = stderr(&run(&["image-transform", s(&original), "--rotate", "90", "-o", s(&turned)]));
+    assert!(log.contains("(lossless)"), "{}", log);
+    assert_eq!(image::image_dimensions(&turned).unwrap(), (48, 64));
+
+    let back = dir.join("back.jpg");
+    stderr(&run(&["image-transform", s(&turned), "--rotate", "270", "-o", s(&back)]));
+    // Same coefficients and quantization tables, so the same pixels despite new Huffman tables
+    assert_eq!(image::open(&back).unwrap().to_rgb8(), image::open(&original).unwrap().to_rgb8());
+}
+
+#[test]
+fn malformed_jpeg_huffman_table_is_an_error_not_a_panic() {
+    let dir = scratch("jpeg_dht");
+    let original = dir.join("photo.jpg");
+    write_jpeg(&original, 64, 48);
+    let mut data = fs::read(&original).unwrap();
+    // Every symbol of the first Huffman table says 15 bits follow, more than DC or AC allow
+    let dht = data.windows(2).position(|m| m == [0xFF, 0xC4]).unwrap();
+    let count: usize = data[dht + 5..dht + 21].iter().map(|&b| b as usize).sum();
+    data[dht + 21..dht + 21 + count].fill(0x0F);
+    let broken = dir.join("broken.jpg");
+    fs::write(&broken, &data).unwrap();
+
+    let out = run(&["image-transform", s(&broken), "--rotate", "90", "-o", s(&dir.join("turned.jpg"))]);
+    let log = String::from_utf8_lossy(&out.stderr);
+    assert!(!log.contains("panicked"), "{}", log);
+    assert!(log.contains("Cannot transform") && log.contains("corrupt JPEG"), "{}", log);
+
+    let out = run(&["stegano-extract", s(&broken)]);
+    let log = String::from_utf8_lossy(&out.stderr);
+    assert!(!out.status.success());
+    assert!(!log.contains("panicked"), "{}", log);
+}
+
+#[test]
+fn fit_keeps_the_aspect_ratio_and_exact_stretches() {
+    let dir = scratch("fit");
+    let input = dir.join("wide.png");
+    image::RgbImage::from_pixel(64, 48, image::Rgb([90, 120, 150])).save(&input).unwrap();
+    let scaled = |mode: &str| {
+        let output = dir.join(format!("{mode}.png"));
+        stderr(&run(&["image-scale", s(&input), "--width", "32", "--height", "32", "--mode", mode, "-o", s(&output)]));
+        image::image_dimensions(&output).unwrap()
+    };
+    // Fit stays inside the box without stretching, fill covers it and crops, exact stretches
+    assert_eq!(scaled("fit"), (32, 24));
+    assert_eq!(scaled("fill"), (32, 32));
+    assert_eq!(scaled("exact"), (32, 32));
+
+    stderr(&run(&["image-thumb", s(&input), "--sizes", "16", "-o", s(&dir)]));
+    assert_eq!(image::image_dimensions(dir.join("wide_16.png")).unwrap(), (16, 12));
+}
+
+#[test]
+fn non_ascii_colors_are_an_error_not_a_panic() {
+    let dir = scratch("colors");
+    let input = dir.join("in.png");
+    image::RgbImage::from_pixel(8, 8, image::Rgb([0, 0, 0])).save(&input).unwrap();
+    let output = dir.join("out.png");
+    // Six and eight bytes long, so the lengths alone would let them through to slicing
+    for color in ["ééé", "#éééé", "ab€d"] {
+        let out = run(&["image-border", s(&input), "--size", "2", "--color", color, "-o", s(&output)]);
+        assert_eq!(out.status.code(), Some(1), "{color}: {}", String::from_utf8_lossy(&out.stderr));
+        assert!(String::from_utf8_lossy(&out.stderr).contains("invalid hex color"), "{color}");
+    }
+    stderr(&run(&["image-border", s(&input), "--size", "2", "--color", " #FF000080 ", "-o", s(&output)]));
+    assert_eq!(image::open(&output).unwrap().to_rgba8().get_pixel(0, 0).0, [255, 0, 0, 128]);
+}
+
+// Bit depth and color type from IHDR, and the PLTE and tRNS chunks if there are any
+fn png_layout(path: &Path) -> (u8, u8, Option<Vec<u8>>, Option<Vec<u8>>) {
+    let bytes = fs::read(path).unwrap();
+    assert_eq!(&bytes[..8], b"\x89PNG\r\n\x1a\n");
+    let (mut ihdr, mut plte, mut trns) = (None, None, None);
+    let mut at = 8;
+    while at + 8 <= bytes.len() {
+        let len = u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+        let data = bytes[at + 8..at + 8 + len].to_vec();
+        match &bytes[at + 4..at + 8] {
+            b"IHDR" => ihdr = Some((data[8], data[9])),
+            b"PLTE" => plte = Some(data),
+            b"tRNS" => trns = Some(data),
+            _ => {}
+        }
+        at += 12 + len;
+    }
+    let (depth, color) = ihdr.unwrap();
+    (depth, color, plte, trns)
+}
+
+// Smooth in both directions and half transparent on the right, so no format keeps it by accident
+fn gradient(width: u32, height: u32) -> image::RgbaImage {
+    image::RgbaImage::from_fn(width, height, |x, y| {
+        image::Rgba([(x * 255 / width) as u8, (y * 255 / height) as u8, 128, if x < width / 2 { 255 } else { 128 }])
+    })
+}
+
+#[test]
+fn convert_keeps_dimensions_in_every_format_and_pixels_in_lossless_ones() {
+    let dir = scratch("convert");
+    let input = dir.join("in.png");
+    let source = image::DynamicImage::ImageRgba8(gradient(40, 30));
+    source.save(&input).unwrap();
+    for (format, ext, lossless) in [
+        ("png", "png", true),
+        ("webp", "webp", true),
+        ("tiff", "tiff", true),
+        ("tga", "tga", true),
+        ("bmp", "bmp", false),
+        ("jpeg", "jpg", false),
+        ("gif", "gif", false),
+    ] {
+        let output = dir.join(format!("out.{ext}"));
+        stderr(&run(&["image-convert", s(&input), "-f", format, "-o", s(&output)]));
+        let back = image::open(&output).unwrap();
+        assert_eq!((back.width(), back.height()), (40, 30), "{format}");
+        if lossless {
+            assert_eq!(back.to_rgba8(), source.to_rgba8(), "{format}");
+        }
+    }
+
+    // This build encodes AVIF without decoding it, so the size comes from the ispe property
+    let output = dir.join("out.avif");
+    stderr(&run(&["image-convert", s(&input), "-f", "avif", "--speed", "10", "-o", s(&output)]));
+    let bytes = fs::read(&output).unwrap();
+    assert_eq!(&bytes[4..12], b"ftypavif");
+    let at = bytes.windows(4).position(|w| w == b"ispe").unwrap() + 8;
+    let size = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+    assert_eq!((size(at), size(at + 4)), (40, 30));
+}
+
+#[test]
+fn quantized_and_palette_pngs_are_indexed_with_trns() {
+    let dir = scratch("palette");
+    let input = dir.join("in.png");
+    gradient(64, 64).save(&input).unwrap();
+
+    // --colors reduces a gradient to an indexed PNG, translucent entries in tRNS
+    let output = dir.join("q.png");
+    stderr(&run(&["image-convert", s(&input), "-f", "png", "--colors", "16", "-o", s(&output)]));
+    let (depth, color, plte, trns) = png_layout(&output);
+    assert_eq!((depth, color), (4, 3));
+    assert!(plte.unwrap().len() <= 16 * 3);
+    assert!(trns.unwrap().iter().all(|&a| a < 255));
+    let back = image::open(&output).unwrap().to_rgba8();
+    assert_eq!(back.dimensions(), (64, 64));
+    assert!(back.pixels().map(|px| px.0).collect::<std::collections::HashSet<_>>().len() <= 16);
+
+    // --png-palette keeps an image of few colors exact, at the smallest bit depth
+    let few = image::RgbaImage::from_fn(16, 16, |x, y| [image::Rgba([255, 0, 0, 255]), image::Rgba([0, 0, 255, 255]), image::Rgba([0, 0, 0, 0])][((x + y) % 3) as usize]);
+    let input = dir.join("few.png");
+    few.save(&input).unwrap();
+    let output = dir.join("few-indexed.png");
+    stderr(&run(&["image-convert", s(&input), "-f", "png", "--png-palette", "-o", s(&output)]));
+    let (depth, color, _, trns) = png_layout(&output);
+    assert_eq!((depth, color), (2, 3));
+    assert_eq!(trns.unwrap(), vec![0]);
+    assert_eq!(image::open(&output).unwrap().to_rgba8(), few);
+}
+
+#[test]
+fn sixteen_bit_sources_stay_sixteen_bit_unless_asked() {
+    let dir = scratch("depth");
+    let input = dir.join("deep.png");
+    let deep = image::ImageBuffer::<image::Rgb<u16>, _>::from_fn(20, 10, |x, y| image::Rgb([x as u16 * 3000, y as u16 * 6000, 12345]));
+    image::DynamicImage::ImageRgb16(deep.clone()).save(&input).unwrap();
+
+    let output = dir.join("copy.png");
+    stderr(&run(&["image-convert", s(&input), "-f", "png", "-o", s(&output)]));
+    let (depth, color, _, _) = png_layout(&output);
+    assert_eq!((depth, color), (16, 2));
+    assert_eq!(image::open(&output).unwrap().to_rgb16(), deep);
+
+    let output = dir.join("eight.png");
+    stderr(&run(&["image-convert", s(&input), "-f", "png", "--depth", "8", "-o", s(&output)]));
+    let (depth, color, _, _) = png_layout(&output);
+    assert_eq!((depth, color), (8, 2));
+    let scaled = dir.join("scaled.png");
+    stderr(&run(&["image-scale", s(&input), "--percent", "50", "-o", s(&scaled)]));
+    let (depth, color, _, _) = png_layout(&scaled);
+    assert_eq!((depth, color), (16, 2));
+    assert_eq!(image::image_dimensions(&scaled).unwrap(), (10, 5));
+}
+
+#[test]
+fn content_decides_the_format_over_the_extension() {
+    let dir = scratch("detect");
+    let input = dir.join("actually-png.jpg");
+    image::DynamicImage::ImageRgba8(gradient(24, 12)).save_with_format(&input, image::ImageFormat::Png).unwrap();
+    let output = dir.join("out.bmp");
+    let out = run(&["image-convert", s(&input), "-f", "bmp", "-o", s(&output)]);
+    assert_eq!(image::image_dimensions(&output).unwrap(), (24, 12));
+    // The mismatch is a warning in the log, never mixed into what goes to stdout
+    let log = stderr(&out);
+    assert!(log.contains("is PNG, not JPEG"), "{log}");
+    assert!(out.stdout.is_empty());
+    let out = run(&["-q", "image-convert", s(&input), "-f", "bmp", "-o", s(&output)]);
+    assert!(stderr(&out).is_empty() && out.stdout.is_empty());
+    let info = run(&["image-info", s(&input)]);
+    assert!(String::from_utf8_lossy(&info.stdout).contains("PNG"), "{}", String::from_utf8_lossy(&info.stdout));
+}
+
+#[test]
+fn compare_writes_a_heatmap_of_changed_pixels() {
+    let dir = scratch("compare");
+    let a = dir.join("a.png");
+    let b = dir.join("b.png");
+    let base = image::RgbaImage::from_pixel(16, 8, image::Rgba([40, 80, 120, 255]));
+    base.save(&a).unwrap();
+    let mut changed = base.clone();
+    changed.put_pixel(3, 2, image::Rgba([40, 80, 255, 255]));
+    changed.put_pixel(10, 5, image::Rgba([42, 80, 120, 255]));
+    changed.save(&b).unwrap();
+
+    let heatmap = dir.join("diff.png");
+    let out = run(&["image-compare", s(&a), s(&b), "--diff-output", s(&heatmap), "--diff-threshold", "2"]);
+    stderr(&out);
+    assert!(String::from_utf8_lossy(&out.stdout).contains("Changed pixels: 1 "));
+    let map = image::open(&heatmap).unwrap().to_rgb8();
+    assert_eq!(map.dimensions(), (16, 8));
+    assert_eq!(map.get_pixel(3, 2).0, [255, 255 - 135, 0]);
+    // Under the threshold, and untouched, both come out gray
+    let gray = map.get_pixel(10, 5).0;
+    assert!(gray[0] == gray[1] && gray[1] == gray[2] && gray == map.get_pixel(0, 0).0);
+}
+
+#[test]
+fn to_srgb_round_trips_through_an_embedded_profile() {
+    let dir = scratch("srgb");
+    let input = dir.join("in.png");
+    let source = image::RgbImage::from_fn(32, 16, |x, y| image::Rgb([(x * 8) as u8, (y * 16) as u8, 200]));
+    source.save(&input).unwrap();
+    let tagged = dir.join("tagged.png");
+    stderr(&run(&["image-convert", s(&input), "-f", "png", "--to-srgb", "--embed-srgb", "-o", s(&tagged)]));
+    let bytes = fs::read(&tagged).unwrap();
+    assert!(bytes.windows(4).any(|w| w == b"iCCP"));
+
+    // sRGB to sRGB through the transform leaves the colors where they were
+    let output = dir.join("out.png");
+    stderr(&run(&["image-convert", s(&tagged), "-f", "png", "--to-srgb", "-o", s(&output)]));
+    let back = image::open(&output).unwrap().to_rgb8();
+    assert_eq!(back.dimensions(), (32, 16));
+    for (a, b) in source.pixels().zip(back.pixels()) {
+        assert!((0..3).all(|c| a[c].abs_diff(b[c]) <= 1), "{:?} vs {:?}", a, b);
+    }
+}
+
+#[test]
+fn smart_fill_keeps_the_most_detailed_region() {
+    let dir = scratch("smart");
+    let input = dir.join("in.png");
+    // Flat on the left, a checkerboard on the right
+    image::RgbImage::from_fn(80, 20, |x, y| {
+        let v = if x >= 60 && (x + y) % 2 == 0 { 255 } else { 100 };
+        image::Rgb([v, v, v])
+    })
+    .save(&input)
+    .unwrap();
+    let fill = |flags: &[&str]| {
+        let output = dir.join("out.png");
+        stderr(&run(&[&["image-scale", s(&input), "--width", "20", "--height", "20", "--mode", "fill", "--filter", "nearest", "-o", s(&output)], flags].concat()));
+        let out = image::open(&output).unwrap().to_luma8();
+        assert_eq!(out.dimensions(), (20, 20));
+        out.pixels().map(|px| px[0]).collect::<std::collections::HashSet<_>>().len()
+    };
+    assert!(fill(&["--smart"]) > 1);
+    assert_eq!(fill(&["--gravity", "w"]), 1);
+}
+
+#[test]
+fn every_editing_command_writes_the_size_it_should() {
+    let dir = scratch("sizes");
+    let input = dir.join("in.png");
+    gradient(60, 40).save(&input).unwrap();
+    let size = |args: &[&str], name: &str| {
+        let output = dir.join(name);
+        stderr(&run(&[args, &[s(&input), "-o", s(&output)]].concat()));
+        image::image_dimensions(&output).unwrap()
+    };
+    assert_eq!(size(&["image-crop", "--rect", "10,5,20,10"], "crop.png"), (20, 10));
+    assert_eq!(size(&["image-crop", "--aspect", "1:1"], "square.png"), (40, 40));
+    assert_eq!(size(&["image-border", "--size", "3"], "border.png"), (66, 46));
+    assert_eq!(size(&["image-border", "--canvas", "100x50"], "canvas.png"), (100, 50));
+    assert_eq!(size(&["image-filter", "--blur", "1.5", "--sharpen"], "filter.png"), (60, 40));
+    assert_eq!(size(&["image-adjust", "--contrast", "20", "--invert"], "adjust.png"), (60, 40));
+    assert_eq!(size(&["image-keyout"], "keyed.png"), (60, 40));
+    assert_eq!(size(&["image-transform", "--rotate", "90"], "rotated.png"), (40, 60));
+    assert_eq!(size(&["image-scale", "--percent", "50"], "half.png"), (30, 20));
+
+    // A lossless edit undone comes back to the same pixels
+    let rotated = dir.join("rotated.png");
+    let back = dir.join("back.png");
+    stderr(&run(&["image-transform", s(&rotated), "--rotate", "270", "-o", s(&back)]));
+    assert_eq!(image::open(&back).unwrap().to_rgba8(), gradient(60, 40));
+}
@@ -0,0 +1,73 @@
+use jj_toolkit::{compression, crypt, hash};
+use std::fs;
+use std::io::Read;
+use std::process::Command;
+
+#[test]
+fn hash_file_matches_the_cli() {
+    let dir = std::env::temp_dir().join(format!("jj_toolkit_library_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("notes.txt");
+    fs::write(&file, "abc").unwrap();
+
+    let digest = hash::hash_file(&file, hash::Algorithm::Sha256, false).unwrap();
+    assert_eq!(digest, "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD");
+    let out = Command::new(env!("CARGO_BIN_EXE_jj_toolkit")).args(["hash", "-a", "sha256", file.to_str().unwrap()]).output().unwrap();
+    assert!(String::from_utf8_lossy(&out.stdout).contains(&digest));
+    assert_eq!(hash::hash_reader(&b"abc"[..], hash::Algorithm::Crc32, true).unwrap(), "891568578");
+}
+
+#[test]
+fn compress_reader_round_trips_every_algorithm() {
+    let text = "the quick brown fox jumps over the lazy dog\n".repeat(200);
+    for algorithm in [
+        compression::Algorithm::Zstd,
+        compression::Algorithm::Lz4,
+        compression::Algorithm::Brotli,
+        compression::Algorithm::Snappy,
+        compression::Algorithm::Gzip,
+        compression::Algorithm::Xz,
+        compression::Algorithm::Bzip2,
+    ] {
+        let mut packed = Vec::new();
+        compression::compress_reader(text.as_bytes(), &mut packed, algorithm, 5).unwrap();
+        assert!(packed.len() < text.len(), "{:?} did not shrink the input", algorithm);
+        let mut unpacked = String::new();
+        compression::decompress_reader(&packed[..], algorithm).unwrap().read_to_string(&mut unpacked).unwrap();
+        assert_eq!(unpacked, text, "{:?}", algorithm);
+    }
+}
+
+#[test]
+fn encrypt_stream_round_trips_and_rejects_a_wrong_password() {
+    // Cheap KDF costs keep the test fast; they travel in the header
+    let params = crypt::KdfParams { m_cost_kib: 64, t_cost: 1, p_cost: 1 };
+    let mut sealed = Vec::new();
+    crypt::encrypt_stream(&b"launch codes"[..], &mut sealed, "hunter2", params).unwrap();
+    assert!(sealed.starts_with(b"JJTOOL"));
+
+    let mut plain = Vec::new();
+    crypt::decrypt_stream(&sealed[..], &mut plain, "hunter2").unwrap();
+    assert_eq!(plain, b"launch codes");
+
+    let err = crypt::decrypt_stream(&sealed[..], &mut Vec::new(), "hunter3").unwrap_err();
+    assert!(err.to_string().contains("wrong password"));
+}
+
+#[test]
+fn encrypt_stream_to_opens_only_with_the_identity() {
+    // A throwaway pair from `keygen -a x25519 --for-crypt`
+    let recipient = "age16vs492lvkvw323egv5pph0g4ppgnflwzy28zvqn37tgt0yugvv7q8g087l";
+    let identity = "# public key: age16vs492lvkvw323egv5pph0g4ppgnflwzy28zvqn37tgt0yugvv7q8g087l\nAGE-SECRET-KEY-1Q96KTH6CFA5PYFMHU8DEZDHMLZLKMT9Y5J2QRJXPRSHD4D5TH4XQ9XPNZC\n";
+    let mut sealed = Vec::new();
+    crypt::encrypt_stream_to(&b"launch codes"[..], &mut sealed, recipient).unwrap();
+
+    let mut plain = Vec::new();
+    crypt::decrypt_stream_with(&sealed[..], &mut plain, identity).unwrap();
+    assert_eq!(plain, b"launch codes");
+
+    let err = crypt::decrypt_stream(&sealed[..], &mut Vec::new(), "hunter2").unwrap_err();
+    assert!(err.to_string().contains("recipient"), "{err}");
+    let other = "AGE-SECRET-KEY-1GFPYYSJZGFPYYSJZGFPYYSJZGFPYYSJZGFPYYSJZGFPYYSJZGFPQ4EGAEX";
+    assert!(crypt::decrypt_stream_with(&sealed[..], &mut Vec::new(), other).is_err());
+}
@@ -0,0 +1,454 @@
+use std::fs;
+use std::path::Path;
+
+mod common;
+use common::{run, s, scratch};
+
+const SQUARE: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="40" height="20" viewBox="0 0 40 20">
+  <rect width="40" height="20" fill="#ff0000"/>
+</svg>"##;
+
+fn write_svg(path: &Path) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, SQUARE).unwrap();
+}
+
+fn dimensions(path: &Path) -> (u32, u32) {
+    let image = image::open(path).unwrap();
+    (image.width(), image.height())
+}
+
+#[test]
+fn single_file_defaults_to_png_next_to_input() {
+    let dir = scratch("single");
+    let svg = dir.join("shape.svg");
+    write_svg(&svg);
+
+    let out = run(&["rasterize", s(&svg)]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let png = dir.join("shape.png");
+    assert_eq!(dimensions(&png), (40, 20));
+    let pixel = image::open(&png).unwrap().to_rgba8().get_pixel(20, 10).0;
+    assert_eq!(pixel, [255, 0, 0, 255]);
+}
+
+#[test]
+fn single_file_keeps_aspect_for_one_dimension() {
+    let dir = scratch("aspect");
+    let svg = dir.join("shape.svg");
+    write_svg(&svg);
+
+    let wide = dir.join("wide.png");
+    assert!(run(&["rasterize", s(&svg), "--width", "80", "-o", s(&wide)]).status.success());
+    assert_eq!(dimensions(&wide), (80, 40));
+
+    let tall = dir.join("tall.png");
+    assert!(run(&["rasterize", s(&svg), "--height", "10", "-o", s(&tall)]).status.success());
+    assert_eq!(dimensions(&tall), (20, 10));
+}
+
+#[test]
+fn single_file_bmp_into_output_dir() {
+    let dir = scratch("bmp");
+    let svg = dir.join("shape.svg");
+    write_svg(&svg);
+    let out_dir = dir.join("out");
+    fs::create_dir_all(&out_dir).unwrap();
+
+    assert!(run(&["rasterize", s(&svg), "-f", "bmp", "-o", s(&out_dir)]).status.success());
+    assert_eq!(dimensions(&out_dir.join("shape.bmp")), (40, 20));
+}
+
+#[test]
+fn single_file_refuses_to_overwrite() {
+    let dir = scratch("overwrite");
+    let svg = dir.join("shape.svg");
+    write_svg(&svg);
+    fs::write(dir.join("shape.png"), b"keep").unwrap();
+
+    assert!(!run(&["rasterize", s(&svg)]).status.success());
+    assert_eq!(fs::read(dir.join("shape.png")).unwrap(), b"keep");
+
+    assert!(run(&["rasterize", s(&svg), "--overwrite"]).status.success());
+    assert_eq!(dimensions(&dir.join("shape.png")), (40, 20));
+}
+
+#[test]
+fn single_file_rejects_non_svg() {
+    let dir = scratch("not_svg");
+    let txt = dir.join("notes.txt");
+    fs::write(&txt, "hello").unwrap();
+
+    assert!(!run(&["rasterize", s(&txt)]).status.success());
+}
+
+#[test]
+fn batch_only_renders_top_level_without_recursive() {
+    let dir = scratch("batch_flat");
+    let input = dir.join("in");
+    write_svg(&input.join("a.svg"));
+    write_svg(&input.join("nested/b.svg"));
+    fs::write(input.join("readme.txt"), "skip").unwrap();
+    let output = dir.join("out");
+
+    let out = run(&["rasterize", s(&input), "-o", s(&output), "-t", "2"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(output.join("a.png").is_file());
+    assert!(!output.join("nested/b.png").exists());
+    assert!(!output.join("readme.png").exists());
+}
+
+#[test]
+fn batch_recursive_mirrors_tree() {
+    let dir = scratch("batch_recursive");
+    let input = dir.join("in");
+    write_svg(&input.join("a.svg"));
+    write_svg(&input.join("nested/deeper/b.svg"));
+    let output = dir.join("out");
+
+    let out = run(&["rasterize", s(&input), "-r", "-f", "bmp", "-o", s(&output)]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(dimensions(&output.join("a.bmp")), (40, 20));
+    assert_eq!(dimensions(&output.join("nested/deeper/b.bmp")), (40, 20));
+    assert!(String::from_utf8_lossy(&out.stdout).contains("Processed 2 file(s): 2 succeeded, 0 failed"));
+}
+
+#[test]
+fn batch_defaults_to_rasterized_subfolder_and_skips_existing() {
+    let dir = scratch("batch_default");
+    write_svg(&dir.join("a.svg"));
+    write_svg(&dir.join("b.svg"));
+    let output = dir.join("rasterized");
+    fs::create_dir_all(&output).unwrap();
+    fs::write(output.join("a.png"), b"keep").unwrap();
+
+    assert!(run(&["rasterize", s(&dir)]).status.success());
+    assert_eq!(fs::read(output.join("a.png")).unwrap(), b"keep");
+    assert_eq!(dimensions(&output.join("b.png")), (40, 20));
+}
+
+#[test]
+fn batch_reports_broken_files_and_fails() {
+    let dir = scratch("batch_broken");
+    let input = dir.join("in");
+    write_svg(&input.join("good.svg"));
+    fs::write(input.join("bad.svg"), "<svg").unwrap();
+    let output = dir.join("out");
+
+    let out = run(&["rasterize", s(&input), "-o", s(&output), "--keep-going"]);
+    assert!(!out.status.success());
+    assert!(output.join("good.png").is_file());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("bad.svg"));
+}
+
+#[test]
+fn batch_stops_at_first_failure_without_keep_going() {
+    let dir = scratch("batch_fail_fast");
+    let input = dir.join("in");
+    fs::create_dir_all(&input).unwrap();
+    fs::write(input.join("a_bad.svg"), "<svg").unwrap();
+    write_svg(&input.join("b_good.svg"));
+    let output = dir.join("out");
+
+    let out = run(&["rasterize", s(&input), "-o", s(&output), "-t", "1"]);
+    assert!(!out.status.success());
+    assert!(!output.join("b_good.png").exists());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("0 succeeded, 1 failed, 1 skipped"));
+}
+
+#[test]
+fn batch_json_summary_lists_paths_and_errors() {
+    let dir = scratch("batch_json");
+    let input = dir.join("in");
+    write_svg(&input.join("good.svg"));
+    fs::write(input.join("bad.svg"), "<svg").unwrap();
+    let output = dir.join("out");
+
+    let out = run(&["rasterize", s(&input), "-o", s(&output), "-k", "--summary", "json"]);
+    assert!(!out.status.success());
+    let summary: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(summary["succeeded"][0].as_str().unwrap(), s(&input.join("good.svg")));
+    assert_eq!(summary["failed"][0]["path"].as_str().unwrap(), s(&input.join("bad.svg")));
+    assert!(summary["failed"][0]["error"].as_str().unwrap().contains("Parse SVG"));
+    assert_eq!(summary["skipped"], 0);
+    assert!(summary["elapsed_secs"].as_f64().unwrap() >= 0.0);
+}
+
+#[test]
+fn pdf_page_keeps_physical_size() {
+    let dir = scratch("pdf");
+    let svg = dir.join("a4.svg");
+    fs::write(&svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="210mm" height="297mm"><rect width="50" height="50"/></svg>"#).unwrap();
+
+    assert!(run(&["rasterize", s(&svg), "-f", "pdf"]).status.success());
+    let pdf = fs::read(dir.join("a4.pdf")).unwrap();
+    assert!(pdf.starts_with(b"%PDF-"));
+    // A4 in points
+    assert!(String::from_utf8_lossy(&pdf).contains("/MediaBox [0 0 595.27563 841.8898]"));
+}
+
+#[test]
+fn missing_font_sources_fail_early() {
+    let dir = scratch("fonts");
+    let svg = dir.join("shape.svg");
+    write_svg(&svg);
+
+    assert!(!run(&["rasterize", s(&svg), "--font-file", s(&dir.join("missing.ttf"))]).status.success());
+    assert!(!run(&["rasterize", s(&svg), "--font-dir", s(&dir.join("missing"))]).status.success());
+    assert!(!dir.join("shape.png").exists());
+}
+
+#[test]
+fn background_fills_behind_drawing() {
+    let dir = scratch("background");
+    let svg = dir.join("dot.svg");
+    fs::write(&svg, r##"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"><rect width="5" height="5" fill="#0000ff"/></svg>"##).unwrap();
+
+    let transparent = dir.join("transparent.png");
+    assert!(run(&["rasterize", s(&svg), "-o", s(&transparent)]).status.success());
+    assert_eq!(image::open(&transparent).unwrap().to_rgba8().get_pixel(8, 8).0, [0, 0, 0, 0]);
+
+    let filled = dir.join("filled.bmp");
+    assert!(run(&["rasterize", s(&svg), "-f", "bmp", "--background", "#00ff00", "-o", s(&filled)]).status.success());
+    let rgba = image::open(&filled).unwrap().to_rgba8();
+    assert_eq!(rgba.get_pixel(8, 8).0, [0, 255, 0, 255]);
+    assert_eq!(rgba.get_pixel(2, 2).0, [0, 0, 255, 255]);
+
+    assert!(!run(&["rasterize", s(&svg), "--background", "nope", "--overwrite"]).status.success());
+}
+
+const SPRITE: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="80" height="40" viewBox="0 0 40 20">
+  <rect id="red" width="10" height="10" fill="#ff0000"/>
+  <g transform="translate(20 5)"><rect id="blue" width="10" height="10" fill="#0000ff"/></g>
+</svg>"##;
+
+#[test]
+fn export_id_renders_only_that_element_area() {
+    let dir = scratch("export_id");
+    let svg = dir.join("sprite.svg");
+    fs::write(&svg, SPRITE).unwrap();
+
+    let blue = dir.join("blue.png");
+    let out = run(&["rasterize", s(&svg), "--export-id", "blue", "-o", s(&blue)]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let rgba = image::open(&blue).unwrap().to_rgba8();
+    assert_eq!(rgba.dimensions(), (20, 20));
+    assert!(rgba.pixels().all(|p| p.0 == [0, 0, 255, 255]));
+
+    assert!(!run(&["rasterize", s(&svg), "--export-id", "green", "--overwrite"]).status.success());
+}
+
+#[test]
+fn viewbox_uses_svg_user_units() {
+    let dir = scratch("viewbox");
+    let svg = dir.join("sprite.svg");
+    fs::write(&svg, SPRITE).unwrap();
+
+    let red = dir.join("red.png");
+    assert!(run(&["rasterize", s(&svg), "--viewbox", "0,0,10,10", "--width", "64", "-o", s(&red)]).status.success());
+    let rgba = image::open(&red).unwrap().to_rgba8();
+    assert_eq!(rgba.dimensions(), (64, 64));
+    assert!(rgba.pixels().all(|p| p.0 == [255, 0, 0, 255]));
+
+    assert!(!run(&["rasterize", s(&svg), "--viewbox", "0,0,10", "--overwrite"]).status.success());
+    assert!(!run(&["rasterize", s(&svg), "--viewbox", "0,0,10,10", "-f", "pdf"]).status.success());
+}
+
+#[test]
+fn sizes_render_one_square_per_size() {
+    let dir = scratch("sizes");
+    let svg = dir.join("icon.svg");
+    write_svg(&svg);
+    let icons = dir.join("icons");
+
+    let out = run(&["rasterize", s(&svg), "--sizes", "16,32,64", "-o", s(&icons)]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    for size in [16, 32, 64] {
+        assert_eq!(dimensions(&icons.join(format!("icon_{size}.png"))), (size, size));
+    }
+
+    assert!(!run(&["rasterize", s(&svg), "--sizes", "16", "-o", s(&dir.join("icon.png"))]).status.success());
+    assert!(!run(&["rasterize", s(&svg), "--sizes", "16", "--width", "8"]).status.success());
+}
+
+#[test]
+fn sizes_in_batch_mode_land_in_mirrored_folders() {
+    let dir = scratch("sizes_batch");
+    let input = dir.join("in");
+    write_svg(&input.join("sub/app.svg"));
+    let output = dir.join("out");
+
+    assert!(run(&["rasterize", s(&input), "-r", "--sizes", "48,96", "-o", s(&output)]).status.success());
+    assert_eq!(dimensions(&output.join("sub/app_48.png")), (48, 48));
+    assert_eq!(dimensions(&output.join("sub/app_96.png")), (96, 96));
+}
+
+#[test]
+fn scales_write_density_variants() {
+    let dir = scratch("scales");
+    let svg = dir.join("logo.svg");
+    write_svg(&svg);
+
+    let out = run(&["rasterize", s(&svg), "--width", "20", "--scales", "1,2,3"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(dimensions(&dir.join("logo.png")), (20, 10));
+    assert_eq!(dimensions(&dir.join("logo@2x.png")), (40, 20));
+    assert_eq!(dimensions(&dir.join("logo@3x.png")), (60, 30));
+
+    let half = dir.join("half.png");
+    assert!(run(&["rasterize", s(&svg), "-s", "0.5", "-o", s(&half)]).status.success());
+    assert_eq!(dimensions(&half), (20, 10));
+}
+
+#[test]
+fn name_template_uses_rendered_size() {
+    let dir = scratch("name_template");
+    let input = dir.join("in");
+    write_svg(&input.join("a.svg"));
+    write_svg(&input.join("nested/b.svg"));
+    let output = dir.join("out");
+
+    let template = "{stem}-{width}x{height}.{ext}";
+    let out = run(&["rasterize", s(&input), "-r", "-o", s(&output), "--width", "80", "--name-template", template]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(dimensions(&output.join("a-80x40.png")), (80, 40));
+    assert_eq!(dimensions(&output.join("nested/b-80x40.png")), (80, 40));
+    assert!(!output.join("a.png").exists());
+
+    fs::write(output.join("a-80x40.png"), b"keep").unwrap();
+    assert!(run(&["rasterize", s(&input), "-o", s(&output), "--width", "80", "--name-template", template]).status.success());
+    assert_eq!(fs::read(output.join("a-80x40.png")).unwrap(), b"keep");
+}
+
+#[test]
+fn name_template_rejects_unknown_fields() {
+    let dir = scratch("name_template_bad");
+    write_svg(&dir.join("a.svg"));
+
+    let out = run(&["rasterize", s(&dir), "--name-template", "{name}.png"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Unknown field '{name}'"));
+}
+
+#[test]
+fn svgz_input_is_gunzipped() {
+    use std::io::Write;
+    let dir = scratch("svgz");
+    let svgz = dir.join("shape.svgz");
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(SQUARE.as_bytes()).unwrap();
+    fs::write(&svgz, encoder.finish().unwrap()).unwrap();
+
+    let out = run(&["rasterize", s(&svgz)]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(dimensions(&dir.join("shape.png")), (40, 20));
+
+    let region = dir.join("region.png");
+    assert!(run(&["rasterize", s(&svgz), "--viewbox", "0,0,10,10", "-o", s(&region)]).status.success());
+    assert_eq!(dimensions(&region), (10, 10));
+
+    let batch = dir.join("batch");
+    assert!(run(&["rasterize", s(&dir), "-o", s(&batch)]).status.success());
+    assert!(batch.join("shape.png").is_file());
+}
+
+#[test]
+fn ico_bundles_canonical_sizes() {
+    let dir = scratch("ico");
+    let svg = dir.join("app.svg");
+    write_svg(&svg);
+
+    let out = run(&["rasterize", s(&svg), "-f", "ico"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let ico = fs::read(dir.join("app.ico")).unwrap();
+    // ICONDIR: reserved, type 1, image count
+    assert_eq!(&ico[..4], &[0, 0, 1, 0]);
+    assert_eq!(u16::from_le_bytes([ico[4], ico[5]]), 7);
+    // The decoder picks the largest entry; 256 is stored as 0
+    assert_eq!(dimensions(&dir.join("app.ico")), (256, 256));
+
+    let small = dir.join("small.ico");
+    assert!(run(&["rasterize", s(&svg), "-f", "ico", "--sizes", "32,16", "-o", s(&small)]).status.success());
+    assert_eq!(dimensions(&small), (32, 32));
+    assert!(!run(&["rasterize", s(&svg), "-f", "ico", "--sizes", "512", "--overwrite"]).status.success());
+}
+
+#[test]
+fn icns_has_png_entries() {
+    let dir = scratch("icns");
+    let svg = dir.join("app.svg");
+    write_svg(&svg);
+
+    let out = run(&["rasterize", s(&svg), "-f", "icns", "--sizes", "16,32"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let icns = fs::read(dir.join("app.icns")).unwrap();
+    assert_eq!(&icns[..4], b"icns");
+    assert_eq!(u32::from_be_bytes(icns[4..8].try_into().unwrap()) as usize, icns.len());
+
+    let mut types = Vec::new();
+    let mut at = 8;
+    while at < icns.len() {
+        let len = u32::from_be_bytes(icns[at + 4..at + 8].try_into().unwrap()) as usize;
+        let png = image::load_from_memory(&icns[at + 8..at + len]).unwrap();
+        types.push((String::from_utf8_lossy(&icns[at..at + 4]).to_string(), png.width()));
+        at += len;
+    }
+    assert_eq!(types, [("icp4".to_string(), 16), ("icp5".to_string(), 32), ("ic11".to_string(), 32)]);
+
+    assert!(!run(&["rasterize", s(&svg), "-f", "icns", "--sizes", "48", "--overwrite"]).status.success());
+    assert!(!run(&["rasterize", s(&svg), "-f", "icns", "--width", "64", "--overwrite"]).status.success());
+}
+
+#[test]
+fn max_pixels_refuses_huge_outputs() {
+    let dir = scratch("max_pixels");
+    let svg = dir.join("shape.svg");
+    write_svg(&svg);
+
+    let out = run(&["rasterize", s(&svg), "--width", "100000"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("above --max-pixels"));
+    assert!(!dir.join("shape.png").exists());
+
+    assert!(!run(&["rasterize", s(&svg), "--width", "400", "--max-pixels", "1000"]).status.success());
+}
+
+#[test]
+fn tiled_render_matches_single_pass() {
+    let dir = scratch("tiled");
+    let svg = dir.join("shape.svg");
+    write_svg(&svg);
+    let whole = dir.join("whole.png");
+    let tiled = dir.join("tiled.png");
+
+    assert!(run(&["rasterize", s(&svg), "--width", "400", "-o", s(&whole)]).status.success());
+    // 400px wide strips of 7 rows, the last one shorter
+    let out = run(&["rasterize", s(&svg), "--width", "400", "--tiled", "--max-pixels", "2800", "-o", s(&tiled)]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    assert_eq!(image::open(&whole).unwrap().to_rgba8(), image::open(&tiled).unwrap().to_rgba8());
+    assert!(!run(&["rasterize", s(&svg), "-f", "bmp", "--tiled"]).status.success());
+}
+
+#[test]
+fn max_memory_renders_png_in_strips_and_refuses_other_formats() {
+    let dir = scratch("max_memory");
+    let svg = dir.join("shape.svg");
+    write_svg(&svg);
+    let whole = dir.join("whole.png");
+    let limited = dir.join("limited.png");
+
+    assert!(run(&["rasterize", s(&svg), "--width", "400", "-o", s(&whole)]).status.success());
+    // 400x200 at 8 bytes a pixel is 625 KiB
+    let out = run(&["rasterize", s(&svg), "--width", "400", "--max-memory", "100K", "-o", s(&limited)]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(image::open(&whole).unwrap().to_rgba8(), image::open(&limited).unwrap().to_rgba8());
+
+    let bmp = dir.join("limited.bmp");
+    let out = run(&["rasterize", s(&svg), "--width", "400", "-f", "bmp", "--max-memory", "100K", "-o", s(&bmp)]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("above --max-memory"));
+    assert!(!bmp.exists());
+}
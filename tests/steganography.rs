@@ -0,0 +1,397 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+mod common;
+use common::{run, s, scratch, stdout};
+
+// Opaque gradient so every channel byte starts with a known value
+fn write_carrier(path: &Path) {
+    image::RgbaImage::from_fn(64, 48, |x, y| image::Rgba([(x * 4) as u8, (y * 5) as u8, 128, 255]))
+        .save(path)
+        .unwrap();
+}
+
+// Grayscale baseline JPEG, 256x8, whose every block holds a -1023 AC coefficient followed by 62
+// twos, written by hand since no encoder produces such values from pixels. The Huffman tables
+// have a 1-bit DC code for a zero difference and 2-bit AC codes for sizes 2 and 10
+fn write_extreme_jpeg(path: &Path) {
+    fn segment(out: &mut Vec<u8>, marker: u8, seg: &[u8]) {
+        out.extend_from_slice(&[0xFF, marker]);
+        out.extend_from_slice(&(seg.len() as u16 + 2).to_be_bytes());
+        out.extend_from_slice(seg);
+    }
+    let mut out = vec![0xFF, 0xD8];
+    segment(&mut out, 0xDB, &[[0u8].as_slice(), &[1; 64]].concat());
+    segment(&mut out, 0xC0, &[8, 0, 8, 1, 0, 1, 1, 0x11, 0]);
+    let mut dht = vec![0x00, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x00];
+    dht.extend_from_slice(&[0x10, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x02, 0x0A]);
+    segment(&mut out, 0xC4, &dht);
+    segment(&mut out, 0xDA, &[1, 1, 0x00, 0, 63, 0]);
+
+    let mut bits = Vec::new();
+    for _ in 0..32 {
+        // DC 0, then code 01 with the ten bits of -1023, then 62 times code 00 with the bits of 2
+        bits.push(0);
+        bits.extend_from_slice(&[0, 1]);
+        bits.extend_from_slice(&[0; 10]);
+        for _ in 0..62 {
+            bits.extend_from_slice(&[0, 0, 1, 0]);
+        }
+    }
+    while bits.len() % 8 != 0 {
+        bits.push(1);
+    }
+    for byte in bits.chunks(8).map(|b| b.iter().fold(0u8, |acc, &bit| (acc << 1) | bit)) {
+        out.push(byte);
+        if byte == 0xFF {
+            out.push(0);
+        }
+    }
+    out.extend_from_slice(&[0xFF, 0xD9]);
+    fs::write(path, out).unwrap();
+}
+
+#[test]
+fn message_round_trip() {
+    let dir = scratch("round_trip");
+    let carrier = dir.join("cover.png");
+    write_carrier(&carrier);
+
+    stdout(&run(&["stegano-embed", s(&carrier), "-m", "hello there"]));
+    let embedded = dir.join("cover_embedded.png");
+    assert_eq!(stdout(&run(&["stegano-extract", s(&embedded)])), "hello there");
+}
+
+#[test]
+fn file_round_trip() {
+    let dir = scratch("file");
+    let carrier = dir.join("cover.png");
+    write_carrier(&carrier);
+    let secret = dir.join("secret.bin");
+    fs::write(&secret, [0u8, 159, 146, 150, 255, 1, 2]).unwrap();
+    let embedded = dir.join("out.png");
+    let recovered = dir.join("recovered.bin");
+
+    stdout(&run(&["stegano-embed", s(&carrier), "-f", s(&secret), "-o", s(&embedded)]));
+    stdout(&run(&["stegano-extract", s(&embedded), "-o", s(&recovered)]));
+    assert_eq!(fs::read(&recovered).unwrap(), fs::read(&secret).unwrap());
+}
+
+#[test]
+fn file_name_is_restored_by_default() {
+    let dir = scratch("file_name");
+    let carrier = dir.join("cover.png");
+    write_carrier(&carrier);
+    let secret = dir.join("report.pdf");
+    fs::write(&secret, b"%PDF-1.7 not really").unwrap();
+    let embedded = dir.join("out.png");
+    stdout(&run(&["stegano-embed", s(&carrier), "-f", s(&secret), "-o", s(&embedded)]));
+
+    let restore = dir.join("restore");
+    fs::create_dir_all(&restore).unwrap();
+    let extract = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_jj_toolkit"))
+            .args(["stegano-extract", s(&embedded)])
+            .args(args)
+            .current_dir(&restore)
+            .output()
+            .unwrap()
+    };
+    assert!(stdout(&extract(&[])).contains("application/pdf"));
+    assert_eq!(fs::read(restore.join("report.pdf")).unwrap(), fs::read(&secret).unwrap());
+
+    // Never clobbers what is already there
+    let again = extract(&[]);
+    assert!(!again.status.success());
+    assert!(String::from_utf8_lossy(&again.stderr).contains("Output exists"));
+
+    // A link planted at the embedded name is replaced, not written through
+    #[cfg(unix)]
+    {
+        let victim = dir.join("victim.txt");
+        fs::write(&victim, "untouched").unwrap();
+        fs::remove_file(restore.join("report.pdf")).unwrap();
+        std::os::unix::fs::symlink(&victim, restore.join("report.pdf")).unwrap();
+        stdout(&extract(&["--force"]));
+        assert_eq!(fs::read(&victim).unwrap(), b"untouched");
+        assert!(!fs::symlink_metadata(restore.join("report.pdf")).unwrap().is_symlink());
+        assert_eq!(fs::read(restore.join("report.pdf")).unwrap(), fs::read(&secret).unwrap());
+    }
+}
+
+#[test]
+fn key_scatters_bits_and_is_needed_to_extract() {
+    let dir = scratch("key");
+    let carrier = dir.join("cover.png");
+    write_carrier(&carrier);
+    let embedded = dir.join("keyed.png");
+
+    stdout(&run(&["stegano-embed", s(&carrier), "-m", "meet at noon", "-k", "hunter2", "-o", s(&embedded)]));
+    assert_eq!(stdout(&run(&["stegano-extract", s(&embedded), "-k", "hunter2"])), "meet at noon");
+
+    // Sequential embedding would have changed the first row; a scattered one spreads the changes out
+    let before = image::open(&carrier).unwrap().to_rgba8();
+    let after = image::open(&embedded).unwrap().to_rgba8();
+    let changed_rows: std::collections::HashSet<u32> = before
+        .enumerate_pixels()
+        .filter(|&(x, y, p)| after.get_pixel(x, y) != p)
+        .map(|(_, y, _)| y)
+        .collect();
+    assert!(changed_rows.len() > 10, "{changed_rows:?}");
+
+    assert!(!run(&["stegano-extract", s(&embedded)]).status.success());
+    assert!(!run(&["stegano-extract", s(&embedded), "-k", "hunter3"]).status.success());
+}
+
+#[test]
+fn alpha_is_left_alone_by_default() {
+    let dir = scratch("channels");
+    let carrier = dir.join("cover.png");
+    write_carrier(&carrier);
+    let rgb = dir.join("rgb.png");
+    let rgba = dir.join("rgba.png");
+    let message = "x".repeat(800);
+
+    stdout(&run(&["stegano-embed", s(&carrier), "-m", &message, "-o", s(&rgb)]));
+    let pixels = image::open(&rgb).unwrap().to_rgba8();
+    assert!(pixels.pixels().all(|p| p[3] == 255));
+    assert_eq!(stdout(&run(&["stegano-extract", s(&rgb)])), message);
+
+    stdout(&run(&["stegano-embed", s(&carrier), "-m", &message, "--channels", "rgba", "-o", s(&rgba)]));
+    let pixels = image::open(&rgba).unwrap().to_rgba8();
+    assert!(pixels.pixels().any(|p| p[3] == 254));
+    assert_eq!(stdout(&run(&["stegano-extract", s(&rgba), "--channels", "rgba"])), message);
+}
+
+#[test]
+fn plain_image_reports_no_data() {
+    let dir = scratch("no_data");
+    let carrier = dir.join("cover.png");
+    write_carrier(&carrier);
+
+    let out = run(&["stegano-extract", s(&carrier)]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("No embedded data found"));
+}
+
+#[test]
+fn damaged_payload_fails_checksum() {
+    let dir = scratch("corrupt");
+    let carrier = dir.join("cover.png");
+    write_carrier(&carrier);
+    let embedded = dir.join("embedded.png");
+    stdout(&run(&["stegano-embed", s(&carrier), "-m", &"payload ".repeat(20), "-o", s(&embedded)]));
+
+    // Pixel 60 is past the 21 byte header (56 pixels of RGB) and inside the payload
+    let mut pixels = image::open(&embedded).unwrap().to_rgba8();
+    pixels.get_pixel_mut(60, 0)[0] ^= 1;
+    pixels.save(&embedded).unwrap();
+
+    let out = run(&["stegano-extract", s(&embedded)]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("checksum mismatch"));
+}
+
+#[test]
+fn lossless_carriers_round_trip() {
+    let dir = scratch("carriers");
+    let carrier = dir.join("cover.png");
+    write_carrier(&carrier);
+
+    for ext in ["png", "bmp", "tiff", "webp"] {
+        let embedded = dir.join(format!("out.{ext}"));
+        stdout(&run(&["stegano-embed", s(&carrier), "-m", "lossless", "-o", s(&embedded)]));
+        assert_eq!(stdout(&run(&["stegano-extract", s(&embedded)])), "lossless", "{ext}");
+    }
+}
+
+#[test]
+fn lossy_carriers_are_refused_or_replaced() {
+    let dir = scratch("lossy");
+    let png = dir.join("cover.png");
+    write_carrier(&png);
+    let jpeg = dir.join("photo.jpg");
+    image::open(&png).unwrap().to_rgb8().save(&jpeg).unwrap();
+
+    let out = run(&["stegano-embed", s(&png), "-m", "x", "-o", s(&dir.join("out.jpg"))]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("lossy"));
+
+    // A JPEG input is fine as long as the result is written losslessly
+    stdout(&run(&["stegano-embed", s(&jpeg), "-m", "from a jpeg"]));
+    let embedded = dir.join("photo_embedded.png");
+    assert_eq!(stdout(&run(&["stegano-extract", s(&embedded)])), "from a jpeg");
+}
+
+#[test]
+fn split_payload_across_covers() {
+    let dir = scratch("split");
+    let covers = dir.join("covers");
+    fs::create_dir_all(&covers).unwrap();
+    // 20x20 RGB holds 150 bytes, 92 after the header, file name and MIME type
+    for name in ["a", "b", "c", "d"] {
+        image::RgbaImage::from_pixel(20, 20, image::Rgba([90, 120, 150, 255]))
+            .save(covers.join(format!("{name}.png")))
+            .unwrap();
+    }
+    let secret = dir.join("secret.bin");
+    let bytes: Vec<u8> = (0..200u32).map(|i| (i * 7) as u8).collect();
+    fs::write(&secret, &bytes).unwrap();
+    let parts = dir.join("parts");
+
+    let out = run(&["stegano-embed", "--covers", s(&covers), "--split", "-f", s(&secret), "-o", s(&parts)]);
+    stdout(&out);
+    assert!(parts.join("c.png").is_file());
+    assert!(!parts.join("d.png").exists());
+
+    let recovered = dir.join("recovered.bin");
+    stdout(&run(&["stegano-extract", s(&parts), "-o", s(&recovered)]));
+    assert_eq!(fs::read(&recovered).unwrap(), bytes);
+
+    let single = run(&["stegano-extract", s(&parts.join("a.png"))]);
+    assert!(String::from_utf8_lossy(&single.stderr).contains("part 1 of 3"));
+
+    fs::remove_file(parts.join("b.png")).unwrap();
+    let out = run(&["stegano-extract", s(&parts), "-o", s(&recovered)]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Missing part(s) 2 of 3"));
+}
+
+#[test]
+fn split_fails_when_covers_are_too_small() {
+    let dir = scratch("split_small");
+    let covers = dir.join("covers");
+    fs::create_dir_all(&covers).unwrap();
+    image::RgbaImage::from_pixel(16, 16, image::Rgba([0, 0, 0, 255])).save(covers.join("a.png")).unwrap();
+
+    let out = run(&["stegano-embed", "--covers", s(&covers), "--split", "-m", &"y".repeat(100)]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("too small"));
+}
+
+#[test]
+fn detect_tells_clean_from_embedded() {
+    let dir = scratch("detect");
+    let clean = dir.join("clean.png");
+    image::RgbImage::from_fn(320, 240, |x, y| {
+        let v = (((x as f32 / 7.0).sin() * (y as f32 / 5.0).cos()) * 60.0 + 128.0) as u8;
+        image::Rgb([(x * 255 / 320) as u8, v, (y * 255 / 240) as u8])
+    })
+    .save(&clean)
+    .unwrap();
+
+    // Random bytes filling most of the capacity
+    let mut state = 0x2545_f491_u32;
+    let noise: Vec<u8> = (0..25_000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+    let secret = dir.join("noise.bin");
+    fs::write(&secret, noise).unwrap();
+    let embedded = dir.join("embedded.png");
+    stdout(&run(&["stegano-embed", s(&clean), "-f", s(&secret), "-o", s(&embedded)]));
+
+    assert!(stdout(&run(&["stegano-detect", s(&clean)])).contains("LSB embedding: unlikely"));
+    assert!(stdout(&run(&["stegano-detect", s(&embedded)])).contains("LSB embedding: likely"));
+}
+
+#[test]
+fn batch_embed_pairs_payloads_with_covers() {
+    let dir = scratch("batch");
+    let (covers, payloads) = (dir.join("covers"), dir.join("payloads"));
+    fs::create_dir_all(covers.join("sub")).unwrap();
+    fs::create_dir_all(payloads.join("sub")).unwrap();
+    write_carrier(&covers.join("alpha.png"));
+    write_carrier(&covers.join("sub/beta.bmp"));
+    fs::write(payloads.join("alpha.txt"), b"first payload").unwrap();
+    fs::write(payloads.join("sub/beta.bin"), [9u8, 8, 7, 6]).unwrap();
+    let out = dir.join("out");
+
+    let flat = run(&["stegano-embed", s(&covers), "-f", s(&payloads), "-o", s(&out)]);
+    assert!(stdout(&flat).contains("1 succeeded"));
+    let _ = fs::remove_dir_all(&out);
+
+    stdout(&run(&["stegano-embed", s(&covers), "-f", s(&payloads), "-r", "-o", s(&out)]));
+    assert!(out.join("alpha.png").is_file());
+    assert!(out.join("sub/beta.bmp").is_file());
+    let manifest = fs::read_to_string(out.join("manifest.json")).unwrap();
+    assert!(manifest.contains("\"sub/beta.bin\""));
+
+    let restored = dir.join("restored");
+    stdout(&run(&["stegano-extract", s(&out), "-o", s(&restored)]));
+    assert_eq!(fs::read(restored.join("alpha.txt")).unwrap(), b"first payload");
+    assert_eq!(fs::read(restored.join("sub/beta.bin")).unwrap(), [9u8, 8, 7, 6]);
+
+    // By name nothing matches a lone cover; round-robin reuses it
+    let single = dir.join("single");
+    fs::create_dir_all(&single).unwrap();
+    write_carrier(&single.join("cover.png"));
+    let unmatched = run(&["stegano-embed", s(&single), "-f", s(&payloads), "-r", "-o", s(&dir.join("none"))]);
+    assert!(String::from_utf8_lossy(&unmatched.stderr).contains("named like"));
+    let rr = dir.join("rr");
+    stdout(&run(&["stegano-embed", s(&single), "-f", s(&payloads), "-r", "--pair", "round-robin", "-o", s(&rr)]));
+    assert!(rr.join("alpha.png").is_file());
+    assert!(rr.join("sub/beta.png").is_file());
+}
+
+#[test]
+fn payload_streams_through_stdin_and_stdout() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let dir = scratch("stdio");
+    let carrier = dir.join("cover.png");
+    write_carrier(&carrier);
+    let embedded = dir.join("out.png");
+    let bytes: Vec<u8> = (0..=255u8).rev().collect();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_jj_toolkit"))
+        .args(["stegano-embed", s(&carrier), "-m", "-", "-o", s(&embedded)])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(&bytes).unwrap();
+    stdout(&child.wait_with_output().unwrap());
+
+    let out = run(&["stegano-extract", s(&embedded), "-o", "-"]);
+    assert!(out.status.success());
+    assert_eq!(out.stdout, bytes);
+}
+
+#[test]
+fn dct_embedding_keeps_a_jpeg_cover() {
+    let dir = scratch("dct");
+    let cover = dir.join("photo.jpg");
+    // Texture leaves plenty of non-zero AC coefficients
+    let img = image::RgbImage::from_fn(128, 96, |x, y| {
+        let v = ((x * 37 + y * 91) ^ (x * y)) as u8;
+        image::Rgb([v, v.wrapping_mul(3), 255 - v])
+    });
+    let mut jpeg = Vec::new();
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 90).encode_image(&img).unwrap();
+    fs::write(&cover, jpeg).unwrap();
+
+    stdout(&run(&["stegano-embed", s(&cover), "--dct", "-m", "shared as a jpeg", "-k", "pw"]));
+    let embedded = dir.join("photo_embedded.jpg");
+    assert!(image::open(&embedded).is_ok());
+    assert_eq!(stdout(&run(&["stegano-extract", s(&embedded), "-k", "pw"])), "shared as a jpeg");
+
+    // -1023 carries nothing, as setting its LSB could leave a -1024 no baseline table can code
+    let extreme = dir.join("extreme.jpg");
+    write_extreme_jpeg(&extreme);
+    let embedded = dir.join("extreme_embedded.jpg");
+    stdout(&run(&["stegano-embed", s(&extreme), "--dct", "-m", "at the edge of the range", "-o", s(&embedded)]));
+    assert!(image::open(&embedded).is_ok());
+    assert_eq!(stdout(&run(&["stegano-extract", s(&embedded)])), "at the edge of the range");
+
+    let png = dir.join("cover.png");
+    write_carrier(&png);
+    let out = run(&["stegano-embed", s(&png), "--dct", "-m", "x"]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("baseline JPEG"));
+}
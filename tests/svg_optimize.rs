@@ -0,0 +1,118 @@
+use std::fs;
+use std::path::Path;
+
+mod common;
+use common::{run, s, scratch};
+
+const NESTED: &str = r##"<?xml version="1.0"?>
+<!-- exported by an editor -->
+<svg xmlns="http://www.w3.org/2000/svg" width="40" height="40" viewBox="0 0 40 40">
+  <defs>
+    <linearGradient id="fade"><stop offset="0" stop-color="#ff0000"/><stop offset="1" stop-color="#0000ff"/></linearGradient>
+  </defs>
+  <g id="layer1" transform="translate(4,4)">
+    <g transform="scale(2)">
+      <rect id="box" x="0.123456789" width="10" height="10" fill="url(#fade)"/>
+    </g>
+  </g>
+</svg>"##;
+
+fn write_svg(path: &Path) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, NESTED).unwrap();
+}
+
+#[test]
+fn minifies_next_to_input() {
+    let dir = scratch("single");
+    let svg = dir.join("art.svg");
+    write_svg(&svg);
+
+    let out = run(&["svg-optimize", s(&svg)]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let text = fs::read_to_string(dir.join("art.min.svg")).unwrap();
+    assert!(!text.contains('\n'));
+    assert!(!text.contains("<!--"));
+    assert!(text.contains("d=\"M 0.123 0"), "{text}");
+}
+
+#[test]
+fn drops_unreferenced_ids_and_collapses_groups() {
+    let dir = scratch("ids");
+    let svg = dir.join("art.svg");
+    write_svg(&svg);
+
+    let out = run(&["svg-optimize", s(&svg)]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let text = fs::read_to_string(dir.join("art.min.svg")).unwrap();
+    assert!(text.contains("id=\"fade\""), "{text}");
+    assert!(!text.contains("layer1") && !text.contains("box"), "{text}");
+    assert_eq!(text.matches("<g").count(), 1, "{text}");
+    assert!(text.contains("transform=\"matrix(2 0 0 2 4 4)\""), "{text}");
+
+    let out = run(&["svg-optimize", "--keep-ids", "--overwrite", s(&svg)]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let text = fs::read_to_string(dir.join("art.min.svg")).unwrap();
+    assert!(text.contains("id=\"layer1\"") && text.contains("id=\"box\""), "{text}");
+}
+
+#[test]
+fn output_renders_like_the_input() {
+    let dir = scratch("render");
+    let svg = dir.join("art.svg");
+    write_svg(&svg);
+    let min = dir.join("min.svg");
+
+    assert!(run(&["svg-optimize", s(&svg), "-o", s(&min)]).status.success());
+    let before = dir.join("before.png");
+    let after = dir.join("after.png");
+    assert!(run(&["rasterize", s(&svg), "-o", s(&before)]).status.success());
+    assert!(run(&["rasterize", s(&min), "-o", s(&after)]).status.success());
+
+    assert_eq!(image::open(&before).unwrap().to_rgba8(), image::open(&after).unwrap().to_rgba8());
+}
+
+#[test]
+fn precision_and_pretty() {
+    let dir = scratch("pretty");
+    let svg = dir.join("art.svg");
+    write_svg(&svg);
+    let out_svg = dir.join("pretty.svg");
+
+    let out = run(&["svg-optimize", s(&svg), "--pretty", "-p", "1", "-o", s(&out_svg)]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let text = fs::read_to_string(&out_svg).unwrap();
+    assert!(text.contains("\n  <"), "{text}");
+    assert!(text.contains("d=\"M 0.1 0"), "{text}");
+}
+
+#[test]
+fn refuses_to_overwrite() {
+    let dir = scratch("overwrite");
+    let svg = dir.join("art.svg");
+    write_svg(&svg);
+
+    assert!(run(&["svg-optimize", s(&svg)]).status.success());
+    assert!(!run(&["svg-optimize", s(&svg)]).status.success());
+    assert!(run(&["svg-optimize", "--overwrite", s(&svg)]).status.success());
+}
+
+#[test]
+fn batch_mirrors_tree() {
+    let dir = scratch("batch");
+    let input = dir.join("in");
+    write_svg(&input.join("a.svg"));
+    write_svg(&input.join("nested/b.svg"));
+    fs::write(input.join("notes.txt"), "skip").unwrap();
+    let output = dir.join("out");
+
+    let out = run(&["svg-optimize", "-r", s(&input), "-o", s(&output)]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    assert!(output.join("a.svg").is_file());
+    assert!(output.join("nested/b.svg").is_file());
+    assert!(!output.join("notes.txt").exists());
+}